@@ -2,5 +2,6 @@ extern crate build;
 
 // FIXME https://github.com/retep998/winapi-rs/pull/319
 fn main() {
+    build::link("bcrypt", false);
     build::link("ncrypt", true)
 }
@@ -1,23 +1,28 @@
 //! Bindings to winapi's certificate-chain related APIs.
 
+use std::io;
 use std::mem;
+use std::ops::BitOr;
+use std::ptr;
 use std::slice;
-use crypt32;
-use winapi;
+use std::time::SystemTime;
+use ffi;
 
 use cert_context::CertContext;
+use cert_store::CertStore;
 use Inner;
 
 /// A certificate chain context (consisting of multiple chains)
-pub struct CertChainContext(pub winapi::PCERT_CHAIN_CONTEXT);
+pub struct CertChainContext(pub ffi::PCERT_CHAIN_CONTEXT);
 
+// Chain contexts are immutable once built and reference counted atomically.
 unsafe impl Sync for CertChainContext {}
 unsafe impl Send for CertChainContext {}
 
 impl Clone for CertChainContext {
     fn clone(&self) -> Self {
         let rced = unsafe {
-            crypt32::CertDuplicateCertificateChain(self.0) as *mut _
+            ffi::CertDuplicateCertificateChain(self.0) as *mut _
         };
         CertChainContext(rced)
     }
@@ -26,7 +31,7 @@ impl Clone for CertChainContext {
 impl Drop for CertChainContext {
     fn drop(&mut self) {
         unsafe {
-            crypt32::CertFreeCertificateChain(self.0);
+            ffi::CertFreeCertificateChain(self.0);
         }
     }
 }
@@ -51,7 +56,7 @@ impl CertChainContext {
                 None
             } else {
                 let chain_slice = slice::from_raw_parts(
-                    cert_chain.rgpChain as *mut winapi::PCERT_SIMPLE_CHAIN,
+                    cert_chain.rgpChain as *mut ffi::PCERT_SIMPLE_CHAIN,
                     cert_chain.cChain as usize);
                 Some(CertChain(chain_slice[index], self.clone()))
             }
@@ -66,10 +71,341 @@ impl CertChainContext {
             idx: 0
         }
     }
+
+    /// Returns the combined trust status of all chains in this context.
+    pub fn trust_status(&self) -> TrustStatus {
+        unsafe { TrustStatus::from_raw(&(*self.0).TrustStatus) }
+    }
+
+    /// Verifies this chain context against the base chain policy.
+    ///
+    /// The returned error carries the status reported by Windows, so
+    /// `Error::kind` distinguishes untrusted roots, expired certificates and
+    /// revoked certificates from each other.
+    pub fn verify(&self) -> io::Result<()> {
+        unsafe {
+            let mut para: ffi::CERT_CHAIN_POLICY_PARA = mem::zeroed();
+            para.cbSize = mem::size_of_val(&para) as ffi::DWORD;
+
+            let mut status: ffi::CERT_CHAIN_POLICY_STATUS = mem::zeroed();
+            status.cbSize = mem::size_of_val(&status) as ffi::DWORD;
+
+            let res = ffi::CertVerifyCertificateChainPolicy(ffi::CERT_CHAIN_POLICY_BASE as ffi::LPCSTR,
+                                                            self.0,
+                                                            &mut para,
+                                                            &mut status);
+            if res == ffi::FALSE {
+                return Err(::error::last_error());
+            }
+
+            debug!("base chain policy returned {:#x} (chain {}, element {})",
+                   status.dwError,
+                   status.lChainIndex,
+                   status.lElementIndex);
+            if status.dwError != ffi::ERROR_SUCCESS {
+                Err(::error::from_code(status.dwError as i32))
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+/// How revocation of the certificates in a chain is checked.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RevocationCheck {
+    /// Revocation is not checked.
+    None,
+    /// Only the end certificate is checked.
+    EndCert,
+    /// Every certificate in the chain is checked.
+    Chain,
+    /// Every certificate in the chain except the root is checked.
+    ChainExcludeRoot,
+}
+
+/// A builder type for certificate chains.
+pub struct ChainBuilder<'a> {
+    cert: &'a CertContext,
+    usages: Vec<Vec<u8>>,
+    revocation: RevocationCheck,
+    cache_only: bool,
+    store: Option<CertStore>,
+    time: Option<SystemTime>,
+}
+
+impl<'a> ChainBuilder<'a> {
+    /// Creates a builder for the chain of `cert`.
+    pub fn new(cert: &'a CertContext) -> ChainBuilder<'a> {
+        ChainBuilder {
+            cert: cert,
+            usages: vec![],
+            revocation: RevocationCheck::None,
+            cache_only: false,
+            store: None,
+            time: None,
+        }
+    }
+
+    /// Requires the chain to be valid for the usage identified by `oid`.
+    ///
+    /// If called several times, the chain must be valid for every usage.
+    pub fn usage(&mut self, oid: &str) -> &mut ChainBuilder<'a> {
+        self.usages.push(oid.bytes().chain(Some(0)).collect());
+        self
+    }
+
+    /// Sets which certificates have their revocation status checked.
+    ///
+    /// Defaults to `RevocationCheck::None`.
+    pub fn revocation(&mut self, revocation: RevocationCheck) -> &mut ChainBuilder<'a> {
+        self.revocation = revocation;
+        self
+    }
+
+    /// If set, revocation is only checked against cached revocation lists
+    /// and responses rather than by accessing the network.
+    pub fn cache_only(&mut self, cache_only: bool) -> &mut ChainBuilder<'a> {
+        self.cache_only = cache_only;
+        self
+    }
+
+    /// Sets an additional store searched for intermediate certificates.
+    pub fn store(&mut self, store: CertStore) -> &mut ChainBuilder<'a> {
+        self.store = Some(store);
+        self
+    }
+
+    /// Sets the time at which the chain is validated.
+    ///
+    /// Defaults to the current time.
+    pub fn time(&mut self, time: SystemTime) -> &mut ChainBuilder<'a> {
+        self.time = Some(time);
+        self
+    }
+
+    /// Builds the chain.
+    ///
+    /// A chain is returned even if it is not trusted; use
+    /// `CertChainContext::trust_status` or `CertChainContext::verify` to
+    /// inspect it.
+    pub fn build(&self) -> io::Result<CertChainContext> {
+        let mut flags = match self.revocation {
+            RevocationCheck::None => 0,
+            RevocationCheck::EndCert => ffi::CERT_CHAIN_REVOCATION_CHECK_END_CERT,
+            RevocationCheck::Chain => ffi::CERT_CHAIN_REVOCATION_CHECK_CHAIN,
+            RevocationCheck::ChainExcludeRoot => {
+                ffi::CERT_CHAIN_REVOCATION_CHECK_CHAIN_EXCLUDE_ROOT
+            }
+        };
+        if self.cache_only {
+            flags |= ffi::CERT_CHAIN_REVOCATION_CHECK_CACHE_ONLY;
+        }
+
+        unsafe {
+            let mut para: ffi::CERT_CHAIN_PARA = mem::zeroed();
+            para.cbSize = mem::size_of_val(&para) as ffi::DWORD;
+            para.RequestedUsage.dwType = ffi::USAGE_MATCH_TYPE_AND;
+
+            let mut identifiers = self.usages
+                .iter()
+                .map(|oid| oid.as_ptr() as ffi::LPSTR)
+                .collect::<Vec<_>>();
+            para.RequestedUsage.Usage.cUsageIdentifier = try!(::dword_len(identifiers.len()));
+            para.RequestedUsage.Usage.rgpszUsageIdentifier = identifiers.as_mut_ptr();
+
+            let store = self.store
+                .as_ref()
+                .map(|s| s.as_inner())
+                .unwrap_or(ptr::null_mut());
+            let mut time = self.time.map(::system_time_to_filetime);
+            let time = time.as_mut().map(|t| t as *mut _).unwrap_or(ptr::null_mut());
+
+            let mut chain = mem::zeroed();
+            let res = ffi::CertGetCertificateChain(ffi::HCCE_CURRENT_USER,
+                                                   self.cert.as_inner(),
+                                                   time,
+                                                   store,
+                                                   &mut para as *mut _ as *mut _,
+                                                   flags,
+                                                   ptr::null_mut(),
+                                                   &mut chain);
+            if res == ffi::TRUE {
+                Ok(CertChainContext(chain as *mut _))
+            } else {
+                Err(::error::last_error())
+            }
+        }
+    }
+}
+
+/// The trust status of a certificate chain.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TrustStatus {
+    errors: TrustErrors,
+    info: TrustInfo,
+}
+
+impl TrustStatus {
+    fn from_raw(status: &ffi::CERT_TRUST_STATUS) -> TrustStatus {
+        TrustStatus {
+            errors: TrustErrors(status.dwErrorStatus),
+            info: TrustInfo(status.dwInfoStatus),
+        }
+    }
+
+    /// Returns the errors found while building the chain.
+    pub fn errors(&self) -> TrustErrors {
+        self.errors
+    }
+
+    /// Returns informational flags about the chain.
+    pub fn info(&self) -> TrustInfo {
+        self.info
+    }
+
+    /// Determines if no errors were found while building the chain.
+    pub fn is_ok(&self) -> bool {
+        self.errors == TrustErrors::none()
+    }
+}
+
+/// Errors found while building a certificate chain.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TrustErrors(ffi::DWORD);
+
+impl TrustErrors {
+    /// No errors.
+    pub fn none() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_NO_ERROR)
+    }
+
+    /// A certificate is expired or not yet valid.
+    pub fn not_time_valid() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_IS_NOT_TIME_VALID)
+    }
+
+    /// A certificate has been revoked.
+    pub fn revoked() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_IS_REVOKED)
+    }
+
+    /// A certificate's signature is invalid.
+    pub fn not_signature_valid() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_IS_NOT_SIGNATURE_VALID)
+    }
+
+    /// The chain is not valid for the requested usages.
+    pub fn not_valid_for_usage() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_IS_NOT_VALID_FOR_USAGE)
+    }
+
+    /// The chain ends in a root which is not trusted.
+    pub fn untrusted_root() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_IS_UNTRUSTED_ROOT)
+    }
+
+    /// The revocation status of a certificate could not be determined.
+    pub fn revocation_status_unknown() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_REVOCATION_STATUS_UNKNOWN)
+    }
+
+    /// A certificate in the chain issued itself indirectly.
+    pub fn cyclic() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_IS_CYCLIC)
+    }
+
+    /// The chain could not be built up to a root.
+    pub fn partial_chain() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_IS_PARTIAL_CHAIN)
+    }
+
+    /// Revocation could not be checked because the revocation server was
+    /// offline.
+    pub fn offline_revocation() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_IS_OFFLINE_REVOCATION)
+    }
+
+    /// A certificate in the chain is explicitly distrusted.
+    pub fn explicit_distrust() -> TrustErrors {
+        TrustErrors(ffi::CERT_TRUST_IS_EXPLICIT_DISTRUST)
+    }
+
+    /// Determines if every error in `other` is present.
+    pub fn contains(&self, other: TrustErrors) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the raw `CERT_TRUST_*` error flags.
+    pub fn as_raw(&self) -> ffi::DWORD {
+        self.0
+    }
+}
+
+impl BitOr for TrustErrors {
+    type Output = TrustErrors;
+
+    fn bitor(self, other: TrustErrors) -> TrustErrors {
+        TrustErrors(self.0 | other.0)
+    }
+}
+
+/// Informational flags about a certificate chain.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TrustInfo(ffi::DWORD);
+
+impl TrustInfo {
+    /// An issuer was found by an exact match.
+    pub fn exact_match_issuer() -> TrustInfo {
+        TrustInfo(ffi::CERT_TRUST_HAS_EXACT_MATCH_ISSUER)
+    }
+
+    /// An issuer was found by matching its key.
+    pub fn key_match_issuer() -> TrustInfo {
+        TrustInfo(ffi::CERT_TRUST_HAS_KEY_MATCH_ISSUER)
+    }
+
+    /// An issuer was found by matching its name.
+    pub fn name_match_issuer() -> TrustInfo {
+        TrustInfo(ffi::CERT_TRUST_HAS_NAME_MATCH_ISSUER)
+    }
+
+    /// The certificate is self-signed.
+    pub fn self_signed() -> TrustInfo {
+        TrustInfo(ffi::CERT_TRUST_IS_SELF_SIGNED)
+    }
+
+    /// The chain has a preferred issuer.
+    pub fn preferred_issuer() -> TrustInfo {
+        TrustInfo(ffi::CERT_TRUST_HAS_PREFERRED_ISSUER)
+    }
+
+    /// The context contains more than one chain.
+    pub fn complex_chain() -> TrustInfo {
+        TrustInfo(ffi::CERT_TRUST_IS_COMPLEX_CHAIN)
+    }
+
+    /// Determines if every flag in `other` is present.
+    pub fn contains(&self, other: TrustInfo) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the raw `CERT_TRUST_*` info flags.
+    pub fn as_raw(&self) -> ffi::DWORD {
+        self.0
+    }
+}
+
+impl BitOr for TrustInfo {
+    type Output = TrustInfo;
+
+    fn bitor(self, other: TrustInfo) -> TrustInfo {
+        TrustInfo(self.0 | other.0)
+    }
 }
 
 /// A (simple) certificate chain
-pub struct CertChain(winapi::PCERT_SIMPLE_CHAIN, CertChainContext);
+pub struct CertChain(ffi::PCERT_SIMPLE_CHAIN, CertChainContext);
 
 impl CertChain {
     /// Returns the number of certificates in the chain
@@ -84,7 +420,7 @@ impl CertChain {
         let elements = unsafe {
             let cert_chain = *self.0;
             slice::from_raw_parts(
-                cert_chain.rgpElement as *mut &mut winapi::CERT_CHAIN_ELEMENT,
+                cert_chain.rgpElement as *mut &mut ffi::CERT_CHAIN_ELEMENT,
                 cert_chain.cElement as usize)
         };
         elements.get(idx).map(|el| {
@@ -104,6 +440,11 @@ impl CertChain {
             idx: 0,
         }
     }
+
+    /// Returns the trust status of this chain.
+    pub fn trust_status(&self) -> TrustStatus {
+        unsafe { TrustStatus::from_raw(&(*self.0).TrustStatus) }
+    }
 }
 
 
@@ -138,3 +479,73 @@ impl<'a> Iterator for Certificates<'a> {
         self.chain.get(idx)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use cert_context::CertContext;
+    use cert_store::Memory;
+    use ErrorKind;
+    use super::*;
+
+    #[test]
+    fn self_signed() {
+        let der = include_bytes!("../test/self-signed.badssl.com.cer");
+        let cert = CertContext::new(der).unwrap();
+        let chain = cert.chain()
+            .time(UNIX_EPOCH + Duration::from_secs(1500000000))
+            .build()
+            .unwrap();
+
+        let status = chain.trust_status();
+        assert!(!status.is_ok());
+        assert!(status.errors().contains(TrustErrors::untrusted_root()));
+        assert!(!status.errors().contains(TrustErrors::not_time_valid()));
+        assert!(status.info().contains(TrustInfo::self_signed()));
+
+        let certs = chain.final_chain().unwrap().certificates().collect::<Vec<_>>();
+        assert_eq!(certs, vec![cert.clone()]);
+        assert_eq!(chain.clone().trust_status(), status);
+
+        let err = ::Error::from(chain.verify().unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::UntrustedRoot);
+    }
+
+    #[test]
+    fn expired() {
+        let der = include_bytes!("../test/self-signed.badssl.com.cer");
+        let cert = CertContext::new(der).unwrap();
+        let chain = cert.chain()
+            .time(UNIX_EPOCH + Duration::from_secs(1600000000))
+            .build()
+            .unwrap();
+
+        let status = chain.trust_status();
+        assert!(status.errors()
+                    .contains(TrustErrors::not_time_valid() | TrustErrors::untrusted_root()));
+        assert!(chain.final_chain().unwrap().trust_status().errors()
+                    .contains(TrustErrors::not_time_valid()));
+    }
+
+    #[test]
+    fn partial_chain() {
+        let mut store = Memory::new().unwrap();
+        let cert = store.add_encoded_certificate(include_bytes!("../test/cert.der")).unwrap();
+
+        let chain = cert.chain()
+            .usage(::ffi::szOID_PKIX_KP_SERVER_AUTH)
+            .revocation(RevocationCheck::ChainExcludeRoot)
+            .cache_only(true)
+            .store(store.into_store())
+            .time(UNIX_EPOCH + Duration::from_secs(1500000000))
+            .build()
+            .unwrap();
+
+        let status = chain.trust_status();
+        assert!(status.errors().contains(TrustErrors::partial_chain()));
+        assert!(!status.info().contains(TrustInfo::self_signed()));
+        assert_eq!(chain.final_chain().unwrap().len(), 1);
+        assert!(chain.verify().is_err());
+    }
+}
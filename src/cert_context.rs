@@ -1,86 +1,271 @@
 //! Bindings to winapi's `PCCERT_CONTEXT` APIs.
 
-use std::ffi::OsString;
+use ffi;
+use std::borrow::Cow;
+use std::ffi::CStr;
 use std::io;
 use std::mem;
-use std::os::windows::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::BitOr;
 use std::ptr;
 use std::slice;
-use crypt32;
-use winapi;
+use std::time::SystemTime;
 
-use Inner;
-use ncrypt_key::NcryptKey;
+use {FromCertKey, FromCertProv, Inner};
+use cert_chain::ChainBuilder;
+use ctl_context::CtlContext;
+use hash;
+use ncrypt_key::{self, NcryptKey, NcryptProvider};
 use crypt_prov::{CryptProv, ProviderType};
+use os_support::{self, Feature};
 
-// FIXME https://github.com/retep998/winapi-rs/pull/318
-const CRYPT_ACQUIRE_COMPARE_KEY_FLAG: winapi::DWORD = 0x4;
-const CRYPT_ACQUIRE_SILENT_FLAG: winapi::DWORD = 0x40;
-const CRYPT_ACQUIRE_ALLOW_NCRYPT_KEY_FLAG: winapi::DWORD = 0x10000;
-
-// FIXME
-const CRYPT_STRING_BASE64HEADER: winapi::DWORD = 0x0;
+lazy_static! {
+    static ref szOID_KEY_USAGE: Vec<u8> =
+        ffi::szOID_KEY_USAGE.bytes().chain(Some(0)).collect();
+    static ref szOID_SUBJECT_ALT_NAME2: Vec<u8> =
+        ffi::szOID_SUBJECT_ALT_NAME2.bytes().chain(Some(0)).collect();
+}
 
 /// A supported hashing algorithm
-pub struct HashAlgorithm(winapi::DWORD, usize);
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashAlgorithm(ffi::DWORD, usize, Cow<'static, str>);
 
 #[allow(missing_docs)]
 impl HashAlgorithm {
     pub fn md5() -> HashAlgorithm {
-        HashAlgorithm(winapi::CALG_MD5, 16)
+        HashAlgorithm(ffi::CALG_MD5, 16, Cow::Borrowed("MD5"))
     }
 
     pub fn sha1() -> HashAlgorithm{
-        HashAlgorithm(winapi::CALG_SHA1, 20)
+        HashAlgorithm(ffi::CALG_SHA1, 20, Cow::Borrowed("SHA1"))
     }
 
     pub fn sha256() -> HashAlgorithm {
-        HashAlgorithm(winapi::CALG_SHA_256, 32)
+        HashAlgorithm(ffi::CALG_SHA_256, 32, Cow::Borrowed("SHA256"))
     }
 
     pub fn sha384() -> HashAlgorithm {
-        HashAlgorithm(winapi::CALG_SHA_384, 48)
+        HashAlgorithm(ffi::CALG_SHA_384, 48, Cow::Borrowed("SHA384"))
     }
 
     pub fn sha512() -> HashAlgorithm {
-        HashAlgorithm(winapi::CALG_SHA_512, 64)
+        HashAlgorithm(ffi::CALG_SHA_512, 64, Cow::Borrowed("SHA512"))
+    }
+
+    /// Returns the CNG hash algorithm with the specified identifier, such as
+    /// `SHA3-256`, and digest length in bytes.
+    ///
+    /// Algorithms without a CryptoAPI equivalent can be used with CNG keys
+    /// and for fingerprints, but not with CryptoAPI providers. Fails with an
+    /// error of kind `InvalidInput` if `digest_len` is wrong for one of the
+    /// algorithms above.
+    pub fn from_cng_name(name: &str, digest_len: usize) -> io::Result<HashAlgorithm> {
+        let alg = match name {
+            "MD5" => HashAlgorithm::md5(),
+            "SHA1" => HashAlgorithm::sha1(),
+            "SHA256" => HashAlgorithm::sha256(),
+            "SHA384" => HashAlgorithm::sha384(),
+            "SHA512" => HashAlgorithm::sha512(),
+            _ => return Ok(HashAlgorithm(0, digest_len, Cow::Owned(name.to_owned()))),
+        };
+        if alg.digest_len() != digest_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "digest length does not match the hash algorithm"));
+        }
+        Ok(alg)
+    }
+
+    fn hash(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        hash::hash(self.clone(), data)
+    }
+
+    /// Returns the CryptoAPI `ALG_ID` of this algorithm, or 0 if it has no
+    /// CryptoAPI equivalent.
+    pub fn as_raw(&self) -> ffi::ALG_ID {
+        self.0
+    }
+
+    /// Returns the length in bytes of digests produced by this algorithm.
+    pub fn digest_len(&self) -> usize {
+        self.1
+    }
+
+    /// Returns the CNG identifier of this algorithm, such as `SHA256`.
+    pub fn cng_name(&self) -> &str {
+        &self.2
+    }
+}
+
+/// The padding scheme of a signature.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Padding {
+    /// PKCS #1 v1.5 padding, for RSA keys.
+    Pkcs1,
+    /// PSS padding with a salt of the specified length in bytes, for RSA
+    /// keys.
+    Pss(u32),
+    /// No padding, for ECDSA keys, with the signature in the specified
+    /// format.
+    Ecdsa(EcdsaFormat),
+}
+
+/// The encoding of an ECDSA signature.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EcdsaFormat {
+    /// The fixed-width big-endian concatenation `r || s` produced natively by
+    /// CNG, as used by JWS and PKCS #11.
+    Raw,
+    /// The DER-encoded `Ecdsa-Sig-Value` sequence, as used by X.509 and TLS.
+    Der,
+}
+
+/// The padding scheme of RSA encryption.
+#[derive(Clone, Debug)]
+pub enum RsaPadding {
+    /// PKCS #1 v1.5 padding.
+    Pkcs1,
+    /// OAEP padding using the specified hash algorithm.
+    ///
+    /// CryptoAPI providers only support SHA-1.
+    Oaep(HashAlgorithm),
+}
+
+/// CNG padding information for an operation, along with the hash algorithm
+/// name it points to.
+// the names are only held to keep the structures' pointers valid
+#[allow(dead_code)]
+enum PaddingInfo {
+    Flags(ffi::DWORD),
+    Pkcs1(ffi::BCRYPT_PKCS1_PADDING_INFO, Vec<u16>),
+    Pss(ffi::BCRYPT_PSS_PADDING_INFO, Vec<u16>),
+    Oaep(ffi::BCRYPT_OAEP_PADDING_INFO, Vec<u16>),
+}
+
+impl PaddingInfo {
+    fn signature(hash: HashAlgorithm, padding: Padding) -> PaddingInfo {
+        let name = hash.2.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        match padding {
+            Padding::Pkcs1 => {
+                PaddingInfo::Pkcs1(ffi::BCRYPT_PKCS1_PADDING_INFO { pszAlgId: name.as_ptr() },
+                                   name)
+            }
+            Padding::Pss(salt) => {
+                PaddingInfo::Pss(ffi::BCRYPT_PSS_PADDING_INFO {
+                                     pszAlgId: name.as_ptr(),
+                                     cbSalt: salt,
+                                 },
+                                 name)
+            }
+            Padding::Ecdsa(_) => PaddingInfo::Flags(0),
+        }
+    }
+
+    fn encryption(padding: RsaPadding) -> PaddingInfo {
+        match padding {
+            RsaPadding::Pkcs1 => PaddingInfo::Flags(ffi::BCRYPT_PAD_PKCS1),
+            RsaPadding::Oaep(hash) => {
+                let name = hash.2.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+                PaddingInfo::Oaep(ffi::BCRYPT_OAEP_PADDING_INFO {
+                                      pszAlgId: name.as_ptr(),
+                                      pbLabel: ptr::null_mut(),
+                                      cbLabel: 0,
+                                  },
+                                  name)
+            }
+        }
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut ffi::c_void {
+        match *self {
+            PaddingInfo::Flags(_) => ptr::null_mut(),
+            PaddingInfo::Pkcs1(ref mut info, _) => info as *mut _ as *mut _,
+            PaddingInfo::Pss(ref mut info, _) => info as *mut _ as *mut _,
+            PaddingInfo::Oaep(ref mut info, _) => info as *mut _ as *mut _,
+        }
+    }
+
+    fn flags(&self) -> ffi::DWORD {
+        match *self {
+            PaddingInfo::Flags(flags) => flags,
+            PaddingInfo::Pkcs1(..) => ffi::BCRYPT_PAD_PKCS1,
+            PaddingInfo::Pss(..) => ffi::BCRYPT_PAD_PSS,
+            PaddingInfo::Oaep(..) => ffi::BCRYPT_PAD_OAEP,
+        }
     }
 }
 
 /// Wrapper of a winapi certificate, or a `PCCERT_CONTEXT`.
 #[derive(Debug)]
-pub struct CertContext(winapi::PCCERT_CONTEXT);
+pub struct CertContext(ffi::PCCERT_CONTEXT);
 
+// Certificate contexts are reference counted atomically and are not modified
+// through shared references, apart from properties which their store locks.
 unsafe impl Sync for CertContext {}
 unsafe impl Send for CertContext {}
 
 impl Drop for CertContext {
     fn drop(&mut self) {
         unsafe {
-            crypt32::CertFreeCertificateContext(self.0);
+            ffi::CertFreeCertificateContext(self.0);
         }
     }
 }
 
 impl Clone for CertContext {
     fn clone(&self) -> CertContext {
-        unsafe { CertContext(crypt32::CertDuplicateCertificateContext(self.0)) }
+        unsafe { CertContext(ffi::CertDuplicateCertificateContext(self.0)) }
     }
 }
 
-inner!(CertContext, winapi::PCCERT_CONTEXT);
+inner!(CertContext, ffi::PCCERT_CONTEXT);
 
 impl CertContext {
+    /// Returns the raw `PCCERT_CONTEXT`.
+    ///
+    /// The certificate context remains owned by this value, and is only valid
+    /// for as long as it is alive. Callers who need to keep it longer should
+    /// take their own reference with `CertDuplicateCertificateContext`.
+    pub fn as_raw(&self) -> ffi::PCCERT_CONTEXT {
+        self.0
+    }
+
+    /// Consumes this value, returning the raw `PCCERT_CONTEXT` along with the
+    /// reference it held.
+    ///
+    /// The caller becomes responsible for releasing it with
+    /// `CertFreeCertificateContext`, or for passing it back to `from_raw`.
+    pub fn into_raw(self) -> ffi::PCCERT_CONTEXT {
+        let raw = self.0;
+        mem::forget(self);
+        raw
+    }
+
+    /// Wraps a raw `PCCERT_CONTEXT`, taking ownership of one reference to it.
+    ///
+    /// The reference is released with `CertFreeCertificateContext` when the
+    /// returned value is dropped. To wrap a certificate context while keeping
+    /// the caller's reference, take a new one with
+    /// `CertDuplicateCertificateContext` first.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must be a valid certificate context which the caller owns a
+    /// reference to.
+    pub unsafe fn from_raw(raw: ffi::PCCERT_CONTEXT) -> CertContext {
+        CertContext(raw)
+    }
+
     /// Decodes a DER-formatted X509 certificate.
     pub fn new(data: &[u8]) -> io::Result<CertContext> {
+        let len = try!(::dword_len(data.len()));
         let ret = unsafe {
-            crypt32::CertCreateCertificateContext(winapi::X509_ASN_ENCODING |
-                                                  winapi::PKCS_7_ASN_ENCODING,
-                                                  data.as_ptr(),
-                                                  data.len() as winapi::DWORD)
+            ffi::CertCreateCertificateContext(ffi::X509_ASN_ENCODING |
+                                              ffi::PKCS_7_ASN_ENCODING,
+                                              data.as_ptr(),
+                                              len)
         };
         if ret.is_null() {
-            Err(io::Error::last_os_error())
+            Err(::error::last_error())
         } else {
             Ok(CertContext(ret))
         }
@@ -89,54 +274,147 @@ impl CertContext {
     /// Decodes a PEM-formatted X509 certificate.
     pub fn from_pem(pem: &str) -> io::Result<CertContext> {
         unsafe {
-            assert!(pem.len() <= winapi::DWORD::max_value() as usize);
+            let pem_len = try!(::dword_len(pem.len()));
 
             let mut len = 0;
-            let ok = crypt32::CryptStringToBinaryA(pem.as_ptr() as winapi::LPCSTR,
-                                                   pem.len() as winapi::DWORD,
-                                                   CRYPT_STRING_BASE64HEADER,
-                                                   ptr::null_mut(),
-                                                   &mut len,
-                                                   ptr::null_mut(),
-                                                   ptr::null_mut());
-            if ok != winapi::TRUE {
-                return Err(io::Error::last_os_error());
+            let ok = ffi::CryptStringToBinaryA(pem.as_ptr() as ffi::LPCSTR,
+                                               pem_len,
+                                               ffi::CRYPT_STRING_BASE64HEADER,
+                                               ptr::null_mut(),
+                                               &mut len,
+                                               ptr::null_mut(),
+                                               ptr::null_mut());
+            if ok != ffi::TRUE {
+                return Err(::error::last_error());
             }
 
             let mut buf = vec![0; len as usize];
-            let ok = crypt32::CryptStringToBinaryA(pem.as_ptr() as winapi::LPCSTR,
-                                                   pem.len() as winapi::DWORD,
-                                                   CRYPT_STRING_BASE64HEADER,
-                                                   buf.as_mut_ptr(),
-                                                   &mut len,
-                                                   ptr::null_mut(),
-                                                   ptr::null_mut());
-            if ok != winapi::TRUE {
-                return Err(io::Error::last_os_error());
+            let ok = ffi::CryptStringToBinaryA(pem.as_ptr() as ffi::LPCSTR,
+                                               pem_len,
+                                               ffi::CRYPT_STRING_BASE64HEADER,
+                                               buf.as_mut_ptr(),
+                                               &mut len,
+                                               ptr::null_mut(),
+                                               ptr::null_mut());
+            if ok != ffi::TRUE {
+                return Err(::error::last_error());
             }
 
             CertContext::new(&buf)
         }
     }
 
+    /// Returns the DER encoding of this certificate.
+    pub fn to_der(&self) -> &[u8] {
+        unsafe {
+            let cert_ctx = *self.0;
+            slice::from_raw_parts(cert_ctx.pbCertEncoded, cert_ctx.cbCertEncoded as usize)
+        }
+    }
+
+    /// Returns the PEM encoding of this certificate.
+    ///
+    /// Lines are terminated by CRLF.
+    pub fn to_pem(&self) -> io::Result<String> {
+        unsafe {
+            let der = self.to_der();
+            let der_len = try!(::dword_len(der.len()));
+
+            let mut len = 0;
+            let ok = ffi::CryptBinaryToStringA(der.as_ptr(),
+                                               der_len,
+                                               ffi::CRYPT_STRING_BASE64HEADER,
+                                               ptr::null_mut(),
+                                               &mut len);
+            if ok != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+
+            let mut buf = vec![0u8; len as usize];
+            let ok = ffi::CryptBinaryToStringA(der.as_ptr(),
+                                               der_len,
+                                               ffi::CRYPT_STRING_BASE64HEADER,
+                                               buf.as_mut_ptr() as ffi::LPSTR,
+                                               &mut len);
+            if ok != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+            // the length no longer counts the trailing nul
+            buf.truncate(len as usize);
+            String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+
     /// Returns a hash of this certificate
     pub fn fingerprint(&self, alg: HashAlgorithm) -> io::Result<Vec<u8>> {
+        if alg.0 == 0 {
+            return hash::hash(alg, self.to_der());
+        }
+
+        // CryptoAPI caches SHA-256 thumbprints like SHA-1 ones from Windows 8
+        if alg.0 == ffi::CALG_SHA_256 &&
+           os_support::os_capabilities().supports(Feature::Sha256Find) {
+            return self.get_bytes(ffi::CERT_SHA256_HASH_PROP_ID);
+        }
+
         unsafe {
             let mut buf = vec![0u8; alg.1];
-            let mut len = buf.len() as winapi::DWORD;
+            let mut len = try!(::dword_len(buf.len()));
+
+            let ret = ffi::CryptHashCertificate(0,
+                                                alg.0,
+                                                0,
+                                                (*self.0).pbCertEncoded,
+                                                (*self.0).cbCertEncoded,
+                                                buf.as_mut_ptr(),
+                                                &mut len);
+
+            if ret != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+            Ok(buf)
+        }
+    }
+
+    /// Returns the DER-encoded `SubjectPublicKeyInfo` of this certificate.
+    pub fn public_key(&self) -> io::Result<Vec<u8>> {
+        unsafe { ::encode_public_key_info(&(*(*self.0).pCertInfo).SubjectPublicKeyInfo) }
+    }
+
+    /// Verifies a signature over `data` made by the private key
+    /// corresponding to this certificate.
+    ///
+    /// Returns `Ok(false)` if the signature does not match.
+    pub fn verify_signature(&self,
+                            data: &[u8],
+                            hash: HashAlgorithm,
+                            padding: Padding,
+                            signature: &[u8])
+                            -> io::Result<bool> {
+        try!(self.import_public_key()).verify(data, hash, padding, signature)
+    }
 
-            let ret = crypt32::CryptHashCertificate(0,
-                                                    alg.0,
-                                                    0,
-                                                    (*self.0).pbCertEncoded,
-                                                    (*self.0).cbCertEncoded,
-                                                    buf.as_mut_ptr(),
-                                                    &mut len);
+    /// Encrypts `data` with the RSA public key of this certificate.
+    ///
+    /// The result can be decrypted with `PrivateKey::decrypt`.
+    pub fn encrypt(&self, data: &[u8], padding: RsaPadding) -> io::Result<Vec<u8>> {
+        try!(self.import_public_key()).encrypt(data, padding)
+    }
 
-            if ret != winapi::TRUE {
-                return Err(io::Error::last_os_error());
+    /// Imports the public key of this certificate into CNG.
+    fn import_public_key(&self) -> io::Result<PublicKey> {
+        unsafe {
+            let mut key = ptr::null_mut();
+            let res = ffi::CryptImportPublicKeyInfoEx2(ffi::X509_ASN_ENCODING,
+                                                       &mut (*(*self.0).pCertInfo)
+                                                               .SubjectPublicKeyInfo,
+                                                       0,
+                                                       ptr::null_mut(),
+                                                       &mut key);
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
             }
-            Ok(buf)
+            Ok(PublicKey(key))
         }
     }
 
@@ -163,38 +441,260 @@ impl CertContext {
     /// * `RSA/SHA256`
     /// * `ECDSA/SHA256`
     pub fn sign_hash_algorithms(&self) -> io::Result<String> {
-        self.get_string(winapi::CERT_SIGN_HASH_CNG_ALG_PROP_ID)
+        self.get_string(ffi::CERT_SIGN_HASH_CNG_ALG_PROP_ID)
     }
 
     /// Returns the signature hash.
     pub fn signature_hash(&self) -> io::Result<Vec<u8>> {
-        self.get_bytes(winapi::CERT_SIGNATURE_HASH_PROP_ID)
+        self.get_bytes(ffi::CERT_SIGNATURE_HASH_PROP_ID)
     }
 
     /// Returns the property displayed by the certificate UI. This property
     /// allows the user to describe the certificate's use.
     pub fn description(&self) -> io::Result<Vec<u8>> {
-        self.get_bytes(winapi::CERT_DESCRIPTION_PROP_ID)
+        self.get_bytes(ffi::CERT_DESCRIPTION_PROP_ID)
     }
 
     /// Returns a string that contains the display name for the certificate.
     pub fn friendly_name(&self) -> io::Result<String> {
-        self.get_string(winapi::CERT_FRIENDLY_NAME_PROP_ID)
+        self.get_string(ffi::CERT_FRIENDLY_NAME_PROP_ID)
     }
 
     /// Configures the string that contains the display name for this
     /// certificate.
     pub fn set_friendly_name(&self, name: &str) -> io::Result<()> {
-        self.set_string(winapi::CERT_FRIENDLY_NAME_PROP_ID, name)
+        self.set_string(ffi::CERT_FRIENDLY_NAME_PROP_ID, name)
+    }
+
+    /// Returns the DER-encoded OCSP response attached to this certificate, if
+    /// any.
+    ///
+    /// Schannel attaches the response stapled by a server to the
+    /// certificate it presented.
+    pub fn ocsp_response(&self) -> io::Result<Option<Vec<u8>>> {
+        self.get_raw_property(ffi::CERT_OCSP_RESPONSE_PROP_ID)
+    }
+
+    /// Attaches a DER-encoded OCSP response to this certificate.
+    ///
+    /// Servers using the certificate staple the response for clients which
+    /// ask for one.
+    pub fn set_ocsp_response(&self, response: &[u8]) -> io::Result<()> {
+        self.set_raw_property(ffi::CERT_OCSP_RESPONSE_PROP_ID, response)
+    }
+
+    /// Returns the raw value of the specified property of this certificate,
+    /// or `None` if it is not set.
+    pub fn get_raw_property(&self, prop: ffi::DWORD) -> io::Result<Option<Vec<u8>>> {
+        match self.get_bytes(prop) {
+            Ok(buf) => Ok(Some(buf)),
+            Err(ref e) if ::error::code(e) == Some(ffi::CRYPT_E_NOT_FOUND) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sets the specified property of this certificate to a
+    /// `CRYPT_DATA_BLOB` holding `value`.
+    pub fn set_raw_property(&self, prop: ffi::DWORD, value: &[u8]) -> io::Result<()> {
+        unsafe {
+            ::set_property(ffi::CertSetCertificateContextProperty, self.0, prop, value)
+        }
+    }
+
+    /// Returns the subject of this certificate in X.500 format, such as
+    /// `C=AU, S=Some-State, CN=foobar.com`.
+    pub fn subject(&self) -> io::Result<String> {
+        name_to_string(&self.info().Subject)
+    }
+
+    /// Returns the DER-encoded subject name of this certificate.
+    pub fn subject_der(&self) -> Vec<u8> {
+        blob_bytes(&self.info().Subject).to_owned()
+    }
+
+    /// Returns the issuer of this certificate in X.500 format, such as
+    /// `C=AU, S=Some-State, O=Internet Widgits Pty Ltd`.
+    pub fn issuer(&self) -> io::Result<String> {
+        name_to_string(&self.info().Issuer)
+    }
+
+    /// Returns the DER-encoded issuer name of this certificate.
+    pub fn issuer_der(&self) -> Vec<u8> {
+        blob_bytes(&self.info().Issuer).to_owned()
+    }
+
+    /// Returns the DER-encoded value of the extension of this certificate
+    /// identified by `oid`, such as `2.5.29.17`, or `None` if it has no such
+    /// extension.
+    pub fn extension(&self, oid: &str) -> io::Result<Option<Vec<u8>>> {
+        if oid.contains('\0') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "OID contains a nul byte"));
+        }
+        let oid = oid.bytes().chain(Some(0)).collect::<Vec<_>>();
+        Ok(self.find_extension(&oid).map(|ext| blob_bytes(&ext.Value).to_owned()))
+    }
+
+    /// Returns the subject alternative names of this certificate, decoded
+    /// from its subject alternative name extension.
+    ///
+    /// Names of kinds which `AltName` does not represent, such as directory
+    /// names, are skipped. Returns an empty list if the certificate has no
+    /// such extension.
+    pub fn subject_alt_names(&self) -> io::Result<Vec<AltName>> {
+        let ext = match self.find_extension(&szOID_SUBJECT_ALT_NAME2) {
+            Some(ext) => ext,
+            None => return Ok(vec![]),
+        };
+
+        let mut alt_names = vec![];
+        unsafe {
+            let mut info: *mut ffi::CERT_ALT_NAME_INFO = ptr::null_mut();
+            let mut len = 0;
+            let res = ffi::CryptDecodeObjectEx(ffi::X509_ASN_ENCODING,
+                                               ffi::X509_ALTERNATE_NAME,
+                                               ext.Value.pbData,
+                                               ext.Value.cbData,
+                                               ffi::CRYPT_DECODE_ALLOC_FLAG,
+                                               ptr::null_mut(),
+                                               &mut info as *mut _ as *mut ffi::c_void,
+                                               &mut len);
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+
+            let entries = if (*info).cAltEntry == 0 {
+                &[][..]
+            } else {
+                slice::from_raw_parts((*info).rgAltEntry, (*info).cAltEntry as usize)
+            };
+            for entry in entries {
+                let alt_name = match entry.dwAltNameChoice {
+                    ffi::CERT_ALT_NAME_DNS_NAME => {
+                        AltName::Dns(::from_wide_ptr(ffi::alt_name_string(entry)))
+                    }
+                    ffi::CERT_ALT_NAME_RFC822_NAME => {
+                        AltName::Email(::from_wide_ptr(ffi::alt_name_string(entry)))
+                    }
+                    ffi::CERT_ALT_NAME_URL => {
+                        AltName::Uri(::from_wide_ptr(ffi::alt_name_string(entry)))
+                    }
+                    ffi::CERT_ALT_NAME_IP_ADDRESS => {
+                        match ip_from_bytes(blob_bytes(ffi::alt_name_blob(entry))) {
+                            Some(ip) => AltName::Ip(ip),
+                            None => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+                alt_names.push(alt_name);
+            }
+            ffi::LocalFree(info as *mut _);
+        }
+        Ok(alt_names)
+    }
+
+    /// Returns the uses this certificate is valid for according to its
+    /// enhanced key usage, as found in `source`.
+    ///
+    /// A certificate without an enhanced key usage is valid for all uses,
+    /// which is reported as `ValidUses::All` rather than an empty list.
+    pub fn enhanced_key_usage(&self, source: UsageSource) -> io::Result<ValidUses> {
+        unsafe {
+            let mut len = 0;
+            let ret = ffi::CertGetEnhancedKeyUsage(self.0, source.0, ptr::null_mut(), &mut len);
+            if ret != ffi::TRUE {
+                let err = ::error::last_error();
+                if ::error::code(&err) == Some(ffi::CRYPT_E_NOT_FOUND) {
+                    return Ok(ValidUses::All);
+                }
+                return Err(err);
+            }
+
+            let mut buf = vec![0u8; len as usize];
+            let ret = ffi::CertGetEnhancedKeyUsage(self.0,
+                                                   source.0,
+                                                   buf.as_mut_ptr() as *mut _,
+                                                   &mut len);
+            if ret != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+
+            // an empty usage means either all uses or none, which is told
+            // apart by the last error
+            let usage = &*(buf.as_ptr() as *const ffi::CTL_USAGE);
+            if usage.cUsageIdentifier == 0 {
+                let err = io::Error::last_os_error();
+                if ::error::code(&err) == Some(ffi::CRYPT_E_NOT_FOUND) {
+                    return Ok(ValidUses::All);
+                }
+                return Ok(ValidUses::Oids(vec![]));
+            }
+
+            let oids = slice::from_raw_parts(usage.rgpszUsageIdentifier,
+                                             usage.cUsageIdentifier as usize)
+                .iter()
+                .map(|&oid| CStr::from_ptr(oid as *const _).to_string_lossy().into_owned())
+                .collect();
+            Ok(ValidUses::Oids(oids))
+        }
+    }
+
+    /// Returns the time from which this certificate is valid.
+    pub fn valid_from(&self) -> SystemTime {
+        ::filetime_to_system_time(&self.info().NotBefore)
+    }
+
+    /// Returns the time until which this certificate is valid.
+    ///
+    /// Certificates without a well-defined expiration date use
+    /// 9999-12-31 23:59:59 UTC.
+    pub fn valid_to(&self) -> SystemTime {
+        ::filetime_to_system_time(&self.info().NotAfter)
     }
 
     /// Verifies the time validity of this certificate relative to the system's
     /// current time.
     pub fn is_time_valid(&self) -> io::Result<bool> {
-        let ret = unsafe { crypt32::CertVerifyTimeValidity(ptr::null_mut(), (*self.0).pCertInfo) };
+        let ret = unsafe { ffi::CertVerifyTimeValidity(ptr::null_mut(), (*self.0).pCertInfo) };
+        Ok(ret == 0)
+    }
+
+    /// Verifies the time validity of this certificate relative to `time`.
+    pub fn is_time_valid_at(&self, time: SystemTime) -> io::Result<bool> {
+        let mut time = ::system_time_to_filetime(time);
+        let ret = unsafe { ffi::CertVerifyTimeValidity(&mut time, (*self.0).pCertInfo) };
         Ok(ret == 0)
     }
 
+    /// Determines if `ctl` has an entry for this certificate, as
+    /// `CertFindSubjectInCTL` does.
+    ///
+    /// Windows only finds certificates in CTLs which identify them by their
+    /// SHA-1 or MD5 hash, and an `InvalidInput` error is returned for other
+    /// CTLs, which can be checked with `CtlContext::contains` instead.
+    pub fn is_in_ctl(&self, ctl: &CtlContext) -> io::Result<bool> {
+        let algorithm = ctl.subject_algorithm();
+        if algorithm != ffi::szOID_OIWSEC_sha1 && algorithm != ffi::szOID_RSA_MD5 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "the CTL does not identify certificates by \
+                                       their SHA-1 or MD5 hash"));
+        }
+        let entry = unsafe {
+            ffi::CertFindSubjectInCTL(ffi::X509_ASN_ENCODING | ffi::PKCS_7_ASN_ENCODING,
+                                      ffi::CTL_CERT_SUBJECT_TYPE,
+                                      self.0 as *mut _,
+                                      ctl.as_inner(),
+                                      0)
+        };
+        Ok(!entry.is_null())
+    }
+
+    /// Returns a builder used to build and validate the certificate chain of
+    /// this certificate.
+    pub fn chain<'a>(&'a self) -> ChainBuilder<'a> {
+        ChainBuilder::new(self)
+    }
+
     /// Returns a builder used to acquire the private key corresponding to this certificate.
     pub fn private_key<'a>(&'a self) -> AcquirePrivateKeyOptions<'a> {
         AcquirePrivateKeyOptions {
@@ -206,12 +706,12 @@ impl CertContext {
     /// Deletes this certificate from its certificate store.
     pub fn delete(self) -> io::Result<()> {
         unsafe {
-            let ret = crypt32::CertDeleteCertificateFromStore(self.0);
+            let ret = ffi::CertDeleteCertificateFromStore(self.0);
             mem::forget(self);
-            if ret == winapi::TRUE {
+            if ret == ffi::TRUE {
                 Ok(())
             } else {
-                Err(io::Error::last_os_error())
+                Err(::error::last_error())
             }
         }
     }
@@ -228,108 +728,120 @@ impl CertContext {
         }
     }
 
-    fn get_encoded_bytes(&self) -> &[u8] {
-        unsafe {
-            let cert_ctx = *self.0;
-            slice::from_raw_parts(cert_ctx.pbCertEncoded, cert_ctx.cbCertEncoded as usize)
+    /// Returns a builder used to create a certificate for `subject` which is
+    /// signed with its own private key, `key`.
+    ///
+    /// `subject` is an X.500 name such as `CN=localhost, O=schannel-rs`.
+    pub fn self_signed<'a>(subject: &str, key: &'a PrivateKey) -> SelfSignedOptions<'a> {
+        SelfSignedOptions {
+            subject: subject.encode_utf16().chain(Some(0)).collect(),
+            key: key,
+            hash: HashAlgorithm::sha256(),
+            validity: None,
+            key_usage: None,
+            alt_names: vec![],
         }
     }
 
-    fn get_bytes(&self, prop: winapi::DWORD) -> io::Result<Vec<u8>> {
+    /// Looks up an extension by its nul-terminated OID.
+    fn find_extension(&self, oid: &[u8]) -> Option<&ffi::CERT_EXTENSION> {
         unsafe {
-            let mut len = 0;
-            let ret =
-                crypt32::CertGetCertificateContextProperty(self.0, prop, ptr::null_mut(), &mut len);
-            if ret != winapi::TRUE {
-                return Err(io::Error::last_os_error());
-            }
-
-            let mut buf = vec![0u8; len as usize];
-            let ret = crypt32::CertGetCertificateContextProperty(self.0,
-                                                                 prop,
-                                                                 buf.as_mut_ptr() as
-                                                                 *mut winapi::c_void,
-                                                                 &mut len);
-            if ret != winapi::TRUE {
-                return Err(io::Error::last_os_error());
+            let info = self.info();
+            let ext = ffi::CertFindExtension(oid.as_ptr() as ffi::LPCSTR,
+                                             info.cExtension,
+                                             info.rgExtension);
+            if ext.is_null() {
+                None
+            } else {
+                Some(&*ext)
             }
-            Ok(buf)
         }
     }
 
-    fn get_string(&self, prop: winapi::DWORD) -> io::Result<String> {
-        unsafe {
-            let mut len = 0;
-            let ret =
-                crypt32::CertGetCertificateContextProperty(self.0, prop, ptr::null_mut(), &mut len);
-            if ret != winapi::TRUE {
-                return Err(io::Error::last_os_error());
-            }
+    fn info(&self) -> &ffi::CERT_INFO {
+        unsafe { &*(*self.0).pCertInfo }
+    }
 
-            // Divide by 2 b/c `len` is the byte length, but we're allocating
-            // u16 pairs which are 2 bytes each.
-            let amt = (len / 2) as usize;
-            let mut buf = vec![0u16; amt];
-            let ret = crypt32::CertGetCertificateContextProperty(self.0,
-                                                                 prop,
-                                                                 buf.as_mut_ptr() as
-                                                                 *mut winapi::c_void,
-                                                                 &mut len);
-            if ret != winapi::TRUE {
-                return Err(io::Error::last_os_error());
-            }
+    fn get_bytes(&self, prop: ffi::DWORD) -> io::Result<Vec<u8>> {
+        unsafe { ::get_property(ffi::CertGetCertificateContextProperty, self.0, prop) }
+    }
 
-            // Chop off the trailing nul byte
-            Ok(OsString::from_wide(&buf[..amt - 1]).into_string().unwrap())
-        }
+    fn get_string(&self, prop: ffi::DWORD) -> io::Result<String> {
+        unsafe { ::get_string_property(ffi::CertGetCertificateContextProperty, self.0, prop) }
     }
 
-    fn set_string(&self, prop: winapi::DWORD, s: &str) -> io::Result<()> {
+    fn set_string(&self, prop: ffi::DWORD, s: &str) -> io::Result<()> {
         unsafe {
-            let data = s.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
-            let data = winapi::CRYPT_DATA_BLOB {
-                cbData: (data.len() * 2) as winapi::DWORD,
-                pbData: data.as_ptr() as *mut _,
-            };
-            let ret = crypt32::CertSetCertificateContextProperty(self.0,
-                                                                 prop,
-                                                                 0,
-                                                                 &data as *const _ as *const _);
-            if ret != winapi::TRUE {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(())
-            }
+            ::set_string_property(ffi::CertSetCertificateContextProperty, self.0, prop, s)
+        }
+    }
+}
+
+fn blob_bytes(blob: &ffi::CERT_NAME_BLOB) -> &[u8] {
+    if blob.cbData == 0 {
+        &[]
+    } else {
+        unsafe { slice::from_raw_parts(blob.pbData, blob.cbData as usize) }
+    }
+}
+
+/// Formats an encoded name with `CertNameToStrW`.
+fn name_to_string(name: &ffi::CERT_NAME_BLOB) -> io::Result<String> {
+    unsafe {
+        let name = name as *const _ as *mut _;
+        let len = ffi::CertNameToStrW(ffi::X509_ASN_ENCODING,
+                                      name,
+                                      ffi::CERT_X500_NAME_STR,
+                                      ptr::null_mut(),
+                                      0);
+        if len == 0 {
+            return Err(::error::last_error());
+        }
+
+        let mut buf = vec![0u16; len as usize];
+        let len = ffi::CertNameToStrW(ffi::X509_ASN_ENCODING,
+                                      name,
+                                      ffi::CERT_X500_NAME_STR,
+                                      buf.as_mut_ptr(),
+                                      len);
+        if len == 0 {
+            return Err(::error::last_error());
+        }
+        // Chop off the trailing nul
+        buf.truncate(len as usize - 1);
+        match String::from_utf16(&buf) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "name is not valid UTF-16")),
         }
     }
 }
 
 impl PartialEq for CertContext {
     fn eq(&self, other: &CertContext) -> bool {
-        self.get_encoded_bytes() == other.get_encoded_bytes()
+        self.to_der() == other.to_der()
     }
 }
 
 /// A builder type for certificate private key lookup.
 pub struct AcquirePrivateKeyOptions<'a> {
     cert: &'a CertContext,
-    flags: winapi::DWORD,
+    flags: ffi::DWORD,
 }
 
 impl<'a> AcquirePrivateKeyOptions<'a> {
     /// If set, the certificate's public key will be compared with the private key to ensure a
     /// match.
     pub fn compare_key(&mut self, compare_key: bool) -> &mut AcquirePrivateKeyOptions<'a> {
-        self.flag(CRYPT_ACQUIRE_COMPARE_KEY_FLAG, compare_key)
+        self.flag(ffi::CRYPT_ACQUIRE_COMPARE_KEY_FLAG, compare_key)
     }
 
     /// If set, the lookup will not display any user interface, even if that causes the lookup to
     /// fail.
     pub fn silent(&mut self, silent: bool) -> &mut AcquirePrivateKeyOptions<'a> {
-        self.flag(CRYPT_ACQUIRE_SILENT_FLAG, silent)
+        self.flag(ffi::CRYPT_ACQUIRE_SILENT_FLAG, silent)
     }
 
-    fn flag(&mut self, flag: winapi::DWORD, set: bool) -> &mut AcquirePrivateKeyOptions<'a> {
+    fn flag(&mut self, flag: ffi::DWORD, set: bool) -> &mut AcquirePrivateKeyOptions<'a> {
         if set {
             self.flags |= flag;
         } else {
@@ -341,25 +853,166 @@ impl<'a> AcquirePrivateKeyOptions<'a> {
     /// Acquires the private key handle.
     pub fn acquire(&self) -> io::Result<PrivateKey> {
         unsafe {
-            let flags = self.flags | CRYPT_ACQUIRE_ALLOW_NCRYPT_KEY_FLAG;
+            let flags = self.flags | ffi::CRYPT_ACQUIRE_ALLOW_NCRYPT_KEY_FLAG;
             let mut handle = 0;
             let mut spec = 0;
-            let mut free = winapi::FALSE;
-            let res = crypt32::CryptAcquireCertificatePrivateKey(self.cert.0,
-                                                                 flags,
-                                                                 ptr::null_mut(),
-                                                                 &mut handle,
-                                                                 &mut spec,
-                                                                 &mut free);
-            if res != winapi::TRUE {
-                return Err(io::Error::last_os_error());
-            }
-            assert!(free == winapi::TRUE);
-            if spec & winapi::CERT_NCRYPT_KEY_SPEC != 0 {
-                Ok(PrivateKey::NcryptKey(NcryptKey::from_inner(handle)))
+            let mut free = ffi::FALSE;
+            let res = ffi::CryptAcquireCertificatePrivateKey(self.cert.0,
+                                                             flags,
+                                                             ptr::null_mut(),
+                                                             &mut handle,
+                                                             &mut spec,
+                                                             &mut free);
+            if res != ffi::TRUE {
+                let err = ::error::last_error();
+                debug!("failed to acquire certificate private key: {}", err);
+                return Err(err);
+            }
+            // keys which aren't persisted, such as those imported with
+            // `PfxImportOptions::no_persist_key`, remain owned by the
+            // certificate
+            if spec & ffi::CERT_NCRYPT_KEY_SPEC != 0 {
+                debug!("acquired CNG private key owned by the {}",
+                       if free == ffi::TRUE { "caller" } else { "certificate" });
+                if free == ffi::TRUE {
+                    Ok(PrivateKey::NcryptKey(NcryptKey::from_inner(handle)))
+                } else {
+                    Ok(PrivateKey::NcryptKey(NcryptKey::from_cert_key(handle, self.cert.clone())))
+                }
             } else {
-                Ok(PrivateKey::CryptProv(CryptProv::from_inner(handle)))
+                if free != ffi::TRUE &&
+                   ffi::CryptContextAddRef(handle, ptr::null_mut(), 0) != ffi::TRUE {
+                    return Err(::error::last_error());
+                }
+                debug!("acquired CryptoAPI private key with key spec {}", spec);
+                Ok(PrivateKey::CryptProv(CryptProv::from_cert_prov(handle, KeySpec(spec))))
+            }
+        }
+    }
+}
+
+/// A CNG public key, used to verify signatures and encrypt data.
+pub struct PublicKey(ffi::BCRYPT_KEY_HANDLE);
+
+impl Drop for PublicKey {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::BCryptDestroyKey(self.0);
+        }
+    }
+}
+
+inner!(PublicKey, ffi::BCRYPT_KEY_HANDLE);
+
+impl PublicKey {
+    /// Verifies a signature over `data` made by the corresponding private
+    /// key.
+    ///
+    /// The data is hashed with `hash` before being verified. Returns
+    /// `Ok(false)` if the signature does not match.
+    pub fn verify(&self,
+                  data: &[u8],
+                  hash: HashAlgorithm,
+                  padding: Padding,
+                  signature: &[u8])
+                  -> io::Result<bool> {
+        let digest = try!(hash.hash(data));
+        self.verify_digest(&digest, hash, padding, signature)
+    }
+
+    /// Verifies a signature over a digest which has already been computed
+    /// with `hash`.
+    pub fn verify_digest(&self,
+                         digest: &[u8],
+                         hash: HashAlgorithm,
+                         padding: Padding,
+                         signature: &[u8])
+                         -> io::Result<bool> {
+        unsafe {
+            let signature = match padding {
+                Padding::Ecdsa(EcdsaFormat::Der) => {
+                    let bits = try!(self.length());
+                    try!(::ecdsa_der_to_raw(signature, (bits as usize + 7) / 8))
+                }
+                _ => signature.to_owned(),
+            };
+
+            let digest_len = try!(::dword_len(digest.len()));
+            let signature_len = try!(::dword_len(signature.len()));
+            let mut padding = PaddingInfo::signature(hash, padding);
+            let status = ffi::BCryptVerifySignature(self.0,
+                                                    padding.as_mut_ptr(),
+                                                    digest.as_ptr() as *mut _,
+                                                    digest_len,
+                                                    signature.as_ptr() as *mut _,
+                                                    signature_len,
+                                                    padding.flags());
+            match status {
+                ffi::STATUS_SUCCESS => Ok(true),
+                ffi::STATUS_INVALID_SIGNATURE => Ok(false),
+                err => Err(::error::from_code(err)),
+            }
+        }
+    }
+
+    /// Encrypts `data` with this RSA key.
+    ///
+    /// The result can be decrypted with `PrivateKey::decrypt`.
+    pub fn encrypt(&self, data: &[u8], padding: RsaPadding) -> io::Result<Vec<u8>> {
+        unsafe {
+            let data_len = try!(::dword_len(data.len()));
+            let mut padding = PaddingInfo::encryption(padding);
+
+            let mut len = 0;
+            let status = ffi::BCryptEncrypt(self.0,
+                                            data.as_ptr() as *mut _,
+                                            data_len,
+                                            padding.as_mut_ptr(),
+                                            ptr::null_mut(),
+                                            0,
+                                            ptr::null_mut(),
+                                            0,
+                                            &mut len,
+                                            padding.flags());
+            if status != ffi::STATUS_SUCCESS {
+                return Err(::error::from_code(status));
+            }
+
+            let mut buf = vec![0; len as usize];
+            let status = ffi::BCryptEncrypt(self.0,
+                                            data.as_ptr() as *mut _,
+                                            data_len,
+                                            padding.as_mut_ptr(),
+                                            ptr::null_mut(),
+                                            0,
+                                            buf.as_mut_ptr(),
+                                            len,
+                                            &mut len,
+                                            padding.flags());
+            if status != ffi::STATUS_SUCCESS {
+                return Err(::error::from_code(status));
+            }
+            buf.truncate(len as usize);
+            Ok(buf)
+        }
+    }
+
+    /// Returns the length of this key in bits.
+    pub fn length(&self) -> io::Result<u32> {
+        unsafe {
+            let name = "KeyLength".encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+            let mut bits: ffi::DWORD = 0;
+            let mut len = 0;
+            let status = ffi::BCryptGetProperty(self.0,
+                                                name.as_ptr(),
+                                                &mut bits as *mut _ as ffi::PUCHAR,
+                                                mem::size_of_val(&bits) as ffi::ULONG,
+                                                &mut len,
+                                                0);
+            if status != ffi::STATUS_SUCCESS {
+                return Err(::error::from_code(status));
             }
+            Ok(bits)
         }
     }
 }
@@ -372,14 +1025,188 @@ pub enum PrivateKey {
     NcryptKey(NcryptKey),
 }
 
+impl PrivateKey {
+    /// Signs `data` with this key.
+    ///
+    /// The data is hashed with `hash` before being signed.
+    pub fn sign(&self, data: &[u8], hash: HashAlgorithm, padding: Padding) -> io::Result<Vec<u8>> {
+        let digest = try!(hash.hash(data));
+        self.sign_digest(&digest, hash, padding)
+    }
+
+    /// Signs a digest which has already been computed with `hash`.
+    ///
+    /// The digest must be `hash.digest_len()` bytes long. CryptoAPI
+    /// providers only support `Padding::Pkcs1`, and only those hash
+    /// algorithms which the provider type supports (SHA-2 requires an
+    /// `rsa_aes` provider).
+    pub fn sign_digest(&self,
+                       digest: &[u8],
+                       hash: HashAlgorithm,
+                       padding: Padding)
+                       -> io::Result<Vec<u8>> {
+        // CryptoAPI reads as many bytes as the algorithm's digest length
+        if digest.len() != hash.digest_len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "digest length does not match the hash algorithm"));
+        }
+        match *self {
+            PrivateKey::CryptProv(ref prov) => capi_sign_digest(prov, digest, hash, padding),
+            PrivateKey::NcryptKey(ref key) => ncrypt_sign_digest(key, digest, hash, padding),
+        }
+    }
+
+    /// Decrypts data which was encrypted with the corresponding RSA public
+    /// key.
+    ///
+    /// CryptoAPI providers decrypt with the container's key exchange key,
+    /// and only support OAEP with SHA-1.
+    pub fn decrypt(&self, data: &[u8], padding: RsaPadding) -> io::Result<Vec<u8>> {
+        match *self {
+            PrivateKey::CryptProv(ref prov) => capi_decrypt(prov, data, padding),
+            PrivateKey::NcryptKey(ref key) => ncrypt_decrypt(key, data, padding),
+        }
+    }
+}
+
+fn capi_sign_digest(prov: &CryptProv,
+                    digest: &[u8],
+                    hash: HashAlgorithm,
+                    padding: Padding)
+                    -> io::Result<Vec<u8>> {
+    if padding != Padding::Pkcs1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "CryptoAPI keys only support PKCS #1 padding"));
+    }
+
+    let key_spec = match try!(prov.key_spec()) {
+        Some(spec) => spec,
+        None => return Err(::error::from_code(ffi::NTE_NO_KEY)),
+    };
+    unsafe { ::capi_sign_digest(prov.as_inner(), key_spec.0, digest, hash.0) }
+}
+
+fn ncrypt_sign_digest(key: &NcryptKey,
+                      digest: &[u8],
+                      hash: HashAlgorithm,
+                      padding: Padding)
+                      -> io::Result<Vec<u8>> {
+    unsafe {
+        let digest_len = try!(::dword_len(digest.len()));
+        let mut info = PaddingInfo::signature(hash, padding);
+        let flags = info.flags() | if key.is_silent() { ffi::NCRYPT_SILENT_FLAG } else { 0 };
+
+        let mut len = 0;
+        let status = ffi::NCryptSignHash(key.as_inner(),
+                                         info.as_mut_ptr(),
+                                         digest.as_ptr() as *mut _,
+                                         digest_len,
+                                         ptr::null_mut(),
+                                         0,
+                                         &mut len,
+                                         flags);
+        if status != ffi::ERROR_SUCCESS as ffi::SECURITY_STATUS {
+            return Err(::error::from_code(status));
+        }
+
+        let mut buf = vec![0; len as usize];
+        let status = ffi::NCryptSignHash(key.as_inner(),
+                                         info.as_mut_ptr(),
+                                         digest.as_ptr() as *mut _,
+                                         digest_len,
+                                         buf.as_mut_ptr(),
+                                         len,
+                                         &mut len,
+                                         flags);
+        if status != ffi::ERROR_SUCCESS as ffi::SECURITY_STATUS {
+            return Err(::error::from_code(status));
+        }
+        buf.truncate(len as usize);
+
+        match padding {
+            Padding::Ecdsa(EcdsaFormat::Der) => ::ecdsa_raw_to_der(&buf),
+            _ => Ok(buf),
+        }
+    }
+}
+
+fn capi_decrypt(prov: &CryptProv, data: &[u8], padding: RsaPadding) -> io::Result<Vec<u8>> {
+    let flags = match padding {
+        RsaPadding::Pkcs1 => 0,
+        RsaPadding::Oaep(hash) if hash.0 == ffi::CALG_SHA1 => ffi::CRYPT_OAEP,
+        RsaPadding::Oaep(_) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "CryptoAPI keys only support OAEP with SHA-1"))
+        }
+    };
+
+    unsafe {
+        let mut key = 0;
+        let res = ffi::CryptGetUserKey(prov.as_inner(), ffi::AT_KEYEXCHANGE, &mut key);
+        if res != ffi::TRUE {
+            return Err(::error::last_error());
+        }
+
+        // CryptoAPI expects little-endian ciphertext
+        let mut buf = data.to_owned();
+        buf.reverse();
+        let mut len = try!(::dword_len(buf.len()));
+        let res = ffi::CryptDecrypt(key, 0, ffi::TRUE, flags, buf.as_mut_ptr(), &mut len);
+        let err = ::error::last_error();
+        ffi::CryptDestroyKey(key);
+
+        if res != ffi::TRUE {
+            return Err(err);
+        }
+        buf.truncate(len as usize);
+        Ok(buf)
+    }
+}
+
+fn ncrypt_decrypt(key: &NcryptKey, data: &[u8], padding: RsaPadding) -> io::Result<Vec<u8>> {
+    unsafe {
+        let data_len = try!(::dword_len(data.len()));
+        let mut info = PaddingInfo::encryption(padding);
+        let flags = info.flags() | if key.is_silent() { ffi::NCRYPT_SILENT_FLAG } else { 0 };
+
+        let mut len = 0;
+        let status = ffi::NCryptDecrypt(key.as_inner(),
+                                        data.as_ptr() as *mut _,
+                                        data_len,
+                                        info.as_mut_ptr(),
+                                        ptr::null_mut(),
+                                        0,
+                                        &mut len,
+                                        flags);
+        if status != ffi::ERROR_SUCCESS as ffi::SECURITY_STATUS {
+            return Err(::error::from_code(status));
+        }
+
+        let mut buf = vec![0; len as usize];
+        let status = ffi::NCryptDecrypt(key.as_inner(),
+                                        data.as_ptr() as *mut _,
+                                        data_len,
+                                        info.as_mut_ptr(),
+                                        buf.as_mut_ptr(),
+                                        len,
+                                        &mut len,
+                                        flags);
+        if status != ffi::ERROR_SUCCESS as ffi::SECURITY_STATUS {
+            return Err(::error::from_code(status));
+        }
+        buf.truncate(len as usize);
+        Ok(buf)
+    }
+}
+
 /// A builder used to set the private key associated with a certificate.
 pub struct SetKeyProvInfo<'a> {
     cert: &'a CertContext,
     container: Option<Vec<u16>>,
     provider: Option<Vec<u16>>,
-    type_: winapi::DWORD,
-    flags: winapi::DWORD,
-    key_spec: winapi::DWORD,
+    type_: ffi::DWORD,
+    flags: ffi::DWORD,
+    key_spec: ffi::DWORD,
 }
 
 impl<'a> SetKeyProvInfo<'a> {
@@ -413,21 +1240,21 @@ impl<'a> SetKeyProvInfo<'a> {
     /// If set, the handle to the key provider can be kept open for subsequent
     /// calls to cryptographic functions.
     pub fn keep_open(&mut self, keep_open: bool) -> &mut SetKeyProvInfo<'a> {
-        self.flag(winapi::CERT_SET_KEY_PROV_HANDLE_PROP_ID, keep_open)
+        self.flag(ffi::CERT_SET_KEY_PROV_HANDLE_PROP_ID, keep_open)
     }
 
     /// If set, the key container contains machine keys.
     pub fn machine_keyset(&mut self, machine_keyset: bool) -> &mut SetKeyProvInfo<'a> {
-        self.flag(winapi::CRYPT_MACHINE_KEYSET, machine_keyset)
+        self.flag(ffi::CRYPT_MACHINE_KEYSET, machine_keyset)
     }
 
     /// If set, the key container will attempt to open keys without any user
     /// interface prompts.
     pub fn silent(&mut self, silent: bool) -> &mut SetKeyProvInfo<'a> {
-        self.flag(winapi::CRYPT_SILENT, silent)
+        self.flag(ffi::CRYPT_SILENT, silent)
     }
 
-    fn flag(&mut self, flag: winapi::DWORD, on: bool) -> &mut SetKeyProvInfo<'a> {
+    fn flag(&mut self, flag: ffi::DWORD, on: bool) -> &mut SetKeyProvInfo<'a> {
         if on {
             self.flags |= flag;
         } else {
@@ -448,7 +1275,7 @@ impl<'a> SetKeyProvInfo<'a> {
             let container = self.container.as_ref().map(|s| s.as_ptr()).unwrap_or(ptr::null());
             let provider = self.provider.as_ref().map(|s| s.as_ptr()).unwrap_or(ptr::null());
 
-            let info = winapi::CRYPT_KEY_PROV_INFO {
+            let info = ffi::CRYPT_KEY_PROV_INFO {
                 pwszContainerName: container as *mut _,
                 pwszProvName: provider as *mut _,
                 dwProvType: self.type_,
@@ -459,32 +1286,543 @@ impl<'a> SetKeyProvInfo<'a> {
             };
 
             let res =
-                crypt32::CertSetCertificateContextProperty(self.cert.0,
-                                                           winapi::CERT_KEY_PROV_INFO_PROP_ID,
-                                                           0,
-                                                           &info as *const _ as *const _);
-            if res == winapi::TRUE {
+                ffi::CertSetCertificateContextProperty(self.cert.0,
+                                                       ffi::CERT_KEY_PROV_INFO_PROP_ID,
+                                                       0,
+                                                       &info as *const _ as *const _);
+            if res == ffi::TRUE {
                 Ok(())
             } else {
-                Err(io::Error::last_os_error())
+                Err(::error::last_error())
+            }
+        }
+    }
+}
+
+/// A builder type for self-signed certificates.
+pub struct SelfSignedOptions<'a> {
+    subject: Vec<u16>,
+    key: &'a PrivateKey,
+    hash: HashAlgorithm,
+    validity: Option<(SystemTime, SystemTime)>,
+    key_usage: Option<KeyUsage>,
+    alt_names: Vec<AltNameValue>,
+}
+
+enum AltNameValue {
+    Dns(Vec<u16>),
+    Ip(Vec<u8>),
+}
+
+impl<'a> SelfSignedOptions<'a> {
+    /// Sets the hash algorithm the certificate is signed with.
+    ///
+    /// CryptoAPI keys only support SHA-2 in `rsa_aes` providers. Defaults to
+    /// SHA-256.
+    pub fn hash(&mut self, hash: HashAlgorithm) -> &mut SelfSignedOptions<'a> {
+        self.hash = hash;
+        self
+    }
+
+    /// Sets the period during which the certificate is valid.
+    ///
+    /// Defaults to one year from the time the certificate is created.
+    pub fn validity(&mut self,
+                    not_before: SystemTime,
+                    not_after: SystemTime)
+                    -> &mut SelfSignedOptions<'a> {
+        self.validity = Some((not_before, not_after));
+        self
+    }
+
+    /// Sets the operations the certificate's key may be used for, in a
+    /// critical key usage extension.
+    ///
+    /// By default the certificate has no key usage extension, which does not
+    /// restrict its key.
+    pub fn key_usage(&mut self, key_usage: KeyUsage) -> &mut SelfSignedOptions<'a> {
+        self.key_usage = Some(key_usage);
+        self
+    }
+
+    /// Adds a DNS name to the subject alternative names of the certificate.
+    pub fn dns_name(&mut self, name: &str) -> &mut SelfSignedOptions<'a> {
+        self.alt_names.push(AltNameValue::Dns(name.encode_utf16().chain(Some(0)).collect()));
+        self
+    }
+
+    /// Adds an IP address to the subject alternative names of the
+    /// certificate.
+    pub fn ip_address(&mut self, addr: IpAddr) -> &mut SelfSignedOptions<'a> {
+        let octets = match addr {
+            IpAddr::V4(addr) => addr.octets().to_vec(),
+            IpAddr::V6(addr) => addr.octets().to_vec(),
+        };
+        self.alt_names.push(AltNameValue::Ip(octets));
+        self
+    }
+
+    /// Creates the certificate.
+    ///
+    /// CryptoAPI keys and persisted CNG keys are linked to the certificate,
+    /// so that it can be used directly as the credential of a TLS server.
+    /// Ephemeral CNG keys are not, as Schannel would be unable to find them.
+    pub fn create(&self) -> io::Result<CertContext> {
+        unsafe {
+            let mut len = 0;
+            let res = ffi::CertStrToNameW(ffi::X509_ASN_ENCODING,
+                                          self.subject.as_ptr(),
+                                          ffi::CERT_X500_NAME_STR,
+                                          ptr::null_mut(),
+                                          ptr::null_mut(),
+                                          &mut len,
+                                          ptr::null_mut());
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+            let mut name = vec![0u8; len as usize];
+            let res = ffi::CertStrToNameW(ffi::X509_ASN_ENCODING,
+                                          self.subject.as_ptr(),
+                                          ffi::CERT_X500_NAME_STR,
+                                          ptr::null_mut(),
+                                          name.as_mut_ptr(),
+                                          &mut len,
+                                          ptr::null_mut());
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+            let mut subject = ffi::CERT_NAME_BLOB {
+                cbData: len,
+                pbData: name.as_mut_ptr(),
+            };
+
+            // CryptoAPI handles don't name their container, which is needed
+            // to link the key to the certificate
+            let mut container;
+            let mut provider;
+            let mut prov_info: ffi::CRYPT_KEY_PROV_INFO = mem::zeroed();
+            let mut prov_info_ptr = ptr::null_mut();
+            let mut flags = 0;
+            let (handle, ecdsa) = match *self.key {
+                PrivateKey::NcryptKey(ref key) => {
+                    if try!(key.key_name()).is_none() {
+                        flags |= ffi::CERT_CREATE_SELFSIGN_NO_KEY_INFO;
+                    }
+                    let group = try!(key.algorithm_group());
+                    (key.as_inner(), group.as_ref().map_or(false, |g| g == "ECDSA"))
+                }
+                PrivateKey::CryptProv(ref prov) => {
+                    container = try!(prov.container_name())
+                        .encode_utf16()
+                        .chain(Some(0))
+                        .collect::<Vec<_>>();
+                    provider = try!(prov.provider_name())
+                        .encode_utf16()
+                        .chain(Some(0))
+                        .collect::<Vec<_>>();
+                    let key_spec = try!(prov.key_spec()).unwrap_or(KeySpec::key_exchange());
+                    prov_info.pwszContainerName = container.as_mut_ptr();
+                    prov_info.pwszProvName = provider.as_mut_ptr();
+                    prov_info.dwProvType = try!(prov.provider_type()).as_raw();
+                    prov_info.dwKeySpec = key_spec.0;
+                    prov_info_ptr = &mut prov_info as *mut _;
+                    (prov.as_raw(), false)
+                }
+            };
+
+            let mut algorithm: ffi::CRYPT_ALGORITHM_IDENTIFIER = mem::zeroed();
+            algorithm.pszObjId = try!(signature_algorithm(ecdsa, &self.hash)).as_ptr() as *mut _;
+
+            let mut times = match self.validity {
+                Some((not_before, not_after)) => {
+                    Some((try!(system_time_to_systemtime(not_before)),
+                          try!(system_time_to_systemtime(not_after))))
+                }
+                None => None,
+            };
+            let (start, end) = match times {
+                Some((ref mut start, ref mut end)) => (start as *mut _, end as *mut _),
+                None => (ptr::null_mut(), ptr::null_mut()),
+            };
+
+            let mut extensions = vec![];
+            let key_usage;
+            if let Some(usage) = self.key_usage {
+                let mut usage_bits = usage.0 as u8;
+                let blob = ffi::CRYPT_BIT_BLOB {
+                    cbData: 1,
+                    pbData: &mut usage_bits,
+                    cUnusedBits: usage_bits.trailing_zeros(),
+                };
+                key_usage = try!(::encode_object(ffi::X509_KEY_USAGE,
+                                                 &blob as *const _ as *const _));
+                extensions.push(ffi::CERT_EXTENSION {
+                    pszObjId: szOID_KEY_USAGE.as_ptr() as *mut _,
+                    fCritical: ffi::TRUE,
+                    Value: ffi::CRYPT_OBJID_BLOB {
+                        cbData: try!(::dword_len(key_usage.len())),
+                        pbData: key_usage.as_ptr() as *mut _,
+                    },
+                });
+            }
+
+            let alt_names;
+            if !self.alt_names.is_empty() {
+                let entries = self.alt_names
+                    .iter()
+                    .map(|name| match *name {
+                        AltNameValue::Dns(ref name) => {
+                            Ok(ffi::alt_name_from_string(ffi::CERT_ALT_NAME_DNS_NAME,
+                                                         name.as_ptr() as *mut _))
+                        }
+                        AltNameValue::Ip(ref octets) => {
+                            let blob = ffi::CRYPT_DATA_BLOB {
+                                cbData: try!(::dword_len(octets.len())),
+                                pbData: octets.as_ptr() as *mut _,
+                            };
+                            Ok(ffi::alt_name_from_blob(ffi::CERT_ALT_NAME_IP_ADDRESS, blob))
+                        }
+                    })
+                    .collect::<io::Result<Vec<_>>>();
+                let mut entries = try!(entries);
+                let info = ffi::CERT_ALT_NAME_INFO {
+                    cAltEntry: try!(::dword_len(entries.len())),
+                    rgAltEntry: entries.as_mut_ptr(),
+                };
+                alt_names = try!(::encode_object(ffi::X509_ALTERNATE_NAME,
+                                                 &info as *const _ as *const _));
+                extensions.push(ffi::CERT_EXTENSION {
+                    pszObjId: szOID_SUBJECT_ALT_NAME2.as_ptr() as *mut _,
+                    fCritical: ffi::FALSE,
+                    Value: ffi::CRYPT_OBJID_BLOB {
+                        cbData: try!(::dword_len(alt_names.len())),
+                        pbData: alt_names.as_ptr() as *mut _,
+                    },
+                });
+            }
+
+            let mut extensions = ffi::CERT_EXTENSIONS {
+                cExtension: try!(::dword_len(extensions.len())),
+                rgExtension: extensions.as_mut_ptr(),
+            };
+
+            let cert = ffi::CertCreateSelfSignCertificate(handle,
+                                                          &mut subject,
+                                                          flags,
+                                                          prov_info_ptr,
+                                                          &mut algorithm,
+                                                          start,
+                                                          end,
+                                                          &mut extensions);
+            if cert.is_null() {
+                Err(::error::last_error())
+            } else {
+                Ok(CertContext(cert))
             }
         }
     }
 }
 
+/// Returns the nul-terminated OID of the algorithm which signs `hash` digests
+/// with an ECDSA or RSA key.
+fn signature_algorithm(ecdsa: bool, hash: &HashAlgorithm) -> io::Result<&'static [u8]> {
+    let oid: &'static [u8] = match (ecdsa, hash.cng_name()) {
+        (false, "SHA1") => b"1.2.840.113549.1.1.5\0",
+        (false, "SHA256") => b"1.2.840.113549.1.1.11\0",
+        (false, "SHA384") => b"1.2.840.113549.1.1.12\0",
+        (false, "SHA512") => b"1.2.840.113549.1.1.13\0",
+        (true, "SHA1") => b"1.2.840.10045.4.1\0",
+        (true, "SHA256") => b"1.2.840.10045.4.3.2\0",
+        (true, "SHA384") => b"1.2.840.10045.4.3.3\0",
+        (true, "SHA512") => b"1.2.840.10045.4.3.4\0",
+        _ => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "unsupported hash algorithm for certificate signatures"))
+        }
+    };
+    Ok(oid)
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))),
+        16 => {
+            let mut segments = [0u16; 8];
+            for (segment, pair) in segments.iter_mut().zip(bytes.chunks(2)) {
+                *segment = (pair[0] as u16) << 8 | pair[1] as u16;
+            }
+            Some(IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                          segments[4], segments[5], segments[6], segments[7])))
+        }
+        _ => None,
+    }
+}
+
+fn system_time_to_systemtime(time: SystemTime) -> io::Result<ffi::SYSTEMTIME> {
+    let file_time = ::system_time_to_filetime(time);
+    unsafe {
+        let mut system_time = mem::zeroed();
+        if ffi::FileTimeToSystemTime(&file_time, &mut system_time) == ffi::TRUE {
+            Ok(system_time)
+        } else {
+            Err(::error::last_error())
+        }
+    }
+}
+
+/// Moves the CryptoAPI private key of `cert` into the CNG key storage provider
+/// `target_provider` under the name `new_name`, and points `cert` at the new
+/// key.
+///
+/// The key must be exportable; an error of kind `PermissionDenied` is
+/// returned otherwise. The certificate is only updated once the new key has
+/// been imported, and is restored if the new key then fails to match it. The
+/// old key container is deleted afterwards if `delete_old` is set; as the
+/// certificate already refers to the new key by then, failing to delete it
+/// does not fail the migration.
+pub fn migrate_to_cng(cert: &CertContext,
+                      target_provider: &str,
+                      new_name: &str,
+                      delete_old: bool)
+                      -> io::Result<NcryptKey> {
+    let old_info = cert.get_bytes(ffi::CERT_KEY_PROV_INFO_PROP_ID).ok();
+    let (machine, key_spec) = match old_info {
+        Some(ref info) => unsafe {
+            let info = &*(info.as_ptr() as *const ffi::CRYPT_KEY_PROV_INFO);
+            (info.dwFlags & ffi::CRYPT_MACHINE_KEYSET != 0, Some(KeySpec(info.dwKeySpec)))
+        },
+        None => (false, None),
+    };
+
+    let pkcs8 = {
+        let prov = match try!(cert.private_key().silent(true).acquire()) {
+            PrivateKey::CryptProv(prov) => prov,
+            PrivateKey::NcryptKey(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "certificate's key is already held by CNG"))
+            }
+        };
+        // export the key pair the certificate is bound to
+        let key_spec = match key_spec {
+            Some(spec) => spec,
+            None => try!(prov.key_spec()).unwrap_or(KeySpec::key_exchange()),
+        };
+        match prov.export_pkcs8(key_spec) {
+            Ok(pkcs8) => pkcs8,
+            Err(ref e) if ::error::code(e) == Some(ffi::NTE_BAD_KEY_STATE) ||
+                          ::error::code(e) == Some(ffi::NTE_PERM) => {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                          "certificate's key is not exportable"))
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    let provider = try!(NcryptProvider::open(target_provider));
+    let key = try!(ncrypt_key::CreateOptions::new()
+        .provider(&provider)
+        .name(new_name)
+        .machine_key(machine)
+        .import_pkcs8(&pkcs8));
+
+    let res = cert.set_key_prov_info()
+        .container(new_name)
+        .provider(target_provider)
+        .machine_keyset(machine)
+        .set()
+        .and_then(|()| {
+            match try!(cert.private_key().compare_key(true).silent(true).acquire()) {
+                PrivateKey::NcryptKey(_) => Ok(()),
+                PrivateKey::CryptProv(_) => {
+                    Err(io::Error::new(io::ErrorKind::Other,
+                                       "certificate does not refer to the migrated key"))
+                }
+            }
+        });
+    if let Err(e) = res {
+        unsafe {
+            let old_info = old_info.as_ref().map_or(ptr::null(), |i| i.as_ptr());
+            ffi::CertSetCertificateContextProperty(cert.0,
+                                                   ffi::CERT_KEY_PROV_INFO_PROP_ID,
+                                                   0,
+                                                   old_info as *const _);
+        }
+        let _ = key.delete();
+        return Err(e);
+    }
+
+    if delete_old {
+        if let Some(ref info) = old_info {
+            unsafe {
+                let info = &*(info.as_ptr() as *const ffi::CRYPT_KEY_PROV_INFO);
+                let mut prov = 0;
+                let res = ffi::CryptAcquireContextW(&mut prov,
+                                                    info.pwszContainerName,
+                                                    info.pwszProvName,
+                                                    info.dwProvType,
+                                                    ffi::CRYPT_DELETEKEYSET |
+                                                    ffi::CRYPT_SILENT |
+                                                    (info.dwFlags &
+                                                     ffi::CRYPT_MACHINE_KEYSET));
+                if res != ffi::TRUE {
+                    debug!("failed to delete the migrated key container: {}",
+                           ::error::last_error());
+                }
+            }
+        }
+    }
+
+    Ok(key)
+}
+
+/// The operations a certificate's key may be used for, as listed in its key
+/// usage extension.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeyUsage(ffi::DWORD);
+
+impl KeyUsage {
+    /// The key may be used for signatures other than on certificates and
+    /// CRLs, such as those made during TLS handshakes.
+    pub fn digital_signature() -> KeyUsage {
+        KeyUsage(ffi::CERT_DIGITAL_SIGNATURE_KEY_USAGE)
+    }
+
+    /// The key may be used for signatures which the signer cannot later deny.
+    pub fn non_repudiation() -> KeyUsage {
+        KeyUsage(ffi::CERT_NON_REPUDIATION_KEY_USAGE)
+    }
+
+    /// The key may be used to encrypt keys, as in RSA key exchange.
+    pub fn key_encipherment() -> KeyUsage {
+        KeyUsage(ffi::CERT_KEY_ENCIPHERMENT_KEY_USAGE)
+    }
+
+    /// The key may be used to encrypt data other than keys.
+    pub fn data_encipherment() -> KeyUsage {
+        KeyUsage(ffi::CERT_DATA_ENCIPHERMENT_KEY_USAGE)
+    }
+
+    /// The key may be used for key agreement.
+    pub fn key_agreement() -> KeyUsage {
+        KeyUsage(ffi::CERT_KEY_AGREEMENT_KEY_USAGE)
+    }
+
+    /// The key may be used to sign certificates.
+    pub fn key_cert_sign() -> KeyUsage {
+        KeyUsage(ffi::CERT_KEY_CERT_SIGN_KEY_USAGE)
+    }
+
+    /// The key may be used to sign CRLs.
+    pub fn crl_sign() -> KeyUsage {
+        KeyUsage(ffi::CERT_CRL_SIGN_KEY_USAGE)
+    }
+
+    /// Determines if every operation allowed by `other` is allowed by this
+    /// usage.
+    pub fn contains(&self, other: KeyUsage) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the raw `CERT_*_KEY_USAGE` flags of this usage.
+    pub fn as_raw(&self) -> ffi::DWORD {
+        self.0
+    }
+}
+
+impl BitOr for KeyUsage {
+    type Output = KeyUsage;
+
+    fn bitor(self, other: KeyUsage) -> KeyUsage {
+        KeyUsage(self.0 | other.0)
+    }
+}
+
+/// A subject alternative name of a certificate.
+///
+/// More variants may be added in the future, so matches should include a
+/// wildcard arm.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AltName {
+    /// A DNS name, which may contain a wildcard.
+    Dns(String),
+    /// An IP address.
+    Ip(IpAddr),
+    /// An email address.
+    Email(String),
+    /// A URI.
+    Uri(String),
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// The uses a certificate is valid for according to its enhanced key usage.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidUses {
+    /// The certificate has no enhanced key usage, and is valid for all uses.
+    All,
+    /// The certificate is only valid for the uses identified by these OIDs,
+    /// of which there may be none.
+    Oids(Vec<String>),
+}
+
+impl ValidUses {
+    /// Determines if the use identified by `oid`, such as
+    /// `1.3.6.1.5.5.7.3.1` for server authentication, is allowed.
+    pub fn allows(&self, oid: &str) -> bool {
+        match *self {
+            ValidUses::All => true,
+            ValidUses::Oids(ref oids) => oids.iter().any(|o| o == oid),
+        }
+    }
+}
+
+/// Where `CertContext::enhanced_key_usage` looks up a certificate's
+/// enhanced key usage.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UsageSource(ffi::DWORD);
+
+impl UsageSource {
+    /// Both the extension and the property, so that the certificate is only
+    /// valid for the uses which both allow.
+    pub fn both() -> UsageSource {
+        UsageSource(0)
+    }
+
+    /// Only the enhanced key usage extension of the certificate.
+    pub fn extension() -> UsageSource {
+        UsageSource(ffi::CERT_FIND_EXT_ONLY_ENHKEY_USAGE_FLAG)
+    }
+
+    /// Only the `CERT_ENHKEY_USAGE_PROP_ID` property, which can restrict a
+    /// certificate locally.
+    pub fn property() -> UsageSource {
+        UsageSource(ffi::CERT_FIND_PROP_ONLY_ENHKEY_USAGE_FLAG)
+    }
+
+    /// Returns the raw `CERT_FIND_*_ENHKEY_USAGE_FLAG` flags of this source.
+    pub fn as_raw(&self) -> ffi::DWORD {
+        self.0
+    }
+}
+
 /// The specification of a private key.
-#[derive(Copy, Clone)]
-pub struct KeySpec(winapi::DWORD);
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeySpec(ffi::DWORD);
 
 impl KeySpec {
     /// A key used to encrypt/decrypt session keys.
     pub fn key_exchange() -> KeySpec {
-        KeySpec(winapi::AT_KEYEXCHANGE)
+        KeySpec(ffi::AT_KEYEXCHANGE)
     }
 
     /// A key used to create and verify digital signatures.
     pub fn signature() -> KeySpec {
-        KeySpec(winapi::AT_SIGNATURE)
+        KeySpec(ffi::AT_SIGNATURE)
+    }
+
+    /// Returns the raw value of this specification.
+    pub fn as_raw(&self) -> ffi::DWORD {
+        self.0
     }
 }
 
@@ -502,6 +1840,84 @@ mod test {
         assert_eq!(der, pem);
     }
 
+    #[test]
+    fn invalid_friendly_name() {
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+
+        // An unpaired high surrogate followed by a nul
+        let raw = [0x00, 0xd8, 0x00, 0x00];
+        cert.set_raw_property(ffi::CERT_FRIENDLY_NAME_PROP_ID, &raw).unwrap();
+        let err = cert.friendly_name().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let invalid = err.get_ref().unwrap().downcast_ref::<::InvalidString>().unwrap();
+        assert_eq!(invalid.as_bytes(), &raw[..]);
+
+        let raw = [0x61, 0x00, 0x62];
+        cert.set_raw_property(ffi::CERT_FRIENDLY_NAME_PROP_ID, &raw).unwrap();
+        assert_eq!(cert.friendly_name().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        cert.set_friendly_name("valid").unwrap();
+        assert_eq!(cert.friendly_name().unwrap(), "valid");
+    }
+
+    #[test]
+    fn self_signed() {
+        use std::time::{Duration, UNIX_EPOCH};
+        use hostname::{self, Reference};
+        use ncrypt_key::{CreateOptions, KeyAlgorithm};
+
+        let not_before = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let not_after = not_before + Duration::from_secs(30 * 24 * 60 * 60);
+        for &algorithm in &[KeyAlgorithm::rsa(), KeyAlgorithm::ecdsa_p256()] {
+            let key = CreateOptions::new().create(algorithm).unwrap();
+            let spki = key.export_public_spki().unwrap();
+            let key = PrivateKey::NcryptKey(key);
+
+            let cert = CertContext::self_signed("CN=schannel-rs, O=schannel-rs", &key)
+                .validity(not_before, not_after)
+                .key_usage(KeyUsage::digital_signature() | KeyUsage::key_encipherment())
+                .dns_name("*.example.com")
+                .ip_address("127.0.0.1".parse().unwrap())
+                .create()
+                .unwrap();
+            assert_eq!(cert.subject().unwrap(), "CN=schannel-rs, O=schannel-rs");
+            assert_eq!(cert.issuer_der(), cert.subject_der());
+            assert_eq!(cert.valid_from(), not_before);
+            assert_eq!(cert.valid_to(), not_after);
+            assert_eq!(cert.public_key().unwrap(), spki);
+
+            let names = [("a.example.com", true), ("example.com", false),
+                         ("127.0.0.1", true), ("::1", false)];
+            for &(name, valid) in &names {
+                assert_eq!(hostname::verify(&cert, &Reference::parse(name)).unwrap(), valid);
+            }
+        }
+    }
+
+    #[test]
+    fn self_signed_unsupported_hash() {
+        use ncrypt_key::{CreateOptions, KeyAlgorithm};
+
+        let key = CreateOptions::new().create(KeyAlgorithm::ecdsa_p256()).unwrap();
+        let key = PrivateKey::NcryptKey(key);
+        let err = CertContext::self_signed("CN=schannel-rs", &key)
+            .hash(HashAlgorithm::md5())
+            .create()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn ocsp_response() {
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+        assert_eq!(cert.ocsp_response().unwrap(), None);
+
+        let response = [0x30, 0x03, 0x0a, 0x01, 0x00];
+        cert.set_ocsp_response(&response).unwrap();
+        assert_eq!(cert.ocsp_response().unwrap(), Some(response.to_vec()));
+    }
+
     #[test]
     fn fingerprint() {
         let der = include_bytes!("../test/cert.der");
@@ -525,5 +1941,161 @@ mod test {
             0x65, 0x44
         ]);
         assert_eq!(hash, pem.fingerprint(HashAlgorithm::sha256()).unwrap());
+
+        let alg = HashAlgorithm::from_cng_name("SHA256", 32).unwrap();
+        assert_eq!(alg.cng_name(), "SHA256");
+        assert_eq!(alg.digest_len(), 32);
+        assert_eq!(hash, der.fingerprint(alg.clone()).unwrap());
+        assert_eq!(hash, hash::hash(alg, der.to_der()).unwrap());
+        let err = HashAlgorithm::from_cng_name("SHA256", 20).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // names read at runtime outlive the strings they were read from
+        let name = format!("SHA3-{}", 256);
+        let alg = HashAlgorithm::from_cng_name(&name, 32).unwrap();
+        drop(name);
+        assert_eq!(alg.cng_name(), "SHA3-256");
+        assert_eq!(alg.as_raw(), 0);
+        assert_eq!(HashAlgorithm::from_cng_name("SHA3-256", 32).unwrap(), alg);
+    }
+
+    #[test]
+    fn encode() {
+        let der = include_bytes!("../test/cert.der");
+        let cert = CertContext::new(der).unwrap();
+        assert_eq!(cert.to_der(), &der[..]);
+
+        let pem = cert.to_pem().unwrap();
+        assert!(pem.starts_with("-----BEGIN CERTIFICATE-----\r\n"));
+        assert!(pem.ends_with("-----END CERTIFICATE-----\r\n"));
+        assert_eq!(CertContext::from_pem(&pem).unwrap(), cert);
+
+        let strip = |s: &str| s.replace("\r", "").replace("\n", "");
+        assert_eq!(strip(&pem), strip(include_str!("../test/cert.pem")));
+    }
+
+    #[test]
+    fn validity() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+        let from = UNIX_EPOCH + Duration::from_secs(1471194003);
+        let to = UNIX_EPOCH + Duration::from_secs(1786554003);
+        assert_eq!(cert.valid_from(), from);
+        assert_eq!(cert.valid_to(), to);
+
+        assert!(!cert.is_time_valid_at(from - Duration::from_secs(1)).unwrap());
+        assert!(cert.is_time_valid_at(from).unwrap());
+        assert!(cert.is_time_valid_at(to).unwrap());
+        assert!(!cert.is_time_valid_at(to + Duration::from_secs(1)).unwrap());
+        // times before 1970, and even 1601, don't panic
+        assert!(!cert.is_time_valid_at(UNIX_EPOCH - Duration::from_secs(1 << 40)).unwrap());
+    }
+
+    #[test]
+    fn names() {
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+        assert_eq!(cert.subject().unwrap(),
+                   "C=AU, S=Some-State, O=Internet Widgits Pty Ltd, CN=foobar.com");
+        assert_eq!(cert.issuer().unwrap(), "C=AU, S=Some-State, O=Internet Widgits Pty Ltd");
+
+        // the names are DER sequences embedded in the certificate
+        let der = include_bytes!("../test/cert.der");
+        for name in &[cert.subject_der(), cert.issuer_der()] {
+            assert_eq!(name[0], 0x30);
+            assert!(der.windows(name.len()).any(|w| w == &name[..]));
+        }
+        assert!(cert.subject_der() != cert.issuer_der());
+    }
+
+    #[test]
+    fn extensions_and_alt_names() {
+        use ncrypt_key::{CreateOptions, KeyAlgorithm};
+
+        let key = CreateOptions::new().create(KeyAlgorithm::ecdsa_p256()).unwrap();
+        let key = PrivateKey::NcryptKey(key);
+        let cert = CertContext::self_signed("CN=schannel-rs", &key)
+            .key_usage(KeyUsage::digital_signature())
+            .dns_name("example.com")
+            .ip_address("::1".parse().unwrap())
+            .create()
+            .unwrap();
+        assert_eq!(cert.subject_alt_names().unwrap(),
+                   vec![AltName::Dns("example.com".to_owned()),
+                        AltName::Ip("::1".parse().unwrap())]);
+
+        let usage = cert.extension(ffi::szOID_KEY_USAGE).unwrap().unwrap();
+        assert_eq!(usage[0], 0x03);
+        assert_eq!(cert.extension("1.2.3.4").unwrap(), None);
+        assert!(cert.extension("2.5\029.17").is_err());
+
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+        assert_eq!(cert.subject_alt_names().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn enhanced_key_usage() {
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+        for &source in &[UsageSource::both(), UsageSource::extension(), UsageSource::property()] {
+            assert_eq!(cert.enhanced_key_usage(source).unwrap(), ValidUses::All);
+        }
+        assert!(ValidUses::All.allows("1.3.6.1.5.5.7.3.1"));
+
+        // restrict the certificate to server authentication
+        let mut server_auth = b"1.3.6.1.5.5.7.3.1\0".to_vec();
+        let mut oids = [server_auth.as_mut_ptr() as ffi::LPSTR];
+        let set_usage = |oids: &mut [ffi::LPSTR]| unsafe {
+            let usage = ffi::CTL_USAGE {
+                cUsageIdentifier: ::dword_len(oids.len()).unwrap(),
+                rgpszUsageIdentifier: oids.as_mut_ptr(),
+            };
+            let encoded = ::encode_object(ffi::X509_ENHANCED_KEY_USAGE,
+                                          &usage as *const _ as *const ffi::c_void)
+                .unwrap();
+            let blob = ffi::CRYPT_DATA_BLOB {
+                cbData: ::dword_len(encoded.len()).unwrap(),
+                pbData: encoded.as_ptr() as *mut _,
+            };
+            let res = ffi::CertSetCertificateContextProperty(cert.as_inner(),
+                                                             ffi::CERT_ENHKEY_USAGE_PROP_ID,
+                                                             0,
+                                                             &blob as *const _ as *const _);
+            assert_eq!(res, ffi::TRUE);
+        };
+
+        set_usage(&mut oids);
+        let server = ValidUses::Oids(vec!["1.3.6.1.5.5.7.3.1".to_owned()]);
+        assert_eq!(cert.enhanced_key_usage(UsageSource::property()).unwrap(), server);
+        assert_eq!(cert.enhanced_key_usage(UsageSource::both()).unwrap(), server);
+        assert_eq!(cert.enhanced_key_usage(UsageSource::extension()).unwrap(),
+                   ValidUses::All);
+        assert!(server.allows("1.3.6.1.5.5.7.3.1"));
+        assert!(!server.allows("1.3.6.1.5.5.7.3.2"));
+
+        // an empty usage is valid for nothing
+        set_usage(&mut []);
+        assert_eq!(cert.enhanced_key_usage(UsageSource::property()).unwrap(),
+                   ValidUses::Oids(vec![]));
+    }
+
+    #[test]
+    fn ip_addresses() {
+        assert_eq!(ip_from_bytes(&[127, 0, 0, 1]),
+                   Some("127.0.0.1".parse().unwrap()));
+        assert_eq!(ip_from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]),
+                   Some("::1".parse().unwrap()));
+        assert_eq!(ip_from_bytes(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34]),
+                   Some("2001:db8::1234".parse().unwrap()));
+        assert_eq!(ip_from_bytes(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn fingerprint_without_sha256_property() {
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+        let hash = cert.fingerprint(HashAlgorithm::sha256()).unwrap();
+
+        let _disabled = os_support::disable(Feature::Sha256Find);
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+        assert_eq!(cert.fingerprint(HashAlgorithm::sha256()).unwrap(), hash);
     }
 }
@@ -1,27 +1,37 @@
 //! Bindings to winapi's certificate-store related APIs.
 
-use crypt32;
-use std::cmp;
+use ffi;
 use std::ffi::OsStr;
 use std::fmt;
 use std::io;
 use std::mem;
 use std::os::windows::prelude::*;
+use std::path::Path;
 use std::ptr;
-use winapi;
 
-use cert_context::CertContext;
+use cert_context::{CertContext, HashAlgorithm, PrivateKey};
 use ctl_context::CtlContext;
+use ncrypt_key::NcryptKey;
+use os_support::{self, Feature};
 
 use Inner;
 
-// FIXME https://github.com/retep998/winapi-rs/pull/318
-const PKCS12_INCLUDE_EXTENDED_PROPERTIES: winapi::DWORD = 0x10;
-const PKCS12_NO_PERSIST_KEY: winapi::DWORD = 0x8000;
+/// The location of a system certificate store.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StoreLocation {
+    /// The stores of the current user, whose private keys are user keys.
+    CurrentUser,
+    /// The stores of the local machine, whose private keys are machine keys.
+    ///
+    /// Modifying these requires administrative rights.
+    LocalMachine,
+}
 
 /// Representation of certificate store on Windows, wrapping a `HCERTSTORE`.
-pub struct CertStore(winapi::HCERTSTORE);
+pub struct CertStore(ffi::HCERTSTORE);
 
+// Stores lock themselves internally, so they may be enumerated and modified
+// from any number of threads at once.
 unsafe impl Sync for CertStore {}
 unsafe impl Send for CertStore {}
 
@@ -34,18 +44,18 @@ impl fmt::Debug for CertStore {
 impl Drop for CertStore {
     fn drop(&mut self) {
         unsafe {
-            crypt32::CertCloseStore(self.0, 0);
+            ffi::CertCloseStore(self.0, 0);
         }
     }
 }
 
 impl Clone for CertStore {
     fn clone(&self) -> CertStore {
-        unsafe { CertStore(crypt32::CertDuplicateStore(self.0)) }
+        unsafe { CertStore(ffi::CertDuplicateStore(self.0)) }
     }
 }
 
-inner!(CertStore, winapi::HCERTSTORE);
+inner!(CertStore, ffi::HCERTSTORE);
 
 /// Argument to the `add_cert` function indicating how a certificate should be
 /// added to a `CertStore`.
@@ -53,11 +63,11 @@ pub enum CertAdd {
     /// The function makes no check for an existing matching certificate or link
     /// to a matching certificate. A new certificate is always added to the
     /// store. This can lead to duplicates in a store.
-    Always = winapi::CERT_STORE_ADD_ALWAYS as isize,
+    Always = ffi::CERT_STORE_ADD_ALWAYS as isize,
 
     /// If a matching certificate or a link to a matching certificate exists,
     /// the operation fails.
-    New = winapi::CERT_STORE_ADD_NEW as isize,
+    New = ffi::CERT_STORE_ADD_NEW as isize,
 
     /// If a matching certificate or a link to a matching certificate exists and
     /// the NotBefore time of the existing context is equal to or greater than
@@ -68,7 +78,7 @@ pub enum CertAdd {
     /// deleted and a new certificate is created and added to the store. If a
     /// matching certificate or a link to a matching certificate does not exist,
     /// a new link is added.
-    Newer = winapi::CERT_STORE_ADD_NEWER as isize,
+    Newer = ffi::CERT_STORE_ADD_NEWER as isize,
 
     /// If a matching certificate or a link to a matching certificate exists and
     /// the NotBefore time of the existing context is equal to or greater than
@@ -78,19 +88,19 @@ pub enum CertAdd {
     /// time of the new context being added, the existing context is deleted
     /// before creating and adding the new context. The new added context
     /// inherits properties from the existing certificate.
-    NewerInheritProperties = winapi::CERT_STORE_ADD_NEWER_INHERIT_PROPERTIES as isize,
+    NewerInheritProperties = ffi::CERT_STORE_ADD_NEWER_INHERIT_PROPERTIES as isize,
 
     /// If a link to a matching certificate exists, that existing certificate or
     /// link is deleted and a new certificate is created and added to the store.
     /// If a matching certificate or a link to a matching certificate does not
     /// exist, a new link is added.
-    ReplaceExisting = winapi::CERT_STORE_ADD_REPLACE_EXISTING as isize,
+    ReplaceExisting = ffi::CERT_STORE_ADD_REPLACE_EXISTING as isize,
 
     /// If a matching certificate exists in the store, the existing context is
     /// not replaced. The existing context inherits properties from the new
     /// certificate.
     ReplaceExistingInheritProperties =
-        winapi::CERT_STORE_ADD_REPLACE_EXISTING_INHERIT_PROPERTIES as isize,
+        ffi::CERT_STORE_ADD_REPLACE_EXISTING_INHERIT_PROPERTIES as isize,
 
     /// If a matching certificate or a link to a matching certificate exists,
     /// that existing certificate or link is used and properties from the
@@ -99,30 +109,48 @@ pub enum CertAdd {
     ///
     /// If a matching certificate or a link to a matching certificate does
     /// not exist, a new certificate is added.
-    UseExisting = winapi::CERT_STORE_ADD_USE_EXISTING as isize,
+    UseExisting = ffi::CERT_STORE_ADD_USE_EXISTING as isize,
 }
 
 impl CertStore {
+    /// Returns the raw `HCERTSTORE`.
+    ///
+    /// The store handle remains owned by this value, and is only valid for as
+    /// long as it is alive. Callers who need to keep it longer should take
+    /// their own reference with `CertDuplicateStore`.
+    pub fn as_raw(&self) -> ffi::HCERTSTORE {
+        self.0
+    }
+
+    /// Consumes this value, returning the raw `HCERTSTORE` along with the
+    /// reference it held.
+    ///
+    /// The caller becomes responsible for releasing it with `CertCloseStore`,
+    /// or for passing it back to `from_raw`.
+    pub fn into_raw(self) -> ffi::HCERTSTORE {
+        let raw = self.0;
+        mem::forget(self);
+        raw
+    }
+
+    /// Wraps a raw `HCERTSTORE`, taking ownership of one reference to it.
+    ///
+    /// The reference is released with `CertCloseStore` when the returned value
+    /// is dropped. To wrap a store handle while keeping the caller's reference,
+    /// take a new one with `CertDuplicateStore` first.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must be a valid store handle which the caller owns a reference to.
+    pub unsafe fn from_raw(raw: ffi::HCERTSTORE) -> CertStore {
+        CertStore(raw)
+    }
+
     /// Opens up the specified key store within the context of the current user.
     ///
     /// Known valid values for `which` are "Root" and "My".
     pub fn open_current_user(which: &str) -> io::Result<CertStore> {
-        unsafe {
-            let data = OsStr::new(which)
-                             .encode_wide()
-                             .chain(Some(0))
-                             .collect::<Vec<_>>();
-            let store = crypt32::CertOpenStore(winapi::CERT_STORE_PROV_SYSTEM_W as winapi::LPCSTR,
-                                               0,
-                                               0,
-                                               winapi::CERT_SYSTEM_STORE_CURRENT_USER,
-                                               data.as_ptr() as *mut _);
-            if store.is_null() {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(CertStore(store))
-            }
-        }
+        SystemStoreOptions::new().location(StoreLocation::CurrentUser).open(which)
     }
 
     /// Opens up the specified key store within the context of the local
@@ -130,22 +158,7 @@ impl CertStore {
     ///
     /// Known valid values for `which` are "Root" and "My".
     pub fn open_local_machine(which: &str) -> io::Result<CertStore> {
-        unsafe {
-            let data = OsStr::new(which)
-                             .encode_wide()
-                             .chain(Some(0))
-                             .collect::<Vec<_>>();
-            let store = crypt32::CertOpenStore(winapi::CERT_STORE_PROV_SYSTEM_W as winapi::LPCSTR,
-                                               0,
-                                               0,
-                                               winapi::CERT_SYSTEM_STORE_LOCAL_MACHINE,
-                                               data.as_ptr() as *mut _);
-            if store.is_null() {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(CertStore(store))
-            }
-        }
+        SystemStoreOptions::new().location(StoreLocation::LocalMachine).open(which)
     }
 
     /// Imports a PKCS#12-encoded key/certificate pair, returned as a
@@ -156,8 +169,8 @@ impl CertStore {
                          password: Option<&str>)
                          -> io::Result<CertStore> {
         unsafe {
-            let mut blob = winapi::CRYPT_INTEGER_BLOB {
-                cbData: data.len() as winapi::DWORD,
+            let mut blob = ffi::CRYPT_INTEGER_BLOB {
+                cbData: try!(::dword_len(data.len())),
                 pbData: data.as_ptr() as *mut u8,
             };
             let password = password.map(|s| {
@@ -167,20 +180,141 @@ impl CertStore {
             });
             let password = password.as_ref().map(|s| s.as_ptr());
             let password = password.unwrap_or(ptr::null());
-            let res = crypt32::PFXImportCertStore(&mut blob,
-                                                  password,
-                                                  0);
+            let res = ffi::PFXImportCertStore(&mut blob,
+                                              password,
+                                              0);
             if res.is_null() {
-                Err(io::Error::last_os_error())
+                Err(::error::last_error())
             } else {
                 Ok(CertStore(res))
             }
         }
     }
 
+    /// Opens a store from the serialized form produced by `to_serialized`.
+    ///
+    /// The returned store is a copy in memory, including the properties of
+    /// the certificates, such as their friendly names and private keys.
+    pub fn from_serialized(data: &[u8]) -> io::Result<CertStore> {
+        unsafe {
+            let mut blob = ffi::CRYPT_DATA_BLOB {
+                cbData: try!(::dword_len(data.len())),
+                pbData: data.as_ptr() as *mut u8,
+            };
+            let store = ffi::CertOpenStore(ffi::CERT_STORE_PROV_SERIALIZED as ffi::LPCSTR,
+                                           0,
+                                           0,
+                                           0,
+                                           &mut blob as *mut _ as *mut _);
+            if store.is_null() {
+                Err(::error::last_error())
+            } else {
+                Ok(CertStore(store))
+            }
+        }
+    }
+
+    /// Opens a store from a file, such as a serialized store written by
+    /// `save_to_file` or a `.sst` file exported by certmgr.
+    ///
+    /// PKCS #7 and single encoded certificates are also accepted. The file is
+    /// read into a store in memory, and changes made to it are not written
+    /// back.
+    pub fn open_file<P: AsRef<Path>>(path: P) -> io::Result<CertStore> {
+        unsafe {
+            let path = wide_path(path.as_ref());
+            let store = ffi::CertOpenStore(ffi::CERT_STORE_PROV_FILENAME_W as ffi::LPCSTR,
+                                           ffi::X509_ASN_ENCODING | ffi::PKCS_7_ASN_ENCODING,
+                                           0,
+                                           ffi::CERT_STORE_OPEN_EXISTING_FLAG,
+                                           path.as_ptr() as *mut _);
+            if store.is_null() {
+                Err(::error::last_error())
+            } else {
+                Ok(CertStore(store))
+            }
+        }
+    }
+
+    /// Serializes this store, including the certificates, CRLs and CTLs in it
+    /// along with their properties.
+    ///
+    /// This is the format of `.sst` files, and it can be read back with
+    /// `from_serialized`.
+    pub fn to_serialized(&self) -> io::Result<Vec<u8>> {
+        unsafe {
+            let mut blob = ffi::CRYPT_DATA_BLOB {
+                cbData: 0,
+                pbData: ptr::null_mut(),
+            };
+            try!(self.save(ffi::CERT_STORE_SAVE_TO_MEMORY, &mut blob as *mut _ as *mut _));
+
+            let mut buf = vec![0; blob.cbData as usize];
+            blob.pbData = buf.as_mut_ptr();
+            try!(self.save(ffi::CERT_STORE_SAVE_TO_MEMORY, &mut blob as *mut _ as *mut _));
+            buf.truncate(blob.cbData as usize);
+            Ok(buf)
+        }
+    }
+
+    /// Writes the serialized form of this store to a file, which is replaced
+    /// if it already exists.
+    ///
+    /// The file can be opened again with `open_file`.
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        unsafe {
+            let path = wide_path(path.as_ref());
+            self.save(ffi::CERT_STORE_SAVE_TO_FILENAME_W, path.as_ptr() as *mut _)
+        }
+    }
+
+    unsafe fn save(&self, save_to: ffi::DWORD, para: *mut ffi::c_void) -> io::Result<()> {
+        let res = ffi::CertSaveStore(self.0,
+                                     ffi::X509_ASN_ENCODING | ffi::PKCS_7_ASN_ENCODING,
+                                     ffi::CERT_STORE_SAVE_AS_STORE,
+                                     save_to,
+                                     para,
+                                     0);
+        if res == ffi::TRUE {
+            Ok(())
+        } else {
+            Err(::error::last_error())
+        }
+    }
+
     /// Returns an iterator over the certificates in this certificate store.
-    pub fn certs(&mut self) -> Certs {
-        Certs { store: self, cur: None }
+    ///
+    /// Each certificate returned holds its own reference, so it remains valid
+    /// after the iterator and the store are dropped.
+    pub fn certs(&self) -> Certs {
+        Certs {
+            store: self,
+            cur: None,
+            #[cfg(feature = "log")]
+            count: 0,
+        }
+    }
+
+    /// Returns a builder used to search this certificate store.
+    ///
+    /// Without any criteria the search matches every certificate.
+    pub fn find<'a>(&'a self) -> FindOptions<'a> {
+        FindOptions {
+            store: self,
+            criterion: Criterion::Any,
+        }
+    }
+
+    /// Returns the number of certificates in this certificate store.
+    ///
+    /// The store is enumerated to count them.
+    pub fn len(&self) -> usize {
+        self.certs().count()
+    }
+
+    /// Determines if this certificate store contains no certificates.
+    pub fn is_empty(&self) -> bool {
+        self.certs().next().is_none()
     }
 
     /// Adds a certificate context to this store.
@@ -191,60 +325,61 @@ impl CertStore {
                     cx: &CertContext,
                     how: CertAdd) -> io::Result<CertContext> {
         unsafe {
-            let how = how as winapi::DWORD;
-            let mut ret = ptr::null();
-            let res = crypt32::CertAddCertificateContextToStore(self.0,
-                                                                cx.as_inner(),
-                                                                how,
-                                                                &mut ret);
-            if res != winapi::TRUE {
-                Err(io::Error::last_os_error())
+            let how = how as ffi::DWORD;
+            let mut ret: ffi::PCCERT_CONTEXT = ptr::null();
+            let res = ffi::CertAddCertificateContextToStore(self.0,
+                                                            cx.as_inner(),
+                                                            how,
+                                                            &mut ret as *mut _ as *mut _);
+            if res != ffi::TRUE {
+                Err(::error::last_error())
             } else {
                 Ok(CertContext::from_inner(ret))
             }
         }
     }
 
-    /// Exports this certificate store as a PKCS#12-encoded blob.
+    /// Adds a CTL context to this store.
     ///
-    /// The password specified will be the password used to unlock the returned
-    /// data.
-    pub fn export_pkcs12(&self, password: &str) -> io::Result<Vec<u8>> {
-        const EXPORT_PRIVATE_KEYS: winapi::DWORD = 0x4;
-
+    /// A copy of the added CTL is returned.
+    pub fn add_ctl(&mut self,
+                   ctl: &CtlContext,
+                   how: CertAdd) -> io::Result<CtlContext> {
         unsafe {
-            let password = password.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
-            let mut blob = winapi::CRYPT_DATA_BLOB {
-                cbData: 0,
-                pbData: 0 as *mut _,
-            };
-            let res = crypt32::PFXExportCertStore(self.0,
-                                                  &mut blob,
-                                                  password.as_ptr(),
-                                                  EXPORT_PRIVATE_KEYS);
-            if res != winapi::TRUE {
-                return Err(io::Error::last_os_error())
-            }
-            let mut ret = Vec::with_capacity(blob.cbData as usize);
-            blob.pbData = ret.as_mut_ptr();
-            let res = crypt32::PFXExportCertStore(self.0,
-                                                  &mut blob,
-                                                  password.as_ptr(),
-                                                  EXPORT_PRIVATE_KEYS);
-            if res != winapi::TRUE {
-                return Err(io::Error::last_os_error())
+            let how = how as ffi::DWORD;
+            let mut ret: ffi::PCCTL_CONTEXT = ptr::null();
+            let res = ffi::CertAddCTLContextToStore(self.0,
+                                                    ctl.as_inner(),
+                                                    how,
+                                                    &mut ret as *mut _ as *mut _);
+            if res != ffi::TRUE {
+                Err(::error::last_error())
+            } else {
+                Ok(CtlContext::from_inner(ret))
             }
-            ret.set_len(blob.cbData as usize);
-            Ok(ret)
         }
     }
+
+    /// Exports this certificate store as a PKCS#12-encoded blob.
+    ///
+    /// The password specified will be the password used to unlock the returned
+    /// data. Private keys are included; `PfxExportOptions` allows more control
+    /// over the export.
+    pub fn export_pkcs12(&self, password: &str) -> io::Result<Vec<u8>> {
+        PfxExportOptions::new()
+            .password(password)
+            .export_private_keys(true)
+            .export(self)
+    }
 }
 
 /// An iterator over the certificates contained in a `CertStore`, returned by
-/// `CertStore::iter`
+/// `CertStore::certs`
 pub struct Certs<'a> {
-    store: &'a mut CertStore,
+    store: &'a CertStore,
     cur: Option<CertContext>,
+    #[cfg(feature = "log")]
+    count: usize,
 }
 
 impl<'a> Iterator for Certs<'a> {
@@ -258,12 +393,18 @@ impl<'a> Iterator for Certs<'a> {
                 ptr
             });
             let cur = cur.unwrap_or(ptr::null_mut());
-            let next = crypt32::CertEnumCertificatesInStore(self.store.0, cur);
+            let next = ffi::CertEnumCertificatesInStore(self.store.0, cur);
 
             if next.is_null() {
+                #[cfg(feature = "log")]
+                trace!("enumerated {} certificates in store", self.count);
                 self.cur = None;
                 None
             } else {
+                #[cfg(feature = "log")]
+                {
+                    self.count += 1;
+                }
                 let next = CertContext::from_inner(next);
                 self.cur = Some(next.clone());
                 Some(next)
@@ -272,11 +413,263 @@ impl<'a> Iterator for Certs<'a> {
     }
 }
 
+impl<'a> IntoIterator for &'a CertStore {
+    type Item = CertContext;
+    type IntoIter = Certs<'a>;
+
+    fn into_iter(self) -> Certs<'a> {
+        self.certs()
+    }
+}
+
+enum Criterion {
+    Any,
+    Subject(Vec<u16>),
+    Issuer(Vec<u16>),
+    Hash(ffi::DWORD, Vec<u8>),
+}
+
+/// A builder type for certificate searches, returned by `CertStore::find`.
+///
+/// Only one criterion is used at a time; setting a criterion replaces any
+/// previously set one.
+pub struct FindOptions<'a> {
+    store: &'a CertStore,
+    criterion: Criterion,
+}
+
+impl<'a> FindOptions<'a> {
+    /// Matches certificates whose subject name contains `subject`.
+    ///
+    /// The comparison is case-insensitive.
+    pub fn subject(&mut self, subject: &str) -> &mut FindOptions<'a> {
+        self.criterion = Criterion::Subject(subject.encode_utf16().chain(Some(0)).collect());
+        self
+    }
+
+    /// Matches certificates whose issuer name contains `issuer`.
+    ///
+    /// The comparison is case-insensitive.
+    pub fn issuer(&mut self, issuer: &str) -> &mut FindOptions<'a> {
+        self.criterion = Criterion::Issuer(issuer.encode_utf16().chain(Some(0)).collect());
+        self
+    }
+
+    /// Matches the certificate with the specified SHA-1 thumbprint.
+    pub fn sha1(&mut self, hash: &[u8]) -> &mut FindOptions<'a> {
+        self.criterion = Criterion::Hash(ffi::CERT_FIND_SHA1_HASH, hash.to_vec());
+        self
+    }
+
+    /// Matches the certificate with the specified SHA-256 thumbprint.
+    ///
+    /// Before Windows 8 this enumerates the store and hashes every
+    /// certificate in it.
+    pub fn sha256(&mut self, hash: &[u8]) -> &mut FindOptions<'a> {
+        self.criterion = Criterion::Hash(ffi::CERT_FIND_SHA256_HASH, hash.to_vec());
+        self
+    }
+
+    /// Matches certificates with the specified key identifier.
+    ///
+    /// This is the subject key identifier extension if the certificate has
+    /// one, and a hash of its public key otherwise.
+    pub fn key_identifier(&mut self, id: &[u8]) -> &mut FindOptions<'a> {
+        self.criterion = Criterion::Hash(ffi::CERT_FIND_KEY_IDENTIFIER, id.to_vec());
+        self
+    }
+
+    /// Returns the first matching certificate.
+    pub fn first(&self) -> Option<CertContext> {
+        self.iter().next()
+    }
+
+    /// Returns an iterator over all matching certificates.
+    pub fn iter<'b>(&'b self) -> FindCerts<'b> {
+        let fallback = match self.criterion {
+            Criterion::Hash(ffi::CERT_FIND_SHA256_HASH, _)
+                if !os_support::os_capabilities().supports(Feature::Sha256Find) => {
+                debug!("searching for a SHA-256 thumbprint by enumeration");
+                Some(self.store.certs())
+            }
+            _ => None,
+        };
+        FindCerts {
+            options: self,
+            cur: None,
+            fallback: fallback,
+        }
+    }
+}
+
+/// An iterator over the certificates matching a search, returned by
+/// `FindOptions::iter`.
+pub struct FindCerts<'a> {
+    options: &'a FindOptions<'a>,
+    cur: Option<CertContext>,
+    fallback: Option<Certs<'a>>,
+}
+
+impl<'a> Iterator for FindCerts<'a> {
+    type Item = CertContext;
+
+    fn next(&mut self) -> Option<CertContext> {
+        if let Some(ref mut certs) = self.fallback {
+            let hash = match self.options.criterion {
+                Criterion::Hash(_, ref hash) => hash,
+                _ => unreachable!(),
+            };
+            return certs.find(|c| {
+                c.fingerprint(HashAlgorithm::sha256()).ok().map_or(false, |f| f == *hash)
+            });
+        }
+
+        unsafe {
+            let mut blob = ffi::CRYPT_HASH_BLOB {
+                cbData: 0,
+                pbData: ptr::null_mut(),
+            };
+            let (find_type, para) = match self.options.criterion {
+                Criterion::Any => (ffi::CERT_FIND_ANY, ptr::null()),
+                Criterion::Subject(ref s) => {
+                    (ffi::CERT_FIND_SUBJECT_STR_W, s.as_ptr() as *const ffi::c_void)
+                }
+                Criterion::Issuer(ref s) => {
+                    (ffi::CERT_FIND_ISSUER_STR_W, s.as_ptr() as *const ffi::c_void)
+                }
+                Criterion::Hash(find_type, ref hash) => {
+                    blob.cbData = match ::dword_len(hash.len()) {
+                        Ok(len) => len,
+                        Err(_) => return None,
+                    };
+                    blob.pbData = hash.as_ptr() as *mut u8;
+                    (find_type, &blob as *const _ as *const ffi::c_void)
+                }
+            };
+
+            // the previous context is freed by the search, so ownership of
+            // our reference to it is handed over
+            let prev = self.cur.take().map(|p| {
+                let ptr = p.as_inner();
+                mem::forget(p);
+                ptr
+            });
+            let prev = prev.unwrap_or(ptr::null_mut());
+            let next = ffi::CertFindCertificateInStore(self.options.store.0,
+                                                       ffi::X509_ASN_ENCODING |
+                                                       ffi::PKCS_7_ASN_ENCODING,
+                                                       0,
+                                                       find_type,
+                                                       para,
+                                                       prev);
+            if next.is_null() {
+                None
+            } else {
+                let next = CertContext::from_inner(next as ffi::PCCERT_CONTEXT);
+                self.cur = Some(next.clone());
+                Some(next)
+            }
+        }
+    }
+}
+
+/// A builder type for opening system certificate stores.
+///
+/// ```no_run
+/// use schannel::cert_store::{StoreLocation, SystemStoreOptions};
+///
+/// let store = SystemStoreOptions::new()
+///     .location(StoreLocation::CurrentUser)
+///     .read_only(true)
+///     .open("My")
+///     .unwrap();
+/// for cert in &store {
+///     println!("{:?}", cert.friendly_name());
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct SystemStoreOptions {
+    location: StoreLocation,
+    flags: ffi::DWORD,
+}
+
+impl Default for SystemStoreOptions {
+    fn default() -> SystemStoreOptions {
+        SystemStoreOptions::new()
+    }
+}
+
+impl SystemStoreOptions {
+    /// Returns a new `SystemStoreOptions` opening stores of the current user.
+    pub fn new() -> SystemStoreOptions {
+        SystemStoreOptions {
+            location: StoreLocation::CurrentUser,
+            flags: 0,
+        }
+    }
+
+    /// Sets the location of the store.
+    pub fn location(&mut self, location: StoreLocation) -> &mut SystemStoreOptions {
+        self.location = location;
+        self
+    }
+
+    /// If set, the store is opened read-only.
+    ///
+    /// Read-only access to the local machine's stores doesn't require
+    /// administrative rights.
+    pub fn read_only(&mut self, read_only: bool) -> &mut SystemStoreOptions {
+        self.flag(ffi::CERT_STORE_READONLY_FLAG, read_only)
+    }
+
+    /// If set, opening a store which doesn't exist fails rather than creating
+    /// it.
+    pub fn open_existing(&mut self, open_existing: bool) -> &mut SystemStoreOptions {
+        self.flag(ffi::CERT_STORE_OPEN_EXISTING_FLAG, open_existing)
+    }
+
+    fn flag(&mut self, flag: ffi::DWORD, set: bool) -> &mut SystemStoreOptions {
+        if set {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+
+    /// Opens the system store named `which`.
+    pub fn open(&self, which: &str) -> io::Result<CertStore> {
+        let location = match self.location {
+            StoreLocation::CurrentUser => ffi::CERT_SYSTEM_STORE_CURRENT_USER,
+            StoreLocation::LocalMachine => ffi::CERT_SYSTEM_STORE_LOCAL_MACHINE,
+        };
+        unsafe {
+            let data = OsStr::new(which)
+                             .encode_wide()
+                             .chain(Some(0))
+                             .collect::<Vec<_>>();
+            let store = ffi::CertOpenStore(ffi::CERT_STORE_PROV_SYSTEM_W as ffi::LPCSTR,
+                                           0,
+                                           0,
+                                           location | self.flags,
+                                           data.as_ptr() as *mut _);
+            if store.is_null() {
+                let err = ::error::last_error();
+                debug!("failed to open {:?} store {:?}: {}", self.location, which, err);
+                Err(err)
+            } else {
+                debug!("opened {:?} store {:?}", self.location, which);
+                Ok(CertStore(store))
+            }
+        }
+    }
+}
+
 /// A builder type for imports of PKCS #12 archives.
 #[derive(Default)]
 pub struct PfxImportOptions {
     password: Option<Vec<u16>>,
-    flags: winapi::DWORD,
+    flags: ffi::DWORD,
 }
 
 impl PfxImportOptions {
@@ -294,18 +687,45 @@ impl PfxImportOptions {
     /// If set, the private key in the archive will not be persisted.
     ///
     /// If not set, private keys are persisted on disk and must be manually deleted.
+    /// Imports with this set fail with an `UnsupportedFeature` error before
+    /// Windows 8.
     pub fn no_persist_key(&mut self, no_persist_key: bool) -> &mut PfxImportOptions {
-        self.flag(PKCS12_NO_PERSIST_KEY, no_persist_key)
+        self.flag(ffi::PKCS12_NO_PERSIST_KEY, no_persist_key)
+    }
+
+    /// If set, private keys will always be imported into a CNG key storage
+    /// provider, even if the archive specifies a CryptoAPI provider.
+    pub fn always_cng(&mut self, always_cng: bool) -> &mut PfxImportOptions {
+        self.flag(ffi::PKCS12_ALWAYS_CNG_KSP, always_cng)
+    }
+
+    /// If set, imported private keys can be exported again.
+    pub fn exportable(&mut self, exportable: bool) -> &mut PfxImportOptions {
+        self.flag(ffi::CRYPT_EXPORTABLE, exportable)
+    }
+
+    /// If set, private keys will be persisted as machine rather than user
+    /// keys.
+    ///
+    /// This requires administrative rights.
+    pub fn machine_keyset(&mut self, machine_keyset: bool) -> &mut PfxImportOptions {
+        self.flag(ffi::CRYPT_MACHINE_KEYSET, machine_keyset)
+    }
+
+    /// If set, private keys will be persisted as user keys, even if the
+    /// archive specifies that they should be machine keys.
+    pub fn user_keyset(&mut self, user_keyset: bool) -> &mut PfxImportOptions {
+        self.flag(ffi::CRYPT_USER_KEYSET, user_keyset)
     }
 
     /// If set, all extended properties of the certificate will be imported.
     pub fn include_extended_properties(&mut self,
                                        include_extended_properties: bool)
                                        -> &mut PfxImportOptions {
-        self.flag(PKCS12_INCLUDE_EXTENDED_PROPERTIES, include_extended_properties)
+        self.flag(ffi::PKCS12_INCLUDE_EXTENDED_PROPERTIES, include_extended_properties)
     }
 
-    fn flag(&mut self, flag: winapi::DWORD, set: bool) -> &mut PfxImportOptions {
+    fn flag(&mut self, flag: ffi::DWORD, set: bool) -> &mut PfxImportOptions {
         if set {
             self.flags |= flag;
         } else {
@@ -316,22 +736,188 @@ impl PfxImportOptions {
 
     /// Imports certificates from a PKCS #12 archive, returning a `CertStore` containing them.
     pub fn import(&self, data: &[u8]) -> io::Result<CertStore> {
+        if self.flags & ffi::PKCS12_NO_PERSIST_KEY != 0 {
+            try!(os_support::require(Feature::Pkcs12NoPersistKey));
+        }
+
         unsafe {
-            let mut blob = winapi::CRYPT_DATA_BLOB {
-                cbData: cmp::min(data.len(), winapi::DWORD::max_value() as usize) as winapi::DWORD,
+            let mut blob = ffi::CRYPT_DATA_BLOB {
+                cbData: try!(::dword_len(data.len())),
                 pbData: data.as_ptr() as *const _ as *mut _,
             };
             let password = self.password.as_ref().map_or(ptr::null(), |p| p.as_ptr());
 
-            let store = crypt32::PFXImportCertStore(&mut blob, password, self.flags);
+            let store = ffi::PFXImportCertStore(&mut blob, password, self.flags);
             if store.is_null() {
-                return Err(io::Error::last_os_error());
+                let err = ::error::last_error();
+                debug!("failed to import PKCS #12 archive: {}", err);
+                return Err(err);
             }
+            debug!("imported PKCS #12 archive");
             Ok(CertStore(store))
         }
     }
 }
 
+/// A builder type for exports of certificate stores to PKCS #12 archives.
+#[derive(Default)]
+pub struct PfxExportOptions {
+    password: Option<Vec<u16>>,
+    flags: ffi::DWORD,
+}
+
+impl PfxExportOptions {
+    /// Returns a new `PfxExportOptions` with default settings.
+    pub fn new() -> PfxExportOptions {
+        PfxExportOptions::default()
+    }
+
+    /// Sets the password to be used to encrypt the archive.
+    pub fn password(&mut self, password: &str) -> &mut PfxExportOptions {
+        self.password = Some(password.encode_utf16().chain(Some(0)).collect());
+        self
+    }
+
+    /// If set, the private keys of the certificates will be exported as well.
+    pub fn export_private_keys(&mut self, export_private_keys: bool) -> &mut PfxExportOptions {
+        self.flag(ffi::EXPORT_PRIVATE_KEYS, export_private_keys)
+    }
+
+    /// If set, the export fails if a certificate has no private key.
+    pub fn report_no_private_key(&mut self,
+                                 report_no_private_key: bool)
+                                 -> &mut PfxExportOptions {
+        self.flag(ffi::REPORT_NO_PRIVATE_KEY, report_no_private_key)
+    }
+
+    /// If set, the export fails if the private key of a certificate can't be
+    /// exported.
+    pub fn report_not_able_to_export_private_key(&mut self,
+                                                 report: bool)
+                                                 -> &mut PfxExportOptions {
+        self.flag(ffi::REPORT_NOT_ABLE_TO_EXPORT_PRIVATE_KEY, report)
+    }
+
+    /// If set, all extended properties of the certificates will be exported.
+    pub fn include_extended_properties(&mut self,
+                                       include_extended_properties: bool)
+                                       -> &mut PfxExportOptions {
+        self.flag(ffi::PKCS12_INCLUDE_EXTENDED_PROPERTIES, include_extended_properties)
+    }
+
+    fn flag(&mut self, flag: ffi::DWORD, set: bool) -> &mut PfxExportOptions {
+        if set {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+
+    /// Exports the certificates in `store` to a PKCS #12 archive.
+    pub fn export(&self, store: &CertStore) -> io::Result<Vec<u8>> {
+        unsafe {
+            let password = self.password.as_ref().map_or(ptr::null(), |p| p.as_ptr());
+            let mut blob = ffi::CRYPT_DATA_BLOB {
+                cbData: 0,
+                pbData: ptr::null_mut(),
+            };
+            let res = ffi::PFXExportCertStoreEx(store.0,
+                                                &mut blob,
+                                                password,
+                                                ptr::null_mut(),
+                                                self.flags);
+            if res != ffi::TRUE {
+                let err = ::error::last_error();
+                debug!("failed to export PKCS #12 archive: {}", err);
+                return Err(err);
+            }
+            let mut ret = Vec::with_capacity(blob.cbData as usize);
+            blob.pbData = ret.as_mut_ptr();
+            let res = ffi::PFXExportCertStoreEx(store.0,
+                                                &mut blob,
+                                                password,
+                                                ptr::null_mut(),
+                                                self.flags);
+            if res != ffi::TRUE {
+                let err = ::error::last_error();
+                debug!("failed to export PKCS #12 archive: {}", err);
+                return Err(err);
+            }
+            ret.set_len(blob.cbData as usize);
+            debug!("exported PKCS #12 archive");
+            Ok(ret)
+        }
+    }
+}
+
+/// Imports the certificate with a private key from a PKCS #12 archive, along
+/// with that key.
+///
+/// The key is imported into memory only; nothing is persisted to disk or to
+/// the registry. Returns an error of kind `InvalidInput` if the archive
+/// contains more than one certificate with a private key, in which case
+/// `pfx_to_cert_and_key_by_thumbprint` can be used instead.
+pub fn pfx_to_cert_and_key(data: &[u8], password: &str) -> io::Result<(CertContext, NcryptKey)> {
+    pfx_cert_and_key(data, password, None)
+}
+
+/// Like `pfx_to_cert_and_key`, but selects the certificate with the specified
+/// SHA-1 thumbprint.
+pub fn pfx_to_cert_and_key_by_thumbprint(data: &[u8],
+                                         password: &str,
+                                         thumbprint: &[u8])
+                                         -> io::Result<(CertContext, NcryptKey)> {
+    pfx_cert_and_key(data, password, Some(thumbprint))
+}
+
+fn pfx_cert_and_key(data: &[u8],
+                    password: &str,
+                    thumbprint: Option<&[u8]>)
+                    -> io::Result<(CertContext, NcryptKey)> {
+    let store = try!(PfxImportOptions::new()
+        .password(password)
+        .no_persist_key(true)
+        .always_cng(true)
+        .import(data));
+
+    let mut found = None;
+    for cert in store.certs() {
+        if let Some(thumbprint) = thumbprint {
+            if try!(cert.fingerprint(HashAlgorithm::sha1())) != thumbprint {
+                continue;
+            }
+        }
+        let key = match cert.private_key().silent(true).acquire() {
+            Ok(PrivateKey::NcryptKey(key)) => key,
+            Ok(PrivateKey::CryptProv(_)) => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "private key was not imported into CNG"))
+            }
+            Err(e) => {
+                if thumbprint.is_some() {
+                    return Err(e);
+                }
+                continue;
+            }
+        };
+        if found.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "archive contains multiple certificates with private keys"));
+        }
+        found = Some((cert, key));
+    }
+
+    found.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound,
+                       "archive contains no matching certificate with a private key")
+    })
+}
+
+fn wide_path(path: &Path) -> Vec<u16> {
+    path.as_os_str().encode_wide().chain(Some(0)).collect()
+}
+
 /// Representation of an in-memory certificate store.
 ///
 /// Internally this contains a `CertStore` which this type can be converted to.
@@ -344,13 +930,13 @@ impl Memory {
     /// Initially the returned certificate store contains no certificates.
     pub fn new() -> io::Result<Memory> {
         unsafe {
-            let store = crypt32::CertOpenStore(winapi::CERT_STORE_PROV_MEMORY as winapi::LPCSTR,
-                                               0,
-                                               0,
-                                               0,
-                                               ptr::null_mut());
+            let store = ffi::CertOpenStore(ffi::CERT_STORE_PROV_MEMORY as ffi::LPCSTR,
+                                           0,
+                                           0,
+                                           0,
+                                           ptr::null_mut());
             if store.is_null() {
-                Err(io::Error::last_os_error())
+                Err(::error::last_error())
             } else {
                 Ok(Memory(CertStore(store)))
             }
@@ -362,19 +948,19 @@ impl Memory {
     /// For example the bytes could be a DER-encoded certificate.
     pub fn add_encoded_certificate(&mut self, cert: &[u8]) -> io::Result<CertContext> {
         unsafe {
-            let mut cert_context = ptr::null();
-
-            let res = crypt32::CertAddEncodedCertificateToStore((self.0).0,
-                                                                winapi::X509_ASN_ENCODING |
-                                                                winapi::PKCS_7_ASN_ENCODING,
-                                                                cert.as_ptr() as *const _,
-                                                                cert.len() as winapi::DWORD,
-                                                                winapi::CERT_STORE_ADD_ALWAYS,
-                                                                &mut cert_context);
-            if res == winapi::TRUE {
+            let mut cert_context: ffi::PCCERT_CONTEXT = ptr::null();
+
+            let res = ffi::CertAddEncodedCertificateToStore((self.0).0,
+                                                            ffi::X509_ASN_ENCODING |
+                                                            ffi::PKCS_7_ASN_ENCODING,
+                                                            cert.as_ptr() as *const _,
+                                                            try!(::dword_len(cert.len())),
+                                                            ffi::CERT_STORE_ADD_ALWAYS,
+                                                            &mut cert_context as *mut _ as *mut _);
+            if res == ffi::TRUE {
                 Ok(CertContext::from_inner(cert_context))
             } else {
-                Err(io::Error::last_os_error())
+                Err(::error::last_error())
             }
         }
     }
@@ -384,19 +970,19 @@ impl Memory {
     /// This can be created through the `ctl_context::Builder` type.
     pub fn add_encoded_ctl(&mut self, ctl: &[u8]) -> io::Result<CtlContext> {
         unsafe {
-            let mut ctl_context = ptr::null();
-
-            let res = crypt32::CertAddEncodedCTLToStore((self.0).0,
-                                                        winapi::X509_ASN_ENCODING |
-                                                        winapi::PKCS_7_ASN_ENCODING,
-                                                        ctl.as_ptr() as *const _,
-                                                        ctl.len() as winapi::DWORD,
-                                                        winapi::CERT_STORE_ADD_ALWAYS,
-                                                        &mut ctl_context);
-            if res == winapi::TRUE {
+            let mut ctl_context: ffi::PCCTL_CONTEXT = ptr::null();
+
+            let res = ffi::CertAddEncodedCTLToStore((self.0).0,
+                                                    ffi::X509_ASN_ENCODING |
+                                                    ffi::PKCS_7_ASN_ENCODING,
+                                                    ctl.as_ptr() as *const _,
+                                                    try!(::dword_len(ctl.len())),
+                                                    ffi::CERT_STORE_ADD_ALWAYS,
+                                                    &mut ctl_context as *mut _ as *mut _);
+            if res == ffi::TRUE {
                 Ok(CtlContext::from_inner(ctl_context))
             } else {
-                Err(io::Error::last_os_error())
+                Err(::error::last_error())
             }
         }
     }
@@ -412,6 +998,216 @@ mod test {
     use super::*;
     use ctl_context::CtlContext;
 
+    #[test]
+    fn raw() {
+        let der = include_bytes!("../test/cert.der");
+        let mut store = Memory::new().unwrap();
+        let cert = store.add_encoded_certificate(der).unwrap();
+        let store = unsafe { CertStore::from_raw(store.into_store().into_raw()) };
+
+        let raw = cert.into_raw();
+        let cert = unsafe { CertContext::from_raw(raw) };
+        assert_eq!(cert.as_raw(), raw);
+        let dup = unsafe {
+            CertContext::from_raw(ffi::CertDuplicateCertificateContext(cert.as_raw()))
+        };
+        drop(cert);
+        assert_eq!(dup.as_raw(), raw);
+        assert_eq!(dup, CertContext::new(der).unwrap());
+        drop(dup);
+
+        // The store only closes cleanly once every context taken from it has
+        // been freed exactly once.
+        unsafe {
+            let raw = store.into_raw();
+            assert_eq!(ffi::CertCloseStore(raw, ffi::CERT_CLOSE_STORE_CHECK_FLAG),
+                       ffi::TRUE);
+        }
+    }
+
+    #[test]
+    fn pfx_without_no_persist_key() {
+        let pfx = include_bytes!("../test/identity.p12");
+        let _disabled = os_support::disable(Feature::Pkcs12NoPersistKey);
+        let err = PfxImportOptions::new()
+            .password("mypass")
+            .no_persist_key(true)
+            .import(pfx)
+            .err()
+            .unwrap();
+        assert_eq!(::Error::from(err).kind(), ::ErrorKind::Unsupported);
+        assert!(pfx_to_cert_and_key(pfx, "mypass").is_err());
+    }
+
+    #[test]
+    fn concurrent_enumeration() {
+        let mut store = Memory::new().unwrap();
+        store.add_encoded_certificate(include_bytes!("../test/cert.der")).unwrap();
+        store.add_encoded_certificate(include_bytes!("../test/self-signed.badssl.com.cer"))
+            .unwrap();
+        let store = store.into_store();
+
+        let threads = (0..8).map(|_| {
+            let store = store.clone();
+            ::std::thread::spawn(move || {
+                for _ in 0..100 {
+                    assert_eq!(store.certs().count(), 2);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn enumerate_pkcs12() {
+        let pfx = include_bytes!("../test/identity.p12");
+        let store = CertStore::import_pkcs12(pfx, Some("mypass")).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(!store.is_empty());
+
+        let mut found = false;
+        for cert in &store {
+            if cert == CertContext::new(include_bytes!("../test/cert.der")).unwrap() {
+                found = true;
+            }
+        }
+        assert!(found);
+
+        // certificates outlive the store they were enumerated from
+        let first = store.certs().next().unwrap();
+        drop(store);
+        assert!(first.subject().is_ok());
+
+        // abandoning an enumeration part-way doesn't leak the context it was
+        // positioned on
+        let store = CertStore::import_pkcs12(pfx, Some("mypass")).unwrap();
+        assert_eq!(store.certs().take(1).count(), 1);
+        unsafe {
+            let raw = store.into_raw();
+            assert_eq!(ffi::CertCloseStore(raw, ffi::CERT_CLOSE_STORE_CHECK_FLAG),
+                       ffi::TRUE);
+        }
+
+        assert!(Memory::new().unwrap().into_store().is_empty());
+    }
+
+    #[test]
+    fn find() {
+        let mut store = Memory::new().unwrap();
+        let cert = store.add_encoded_certificate(include_bytes!("../test/cert.der")).unwrap();
+        let badssl = include_bytes!("../test/self-signed.badssl.com.cer");
+        let badssl = store.add_encoded_certificate(badssl).unwrap();
+        let store = store.into_store();
+
+        assert_eq!(store.find().iter().count(), 2);
+        assert_eq!(store.find().subject("FOOBAR.COM").first(), Some(cert.clone()));
+        assert_eq!(store.find().subject("badssl").iter().collect::<Vec<_>>(),
+                   vec![badssl.clone()]);
+        assert_eq!(store.find().issuer("widgits pty").first(), Some(cert.clone()));
+        assert!(store.find().subject("example.com").first().is_none());
+
+        let sha1 = cert.fingerprint(HashAlgorithm::sha1()).unwrap();
+        assert_eq!(store.find().sha1(&sha1).first(), Some(cert.clone()));
+        assert!(store.find().sha1(&[0; 20]).first().is_none());
+
+        let sha256 = badssl.fingerprint(HashAlgorithm::sha256()).unwrap();
+        assert_eq!(store.find().sha256(&sha256).first(), Some(badssl.clone()));
+        {
+            let _disabled = os_support::disable(Feature::Sha256Find);
+            assert_eq!(store.find().sha256(&sha256).iter().collect::<Vec<_>>(),
+                       vec![badssl.clone()]);
+        }
+
+        let id = cert.get_raw_property(ffi::CERT_KEY_IDENTIFIER_PROP_ID).unwrap().unwrap();
+        assert_eq!(store.find().key_identifier(&id).first(), Some(cert.clone()));
+
+        // abandoned searches don't leak the context they were positioned on
+        drop((cert, badssl));
+        assert!(store.find().issuer("Widgits").first().is_some());
+        unsafe {
+            let raw = store.into_raw();
+            assert_eq!(ffi::CertCloseStore(raw, ffi::CERT_CLOSE_STORE_CHECK_FLAG),
+                       ffi::TRUE);
+        }
+    }
+
+    #[test]
+    fn system_store() {
+        let store = SystemStoreOptions::new().read_only(true).open("Root").unwrap();
+        assert!(!store.is_empty());
+
+        let mut memory = Memory::new().unwrap();
+        let cert = memory.add_encoded_certificate(include_bytes!("../test/cert.der")).unwrap();
+        let mut store = SystemStoreOptions::new()
+            .location(StoreLocation::LocalMachine)
+            .read_only(true)
+            .open("My")
+            .unwrap();
+        assert!(store.add_cert(&cert, CertAdd::New).is_err());
+
+        let err = SystemStoreOptions::new()
+            .open_existing(true)
+            .open("schannel-rs-missing-store")
+            .err()
+            .unwrap();
+        assert_eq!(::Error::from(err).kind(), ::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn pfx_export() {
+        let pfx = include_bytes!("../test/identity.p12");
+        let imported = PfxImportOptions::new()
+            .password("mypass")
+            .always_cng(true)
+            .exportable(true)
+            .import(pfx)
+            .unwrap();
+        let cert = imported.certs()
+            .find(|c| c.private_key().silent(true).acquire().is_ok())
+            .unwrap();
+        let mut store = Memory::new().unwrap().into_store();
+        store.add_cert(&cert, CertAdd::Always).unwrap();
+
+        let exported = PfxExportOptions::new()
+            .password("exported")
+            .export_private_keys(true)
+            .report_no_private_key(true)
+            .report_not_able_to_export_private_key(true)
+            .export(&store)
+            .unwrap();
+        let reimported = PfxImportOptions::new()
+            .password("exported")
+            .no_persist_key(true)
+            .import(&exported)
+            .unwrap();
+        let certs = reimported.certs().collect::<Vec<_>>();
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].fingerprint(HashAlgorithm::sha256()).unwrap(),
+                   cert.fingerprint(HashAlgorithm::sha256()).unwrap());
+        certs[0].private_key().compare_key(true).silent(true).acquire().unwrap();
+
+        // without its key, the certificate alone can't satisfy
+        // `report_no_private_key`
+        let mut store = Memory::new().unwrap();
+        store.add_encoded_certificate(include_bytes!("../test/cert.der")).unwrap();
+        let store = store.into_store();
+        assert!(PfxExportOptions::new()
+                    .export_private_keys(true)
+                    .report_no_private_key(true)
+                    .export(&store)
+                    .is_err());
+        let exported = PfxExportOptions::new().export(&store).unwrap();
+        assert_eq!(CertStore::import_pkcs12(&exported, None).unwrap().len(), 1);
+
+        for cert in imported.certs() {
+            if let Ok(PrivateKey::NcryptKey(key)) = cert.private_key().silent(true).acquire() {
+                key.delete().unwrap();
+            }
+        }
+    }
+
     #[test]
     fn load() {
         let cert = include_bytes!("../test/cert.der");
@@ -435,7 +1231,7 @@ mod test {
     #[test]
     fn pfx_import() {
         let pfx = include_bytes!("../test/identity.p12");
-        let mut store = PfxImportOptions::new()
+        let store = PfxImportOptions::new()
                         .include_extended_properties(true)
                         .password("mypass")
                         .import(pfx)
@@ -448,4 +1244,46 @@ mod test {
                          .count();
         assert_eq!(pkeys, 1);
     }
+
+    #[test]
+    fn serialized_round_trip() {
+        fn container(cert: &CertContext) -> String {
+            unsafe {
+                let info = ::get_property(ffi::CertGetCertificateContextProperty,
+                                          cert.as_inner(),
+                                          ffi::CERT_KEY_PROV_INFO_PROP_ID)
+                    .unwrap();
+                let info = &*(info.as_ptr() as *const ffi::CRYPT_KEY_PROV_INFO);
+                ::from_wide_ptr(info.pwszContainerName)
+            }
+        }
+
+        let mut store = Memory::new().unwrap();
+        let cert = store.add_encoded_certificate(include_bytes!("../test/cert.der")).unwrap();
+        cert.set_friendly_name("schannel-rs serialized").unwrap();
+        cert.set_key_prov_info().container("schannel-rs-container").set().unwrap();
+        let store = store.into_store();
+
+        let data = store.to_serialized().unwrap();
+        let loaded = CertStore::from_serialized(&data).unwrap();
+        let certs = loaded.certs().collect::<Vec<_>>();
+        assert_eq!(certs, vec![cert.clone()]);
+        assert_eq!(certs[0].friendly_name().unwrap(), "schannel-rs serialized");
+        assert_eq!(container(&certs[0]), "schannel-rs-container");
+
+        let path = ::std::env::temp_dir()
+            .join(format!("schannel-rs-{}.sst", ::std::process::id()));
+        store.save_to_file(&path).unwrap();
+        let opened = CertStore::open_file(&path);
+        ::std::fs::remove_file(&path).unwrap();
+        let certs = opened.unwrap().certs().collect::<Vec<_>>();
+        assert_eq!(certs, vec![cert]);
+        assert_eq!(certs[0].friendly_name().unwrap(), "schannel-rs serialized");
+        assert_eq!(container(&certs[0]), "schannel-rs-container");
+
+        assert!(CertStore::open_file(&path).is_err());
+        assert!(CertStore::from_serialized(b"not a store").is_err());
+        let empty = Memory::new().unwrap().into_store().to_serialized().unwrap();
+        assert!(CertStore::from_serialized(&empty).unwrap().is_empty());
+    }
 }
@@ -1,14 +1,13 @@
-use winapi;
-use secur32;
+use ffi;
 use std::ops::Deref;
 use std::slice;
 
-pub struct ContextBuffer(pub winapi::SecBuffer);
+pub struct ContextBuffer(pub ffi::SecBuffer);
 
 impl Drop for ContextBuffer {
     fn drop(&mut self) {
         unsafe {
-            secur32::FreeContextBuffer(self.0.pvBuffer);
+            ffi::FreeContextBuffer(self.0.pvBuffer);
         }
     }
 }
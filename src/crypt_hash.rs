@@ -0,0 +1,36 @@
+//! CryptoAPI hashes.
+use ffi;
+use std::io;
+
+/// A handle to a hash object of a CryptoAPI provider.
+///
+/// This exists to derive session keys with `CryptProv::derive_key` for
+/// compatibility with legacy formats.
+pub struct CryptHash(ffi::HCRYPTHASH);
+
+impl Drop for CryptHash {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::CryptDestroyHash(self.0);
+        }
+    }
+}
+
+inner!(CryptHash, ffi::HCRYPTHASH);
+
+impl CryptHash {
+    /// Adds data to the hash.
+    pub fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        unsafe {
+            let res = ffi::CryptHashData(self.0,
+                                         data.as_ptr(),
+                                         try!(::dword_len(data.len())),
+                                         0);
+            if res == ffi::TRUE {
+                Ok(())
+            } else {
+                Err(::error::last_error())
+            }
+        }
+    }
+}
@@ -1,16 +1,202 @@
-//! CryptoAPI private keys.
-use advapi32;
-use winapi;
+//! CryptoAPI keys.
+use ffi;
+use std::io;
+use std::ptr;
+use std::sync::Mutex;
+
+use {FromProvKey, Inner};
+use cert_context::{HashAlgorithm, KeySpec};
+use crypt_prov::CryptProv;
 
 /// A handle to a key.
-pub struct CryptKey(winapi::HCRYPTKEY);
+///
+/// CryptoAPI key handles must not be used by several threads at once, so
+/// operations with them are serialized by an internal lock.
+pub struct CryptKey(ffi::HCRYPTKEY, Mutex<()>, Option<(CryptProv, KeySpec)>);
+
+unsafe impl Send for CryptKey {}
+unsafe impl Sync for CryptKey {}
 
 impl Drop for CryptKey {
     fn drop(&mut self) {
         unsafe {
-            advapi32::CryptDestroyKey(self.0);
+            ffi::CryptDestroyKey(self.0);
+        }
+    }
+}
+
+impl Inner<ffi::HCRYPTKEY> for CryptKey {
+    unsafe fn from_inner(t: ffi::HCRYPTKEY) -> CryptKey {
+        CryptKey(t, Mutex::new(()), None)
+    }
+
+    fn as_inner(&self) -> ffi::HCRYPTKEY {
+        self.0
+    }
+
+    fn get_mut(&mut self) -> &mut ffi::HCRYPTKEY {
+        &mut self.0
+    }
+}
+
+impl FromProvKey<ffi::HCRYPTKEY> for CryptKey {
+    unsafe fn from_prov_key(t: ffi::HCRYPTKEY, prov: CryptProv, spec: KeySpec) -> CryptKey {
+        CryptKey(t, Mutex::new(()), Some((prov, spec)))
+    }
+}
+
+impl CryptKey {
+    /// Encrypts `data` with this session key as a complete message.
+    ///
+    /// Block ciphers use the provider's defaults of CBC mode with a zero IV
+    /// and PKCS #5 padding. This is intended for compatibility with legacy
+    /// formats only.
+    pub fn encrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let _guard = self.1.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            let data_len = try!(::dword_len(data.len()));
+            let mut len = data_len;
+            let res = ffi::CryptEncrypt(self.0,
+                                        0,
+                                        ffi::TRUE,
+                                        0,
+                                        ptr::null_mut(),
+                                        &mut len,
+                                        0);
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+
+            let mut buf = data.to_owned();
+            buf.resize(len as usize, 0);
+            let mut len = data_len;
+            let res = ffi::CryptEncrypt(self.0,
+                                        0,
+                                        ffi::TRUE,
+                                        0,
+                                        buf.as_mut_ptr(),
+                                        &mut len,
+                                        try!(::dword_len(buf.len())));
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+            buf.truncate(len as usize);
+            Ok(buf)
+        }
+    }
+
+    /// Decrypts a complete message encrypted with this session key.
+    ///
+    /// This is intended for compatibility with legacy formats only.
+    pub fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let _guard = self.1.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            let mut buf = data.to_owned();
+            let mut len = try!(::dword_len(buf.len()));
+            let res = ffi::CryptDecrypt(self.0, 0, ffi::TRUE, 0, buf.as_mut_ptr(), &mut len);
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+            buf.truncate(len as usize);
+            Ok(buf)
+        }
+    }
+
+    /// Signs `data` with this RSA key pair, using PKCS #1 v1.5 padding.
+    ///
+    /// The data is hashed with `hash` first. Only key pairs of a provider's
+    /// container, such as those returned by `CryptProv::user_key` or
+    /// imported with `CryptProv::import`, can sign. The signature is returned
+    /// in the conventional big-endian order rather than CryptoAPI's
+    /// little-endian one.
+    pub fn sign(&self, data: &[u8], hash: HashAlgorithm) -> io::Result<Vec<u8>> {
+        let (prov, spec) = try!(self.key_pair());
+        let digest = try!(::hash::hash(hash.clone(), data));
+        let _guard = self.1.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe { ::capi_sign_digest(prov.as_inner(), spec.as_raw(), &digest, hash.as_raw()) }
+    }
+
+    /// Verifies a big-endian PKCS #1 v1.5 signature over `data` made with
+    /// this RSA key pair.
+    ///
+    /// Returns `Ok(false)` if the signature does not match.
+    pub fn verify_signature(&self,
+                            data: &[u8],
+                            hash: HashAlgorithm,
+                            signature: &[u8])
+                            -> io::Result<bool> {
+        let (prov, _) = try!(self.key_pair());
+        let _guard = self.1.lock().unwrap_or_else(|e| e.into_inner());
+        let mut hash = try!(prov.hash(hash));
+        try!(hash.update(data));
+        unsafe {
+            let mut signature = signature.to_owned();
+            signature.reverse();
+            let res = ffi::CryptVerifySignatureW(hash.as_inner(),
+                                                 signature.as_ptr(),
+                                                 try!(::dword_len(signature.len())),
+                                                 self.0,
+                                                 ptr::null(),
+                                                 0);
+            if res == ffi::TRUE {
+                return Ok(true);
+            }
+            let err = ::error::last_error();
+            if ::error::code(&err) == Some(ffi::NTE_BAD_SIGNATURE) {
+                Ok(false)
+            } else {
+                Err(err)
+            }
+        }
+    }
+
+    fn key_pair(&self) -> io::Result<(&CryptProv, KeySpec)> {
+        match self.2 {
+            Some((ref prov, spec)) => Ok((prov, spec)),
+            None => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "session keys can't sign or verify signatures"))
+            }
         }
     }
 }
 
-inner!(CryptKey, winapi::HCRYPTKEY);
+/// A symmetric cipher of a session key.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CipherAlgorithm(ffi::ALG_ID);
+
+#[allow(missing_docs)]
+impl CipherAlgorithm {
+    pub fn rc2() -> CipherAlgorithm {
+        CipherAlgorithm(ffi::CALG_RC2)
+    }
+
+    pub fn rc4() -> CipherAlgorithm {
+        CipherAlgorithm(ffi::CALG_RC4)
+    }
+
+    pub fn des() -> CipherAlgorithm {
+        CipherAlgorithm(ffi::CALG_DES)
+    }
+
+    pub fn triple_des() -> CipherAlgorithm {
+        CipherAlgorithm(ffi::CALG_3DES)
+    }
+
+    pub fn aes_128() -> CipherAlgorithm {
+        CipherAlgorithm(ffi::CALG_AES_128)
+    }
+
+    pub fn aes_192() -> CipherAlgorithm {
+        CipherAlgorithm(ffi::CALG_AES_192)
+    }
+
+    pub fn aes_256() -> CipherAlgorithm {
+        CipherAlgorithm(ffi::CALG_AES_256)
+    }
+
+    /// Returns the raw `ALG_ID` of this algorithm.
+    pub fn as_raw(&self) -> ffi::ALG_ID {
+        self.0
+    }
+}
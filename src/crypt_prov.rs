@@ -1,28 +1,314 @@
 //! CryptoAPI key providers.
-use advapi32;
-use crypt32;
-use kernel32;
+use ffi;
 use std::io;
+use std::mem;
 use std::ptr;
-use winapi;
+use std::slice;
+use std::sync::{Once, ONCE_INIT};
 
-use Inner;
-use crypt_key::CryptKey;
+use {FromCertProv, FromProvKey, Inner};
+use cert_context::{HashAlgorithm, KeySpec, PublicKey};
+use crypt_hash::CryptHash;
+use crypt_key::{CipherAlgorithm, CryptKey};
 
 /// A CryptoAPI handle to a provider of a key.
-pub struct CryptProv(winapi::HCRYPTPROV);
+///
+/// Clones share the same handle, whose reference count is incremented with
+/// `CryptContextAddRef`. Keys imported through one clone are visible through
+/// all of them.
+pub struct CryptProv(ffi::HCRYPTPROV, Option<KeySpec>);
+
+// Provider handles may be shared between threads; the keys and hashes created
+// from them carry their own guarantees.
+unsafe impl Send for CryptProv {}
+unsafe impl Sync for CryptProv {}
 
 impl Drop for CryptProv {
     fn drop(&mut self) {
         unsafe {
-            advapi32::CryptReleaseContext(self.0, 0);
+            ffi::CryptReleaseContext(self.0, 0);
+        }
+    }
+}
+
+impl Clone for CryptProv {
+    fn clone(&self) -> CryptProv {
+        unsafe {
+            ffi::CryptContextAddRef(self.0, ptr::null_mut(), 0);
         }
+        CryptProv(self.0, self.1)
+    }
+}
+
+impl Inner<ffi::HCRYPTPROV> for CryptProv {
+    unsafe fn from_inner(t: ffi::HCRYPTPROV) -> CryptProv {
+        CryptProv(t, None)
+    }
+
+    fn as_inner(&self) -> ffi::HCRYPTPROV {
+        self.0
+    }
+
+    fn get_mut(&mut self) -> &mut ffi::HCRYPTPROV {
+        &mut self.0
     }
 }
 
-inner!(CryptProv, winapi::HCRYPTPROV);
+impl FromCertProv<ffi::HCRYPTPROV> for CryptProv {
+    unsafe fn from_cert_prov(t: ffi::HCRYPTPROV, spec: KeySpec) -> CryptProv {
+        CryptProv(t, Some(spec))
+    }
+}
 
 impl CryptProv {
+    /// Returns the raw `HCRYPTPROV`.
+    ///
+    /// The provider handle remains owned by this value, and is only valid for
+    /// as long as it is alive. Callers who need to keep it longer should take
+    /// their own reference with `CryptContextAddRef`.
+    pub fn as_raw(&self) -> ffi::HCRYPTPROV {
+        self.0
+    }
+
+    /// Consumes this value, returning the raw `HCRYPTPROV` along with the
+    /// reference it held.
+    ///
+    /// The caller becomes responsible for releasing it with
+    /// `CryptReleaseContext`, or for passing it back to `from_raw`.
+    pub fn into_raw(self) -> ffi::HCRYPTPROV {
+        let raw = self.0;
+        mem::forget(self);
+        raw
+    }
+
+    /// Wraps a raw `HCRYPTPROV`, taking ownership of one reference to it.
+    ///
+    /// The reference is released with `CryptReleaseContext` when the returned
+    /// value is dropped. To wrap a provider handle while keeping the caller's
+    /// reference, take a new one with `CryptContextAddRef` first.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must be a valid provider handle which the caller owns a reference
+    /// to.
+    pub unsafe fn from_raw(raw: ffi::HCRYPTPROV) -> CryptProv {
+        CryptProv(raw, None)
+    }
+
+    /// Deletes the key container with the specified name.
+    ///
+    /// This is a shorthand for `AcquireOptions::delete`.
+    ///
+    /// Certificates whose key provider information still refers to this
+    /// container are not updated by this call. Their
+    /// `CERT_KEY_PROV_INFO_PROP_ID` property will name a container which no
+    /// longer exists, and acquiring their private key will fail afterwards.
+    pub fn delete_container(type_: ProviderType,
+                            container: &str,
+                            machine_keyset: bool)
+                            -> io::Result<()> {
+        AcquireOptions::new()
+            .container(container)
+            .machine_keyset(machine_keyset)
+            .delete(type_)
+    }
+
+    /// Returns the name of this provider's key container.
+    pub fn container_name(&self) -> io::Result<String> {
+        self.get_string(ffi::PP_CONTAINER)
+    }
+
+    /// Returns the name of the CSP which this provider was acquired from.
+    pub fn provider_name(&self) -> io::Result<String> {
+        self.get_string(ffi::PP_NAME)
+    }
+
+    /// Returns the type of the CSP which this provider was acquired from.
+    pub fn provider_type(&self) -> io::Result<ProviderType> {
+        let buf = try!(self.get_param(ffi::PP_PROVTYPE));
+        if buf.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid provider type"));
+        }
+        let type_ = buf[0] as ffi::DWORD | (buf[1] as ffi::DWORD) << 8 |
+                    (buf[2] as ffi::DWORD) << 16 |
+                    (buf[3] as ffi::DWORD) << 24;
+        Ok(ProviderType(type_))
+    }
+
+    /// Returns the specification of the key in this provider's container.
+    ///
+    /// Providers acquired for the private key of a certificate report the key
+    /// pair the certificate is bound to. Other handles don't record which of
+    /// the container's keys they were acquired for, so if the container holds
+    /// both a signature and a key exchange key, the signature key is
+    /// reported. Returns `None` if the container holds no keys.
+    pub fn key_spec(&self) -> io::Result<Option<KeySpec>> {
+        if let Some(spec) = self.1 {
+            return Ok(Some(spec));
+        }
+        for &spec in &[KeySpec::signature(), KeySpec::key_exchange()] {
+            unsafe {
+                let mut key = 0;
+                if ffi::CryptGetUserKey(self.0, spec.as_raw(), &mut key) == ffi::TRUE {
+                    ffi::CryptDestroyKey(key);
+                    return Ok(Some(spec));
+                }
+            }
+            let err = ::error::last_error();
+            if ::error::code(&err) != Some(ffi::NTE_NO_KEY) {
+                return Err(err);
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_string(&self, param: ffi::DWORD) -> io::Result<String> {
+        let buf = try!(self.get_param(param));
+        // the string is in the ANSI code page
+        let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+
+    fn get_param(&self, param: ffi::DWORD) -> io::Result<Vec<u8>> {
+        unsafe {
+            let mut len = 0;
+            let res = ffi::CryptGetProvParam(self.0, param, ptr::null_mut(), &mut len, 0);
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+
+            let mut buf = vec![0u8; len as usize];
+            let res = ffi::CryptGetProvParam(self.0, param, buf.as_mut_ptr(), &mut len, 0);
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+            buf.truncate(len as usize);
+            Ok(buf)
+        }
+    }
+
+    /// Returns the DER-encoded `SubjectPublicKeyInfo` of the specified key in
+    /// this provider's container.
+    pub fn export_public_spki(&self, key_spec: KeySpec) -> io::Result<Vec<u8>> {
+        unsafe { ::export_public_key_info(self.0, key_spec.as_raw()) }
+    }
+
+    /// Exports the specified key in this provider's container as an
+    /// unencrypted DER-encoded PKCS #8 `PrivateKeyInfo`.
+    ///
+    /// Only RSA keys are supported. The result contains the private key in
+    /// plaintext, and should be handled accordingly. The key must have been
+    /// generated or imported as exportable; `NTE_BAD_KEY_STATE` is returned
+    /// otherwise.
+    pub fn export_pkcs8(&self, key_spec: KeySpec) -> io::Result<Vec<u8>> {
+        unsafe {
+            let mut key = 0;
+            let res = ffi::CryptGetUserKey(self.0, key_spec.as_raw(), &mut key);
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+
+            let mut len = 0;
+            let mut res = ffi::CryptExportKey(key,
+                                              0,
+                                              ffi::PRIVATEKEYBLOB,
+                                              0,
+                                              ptr::null_mut(),
+                                              &mut len);
+            let mut blob = vec![0; len as usize];
+            if res == ffi::TRUE {
+                res = ffi::CryptExportKey(key,
+                                          0,
+                                          ffi::PRIVATEKEYBLOB,
+                                          0,
+                                          blob.as_mut_ptr(),
+                                          &mut len);
+            }
+            let err = ::error::last_error();
+            ffi::CryptDestroyKey(key);
+            if res != ffi::TRUE {
+                return Err(err);
+            }
+
+            let mut pkcs1 = try!(::encode_object(ffi::PKCS_RSA_PRIVATE_KEY,
+                                                 blob.as_ptr() as *const _));
+            let mut oid = b"1.2.840.113549.1.1.1\0".to_vec();
+            let mut null = [0x05, 0x00];
+            let info = ffi::CRYPT_PRIVATE_KEY_INFO {
+                Version: 0,
+                Algorithm: ffi::CRYPT_ALGORITHM_IDENTIFIER {
+                    pszObjId: oid.as_mut_ptr() as ffi::LPSTR,
+                    Parameters: ffi::CRYPT_OBJID_BLOB {
+                        cbData: try!(::dword_len(null.len())),
+                        pbData: null.as_mut_ptr(),
+                    },
+                },
+                PrivateKey: ffi::CRYPT_DER_BLOB {
+                    cbData: try!(::dword_len(pkcs1.len())),
+                    pbData: pkcs1.as_mut_ptr(),
+                },
+                pAttributes: ptr::null_mut(),
+            };
+            ::encode_object(ffi::PKCS_PRIVATE_KEY_INFO, &info as *const _ as *const _)
+        }
+    }
+
+    /// Creates a hash object, to be passed to `derive_key`.
+    pub fn hash(&self, alg: HashAlgorithm) -> io::Result<CryptHash> {
+        unsafe {
+            let mut hash = 0;
+            let res = ffi::CryptCreateHash(self.0, alg.as_raw(), 0, 0, &mut hash);
+            if res == ffi::TRUE {
+                Ok(CryptHash::from_inner(hash))
+            } else {
+                Err(::error::last_error())
+            }
+        }
+    }
+
+    /// Derives a session key from the value of `hash`, as `CryptDeriveKey`
+    /// does.
+    ///
+    /// `flags` is passed through to `CryptDeriveKey`; its upper 16 bits may
+    /// specify the key length in bits. This is intended for compatibility
+    /// with legacy formats which derive keys from passwords this way, and
+    /// should not be used otherwise.
+    pub fn derive_key(&self,
+                      alg: CipherAlgorithm,
+                      hash: &CryptHash,
+                      flags: ffi::DWORD)
+                      -> io::Result<CryptKey> {
+        unsafe {
+            let mut key = 0;
+            let res = ffi::CryptDeriveKey(self.0,
+                                          alg.as_raw(),
+                                          hash.as_inner(),
+                                          flags,
+                                          &mut key);
+            if res == ffi::TRUE {
+                Ok(CryptKey::from_inner(key))
+            } else {
+                Err(::error::last_error())
+            }
+        }
+    }
+
+    /// Returns the key pair of this provider's container with the specified
+    /// specification.
+    ///
+    /// The returned key keeps this provider alive, and can sign data.
+    pub fn user_key(&self, key_spec: KeySpec) -> io::Result<CryptKey> {
+        unsafe {
+            let mut key = 0;
+            let res = ffi::CryptGetUserKey(self.0, key_spec.as_raw(), &mut key);
+            if res == ffi::TRUE {
+                Ok(CryptKey::from_prov_key(key, self.clone(), key_spec))
+            } else {
+                Err(::error::last_error())
+            }
+        }
+    }
+
     /// Imports a key into this provider.
     pub fn import<'a>(&'a mut self) -> ImportOptions<'a> {
         ImportOptions {
@@ -32,11 +318,147 @@ impl CryptProv {
     }
 }
 
+/// Imports an RSA public key from its big-endian modulus `n` and public
+/// exponent `e`.
+///
+/// Leading zero bytes, such as those of a DER-encoded `INTEGER`, are ignored.
+pub fn import_rsa_public(n: &[u8], e: &[u8]) -> io::Result<PublicKey> {
+    fn strip(bytes: &[u8]) -> &[u8] {
+        let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+        &bytes[start..]
+    }
+
+    let n = strip(n);
+    let e = strip(e);
+    if n.is_empty() || e.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "RSA modulus and exponent must be nonzero"));
+    }
+
+    let header = ffi::BCRYPT_RSAKEY_BLOB {
+        Magic: ffi::BCRYPT_RSAPUBLIC_MAGIC,
+        BitLength: try!(::dword_len(n.len() * 8 - n[0].leading_zeros() as usize)),
+        cbPublicExp: try!(::dword_len(e.len())),
+        cbModulus: try!(::dword_len(n.len())),
+        cbPrime1: 0,
+        cbPrime2: 0,
+    };
+    let mut blob = Vec::with_capacity(mem::size_of_val(&header) + e.len() + n.len());
+    unsafe {
+        let ptr = &header as *const _ as *const u8;
+        blob.extend_from_slice(slice::from_raw_parts(ptr, mem::size_of_val(&header)));
+    }
+    blob.extend_from_slice(e);
+    blob.extend_from_slice(n);
+
+    unsafe {
+        let alg = try!(rsa_algorithm());
+        let blob_type = "RSAPUBLICBLOB".encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        let mut key = ptr::null_mut();
+        let status = ffi::BCryptImportKeyPair(alg,
+                                              ptr::null_mut(),
+                                              blob_type.as_ptr(),
+                                              &mut key,
+                                              blob.as_mut_ptr(),
+                                              try!(::dword_len(blob.len())),
+                                              0);
+        if status != ffi::STATUS_SUCCESS {
+            return Err(::error::from_code(status));
+        }
+        Ok(PublicKey::from_inner(key))
+    }
+}
+
+/// Returns a handle to the CNG RSA algorithm provider.
+///
+/// The provider is opened once and never closed, as keys imported through it
+/// may outlive any particular caller.
+fn rsa_algorithm() -> io::Result<ffi::BCRYPT_ALG_HANDLE> {
+    static INIT: Once = ONCE_INIT;
+    static mut ALG: usize = 0;
+    static mut STATUS: ffi::NTSTATUS = ffi::STATUS_SUCCESS;
+
+    unsafe {
+        INIT.call_once(|| {
+            let name = "RSA".encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+            let mut alg = ptr::null_mut();
+            STATUS = ffi::BCryptOpenAlgorithmProvider(&mut alg, name.as_ptr(), ptr::null(), 0);
+            ALG = alg as usize;
+        });
+        if STATUS != ffi::STATUS_SUCCESS {
+            return Err(::error::from_code(STATUS));
+        }
+        Ok(ALG as ffi::BCRYPT_ALG_HANDLE)
+    }
+}
+
+/// Returns the cryptographic service providers installed on this machine.
+pub fn providers() -> io::Result<Vec<ProviderInfo>> {
+    unsafe {
+        let mut providers = vec![];
+        for index in 0.. {
+            let mut type_ = 0;
+            let mut len = 0;
+            let res = ffi::CryptEnumProvidersW(index,
+                                               ptr::null_mut(),
+                                               0,
+                                               &mut type_,
+                                               ptr::null_mut(),
+                                               &mut len);
+            if res != ffi::TRUE {
+                let err = ::error::last_error();
+                if ::error::code(&err) == Some(ffi::ERROR_NO_MORE_ITEMS as i32) {
+                    break;
+                }
+                return Err(err);
+            }
+
+            let mut buf = vec![0u16; len as usize / 2];
+            let res = ffi::CryptEnumProvidersW(index,
+                                               ptr::null_mut(),
+                                               0,
+                                               &mut type_,
+                                               buf.as_mut_ptr(),
+                                               &mut len);
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+
+            // Chop off the trailing nul
+            let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+            providers.push(ProviderInfo {
+                name: String::from_utf16_lossy(&buf[..len]),
+                type_: ProviderType(type_),
+            });
+        }
+        Ok(providers)
+    }
+}
+
+/// An installed cryptographic service provider.
+pub struct ProviderInfo {
+    name: String,
+    type_: ProviderType,
+}
+
+impl ProviderInfo {
+    /// Returns the name of the provider, which can be passed to
+    /// `AcquireOptions::provider`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the type of the provider.
+    pub fn type_(&self) -> ProviderType {
+        self.type_
+    }
+}
+
 /// A builder for `CryptProv`s.
 pub struct AcquireOptions {
     container: Option<Vec<u16>>,
     provider: Option<Vec<u16>>,
-    flags: winapi::DWORD,
+    flags: ffi::DWORD,
 }
 
 impl AcquireOptions {
@@ -65,26 +487,26 @@ impl AcquireOptions {
 
     /// If set, private keys will not be accessible or persisted.
     pub fn verify_context(&mut self, verify_context: bool) -> &mut AcquireOptions {
-        self.flag(winapi::CRYPT_VERIFYCONTEXT, verify_context)
+        self.flag(ffi::CRYPT_VERIFYCONTEXT, verify_context)
     }
 
     /// If set, the container will be created.
     pub fn new_keyset(&mut self, new_keyset: bool) -> &mut AcquireOptions {
-        self.flag(winapi::CRYPT_NEWKEYSET, new_keyset)
+        self.flag(ffi::CRYPT_NEWKEYSET, new_keyset)
     }
 
     /// If set, the container will be stored as a machine rather than user keys.
     pub fn machine_keyset(&mut self, machine_keyset: bool) -> &mut AcquireOptions {
-        self.flag(winapi::CRYPT_MACHINE_KEYSET, machine_keyset)
+        self.flag(ffi::CRYPT_MACHINE_KEYSET, machine_keyset)
     }
 
     /// If set, an error will be returned if user intervention is required
     /// rather than displaying a dialog.
     pub fn silent(&mut self, silent: bool) -> &mut AcquireOptions {
-        self.flag(winapi::CRYPT_SILENT, silent)
+        self.flag(ffi::CRYPT_SILENT, silent)
     }
 
-    fn flag(&mut self, flag: winapi::DWORD, on: bool) -> &mut AcquireOptions {
+    fn flag(&mut self, flag: ffi::DWORD, on: bool) -> &mut AcquireOptions {
         if on {
             self.flags |= flag;
         } else {
@@ -96,73 +518,118 @@ impl AcquireOptions {
 
     /// Acquires a container.
     pub fn acquire(&self, type_: ProviderType) -> io::Result<CryptProv> {
-        unsafe {
-            let container = self.container.as_ref().map(|s| s.as_ptr()).unwrap_or(ptr::null());
-            let provider = self.provider.as_ref().map(|s| s.as_ptr()).unwrap_or(ptr::null());
-
-            let mut prov = 0;
-            let res = advapi32::CryptAcquireContextW(&mut prov,
-                                                     container as *mut _,
-                                                     provider as *mut _,
-                                                     type_.0,
-                                                     self.flags);
-            if res == winapi::TRUE {
-                Ok(CryptProv(prov))
-            } else {
-                Err(io::Error::last_os_error())
-            }
+        let ret = self.acquire_with(type_, acquire_context).map(|prov| CryptProv(prov, None));
+        match ret {
+            Ok(_) => debug!("acquired provider of type {}", type_.0),
+            Err(ref e) => debug!("failed to acquire provider of type {}: {}", type_.0, e),
+        }
+        ret
+    }
+
+    /// Deletes the container.
+    ///
+    /// The `verify_context` and `new_keyset` settings are ignored.
+    pub fn delete(&self, type_: ProviderType) -> io::Result<()> {
+        // the returned handle is not valid when deleting a key set
+        self.delete_with(type_, acquire_context).map(|_| ())
+    }
+
+    // The `CryptAcquireContextW` call is a parameter so that tests can
+    // observe the flags passed to it.
+    fn acquire_with<F>(&self, type_: ProviderType, f: F) -> io::Result<ffi::HCRYPTPROV>
+        where F: FnOnce(ffi::LPCWSTR, ffi::LPCWSTR, ffi::DWORD, ffi::DWORD)
+                        -> io::Result<ffi::HCRYPTPROV>
+    {
+        self.call(type_, self.flags, f)
+    }
+
+    fn delete_with<F>(&self, type_: ProviderType, f: F) -> io::Result<ffi::HCRYPTPROV>
+        where F: FnOnce(ffi::LPCWSTR, ffi::LPCWSTR, ffi::DWORD, ffi::DWORD)
+                        -> io::Result<ffi::HCRYPTPROV>
+    {
+        let flags = self.flags & !(ffi::CRYPT_VERIFYCONTEXT | ffi::CRYPT_NEWKEYSET);
+        self.call(type_, flags | ffi::CRYPT_DELETEKEYSET, f)
+    }
+
+    fn call<F>(&self, type_: ProviderType, flags: ffi::DWORD, f: F)
+               -> io::Result<ffi::HCRYPTPROV>
+        where F: FnOnce(ffi::LPCWSTR, ffi::LPCWSTR, ffi::DWORD, ffi::DWORD)
+                        -> io::Result<ffi::HCRYPTPROV>
+    {
+        let container = self.container.as_ref().map(|s| s.as_ptr()).unwrap_or(ptr::null());
+        let provider = self.provider.as_ref().map(|s| s.as_ptr()).unwrap_or(ptr::null());
+        f(container, provider, type_.0, flags)
+    }
+}
+
+fn acquire_context(container: ffi::LPCWSTR,
+                   provider: ffi::LPCWSTR,
+                   type_: ffi::DWORD,
+                   flags: ffi::DWORD)
+                   -> io::Result<ffi::HCRYPTPROV> {
+    unsafe {
+        let mut prov = 0;
+        let res = ffi::CryptAcquireContextW(&mut prov,
+                                            container as *mut _,
+                                            provider as *mut _,
+                                            type_,
+                                            flags);
+        if res == ffi::TRUE {
+            Ok(prov)
+        } else {
+            Err(::error::last_error())
         }
     }
 }
 
 /// An identifier of the type of cryptography provider to be used with a
 /// container.
-#[derive(Copy, Clone)]
-pub struct ProviderType(winapi::DWORD);
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ProviderType(ffi::DWORD);
 
 #[allow(missing_docs)]
 impl ProviderType {
     pub fn rsa_full() -> ProviderType {
-        ProviderType(winapi::PROV_RSA_FULL)
+        ProviderType(ffi::PROV_RSA_FULL)
     }
 
     pub fn rsa_aes() -> ProviderType {
-        ProviderType(winapi::PROV_RSA_AES)
+        ProviderType(ffi::PROV_RSA_AES)
     }
 
     pub fn rsa_sig() -> ProviderType {
-        ProviderType(winapi::PROV_RSA_SIG)
+        ProviderType(ffi::PROV_RSA_SIG)
     }
 
     pub fn rsa_schannel() -> ProviderType {
-        ProviderType(winapi::PROV_RSA_SCHANNEL)
+        ProviderType(ffi::PROV_RSA_SCHANNEL)
     }
 
     pub fn dss() -> ProviderType {
-        ProviderType(winapi::PROV_DSS)
+        ProviderType(ffi::PROV_DSS)
     }
 
     pub fn dss_dh() -> ProviderType {
-        ProviderType(winapi::PROV_DSS_DH)
+        ProviderType(ffi::PROV_DSS_DH)
     }
 
     pub fn dh_schannel() -> ProviderType {
-        ProviderType(winapi::PROV_DH_SCHANNEL)
+        ProviderType(ffi::PROV_DH_SCHANNEL)
     }
 
     pub fn fortezza() -> ProviderType {
-        ProviderType(winapi::PROV_FORTEZZA)
+        ProviderType(ffi::PROV_FORTEZZA)
     }
 
     pub fn ms_exchange() -> ProviderType {
-        ProviderType(winapi::PROV_MS_EXCHANGE)
+        ProviderType(ffi::PROV_MS_EXCHANGE)
     }
 
     pub fn ssl() -> ProviderType {
-        ProviderType(winapi::PROV_SSL)
+        ProviderType(ffi::PROV_SSL)
     }
 
-    pub fn as_raw(&self) -> winapi::DWORD {
+    pub fn as_raw(&self) -> ffi::DWORD {
         self.0
     }
 }
@@ -170,37 +637,49 @@ impl ProviderType {
 /// A builder for key imports.
 pub struct ImportOptions<'a> {
     prov: &'a mut CryptProv,
-    flags: winapi::DWORD,
+    flags: ffi::DWORD,
 }
 
 impl<'a> ImportOptions<'a> {
+    /// If set, the key can be exported from the provider after it has been
+    /// imported.
+    pub fn exportable(&mut self, exportable: bool) -> &mut ImportOptions<'a> {
+        if exportable {
+            self.flags |= ffi::CRYPT_EXPORTABLE;
+        } else {
+            self.flags &= !ffi::CRYPT_EXPORTABLE;
+        }
+        self
+    }
+
     /// Imports a DER-encoded private key.
     pub fn import(&mut self, der: &[u8]) -> io::Result<CryptKey> {
         unsafe {
-            assert!(der.len() <= winapi::DWORD::max_value() as usize);
+            let der_len = try!(::dword_len(der.len()));
             let mut buf = ptr::null_mut();
             let mut len = 0;
-            let res = crypt32::CryptDecodeObjectEx(winapi::X509_ASN_ENCODING |
-                                                   winapi::PKCS_7_ASN_ENCODING,
-                                                   winapi::PKCS_RSA_PRIVATE_KEY,
-                                                   der.as_ptr(),
-                                                   der.len() as winapi::DWORD,
-                                                   winapi::CRYPT_DECODE_ALLOC_FLAG,
-                                                   ptr::null_mut(),
-                                                   &mut buf as *mut _ as *mut winapi::c_void,
-                                                   &mut len);
-            if res == winapi::FALSE {
-                return Err(io::Error::last_os_error());
+            let res = ffi::CryptDecodeObjectEx(ffi::X509_ASN_ENCODING |
+                                               ffi::PKCS_7_ASN_ENCODING,
+                                               ffi::PKCS_RSA_PRIVATE_KEY,
+                                               der.as_ptr(),
+                                               der_len,
+                                               ffi::CRYPT_DECODE_ALLOC_FLAG,
+                                               ptr::null_mut(),
+                                               &mut buf as *mut _ as *mut ffi::c_void,
+                                               &mut len);
+            if res == ffi::FALSE {
+                return Err(::error::last_error());
             }
 
             let mut key = 0;
-            let res = advapi32::CryptImportKey(self.prov.0, buf, len, 0, self.flags, &mut key);
-            kernel32::LocalFree(buf as *mut _);
+            let res = ffi::CryptImportKey(self.prov.0, buf, len, 0, self.flags, &mut key);
+            ffi::LocalFree(buf as *mut _);
 
-            if res == winapi::TRUE {
-                Ok(CryptKey::from_inner(key))
+            // the decoded blob is always a key exchange key pair
+            if res == ffi::TRUE {
+                Ok(CryptKey::from_prov_key(key, self.prov.clone(), KeySpec::key_exchange()))
             } else {
-                Err(io::Error::last_os_error())
+                Err(::error::last_error())
             }
         }
     }
@@ -208,8 +687,34 @@ impl<'a> ImportOptions<'a> {
 
 #[cfg(test)]
 mod test {
+    use std::io;
+    use ffi;
+
+    use cert_context::{self, CertContext, HashAlgorithm, Padding, PrivateKey, RsaPadding};
+    use ncrypt_key::CreateOptions;
     use super::*;
 
+    const MS_KSP: &'static str = "Microsoft Software Key Storage Provider";
+
+    #[test]
+    fn raw() {
+        let prov = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        let raw = prov.into_raw();
+        let prov = unsafe { CryptProv::from_raw(raw) };
+        assert_eq!(prov.as_raw(), raw);
+
+        let other = unsafe {
+            assert_eq!(ffi::CryptContextAddRef(raw, ptr::null_mut(), 0), ffi::TRUE);
+            CryptProv::from_raw(raw)
+        };
+        drop(prov);
+        // the second reference keeps the provider alive
+        assert_eq!(other.provider_type().unwrap(), ProviderType::rsa_aes());
+    }
+
     #[test]
     fn rsa_key() {
         let key = include_bytes!("../test/key.key");
@@ -222,4 +727,421 @@ mod test {
             .import(key)
             .unwrap();
     }
+
+    #[test]
+    fn export_public_spki() {
+        let key = include_bytes!("../test/key.key");
+        let cert = include_bytes!("../test/cert.der");
+        let cert = CertContext::new(cert).unwrap();
+
+        let mut context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_full())
+            .unwrap();
+        context.import()
+            .import(key)
+            .unwrap();
+
+        let spki = context.export_public_spki(KeySpec::key_exchange()).unwrap();
+        assert_eq!(spki, cert.public_key().unwrap());
+    }
+
+    #[test]
+    fn sign() {
+        let key = include_bytes!("../test/key.key");
+        let cert = include_bytes!("../test/cert.der");
+        let cert = CertContext::new(cert).unwrap();
+
+        let mut context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        context.import()
+            .import(key)
+            .unwrap();
+        let key = PrivateKey::CryptProv(context);
+
+        let sig = key.sign(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1).unwrap();
+        assert!(cert.verify_signature(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1, &sig)
+            .unwrap());
+        assert!(key.sign(b"hello", HashAlgorithm::sha256(), Padding::Pss(32)).is_err());
+
+        let err = key.sign_digest(&[0; 4], HashAlgorithm::sha256(), Padding::Pkcs1)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn sign_with_key() {
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+        let mut context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        let imported = context.import()
+            .import(include_bytes!("../test/key.key"))
+            .unwrap();
+        let key = context.user_key(KeySpec::key_exchange()).unwrap();
+        drop(context);
+
+        for key in &[imported, key] {
+            let sig = key.sign(b"hello", HashAlgorithm::sha256()).unwrap();
+            assert!(cert.verify_signature(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1, &sig)
+                .unwrap());
+            assert!(key.verify_signature(b"hello", HashAlgorithm::sha256(), &sig).unwrap());
+            assert!(!key.verify_signature(b"world", HashAlgorithm::sha256(), &sig).unwrap());
+        }
+
+        let context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        assert!(context.user_key(KeySpec::signature()).is_err());
+        let mut hash = context.hash(HashAlgorithm::sha256()).unwrap();
+        hash.update(b"schannel-rs").unwrap();
+        let session = context.derive_key(CipherAlgorithm::aes_256(), &hash, 0).unwrap();
+        let err = session.sign(b"hello", HashAlgorithm::sha256()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn acquire_flags() {
+        let mut options = AcquireOptions::new();
+        options.container("schannel-rs-test-flags")
+            .verify_context(true)
+            .new_keyset(true)
+            .machine_keyset(true)
+            .silent(true);
+
+        let mut seen = None;
+        options.acquire_with(ProviderType::rsa_aes(), |container, provider, type_, flags| {
+                assert!(!container.is_null());
+                assert!(provider.is_null());
+                seen = Some((type_, flags));
+                Ok(0)
+            })
+            .unwrap();
+        assert_eq!(seen,
+                   Some((ffi::PROV_RSA_AES,
+                         ffi::CRYPT_VERIFYCONTEXT | ffi::CRYPT_NEWKEYSET |
+                         ffi::CRYPT_MACHINE_KEYSET |
+                         ffi::CRYPT_SILENT)));
+
+        options.delete_with(ProviderType::rsa_aes(), |_, _, _, flags| {
+                seen = Some((0, flags));
+                Ok(0)
+            })
+            .unwrap();
+        assert_eq!(seen,
+                   Some((0,
+                         ffi::CRYPT_DELETEKEYSET | ffi::CRYPT_MACHINE_KEYSET |
+                         ffi::CRYPT_SILENT)));
+
+        options.silent(false).machine_keyset(false).verify_context(false);
+        options.acquire_with(ProviderType::rsa_aes(), |_, _, _, flags| {
+                seen = Some((0, flags));
+                Ok(0)
+            })
+            .unwrap();
+        assert_eq!(seen, Some((0, ffi::CRYPT_NEWKEYSET)));
+    }
+
+    #[test]
+    fn names() {
+        let key = include_bytes!("../test/key.key");
+        let cert = include_bytes!("../test/cert.der");
+        let cert = CertContext::new(cert).unwrap();
+        let container = "schannel-rs-test-names";
+        let provider = "Microsoft Enhanced Cryptographic Provider v1.0";
+        let type_ = ProviderType::rsa_full();
+
+        let mut options = AcquireOptions::new();
+        options.container(container).provider(provider);
+        let mut context = match options.acquire(type_) {
+            Ok(context) => context,
+            Err(_) => options.new_keyset(true).acquire(type_).unwrap(),
+        };
+        context.import().import(key).unwrap();
+        // a signature key pair alongside the certificate's key exchange one
+        unsafe {
+            let mut key = 0;
+            assert!(ffi::CryptGenKey(context.as_inner(), ffi::AT_SIGNATURE, 0, &mut key) ==
+                    ffi::TRUE);
+            ffi::CryptDestroyKey(key);
+        }
+        drop(context);
+        cert.set_key_prov_info()
+            .container(container)
+            .provider(provider)
+            .type_(type_)
+            .key_spec(KeySpec::key_exchange())
+            .set()
+            .unwrap();
+
+        match cert.private_key().acquire().unwrap() {
+            PrivateKey::CryptProv(prov) => {
+                assert_eq!(prov.container_name().unwrap(), container);
+                assert_eq!(prov.provider_name().unwrap(), provider);
+                assert_eq!(prov.provider_type().unwrap(), type_);
+                assert_eq!(prov.key_spec().unwrap(), Some(KeySpec::key_exchange()));
+
+                let key = PrivateKey::CryptProv(prov);
+                let sig = key.sign(b"hello", HashAlgorithm::sha1(), Padding::Pkcs1).unwrap();
+                assert!(cert.verify_signature(b"hello", HashAlgorithm::sha1(), Padding::Pkcs1, &sig)
+                    .unwrap());
+            }
+            PrivateKey::NcryptKey(_) => panic!("expected a CryptoAPI key"),
+        }
+
+        CryptProv::delete_container(type_, container, false).unwrap();
+    }
+
+    #[test]
+    fn import_rsa_public() {
+        let key = include_bytes!("../test/key.key");
+
+        let mut context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        context.import()
+            .import(key)
+            .unwrap();
+
+        // PUBLICKEYBLOB: BLOBHEADER, RSAPUBKEY, then the little-endian modulus
+        let blob = unsafe {
+            let mut key = 0;
+            assert!(ffi::CryptGetUserKey(context.as_inner(),
+                                         ffi::AT_KEYEXCHANGE,
+                                         &mut key) == ffi::TRUE);
+            let mut len = 0;
+            assert!(ffi::CryptExportKey(key,
+                                        0,
+                                        ffi::PUBLICKEYBLOB,
+                                        0,
+                                        ptr::null_mut(),
+                                        &mut len) == ffi::TRUE);
+            let mut blob = vec![0; len as usize];
+            assert!(ffi::CryptExportKey(key,
+                                        0,
+                                        ffi::PUBLICKEYBLOB,
+                                        0,
+                                        blob.as_mut_ptr(),
+                                        &mut len) == ffi::TRUE);
+            ffi::CryptDestroyKey(key);
+            blob
+        };
+        let bits = blob[12] as usize | (blob[13] as usize) << 8;
+        let e = [0, 0, 0, blob[18], blob[17], blob[16]];
+        let mut n = vec![0, 0];
+        n.extend(blob[20..20 + bits / 8].iter().rev());
+
+        let public = super::import_rsa_public(&n, &e).unwrap();
+        assert_eq!(public.length().unwrap(), bits as u32);
+
+        let key = PrivateKey::CryptProv(context);
+        let sig = key.sign(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1).unwrap();
+        assert!(public.verify(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1, &sig).unwrap());
+        assert!(!public.verify(b"world", HashAlgorithm::sha256(), Padding::Pkcs1, &sig).unwrap());
+
+        assert!(super::import_rsa_public(&[0, 0], &e).is_err());
+    }
+
+    #[test]
+    fn clone() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<CryptProv>();
+
+        let key = include_bytes!("../test/key.key");
+        let cert = include_bytes!("../test/cert.der");
+        let cert = CertContext::new(cert).unwrap();
+
+        let mut context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        context.import()
+            .import(key)
+            .unwrap();
+        let clone = context.clone();
+        drop(context);
+
+        let key = PrivateKey::CryptProv(clone);
+        let sig = key.sign(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1).unwrap();
+        assert!(cert.verify_signature(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1, &sig)
+            .unwrap());
+    }
+
+    #[test]
+    fn derive_key() {
+        let context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        let mut hash = context.hash(HashAlgorithm::sha256()).unwrap();
+        hash.update(b"schannel-rs").unwrap();
+        let key = context.derive_key(CipherAlgorithm::aes_256(), &hash, 0).unwrap();
+
+        let data = include_bytes!("../test/legacy-aes.bin");
+        let plaintext = key.decrypt(data).unwrap();
+        assert_eq!(plaintext, b"hello from a legacy file format");
+        assert_eq!(key.encrypt(&plaintext).unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn shared_session_key() {
+        let context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        let mut hash = context.hash(HashAlgorithm::sha256()).unwrap();
+        hash.update(b"schannel-rs").unwrap();
+        let key = ::std::sync::Arc::new(context.derive_key(CipherAlgorithm::aes_256(), &hash, 0)
+            .unwrap());
+
+        let threads = (0..8).map(|_| {
+            let key = key.clone();
+            ::std::thread::spawn(move || {
+                let data = include_bytes!("../test/legacy-aes.bin");
+                for _ in 0..100 {
+                    let plaintext = key.decrypt(data).unwrap();
+                    assert_eq!(key.encrypt(&plaintext).unwrap(), &data[..]);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn decrypt() {
+        let key = include_bytes!("../test/key.key");
+        let cert = include_bytes!("../test/cert.der");
+        let cert = CertContext::new(cert).unwrap();
+
+        let mut context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        context.import()
+            .import(key)
+            .unwrap();
+        let key = PrivateKey::CryptProv(context);
+
+        let oaep = RsaPadding::Oaep(HashAlgorithm::sha1());
+        for padding in &[RsaPadding::Pkcs1, oaep.clone()] {
+            let ciphertext = cert.encrypt(b"hello", padding.clone()).unwrap();
+            assert_eq!(key.decrypt(&ciphertext, padding.clone()).unwrap(), b"hello");
+        }
+
+        let ciphertext = cert.encrypt(b"hello", oaep).unwrap();
+        assert!(key.decrypt(&ciphertext, RsaPadding::Pkcs1).is_err());
+        let oaep = RsaPadding::Oaep(HashAlgorithm::sha256());
+        assert!(key.decrypt(&ciphertext, oaep).is_err());
+    }
+
+    #[test]
+    fn export_pkcs8() {
+        let key = include_bytes!("../test/key.key");
+        let cert = include_bytes!("../test/cert.der");
+        let cert = CertContext::new(cert).unwrap();
+
+        let mut context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_full())
+            .unwrap();
+        context.import()
+            .import(key)
+            .unwrap();
+        let err = context.export_pkcs8(KeySpec::key_exchange()).err().unwrap();
+        assert_eq!(::error::code(&err), Some(ffi::NTE_BAD_KEY_STATE));
+
+        let mut context = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_full())
+            .unwrap();
+        context.import()
+            .exportable(true)
+            .import(key)
+            .unwrap();
+        let pkcs8 = context.export_pkcs8(KeySpec::key_exchange()).unwrap();
+
+        let key = CreateOptions::new().import_pkcs8(&pkcs8).unwrap();
+        assert_eq!(key.export_public_spki().unwrap(), cert.public_key().unwrap());
+    }
+
+    #[test]
+    fn migrate_to_cng() {
+        let key = include_bytes!("../test/key.key");
+        let cert = include_bytes!("../test/cert.der");
+        let cert = CertContext::new(cert).unwrap();
+        let container = "schannel-rs-test-migrate-to-cng";
+        let name = "schannel-rs-test-migrate-to-cng-cng";
+        let type_ = ProviderType::rsa_full();
+
+        let mut options = AcquireOptions::new();
+        options.container(container);
+        let mut context = match options.acquire(type_) {
+            Ok(context) => context,
+            Err(_) => options.new_keyset(true).acquire(type_).unwrap(),
+        };
+        context.import().import(key).unwrap();
+        cert.set_key_prov_info()
+            .container(container)
+            .type_(type_)
+            .key_spec(KeySpec::key_exchange())
+            .set()
+            .unwrap();
+
+        let err = cert_context::migrate_to_cng(&cert, MS_KSP, name, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        match cert.private_key().acquire().unwrap() {
+            PrivateKey::CryptProv(_) => {}
+            PrivateKey::NcryptKey(_) => panic!("key was migrated"),
+        }
+
+        context.import().exportable(true).import(key).unwrap();
+        drop(context);
+        let key = cert_context::migrate_to_cng(&cert, MS_KSP, name, true).unwrap();
+        assert_eq!(key.export_public_spki().unwrap(), cert.public_key().unwrap());
+        match cert.private_key().acquire().unwrap() {
+            PrivateKey::NcryptKey(_) => {}
+            PrivateKey::CryptProv(_) => panic!("key was not migrated"),
+        }
+
+        let err = AcquireOptions::new().container(container).acquire(type_).err().unwrap();
+        assert_eq!(::error::code(&err), Some(ffi::NTE_BAD_KEYSET));
+        key.delete().unwrap();
+    }
+
+    #[test]
+    fn enumerate_providers() {
+        let providers = providers().unwrap();
+        assert!(providers.iter().any(|p| {
+            p.name() == "Microsoft Enhanced RSA and AES Cryptographic Provider" &&
+            p.type_() == ProviderType::rsa_aes()
+        }));
+    }
+
+    #[test]
+    fn delete_container() {
+        let name = "schannel-rs-test-delete-container";
+        let type_ = ProviderType::rsa_full();
+
+        let mut options = AcquireOptions::new();
+        options.container(name);
+        if options.acquire(type_).is_err() {
+            options.new_keyset(true).acquire(type_).unwrap();
+        }
+        CryptProv::delete_container(type_, name, false).unwrap();
+
+        let err = AcquireOptions::new()
+            .container(name)
+            .acquire(type_)
+            .err()
+            .unwrap();
+        assert_eq!(::error::code(&err), Some(ffi::NTE_BAD_KEYSET));
+    }
 }
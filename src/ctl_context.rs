@@ -1,65 +1,456 @@
 //! Bindings to Certificate Trust Lists (CTL) in winapi.
 
-#![allow(dead_code)]
-
-use crypt32;
+use ffi;
+use std::ffi::CStr;
 use std::io;
 use std::mem;
 use std::ptr;
-use winapi;
+use std::slice;
+use std::time::SystemTime;
 
-use cert_context::CertContext;
+use cert_context::{CertContext, HashAlgorithm, PrivateKey};
+use cert_store::CertStore;
 use Inner;
 
 lazy_static! {
 	static ref szOID_OIWSEC_sha1: Vec<u8> =
-		winapi::szOID_OIWSEC_sha1.bytes().chain(Some(0)).collect();
+		ffi::szOID_OIWSEC_sha1.bytes().chain(Some(0)).collect();
 }
 
 /// Wrapped `PCCTL_CONTEXT` which represents a certificate trust list to
 /// Windows.
-pub struct CtlContext(winapi::PCCTL_CONTEXT);
+pub struct CtlContext(ffi::PCCTL_CONTEXT);
 
+// Like certificate contexts, CTL contexts are reference counted atomically
+// and are not modified through shared references.
 unsafe impl Send for CtlContext {}
 unsafe impl Sync for CtlContext {}
 
 impl Drop for CtlContext {
     fn drop(&mut self) {
         unsafe {
-            crypt32::CertFreeCTLContext(self.0);
+            ffi::CertFreeCTLContext(self.0);
         }
     }
 }
 
-impl Inner<winapi::PCCTL_CONTEXT> for CtlContext {
-    unsafe fn from_inner(t: winapi::PCCTL_CONTEXT) -> CtlContext {
+impl Inner<ffi::PCCTL_CONTEXT> for CtlContext {
+    unsafe fn from_inner(t: ffi::PCCTL_CONTEXT) -> CtlContext {
         CtlContext(t)
     }
 
-    fn as_inner(&self) -> winapi::PCCTL_CONTEXT {
+    fn as_inner(&self) -> ffi::PCCTL_CONTEXT {
         self.0
     }
 
-    fn get_mut(&mut self) -> &mut winapi::PCCTL_CONTEXT {
+    fn get_mut(&mut self) -> &mut ffi::PCCTL_CONTEXT {
         &mut self.0
     }
 }
 
 impl CtlContext {
+    /// Returns the raw `PCCTL_CONTEXT`.
+    ///
+    /// The CTL context remains owned by this value, and is only valid for as
+    /// long as it is alive. Callers who need to keep it longer should take
+    /// their own reference with `CertDuplicateCTLContext`.
+    pub fn as_raw(&self) -> ffi::PCCTL_CONTEXT {
+        self.0
+    }
+
+    /// Consumes this value, returning the raw `PCCTL_CONTEXT` along with the
+    /// reference it held.
+    ///
+    /// The caller becomes responsible for releasing it with
+    /// `CertFreeCTLContext`, or for passing it back to `from_raw`.
+    pub fn into_raw(self) -> ffi::PCCTL_CONTEXT {
+        let raw = self.0;
+        mem::forget(self);
+        raw
+    }
+
+    /// Wraps a raw `PCCTL_CONTEXT`, taking ownership of one reference to it.
+    ///
+    /// The reference is released with `CertFreeCTLContext` when the returned
+    /// value is dropped. To wrap a CTL context while keeping the caller's
+    /// reference, take a new one with `CertDuplicateCTLContext` first.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must be a valid CTL context which the caller owns a reference to.
+    pub unsafe fn from_raw(raw: ffi::PCCTL_CONTEXT) -> CtlContext {
+        CtlContext(raw)
+    }
+
+    /// Decodes a DER-formatted CTL.
+    pub fn new(data: &[u8]) -> io::Result<CtlContext> {
+        unsafe {
+            let ret = ffi::CertCreateCTLContext(ffi::X509_ASN_ENCODING |
+                                                ffi::PKCS_7_ASN_ENCODING,
+                                                data.as_ptr(),
+                                                try!(::dword_len(data.len())));
+            if ret.is_null() {
+                Err(::error::last_error())
+            } else {
+                Ok(CtlContext(ret))
+            }
+        }
+    }
+
     /// Returns a builder reader to create an encoded `CtlContext`.
     pub fn builder() -> Builder {
         Builder {
             certificates: vec![],
             usages: vec![],
+            list_identifier: vec![],
+            this_update: None,
+            next_update: None,
+            signer: None,
+            entries: vec![],
+            deduplicate: false,
+        }
+    }
+
+    /// Returns the encoded form of this CTL.
+    pub fn to_der(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts((*self.0).pbCtlEncoded, (*self.0).cbCtlEncoded as usize) }
+    }
+
+    /// Returns the OIDs of the usages which the subjects of this CTL are
+    /// trusted for.
+    pub fn usages(&self) -> Vec<String> {
+        unsafe {
+            let usage = &self.info().SubjectUsage;
+            if usage.cUsageIdentifier == 0 {
+                return vec![];
+            }
+            slice::from_raw_parts(usage.rgpszUsageIdentifier, usage.cUsageIdentifier as usize)
+                .iter()
+                .map(|&oid| CStr::from_ptr(oid as *const _).to_string_lossy().into_owned())
+                .collect()
+        }
+    }
+
+    /// Returns the time at which this CTL was issued.
+    pub fn this_update(&self) -> SystemTime {
+        ::filetime_to_system_time(&self.info().ThisUpdate)
+    }
+
+    /// Returns the time by which the next version of this CTL will be
+    /// issued, if any.
+    pub fn next_update(&self) -> Option<SystemTime> {
+        let time = &self.info().NextUpdate;
+        if time.dwLowDateTime == 0 && time.dwHighDateTime == 0 {
+            None
+        } else {
+            Some(::filetime_to_system_time(time))
+        }
+    }
+
+    /// Returns an iterator over the entries of this CTL.
+    pub fn entries(&self) -> Entries {
+        let info = self.info();
+        let entries = if info.cCTLEntry == 0 {
+            &[][..]
+        } else {
+            unsafe { slice::from_raw_parts(info.rgCTLEntry, info.cCTLEntry as usize) }
+        };
+        Entries(entries.iter())
+    }
+
+    /// Returns an iterator over the identifiers of the certificates listed by
+    /// this CTL, which are their hashes with the algorithm named by
+    /// `subject_algorithm`.
+    pub fn certificates(&self) -> Certificates {
+        Certificates(self.entries())
+    }
+
+    /// Returns the identifier of this CTL, if it has one.
+    pub fn list_identifier(&self) -> &[u8] {
+        unsafe { blob(&self.info().ListIdentifier) }
+    }
+
+    /// Verifies the signature of this CTL, returning the certificate which
+    /// signed it.
+    ///
+    /// The signer's certificate must be included in the CTL. Returns `None`
+    /// if the CTL is unsigned. This does not check that the signer is
+    /// trusted.
+    pub fn signer(&self) -> io::Result<Option<CertContext>> {
+        unsafe {
+            let msg = (*self.0).hCryptMsg;
+            let mut count: ffi::DWORD = 0;
+            let mut len = mem::size_of_val(&count) as ffi::DWORD;
+            let res = ffi::CryptMsgGetParam(msg,
+                                            ffi::CMSG_SIGNER_COUNT_PARAM,
+                                            0,
+                                            &mut count as *mut _ as *mut _,
+                                            &mut len);
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+
+            let mut signer: ffi::PCCERT_CONTEXT = ptr::null();
+            let res = ffi::CryptMsgGetAndVerifySigner(msg,
+                                                      0,
+                                                      ptr::null_mut(),
+                                                      0,
+                                                      &mut signer as *mut _ as *mut _,
+                                                      ptr::null_mut());
+            if res != ffi::TRUE {
+                return Err(::error::last_error());
+            }
+            Ok(Some(CertContext::from_inner(signer)))
+        }
+    }
+
+    /// Returns the OID of the hash algorithm identifying the subjects of this
+    /// CTL.
+    pub fn subject_algorithm(&self) -> String {
+        unsafe {
+            let oid = self.info().SubjectAlgorithm.pszObjId;
+            if oid.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(oid as *const _).to_string_lossy().into_owned()
+            }
+        }
+    }
+
+    /// Determines if this CTL has an entry for `cert`, identified by its hash
+    /// with the specified algorithm.
+    ///
+    /// An error is returned if `hash` is not the `SubjectAlgorithm` of this
+    /// CTL.
+    pub fn contains(&self, cert: &CertContext, hash: HashAlgorithm) -> io::Result<bool> {
+        let oid = unsafe { CStr::from_ptr(try!(hash_oid(&hash)) as *const _) };
+        if oid.to_bytes() != self.subject_algorithm().as_bytes() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "hash algorithm does not match the CTL's"));
+        }
+        let identifier = try!(cert.fingerprint(hash));
+        Ok(self.entries().any(|e| e.subject_identifier() == &identifier[..]))
+    }
+
+    /// Determines if this CTL is past its `NextUpdate` time.
+    pub fn is_expired(&self) -> bool {
+        self.next_update().map_or(false, |t| t < SystemTime::now())
+    }
+
+    /// Returns the display name of this CTL.
+    pub fn friendly_name(&self) -> io::Result<String> {
+        unsafe {
+            ::get_string_property(ffi::CertGetCTLContextProperty,
+                                  self.0,
+                                  ffi::CERT_FRIENDLY_NAME_PROP_ID)
+        }
+    }
+
+    /// Sets the display name of this CTL.
+    pub fn set_friendly_name(&self, name: &str) -> io::Result<()> {
+        unsafe {
+            ::set_string_property(ffi::CertSetCTLContextProperty,
+                                  self.0,
+                                  ffi::CERT_FRIENDLY_NAME_PROP_ID,
+                                  name)
+        }
+    }
+
+    /// Returns the raw value of the specified property of this CTL, or `None`
+    /// if it is not set.
+    pub fn get_raw_property(&self, prop: ffi::DWORD) -> io::Result<Option<Vec<u8>>> {
+        unsafe {
+            match ::get_property(ffi::CertGetCTLContextProperty, self.0, prop) {
+                Ok(buf) => Ok(Some(buf)),
+                Err(ref e) if ::error::code(e) == Some(ffi::CRYPT_E_NOT_FOUND) => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+    }
+
+    /// Sets the specified property of this CTL to a `CRYPT_DATA_BLOB` holding
+    /// `value`.
+    pub fn set_raw_property(&self, prop: ffi::DWORD, value: &[u8]) -> io::Result<()> {
+        unsafe { ::set_property(ffi::CertSetCTLContextProperty, self.0, prop, value) }
+    }
+
+    fn info(&self) -> &ffi::CTL_INFO {
+        unsafe { &*(*self.0).pCtlInfo }
+    }
+}
+
+/// The result of `verify_usage`.
+pub enum UsageStatus {
+    /// The certificate is trusted for the usage by the contained CTL.
+    Trusted(CtlContext),
+    /// The certificate is listed by the contained CTL, which has expired.
+    Expired(CtlContext),
+    /// No CTL for the usage lists the certificate.
+    NotMember,
+    /// Windows was unable to check the usage of the certificate.
+    NotVerified,
+}
+
+/// Determines if `cert` is trusted for the usage with the OID `usage` by a
+/// signed CTL in one of `ctl_stores`, as `CertVerifyCTLUsage` does.
+///
+/// The signature of the CTL is verified against the certificates it
+/// contains. Expired CTLs are reported as such rather than as not listing the
+/// certificate. Failures to reach a revocation server are returned as
+/// errors.
+pub fn verify_usage(cert: &CertContext,
+                    usage: &str,
+                    ctl_stores: &[&CertStore])
+                    -> io::Result<UsageStatus> {
+    unsafe {
+        let usage = usage.bytes().chain(Some(0)).collect::<Vec<_>>();
+        let mut usage_ptr = usage.as_ptr() as ffi::LPSTR;
+        let mut subject_usage = ffi::CTL_USAGE {
+            cUsageIdentifier: 1,
+            rgpszUsageIdentifier: &mut usage_ptr,
+        };
+
+        let mut stores = ctl_stores.iter().map(|s| s.as_inner()).collect::<Vec<_>>();
+        let mut para: ffi::CTL_VERIFY_USAGE_PARA = mem::zeroed();
+        para.cbSize = mem::size_of_val(&para) as ffi::DWORD;
+        para.cCtlStore = try!(::dword_len(stores.len()));
+        para.rghCtlStore = stores.as_mut_ptr();
+
+        let mut ctl = ptr::null();
+        let mut status: ffi::CTL_VERIFY_USAGE_STATUS = mem::zeroed();
+        status.cbSize = mem::size_of_val(&status) as ffi::DWORD;
+        status.ppCtl = &mut ctl as *mut _ as *mut _;
+
+        // the validity period is checked below so that expiry can be reported
+        let res = ffi::CertVerifyCTLUsage(ffi::X509_ASN_ENCODING |
+                                          ffi::PKCS_7_ASN_ENCODING,
+                                          ffi::CTL_CERT_SUBJECT_TYPE,
+                                          cert.as_inner() as *mut _,
+                                          &mut subject_usage,
+                                          ffi::CERT_VERIFY_NO_TIME_CHECK_FLAG,
+                                          &mut para,
+                                          &mut status);
+        if res != ffi::TRUE {
+            let err = ::error::last_error();
+            return match ::error::code(&err) {
+                Some(ffi::CRYPT_E_NOT_IN_CTL) => Ok(UsageStatus::NotMember),
+                Some(ffi::CRYPT_E_NO_VERIFY_USAGE_DLL) |
+                Some(ffi::CRYPT_E_NO_VERIFY_USAGE_CHECK) => Ok(UsageStatus::NotVerified),
+                _ => Err(err),
+            };
+        }
+
+        let ctl = CtlContext(ctl);
+        if ctl.is_expired() {
+            Ok(UsageStatus::Expired(ctl))
+        } else {
+            Ok(UsageStatus::Trusted(ctl))
+        }
+    }
+}
+
+/// An iterator over the entries of a CTL.
+pub struct Entries<'a>(slice::Iter<'a, ffi::CTL_ENTRY>);
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = CtlEntry<'a>;
+
+    fn next(&mut self) -> Option<CtlEntry<'a>> {
+        self.0.next().map(CtlEntry)
+    }
+}
+
+/// An iterator over the identifiers of the certificates listed by a CTL.
+pub struct Certificates<'a>(Entries<'a>);
+
+impl<'a> Iterator for Certificates<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        self.0.next().map(|e| e.subject_identifier())
+    }
+}
+
+/// An entry of a CTL.
+pub struct CtlEntry<'a>(&'a ffi::CTL_ENTRY);
+
+impl<'a> CtlEntry<'a> {
+    /// Returns the identifier of the subject of this entry, which is
+    /// typically a hash of a certificate.
+    pub fn subject_identifier(&self) -> &'a [u8] {
+        unsafe { blob(&self.0.SubjectIdentifier) }
+    }
+
+    /// Returns the attributes of this entry.
+    pub fn attributes(&self) -> Vec<Attribute<'a>> {
+        if self.0.cAttribute == 0 {
+            return vec![];
+        }
+        unsafe {
+            slice::from_raw_parts(self.0.rgAttribute, self.0.cAttribute as usize)
+                .iter()
+                .map(Attribute)
+                .collect()
+        }
+    }
+}
+
+/// An attribute of a CTL entry.
+pub struct Attribute<'a>(&'a ffi::CRYPT_ATTRIBUTE);
+
+impl<'a> Attribute<'a> {
+    /// Returns the OID of this attribute.
+    pub fn oid(&self) -> String {
+        unsafe { CStr::from_ptr(self.0.pszObjId as *const _).to_string_lossy().into_owned() }
+    }
+
+    /// Returns the DER-encoded values of this attribute.
+    pub fn values(&self) -> Vec<&'a [u8]> {
+        if self.0.cValue == 0 {
+            return vec![];
+        }
+        unsafe {
+            slice::from_raw_parts(self.0.rgValue, self.0.cValue as usize)
+                .iter()
+                .map(|value| blob(value))
+                .collect()
         }
     }
 }
 
+unsafe fn blob<'a>(blob: &'a ffi::CRYPT_DATA_BLOB) -> &'a [u8] {
+    if blob.cbData == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(blob.pbData, blob.cbData as usize)
+    }
+}
+
 /// Used to build an encoded `CtlContext` which can be added to a `Memory` store
 /// to get back the actual `CtlContext`.
 pub struct Builder {
     certificates: Vec<CertContext>,
     usages: Vec<Vec<u8>>,
+    list_identifier: Vec<u8>,
+    this_update: Option<SystemTime>,
+    next_update: Option<SystemTime>,
+    signer: Option<(CertContext, PrivateKey, HashAlgorithm)>,
+    entries: Vec<Entry>,
+    deduplicate: bool,
+}
+
+enum Subject {
+    Certificate(CertContext, HashAlgorithm),
+    Hash(Vec<u8>),
+}
+
+struct Entry {
+    subject: Subject,
+    // nul-terminated OIDs and their DER-encoded values
+    attributes: Vec<(Vec<u8>, Vec<u8>)>,
 }
 
 impl Builder {
@@ -69,8 +460,72 @@ impl Builder {
         self
     }
 
+    /// Adds an entry for a certificate, identified by its hash with the
+    /// specified algorithm.
+    ///
+    /// All entries identified by hashes must use the same algorithm, which
+    /// is recorded as the `SubjectAlgorithm` of the CTL. Certificates added
+    /// with `certificate` are identified by their SHA-1 hash.
+    pub fn add_certificate(&mut self, cert: &CertContext, hash: HashAlgorithm) -> &mut Builder {
+        self.add_certificate_with_attributes(cert, hash, &[])
+    }
+
+    /// Adds an entry for each of `certs`, identified by their hashes with the
+    /// specified algorithm.
+    pub fn add_certificates(&mut self, certs: &[CertContext], hash: HashAlgorithm) -> &mut Builder {
+        for cert in certs {
+            self.add_certificate(cert, hash.clone());
+        }
+        self
+    }
+
+    /// Like `add_certificate`, but also attaches attributes to the entry,
+    /// each given as an OID and a DER-encoded value.
+    pub fn add_certificate_with_attributes(&mut self,
+                                           cert: &CertContext,
+                                           hash: HashAlgorithm,
+                                           attributes: &[(&str, &[u8])])
+                                           -> &mut Builder {
+        self.entries.push(Entry {
+            subject: Subject::Certificate(cert.clone(), hash),
+            attributes: encode_attributes(attributes),
+        });
+        self
+    }
+
+    /// Adds an entry with the specified subject identifier, typically a hash
+    /// of a certificate computed with the algorithm used by the other
+    /// entries.
+    pub fn add_hash(&mut self, hash: &[u8]) -> &mut Builder {
+        self.add_hash_with_attributes(hash, &[])
+    }
+
+    /// Like `add_hash`, but also attaches attributes to the entry, each given
+    /// as an OID and a DER-encoded value.
+    pub fn add_hash_with_attributes(&mut self,
+                                    hash: &[u8],
+                                    attributes: &[(&str, &[u8])])
+                                    -> &mut Builder {
+        self.entries.push(Entry {
+            subject: Subject::Hash(hash.to_owned()),
+            attributes: encode_attributes(attributes),
+        });
+        self
+    }
+
+    /// If set, entries whose subject identifier duplicates that of an earlier
+    /// entry are dropped, rather than causing `encode_and_sign` to fail.
+    ///
+    /// Defaults to `false`.
+    pub fn deduplicate(&mut self, deduplicate: bool) -> &mut Builder {
+        self.deduplicate = deduplicate;
+        self
+    }
+
     /// Adds a usage string to be passed in the `SubjectUsage` field to
     /// `CryptMsgEncodeAndSignCTL` later on.
+    ///
+    /// This may be called multiple times to add several usages.
     pub fn usage(&mut self, usage: &str) -> &mut Builder {
         let mut usage = usage.as_bytes().to_owned();
         usage.push(0);
@@ -78,106 +533,530 @@ impl Builder {
         self
     }
 
+    /// Sets the identifier of the CTL.
+    pub fn list_identifier(&mut self, list_identifier: &[u8]) -> &mut Builder {
+        self.list_identifier = list_identifier.to_owned();
+        self
+    }
+
+    /// Sets the time at which the CTL was issued.
+    ///
+    /// Defaults to the current time.
+    pub fn this_update(&mut self, this_update: SystemTime) -> &mut Builder {
+        self.this_update = Some(this_update);
+        self
+    }
+
+    /// Sets the time by which the next version of the CTL will be issued.
+    ///
+    /// The CTL does not expire if this is not set.
+    pub fn next_update(&mut self, next_update: SystemTime) -> &mut Builder {
+        self.next_update = Some(next_update);
+        self
+    }
+
+    /// Sets the certificate and corresponding private key used to sign the
+    /// CTL, along with the hash algorithm of the signature.
+    ///
+    /// The certificate is included in the CTL. If this is not set, the CTL is
+    /// left unsigned.
+    pub fn signer(&mut self,
+                  cert: CertContext,
+                  key: PrivateKey,
+                  hash: HashAlgorithm)
+                  -> &mut Builder {
+        self.signer = Some((cert, key, hash));
+        self
+    }
+
+    /// Encodes and signs the CTL like `encode_and_sign`, and decodes the result.
+    pub fn build(&self) -> io::Result<CtlContext> {
+        CtlContext::new(&try!(self.encode_and_sign()))
+    }
+
     /// Calls `CryptMsgEncodeAndSignCTL` to encode this list of certificates
     /// into a CTL.
     ///
     /// This can later be passed to `Memory::add_encoded_ctl`.
     pub fn encode_and_sign(&self) -> io::Result<Vec<u8>> {
         unsafe {
-            let encoding = winapi::X509_ASN_ENCODING | winapi::PKCS_7_ASN_ENCODING;
+            let encoding = ffi::X509_ASN_ENCODING | ffi::PKCS_7_ASN_ENCODING;
 
             let mut usages = self.usages.iter().map(|u| u.as_ptr()).collect::<Vec<_>>();
             let mut entry_data = vec![];
             let mut entries = vec![];
+            let mut subject_alg = None;
             for certificate in &self.certificates {
                 let data = try!(cert_entry(certificate));
-                entries.push(*(data.as_ptr() as *const winapi::CTL_ENTRY));
+                entries.push(*(data.as_ptr() as *const ffi::CTL_ENTRY));
                 entry_data.push(data);
+                subject_alg = Some(HashAlgorithm::sha1());
+            }
+
+            let mut identifiers = vec![];
+            let mut attributes = vec![];
+            let mut values = vec![];
+            for entry in &self.entries {
+                let identifier = match entry.subject {
+                    Subject::Certificate(ref cert, ref hash) => {
+                        if subject_alg.as_ref().map_or(false, |alg| alg != hash) {
+                            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                      "entries use different hash algorithms"));
+                        }
+                        subject_alg = Some(hash.clone());
+                        try!(cert.fingerprint(hash.clone()))
+                    }
+                    Subject::Hash(ref hash) => hash.clone(),
+                };
+                let mut blobs = try!(entry.attributes
+                    .iter()
+                    .map(|&(_, ref value)| {
+                        Ok(ffi::CRYPT_ATTR_BLOB {
+                            cbData: try!(::dword_len(value.len())),
+                            pbData: value.as_ptr() as *mut _,
+                        })
+                    })
+                    .collect::<io::Result<Vec<_>>>());
+                let attrs = entry.attributes
+                    .iter()
+                    .zip(blobs.iter_mut())
+                    .map(|(&(ref oid, _), blob)| {
+                        ffi::CRYPT_ATTRIBUTE {
+                            pszObjId: oid.as_ptr() as ffi::LPSTR,
+                            cValue: 1,
+                            rgValue: blob,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                identifiers.push(identifier);
+                attributes.push(attrs);
+                values.push(blobs);
+            }
+            for (identifier, attrs) in identifiers.iter().zip(attributes.iter_mut()) {
+                entries.push(ffi::CTL_ENTRY {
+                    SubjectIdentifier: ffi::CRYPT_DATA_BLOB {
+                        cbData: try!(::dword_len(identifier.len())),
+                        pbData: identifier.as_ptr() as *mut _,
+                    },
+                    cAttribute: try!(::dword_len(attrs.len())),
+                    rgAttribute: attrs.as_mut_ptr(),
+                });
             }
 
-            let mut ctl_info: winapi::CTL_INFO = mem::zeroed();
-            ctl_info.dwVersion = winapi::CTL_V1;
-            ctl_info.SubjectUsage.cUsageIdentifier = usages.len() as winapi::DWORD;
-            ctl_info.SubjectUsage.rgpszUsageIdentifier = usages.as_mut_ptr() as *mut winapi::LPSTR;
-            ctl_info.SubjectAlgorithm.pszObjId = szOID_OIWSEC_sha1.as_ptr() as winapi::LPSTR;
-            ctl_info.cCTLEntry = entries.len() as winapi::DWORD;
+            let mut seen = vec![];
+            let mut unique = Vec::with_capacity(entries.len());
+            for entry in entries {
+                let identifier = blob(&entry.SubjectIdentifier).to_owned();
+                if seen.contains(&identifier) {
+                    if self.deduplicate {
+                        continue;
+                    }
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "duplicate CTL entry"));
+                }
+                seen.push(identifier);
+                unique.push(entry);
+            }
+            let mut entries = unique;
+            let subject_alg = match subject_alg {
+                Some(ref alg) => try!(hash_oid(alg)) as ffi::LPSTR,
+                None => szOID_OIWSEC_sha1.as_ptr() as ffi::LPSTR,
+            };
+
+            let mut ctl_info: ffi::CTL_INFO = mem::zeroed();
+            ctl_info.dwVersion = ffi::CTL_V1;
+            ctl_info.SubjectUsage.cUsageIdentifier = try!(::dword_len(usages.len()));
+            ctl_info.SubjectUsage.rgpszUsageIdentifier = usages.as_mut_ptr() as *mut ffi::LPSTR;
+            ctl_info.ListIdentifier.cbData = try!(::dword_len(self.list_identifier.len()));
+            ctl_info.ListIdentifier.pbData = self.list_identifier.as_ptr() as *mut _;
+            ctl_info.ThisUpdate =
+                ::system_time_to_filetime(self.this_update.unwrap_or_else(SystemTime::now));
+            if let Some(next_update) = self.next_update {
+                ctl_info.NextUpdate = ::system_time_to_filetime(next_update);
+            }
+            ctl_info.SubjectAlgorithm.pszObjId = subject_alg;
+            ctl_info.cCTLEntry = try!(::dword_len(entries.len()));
             ctl_info.rgCTLEntry = entries.as_mut_ptr();
 
-            let mut sign_info: winapi::CMSG_SIGNED_ENCODE_INFO = mem::zeroed();
-            sign_info.cbSize = mem::size_of_val(&sign_info) as winapi::DWORD;
+            let mut sign_info: ffi::CMSG_SIGNED_ENCODE_INFO = mem::zeroed();
+            sign_info.cbSize = mem::size_of_val(&sign_info) as ffi::DWORD;
             let mut encoded_certs = self.certificates
                 .iter()
+                .chain(self.signer.as_ref().map(|s| &s.0))
                 .map(|c| {
-                    winapi::CERT_BLOB {
+                    ffi::CERT_BLOB {
                         cbData: (*c.as_inner()).cbCertEncoded,
                         pbData: (*c.as_inner()).pbCertEncoded,
                     }
                 })
                 .collect::<Vec<_>>();
             sign_info.rgCertEncoded = encoded_certs.as_mut_ptr();
-            sign_info.cCertEncoded = encoded_certs.len() as winapi::DWORD;
+            sign_info.cCertEncoded = try!(::dword_len(encoded_certs.len()));
 
-            let flags = winapi::CMSG_ENCODE_SORTED_CTL_FLAG |
-                        winapi::CMSG_ENCODE_HASHED_SUBJECT_IDENTIFIER_FLAG;
+            let mut signer_info: ffi::CMSG_SIGNER_ENCODE_INFO = mem::zeroed();
+            if let Some((ref cert, ref key, ref hash)) = self.signer {
+                signer_info.cbSize = mem::size_of_val(&signer_info) as ffi::DWORD;
+                signer_info.pCertInfo = (*cert.as_inner()).pCertInfo;
+                match *key {
+                    PrivateKey::CryptProv(ref prov) => {
+                        *ffi::signer_crypt_prov(&mut signer_info) = prov.as_inner();
+                        signer_info.dwKeySpec = match try!(prov.key_spec()) {
+                            Some(spec) => spec.as_raw(),
+                            None => {
+                                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                          "signer's container holds no key"))
+                            }
+                        };
+                    }
+                    PrivateKey::NcryptKey(ref key) => {
+                        *ffi::signer_ncrypt_key(&mut signer_info) = key.as_inner();
+                        signer_info.dwKeySpec = ffi::CERT_NCRYPT_KEY_SPEC;
+                    }
+                }
+                signer_info.HashAlgorithm.pszObjId = try!(hash_oid(hash)) as ffi::LPSTR;
+                sign_info.cSigners = 1;
+                sign_info.rgSigners = &mut signer_info;
+            }
+
+            let flags = ffi::CMSG_ENCODE_SORTED_CTL_FLAG |
+                        ffi::CMSG_ENCODE_HASHED_SUBJECT_IDENTIFIER_FLAG;
 
             let mut size = 0;
 
-            let res = crypt32::CryptMsgEncodeAndSignCTL(encoding,
-                                                        &mut ctl_info,
-                                                        &mut sign_info,
-                                                        flags,
-                                                        ptr::null_mut(),
-                                                        &mut size);
-            if res == winapi::FALSE {
-                return Err(io::Error::last_os_error())
+            let res = ffi::CryptMsgEncodeAndSignCTL(encoding,
+                                                    &mut ctl_info,
+                                                    &mut sign_info,
+                                                    flags,
+                                                    ptr::null_mut(),
+                                                    &mut size);
+            if res == ffi::FALSE {
+                return Err(::error::last_error())
             }
 
             let mut encoded = vec![0; size as usize];
 
-            let res = crypt32::CryptMsgEncodeAndSignCTL(encoding,
-                                                        &mut ctl_info,
-                                                        &mut sign_info,
-                                                        flags,
-                                                        encoded.as_mut_ptr() as *mut winapi::BYTE,
-                                                        &mut size);
-            if res == winapi::FALSE {
-                return Err(io::Error::last_os_error())
+            let res = ffi::CryptMsgEncodeAndSignCTL(encoding,
+                                                    &mut ctl_info,
+                                                    &mut sign_info,
+                                                    flags,
+                                                    encoded.as_mut_ptr() as *mut ffi::BYTE,
+                                                    &mut size);
+            if res == ffi::FALSE {
+                return Err(::error::last_error())
             }
+            encoded.truncate(size as usize);
 
             Ok(encoded)
         }
     }
 }
 
+/// Returns the OID of a hash algorithm, which is valid for the lifetime of the
+/// process.
+fn hash_oid(hash: &HashAlgorithm) -> io::Result<ffi::LPCSTR> {
+    unsafe {
+        let name = hash.cng_name().encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        let info = ffi::CryptFindOIDInfo(ffi::CRYPT_OID_INFO_CNG_ALGID_KEY,
+                                         name.as_ptr() as *mut _,
+                                         ffi::CRYPT_HASH_ALG_OID_GROUP_ID);
+        if info.is_null() {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown hash algorithm"))
+        } else {
+            Ok(ffi::oid_info_oid(info))
+        }
+    }
+}
+
+fn encode_attributes(attributes: &[(&str, &[u8])]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    attributes.iter()
+        .map(|&(oid, value)| (oid.bytes().chain(Some(0)).collect(), value.to_owned()))
+        .collect()
+}
+
 fn cert_entry(cert: &CertContext) -> io::Result<Vec<u8>> {
     unsafe {
         let mut size = 0;
 
-        let res = crypt32::CertCreateCTLEntryFromCertificateContextProperties(
+        let res = ffi::CertCreateCTLEntryFromCertificateContextProperties(
 			cert.as_inner(),
 			0,
 			ptr::null_mut(),
-			winapi::CTL_ENTRY_FROM_PROP_CHAIN_FLAG,
+			ffi::CTL_ENTRY_FROM_PROP_CHAIN_FLAG,
 			ptr::null_mut(),
 			ptr::null_mut(),
 			&mut size);
-        if res == winapi::FALSE {
-            return Err(io::Error::last_os_error());
+        if res == ffi::FALSE {
+            return Err(::error::last_error());
         }
 
         let mut entry = vec![0u8; size as usize];
-        let res = crypt32::CertCreateCTLEntryFromCertificateContextProperties(
+        let res = ffi::CertCreateCTLEntryFromCertificateContextProperties(
 			cert.as_inner(),
 			0,
 			ptr::null_mut(),
-			winapi::CTL_ENTRY_FROM_PROP_CHAIN_FLAG,
+			ffi::CTL_ENTRY_FROM_PROP_CHAIN_FLAG,
 			ptr::null_mut(),
-			entry.as_mut_ptr() as winapi::PCTL_ENTRY,
+			entry.as_mut_ptr() as ffi::PCTL_ENTRY,
 			&mut size);
-        if res == winapi::FALSE {
-            Err(io::Error::last_os_error())
+        if res == ffi::FALSE {
+            Err(::error::last_error())
         } else {
             Ok(entry)
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use cert_context::{CertContext, HashAlgorithm, PrivateKey};
+    use cert_store::{self, Memory};
+    use super::*;
+
+    const CODE_SIGNING: &'static str = "1.3.6.1.5.5.7.3.3";
+
+    #[test]
+    fn raw() {
+        let cert = include_bytes!("../test/self-signed.badssl.com.cer");
+        let cert = CertContext::new(cert).unwrap();
+        let der = CtlContext::builder()
+            .certificate(cert)
+            .usage(CODE_SIGNING)
+            .encode_and_sign()
+            .unwrap();
+        let mut store = Memory::new().unwrap();
+        let ctl = store.add_encoded_ctl(&der).unwrap();
+
+        let raw = ctl.into_raw();
+        let ctl = unsafe { CtlContext::from_raw(raw) };
+        assert_eq!(ctl.as_raw(), raw);
+        let dup = unsafe { CtlContext::from_raw(ffi::CertDuplicateCTLContext(raw)) };
+        drop(ctl);
+        assert_eq!(dup.to_der(), &der[..]);
+        drop(dup);
+
+        unsafe {
+            let raw = store.into_store().into_raw();
+            assert_eq!(ffi::CertCloseStore(raw, ffi::CERT_CLOSE_STORE_CHECK_FLAG),
+                       ffi::TRUE);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let cert = include_bytes!("../test/self-signed.badssl.com.cer");
+        let cert = CertContext::new(cert).unwrap();
+        let der = CtlContext::builder()
+            .certificate(cert.clone())
+            .usage(CODE_SIGNING)
+            .encode_and_sign()
+            .unwrap();
+
+        let ctl = CtlContext::new(&der).unwrap();
+        assert_eq!(ctl.to_der(), &der[..]);
+        assert_eq!(ctl.usages(), vec![CODE_SIGNING.to_string()]);
+        assert_eq!(ctl.next_update(), None);
+        let entries = ctl.entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].subject_identifier(),
+                   &cert.fingerprint(HashAlgorithm::sha1()).unwrap()[..]);
+        assert!(ctl.signer().unwrap().is_none());
+    }
+
+    #[test]
+    fn sign() {
+        let cert = include_bytes!("../test/self-signed.badssl.com.cer");
+        let cert = CertContext::new(cert).unwrap();
+        let pfx = include_bytes!("../test/identity.p12");
+        let (signer, key) = cert_store::pfx_to_cert_and_key(pfx, "mypass").unwrap();
+
+        let this_update = UNIX_EPOCH + Duration::from_secs(1500000000);
+        let next_update = this_update + Duration::from_secs(86400);
+        let der = CtlContext::builder()
+            .certificate(cert)
+            .usage(CODE_SIGNING)
+            .usage("1.3.6.1.5.5.7.3.1")
+            .list_identifier(b"schannel-rs")
+            .this_update(this_update)
+            .next_update(next_update)
+            .signer(signer.clone(), PrivateKey::NcryptKey(key), HashAlgorithm::sha256())
+            .encode_and_sign()
+            .unwrap();
+
+        let ctl = CtlContext::new(&der).unwrap();
+        assert_eq!(ctl.usages().len(), 2);
+        assert_eq!(ctl.list_identifier(), b"schannel-rs");
+        assert_eq!(ctl.this_update(), this_update);
+        assert_eq!(ctl.next_update(), Some(next_update));
+        assert_eq!(ctl.entries().count(), 1);
+        assert!(ctl.signer().unwrap().unwrap() == signer);
+    }
+
+    #[test]
+    fn hash_entries() {
+        let cert = include_bytes!("../test/cert.der");
+        let cert = CertContext::new(cert).unwrap();
+        let other = [0x42; 32];
+        let name = [0x04, 0x02, 0x68, 0x69];
+        let name_oid = "1.3.6.1.4.1.311.10.11.11";
+
+        let mut builder = CtlContext::builder();
+        builder.usage(CODE_SIGNING)
+            .add_certificate_with_attributes(&cert, HashAlgorithm::sha256(), &[(name_oid, &name)])
+            .add_hash(&other)
+            .add_hash(&other);
+        assert_eq!(builder.encode_and_sign().err().unwrap().kind(),
+                   io::ErrorKind::InvalidInput);
+
+        let der = builder.deduplicate(true).encode_and_sign().unwrap();
+        let ctl = CtlContext::new(&der).unwrap();
+        let hash = cert.fingerprint(HashAlgorithm::sha256()).unwrap();
+        let entries = ctl.entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        let entry = entries.iter().find(|e| e.subject_identifier() == &hash[..]).unwrap();
+        let attributes = entry.attributes();
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(attributes[0].oid(), name_oid);
+        assert_eq!(attributes[0].values(), vec![&name[..]]);
+        let entry = entries.iter().find(|e| e.subject_identifier() == &other[..]).unwrap();
+        assert!(entry.attributes().is_empty());
+
+        let mut builder = CtlContext::builder();
+        builder.add_certificate(&cert, HashAlgorithm::sha256())
+            .add_certificate(&cert, HashAlgorithm::sha1());
+        assert!(builder.encode_and_sign().is_err());
+    }
+
+    #[test]
+    fn properties() {
+        let cert = include_bytes!("../test/self-signed.badssl.com.cer");
+        let cert = CertContext::new(cert).unwrap();
+        let der = CtlContext::builder()
+            .certificate(cert)
+            .usage(CODE_SIGNING)
+            .encode_and_sign()
+            .unwrap();
+        let mut store = Memory::new().unwrap();
+        let ctl = store.add_encoded_ctl(&der).unwrap();
+
+        assert!(ctl.friendly_name().is_err());
+        ctl.set_friendly_name("schannel-rs test").unwrap();
+        assert_eq!(ctl.friendly_name().unwrap(), "schannel-rs test");
+
+        assert_eq!(ctl.get_raw_property(ffi::CERT_DESCRIPTION_PROP_ID).unwrap(), None);
+        let description = "description".encode_utf16()
+            .chain(Some(0))
+            .flat_map(|c| vec![c as u8, (c >> 8) as u8])
+            .collect::<Vec<_>>();
+        ctl.set_raw_property(ffi::CERT_DESCRIPTION_PROP_ID, &description).unwrap();
+        assert_eq!(ctl.get_raw_property(ffi::CERT_DESCRIPTION_PROP_ID).unwrap(),
+                   Some(description));
+
+        // An unpaired low surrogate
+        let raw = [0x00, 0xdc, 0x00, 0x00];
+        ctl.set_raw_property(ffi::CERT_FRIENDLY_NAME_PROP_ID, &raw).unwrap();
+        assert_eq!(ctl.friendly_name().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn membership() {
+        let member = include_bytes!("../test/self-signed.badssl.com.cer");
+        let member = CertContext::new(member).unwrap();
+        let other = include_bytes!("../test/cert.der");
+        let other = CertContext::new(other).unwrap();
+        let pfx = include_bytes!("../test/identity.p12");
+
+        let build = |next_update: SystemTime| {
+            let (signer, key) = cert_store::pfx_to_cert_and_key(pfx, "mypass").unwrap();
+            CtlContext::builder()
+                .certificate(member.clone())
+                .usage(CODE_SIGNING)
+                .next_update(next_update)
+                .signer(signer, PrivateKey::NcryptKey(key), HashAlgorithm::sha256())
+                .encode_and_sign()
+                .unwrap()
+        };
+
+        let ctl = CtlContext::new(&build(SystemTime::now() + Duration::from_secs(3600))).unwrap();
+        assert!(ctl.contains(&member, HashAlgorithm::sha1()).unwrap());
+        assert!(!ctl.contains(&other, HashAlgorithm::sha1()).unwrap());
+        assert!(ctl.contains(&member, HashAlgorithm::sha256()).is_err());
+
+        let mut store = Memory::new().unwrap();
+        store.add_encoded_ctl(ctl.to_der()).unwrap();
+        let store = store.into_store();
+        match verify_usage(&member, CODE_SIGNING, &[&store]).unwrap() {
+            UsageStatus::Trusted(ctl) => assert!(!ctl.is_expired()),
+            _ => panic!("expected the certificate to be trusted"),
+        }
+        match verify_usage(&other, CODE_SIGNING, &[&store]).unwrap() {
+            UsageStatus::NotMember => {}
+            _ => panic!("expected the certificate not to be a member"),
+        }
+        match verify_usage(&member, "1.3.6.1.5.5.7.3.1", &[&store]).unwrap() {
+            UsageStatus::NotMember | UsageStatus::NotVerified => {}
+            _ => panic!("expected no CTL to trust the certificate for the usage"),
+        }
+
+        let expired = build(SystemTime::now() - Duration::from_secs(3600));
+        let mut store = Memory::new().unwrap();
+        store.add_encoded_ctl(&expired).unwrap();
+        let store = store.into_store();
+        match verify_usage(&member, CODE_SIGNING, &[&store]).unwrap() {
+            UsageStatus::Expired(ctl) => assert!(ctl.is_expired()),
+            _ => panic!("expected the CTL to be expired"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let member = include_bytes!("../test/self-signed.badssl.com.cer");
+        let member = CertContext::new(member).unwrap();
+        let other = include_bytes!("../test/cert.der");
+        let other = CertContext::new(other).unwrap();
+        let certs = [member.clone(), other.clone()];
+
+        let ctl = CtlContext::builder()
+            .add_certificates(&certs, HashAlgorithm::sha1())
+            .usage(CODE_SIGNING)
+            .build()
+            .unwrap();
+        let ctl = CtlContext::new(ctl.to_der()).unwrap();
+        let identifiers = ctl.certificates().collect::<Vec<_>>();
+        assert_eq!(identifiers,
+                   vec![&member.fingerprint(HashAlgorithm::sha1()).unwrap()[..],
+                        &other.fingerprint(HashAlgorithm::sha1()).unwrap()[..]]);
+        assert!(member.is_in_ctl(&ctl).unwrap());
+        assert!(other.is_in_ctl(&ctl).unwrap());
+
+        let ctl = CtlContext::builder()
+            .add_certificates(&certs[..1], HashAlgorithm::sha1())
+            .usage(CODE_SIGNING)
+            .build()
+            .unwrap();
+        assert!(member.is_in_ctl(&ctl).unwrap());
+        assert!(!other.is_in_ctl(&ctl).unwrap());
+
+        let mut store = Memory::new().unwrap().into_store();
+        let added = store.add_ctl(&ctl, cert_store::CertAdd::Always).unwrap();
+        assert_eq!(added.to_der(), ctl.to_der());
+        assert!(member.is_in_ctl(&added).unwrap());
+
+        // Windows only finds certificates by their SHA-1 or MD5 hashes
+        let ctl = CtlContext::builder()
+            .add_certificates(&certs[..1], HashAlgorithm::sha256())
+            .usage(CODE_SIGNING)
+            .build()
+            .unwrap();
+        assert_eq!(member.is_in_ctl(&ctl).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(ctl.contains(&member, HashAlgorithm::sha256()).unwrap());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(CtlContext::new(&[]).is_err());
+        assert!(CtlContext::new(&[0x30, 0x03, 0x02, 0x01, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,444 @@
+//! Error classification.
+use std::error;
+use std::fmt;
+use std::io;
+use ffi;
+use os_support::Feature;
+
+/// A broad category of SSPI, CryptoAPI or CNG failure.
+///
+/// More variants may be added in the future, so matches should include a
+/// wildcard arm.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// A key container, key, certificate, property or algorithm does not
+    /// exist.
+    NotFound,
+    /// A key container, key or certificate already exists.
+    Exists,
+    /// The caller lacks permission to perform the operation.
+    AccessDenied,
+    /// A certificate chain does not end in a trusted root.
+    UntrustedRoot,
+    /// A certificate does not match the name of the peer.
+    WrongPrincipal,
+    /// A certificate has expired or is not yet valid.
+    Expired,
+    /// A certificate has been revoked.
+    Revoked,
+    /// More data is required to complete the operation.
+    IncompleteMessage,
+    /// Input was malformed, corrupt or failed an integrity check.
+    BadData,
+    /// The algorithm, provider or operation is not supported.
+    Unsupported,
+    /// An argument was invalid.
+    InvalidParameter,
+    /// An error not covered by any other variant.
+    Other,
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// An error produced by this crate.
+///
+/// Functions in this crate return `io::Error`s wrapping this type when they
+/// fail with a `SECURITY_STATUS`, `HRESULT`, `NTSTATUS` or Win32 error code.
+/// It can be recovered with `get_ref` and `downcast_ref`, or by converting
+/// the `io::Error` with `From`, which also classifies errors which don't
+/// wrap one.
+pub struct Error(io::Error);
+
+impl Error {
+    /// Returns the category of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self.0.raw_os_error() {
+            Some(code) => classify(code),
+            None => {
+                if self.0.get_ref().map_or(false, |e| e.is::<UnsupportedFeature>()) {
+                    return ErrorKind::Unsupported;
+                }
+                match self.0.kind() {
+                    io::ErrorKind::NotFound => ErrorKind::NotFound,
+                    io::ErrorKind::AlreadyExists => ErrorKind::Exists,
+                    io::ErrorKind::PermissionDenied => ErrorKind::AccessDenied,
+                    io::ErrorKind::InvalidInput => ErrorKind::InvalidParameter,
+                    io::ErrorKind::InvalidData => ErrorKind::BadData,
+                    _ => ErrorKind::Other,
+                }
+            }
+        }
+    }
+
+    /// Returns the raw error code, if there is one.
+    pub fn code(&self) -> Option<i32> {
+        self.0.raw_os_error()
+    }
+
+    /// Returns a shared reference to the underlying `io::Error`.
+    pub fn get_ref(&self) -> &io::Error {
+        &self.0
+    }
+
+    /// Returns the underlying `io::Error`.
+    pub fn into_inner(self) -> io::Error {
+        self.0
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        if err.get_ref().map_or(false, |e| e.is::<Error>()) {
+            let inner = err.into_inner().unwrap();
+            return *inner.downcast::<Error>().unwrap();
+        }
+        Error(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err.0.raw_os_error() {
+            Some(_) => io::Error::new(err.0.kind(), err),
+            None => err.0,
+        }
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Error")
+            .field("kind", &self.kind())
+            .field("inner", &self.0)
+            .finish()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, fmt)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        "schannel error"
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.0)
+    }
+}
+
+/// The payload of the `InvalidData` error returned when a string property
+/// is not valid UTF-16.
+///
+/// It can be recovered from the `io::Error` with `get_ref` and `downcast_ref`.
+#[derive(Debug)]
+pub struct InvalidString(Vec<u8>);
+
+impl InvalidString {
+    /// Returns the raw bytes of the property.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for InvalidString {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("property is not valid UTF-16")
+    }
+}
+
+impl error::Error for InvalidString {
+    fn description(&self) -> &str {
+        "property is not valid UTF-16"
+    }
+}
+
+/// The payload of the error returned when a feature is not available on the
+/// running version of Windows.
+///
+/// It can be recovered from the `io::Error` with `get_ref` and
+/// `downcast_ref`, and is classified as `ErrorKind::Unsupported`.
+#[derive(Debug)]
+pub struct UnsupportedFeature(Feature);
+
+impl UnsupportedFeature {
+    /// Returns the missing feature.
+    pub fn feature(&self) -> Feature {
+        self.0
+    }
+}
+
+impl fmt::Display for UnsupportedFeature {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} requires {} or later", self.0, self.0.minimum_version())
+    }
+}
+
+impl error::Error for UnsupportedFeature {
+    fn description(&self) -> &str {
+        "feature not supported by this version of Windows"
+    }
+}
+
+/// Builds the error for a raw error code.
+///
+/// All errors carrying a code are built here so that they are classified
+/// the same way by `Error::kind`.
+pub fn from_code(code: i32) -> io::Error {
+    io::Error::from(Error(io::Error::from_raw_os_error(code)))
+}
+
+/// Returns the raw error code of an error returned by this crate, if there
+/// is one.
+pub fn code(err: &io::Error) -> Option<i32> {
+    match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(e) => e.code(),
+        None => err.raw_os_error(),
+    }
+}
+
+/// Builds the `InvalidData` error for a string property holding `bytes`.
+pub fn invalid_string(bytes: Vec<u8>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, InvalidString(bytes))
+}
+
+/// Builds the error for a feature missing from the running version of
+/// Windows.
+pub fn unsupported(feature: Feature) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, UnsupportedFeature(feature))
+}
+
+/// Builds the error for the calling thread's last error code.
+pub fn last_error() -> io::Error {
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(code) => from_code(code),
+        None => err,
+    }
+}
+
+fn classify(code: i32) -> ErrorKind {
+    const ERROR_FILE_NOT_FOUND: i32 = ffi::ERROR_FILE_NOT_FOUND as i32;
+    const ERROR_NOT_FOUND: i32 = ffi::ERROR_NOT_FOUND as i32;
+    const ERROR_ALREADY_EXISTS: i32 = ffi::ERROR_ALREADY_EXISTS as i32;
+    const ERROR_FILE_EXISTS: i32 = ffi::ERROR_FILE_EXISTS as i32;
+    const ERROR_ACCESS_DENIED: i32 = ffi::ERROR_ACCESS_DENIED as i32;
+    const ERROR_INVALID_DATA: i32 = ffi::ERROR_INVALID_DATA as i32;
+    const ERROR_NOT_SUPPORTED: i32 = ffi::ERROR_NOT_SUPPORTED as i32;
+    const ERROR_INVALID_PARAMETER: i32 = ffi::ERROR_INVALID_PARAMETER as i32;
+
+    match code {
+        ffi::CRYPT_E_NOT_FOUND |
+        ffi::NTE_NOT_FOUND |
+        ffi::NTE_BAD_KEYSET |
+        ffi::NTE_NO_KEY |
+        ffi::STATUS_NOT_FOUND |
+        ERROR_FILE_NOT_FOUND |
+        ERROR_NOT_FOUND => ErrorKind::NotFound,
+
+        ffi::CRYPT_E_EXISTS |
+        ffi::NTE_EXISTS |
+        ERROR_ALREADY_EXISTS |
+        ERROR_FILE_EXISTS => ErrorKind::Exists,
+
+        ffi::E_ACCESSDENIED |
+        ffi::NTE_PERM |
+        ffi::SEC_E_LOGON_DENIED |
+        ERROR_ACCESS_DENIED => ErrorKind::AccessDenied,
+
+        ffi::CERT_E_UNTRUSTEDROOT |
+        ffi::CERT_E_CHAINING |
+        ffi::CRYPT_E_NO_TRUSTED_SIGNER |
+        ffi::SEC_E_UNTRUSTED_ROOT => ErrorKind::UntrustedRoot,
+
+        ffi::CERT_E_CN_NO_MATCH |
+        ffi::SEC_E_WRONG_PRINCIPAL => ErrorKind::WrongPrincipal,
+
+        ffi::CERT_E_EXPIRED |
+        ffi::SEC_E_CERT_EXPIRED => ErrorKind::Expired,
+
+        ffi::CERT_E_REVOKED |
+        ffi::CRYPT_E_REVOKED => ErrorKind::Revoked,
+
+        ffi::SEC_E_INCOMPLETE_MESSAGE => ErrorKind::IncompleteMessage,
+
+        ffi::NTE_BAD_DATA |
+        ffi::NTE_BAD_SIGNATURE |
+        ffi::CRYPT_E_ASN1_BADTAG |
+        ffi::CRYPT_E_ASN1_EOD |
+        ffi::CRYPT_E_ASN1_CORRUPT |
+        ffi::CRYPT_E_BAD_ENCODE |
+        ffi::SEC_E_DECRYPT_FAILURE |
+        ffi::SEC_E_MESSAGE_ALTERED |
+        ffi::SEC_E_ILLEGAL_MESSAGE |
+        ffi::SEC_E_INVALID_TOKEN |
+        ERROR_INVALID_DATA => ErrorKind::BadData,
+
+        ffi::NTE_NOT_SUPPORTED |
+        ffi::NTE_BAD_ALGID |
+        ffi::E_NOTIMPL |
+        ffi::SEC_E_UNSUPPORTED_FUNCTION |
+        ffi::SEC_E_ALGORITHM_MISMATCH |
+        ffi::STATUS_NOT_SUPPORTED |
+        ERROR_NOT_SUPPORTED => ErrorKind::Unsupported,
+
+        ffi::E_INVALIDARG |
+        ffi::NTE_INVALID_PARAMETER |
+        ffi::STATUS_INVALID_PARAMETER |
+        ERROR_INVALID_PARAMETER => ErrorKind::InvalidParameter,
+
+        _ => ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use ffi;
+
+    use cert_context::{CertContext, HashAlgorithm};
+    use cert_store::{CertAdd, Memory};
+    use crypt_key::CipherAlgorithm;
+    use crypt_prov::{AcquireOptions, ProviderType};
+    use ctl_context::CtlContext;
+    use hash::Hasher;
+    use schannel_cred::{Algorithm, Direction, SchannelCred};
+    use super::*;
+
+    fn kind<T>(res: io::Result<T>) -> ErrorKind {
+        match res {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => Error::from(e).kind(),
+        }
+    }
+
+    #[test]
+    fn malformed_certificate() {
+        assert_eq!(kind(CertContext::new(b"garbage")), ErrorKind::BadData);
+    }
+
+    #[test]
+    fn malformed_ctl() {
+        assert_eq!(kind(CtlContext::new(b"garbage")), ErrorKind::BadData);
+    }
+
+    #[test]
+    fn missing_container() {
+        let res = AcquireOptions::new()
+            .container("schannel-rs-error-missing")
+            .acquire(ProviderType::rsa_full());
+        assert_eq!(kind(res), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn existing_container() {
+        let name = "schannel-rs-error-exists";
+        let _ = AcquireOptions::new().container(name).delete(ProviderType::rsa_full());
+        AcquireOptions::new()
+            .container(name)
+            .new_keyset(true)
+            .acquire(ProviderType::rsa_full())
+            .unwrap();
+        let res = AcquireOptions::new()
+            .container(name)
+            .new_keyset(true)
+            .acquire(ProviderType::rsa_full());
+        AcquireOptions::new().container(name).delete(ProviderType::rsa_full()).unwrap();
+        assert_eq!(kind(res), ErrorKind::Exists);
+    }
+
+    #[test]
+    fn missing_property() {
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+        assert_eq!(kind(cert.friendly_name()), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn missing_algorithm() {
+        let res = Hasher::new(HashAlgorithm::from_cng_name("NOT-A-HASH", 1).unwrap());
+        assert_eq!(kind(res), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn unsupported_hash() {
+        let prov = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        let res = prov.hash(HashAlgorithm::from_cng_name("SHA3-256", 32).unwrap());
+        assert_eq!(kind(res), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn corrupt_ciphertext() {
+        let prov = AcquireOptions::new()
+            .verify_context(true)
+            .acquire(ProviderType::rsa_aes())
+            .unwrap();
+        let mut hash = prov.hash(HashAlgorithm::sha256()).unwrap();
+        hash.update(b"not the right password").unwrap();
+        let key = prov.derive_key(CipherAlgorithm::aes_256(), &hash, 0).unwrap();
+        let res = key.decrypt(include_bytes!("../test/legacy-aes.bin"));
+        assert_eq!(kind(res), ErrorKind::BadData);
+    }
+
+    #[test]
+    fn existing_certificate() {
+        let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+        let mut store = Memory::new().unwrap().into_store();
+        store.add_cert(&cert, CertAdd::New).unwrap();
+        assert_eq!(kind(store.add_cert(&cert, CertAdd::New)), ErrorKind::Exists);
+    }
+
+    #[test]
+    fn unsupported_algorithm() {
+        let res = SchannelCred::builder()
+            .supported_algorithms(&[Algorithm::Rc2, Algorithm::Ecdsa])
+            .acquire(Direction::Outbound);
+        assert_eq!(kind(res), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn classify_codes() {
+        let cases = [(ffi::CERT_E_UNTRUSTEDROOT, ErrorKind::UntrustedRoot),
+                     (ffi::CERT_E_CN_NO_MATCH, ErrorKind::WrongPrincipal),
+                     (ffi::CERT_E_EXPIRED, ErrorKind::Expired),
+                     (ffi::CERT_E_REVOKED, ErrorKind::Revoked),
+                     (ffi::SEC_E_INCOMPLETE_MESSAGE, ErrorKind::IncompleteMessage),
+                     (ffi::NTE_PERM, ErrorKind::AccessDenied),
+                     (ffi::E_INVALIDARG, ErrorKind::InvalidParameter),
+                     (ffi::SEC_E_INTERNAL_ERROR, ErrorKind::Other)];
+        for &(code, expected) in &cases {
+            let err = Error::from(from_code(code));
+            assert_eq!(err.kind(), expected);
+            assert_eq!(err.code(), Some(code));
+            let err = io::Error::from(err);
+            assert_eq!(::error::code(&err), Some(code));
+            assert_eq!(Error::from(err).code(), Some(code));
+        }
+    }
+
+    #[test]
+    fn downcast() {
+        let err = CertContext::new(b"garbage").err().unwrap();
+        let code = err.get_ref().unwrap().downcast_ref::<Error>().unwrap().code();
+        assert!(code.is_some());
+        assert_eq!(::error::code(&err), code);
+
+        let err = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::BadData);
+        assert_eq!(err.code(), code);
+        assert_eq!(err.get_ref().raw_os_error(), code);
+    }
+
+    #[test]
+    fn custom() {
+        let err = io::Error::new(io::ErrorKind::InvalidInput, "bad input");
+        assert_eq!(Error::from(err).kind(), ErrorKind::InvalidParameter);
+        let err = io::Error::new(io::ErrorKind::Other, "other");
+        assert_eq!(Error::from(err).kind(), ErrorKind::Other);
+    }
+}
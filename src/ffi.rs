@@ -0,0 +1,554 @@
+//! Bindings to the Windows APIs used by this crate.
+//!
+//! The rest of the crate refers to Windows items only through this module so
+//! that it does not care which bindings provide them. By default they come
+//! from `winapi` 0.2 and its `*-sys` companions, with anything those crates
+//! lack declared here; with the `windows-sys` feature they come from the
+//! `windows-sys` crate instead.
+#![allow(non_camel_case_types, non_snake_case, dead_code)]
+
+use std::mem;
+
+pub use self::backend::*;
+
+#[cfg(not(feature = "windows-sys"))]
+mod backend {
+    pub use advapi32::*;
+    pub use crypt32::*;
+    pub use kernel32::*;
+    pub use secur32::*;
+    pub use winapi::*;
+
+    // FIXME https://github.com/retep998/winapi-rs/pull/319
+    extern "system" {
+        pub fn NCryptSignHash(hKey: NCRYPT_KEY_HANDLE,
+                              pPaddingInfo: *mut c_void,
+                              pbHashValue: PBYTE,
+                              cbHashValue: DWORD,
+                              pbSignature: PBYTE,
+                              cbSignature: DWORD,
+                              pcbResult: *mut DWORD,
+                              dwFlags: DWORD)
+                              -> SECURITY_STATUS;
+        pub fn BCryptVerifySignature(hKey: BCRYPT_KEY_HANDLE,
+                                     pPaddingInfo: *mut c_void,
+                                     pbHash: PUCHAR,
+                                     cbHash: ULONG,
+                                     pbSignature: PUCHAR,
+                                     cbSignature: ULONG,
+                                     dwFlags: ULONG)
+                                     -> NTSTATUS;
+        pub fn NCryptDecrypt(hKey: NCRYPT_KEY_HANDLE,
+                             pbInput: PBYTE,
+                             cbInput: DWORD,
+                             pPaddingInfo: *mut c_void,
+                             pbOutput: PBYTE,
+                             cbOutput: DWORD,
+                             pcbResult: *mut DWORD,
+                             dwFlags: DWORD)
+                             -> SECURITY_STATUS;
+        pub fn BCryptEncrypt(hKey: BCRYPT_KEY_HANDLE,
+                             pbInput: PUCHAR,
+                             cbInput: ULONG,
+                             pPaddingInfo: *mut c_void,
+                             pbIV: PUCHAR,
+                             cbIV: ULONG,
+                             pbOutput: PUCHAR,
+                             cbOutput: ULONG,
+                             pcbResult: *mut ULONG,
+                             dwFlags: ULONG)
+                             -> NTSTATUS;
+        pub fn BCryptDestroyKey(hKey: BCRYPT_KEY_HANDLE) -> NTSTATUS;
+        pub fn BCryptGetProperty(hObject: BCRYPT_HANDLE,
+                                 pszProperty: LPCWSTR,
+                                 pbOutput: PUCHAR,
+                                 cbOutput: ULONG,
+                                 pcbResult: *mut ULONG,
+                                 dwFlags: ULONG)
+                                 -> NTSTATUS;
+        pub fn BCryptOpenAlgorithmProvider(phAlgorithm: *mut BCRYPT_ALG_HANDLE,
+                                           pszAlgId: LPCWSTR,
+                                           pszImplementation: LPCWSTR,
+                                           dwFlags: ULONG)
+                                           -> NTSTATUS;
+        pub fn BCryptImportKeyPair(hAlgorithm: BCRYPT_ALG_HANDLE,
+                                   hImportKey: BCRYPT_KEY_HANDLE,
+                                   pszBlobType: LPCWSTR,
+                                   phKey: *mut BCRYPT_KEY_HANDLE,
+                                   pbInput: PUCHAR,
+                                   cbInput: ULONG,
+                                   dwFlags: ULONG)
+                                   -> NTSTATUS;
+        pub fn BCryptCloseAlgorithmProvider(hAlgorithm: BCRYPT_ALG_HANDLE,
+                                            dwFlags: ULONG)
+                                            -> NTSTATUS;
+        pub fn BCryptCreateHash(hAlgorithm: BCRYPT_ALG_HANDLE,
+                                phHash: *mut BCRYPT_HASH_HANDLE,
+                                pbHashObject: PUCHAR,
+                                cbHashObject: ULONG,
+                                pbSecret: PUCHAR,
+                                cbSecret: ULONG,
+                                dwFlags: ULONG)
+                                -> NTSTATUS;
+        pub fn BCryptHashData(hHash: BCRYPT_HASH_HANDLE,
+                              pbInput: PUCHAR,
+                              cbInput: ULONG,
+                              dwFlags: ULONG)
+                              -> NTSTATUS;
+        pub fn BCryptFinishHash(hHash: BCRYPT_HASH_HANDLE,
+                                pbOutput: PUCHAR,
+                                cbOutput: ULONG,
+                                dwFlags: ULONG)
+                                -> NTSTATUS;
+        pub fn BCryptDestroyHash(hHash: BCRYPT_HASH_HANDLE) -> NTSTATUS;
+        pub fn NCryptFreeObject(handle: NCRYPT_HANDLE) -> SECURITY_STATUS;
+        pub fn NCryptOpenStorageProvider(phProvider: *mut NCRYPT_PROV_HANDLE,
+                                         pszProviderName: LPCWSTR,
+                                         dwFlags: DWORD)
+                                         -> SECURITY_STATUS;
+        pub fn NCryptOpenKey(hProvider: NCRYPT_PROV_HANDLE,
+                             phKey: *mut NCRYPT_KEY_HANDLE,
+                             pszKeyName: LPCWSTR,
+                             dwLegacyKeySpec: DWORD,
+                             dwFlags: DWORD)
+                             -> SECURITY_STATUS;
+        pub fn NCryptCreatePersistedKey(hProvider: NCRYPT_PROV_HANDLE,
+                                        phKey: *mut NCRYPT_KEY_HANDLE,
+                                        pszAlgId: LPCWSTR,
+                                        pszKeyName: LPCWSTR,
+                                        dwLegacyKeySpec: DWORD,
+                                        dwFlags: DWORD)
+                                        -> SECURITY_STATUS;
+        pub fn NCryptFinalizeKey(hKey: NCRYPT_KEY_HANDLE,
+                                 dwFlags: DWORD)
+                                 -> SECURITY_STATUS;
+        pub fn NCryptExportKey(hKey: NCRYPT_KEY_HANDLE,
+                               hExportKey: NCRYPT_KEY_HANDLE,
+                               pszBlobType: LPCWSTR,
+                               pParameterList: *mut BCryptBufferDesc,
+                               pbOutput: PBYTE,
+                               cbOutput: DWORD,
+                               pcbResult: *mut DWORD,
+                               dwFlags: DWORD)
+                               -> SECURITY_STATUS;
+        pub fn NCryptImportKey(hProvider: NCRYPT_PROV_HANDLE,
+                               hImportKey: NCRYPT_KEY_HANDLE,
+                               pszBlobType: LPCWSTR,
+                               pParameterList: *mut BCryptBufferDesc,
+                               phKey: *mut NCRYPT_KEY_HANDLE,
+                               pbData: PBYTE,
+                               cbData: DWORD,
+                               dwFlags: DWORD)
+                               -> SECURITY_STATUS;
+        pub fn NCryptDeleteKey(hKey: NCRYPT_KEY_HANDLE,
+                               dwFlags: DWORD)
+                               -> SECURITY_STATUS;
+        pub fn NCryptSetProperty(hObject: NCRYPT_HANDLE,
+                                 pszProperty: LPCWSTR,
+                                 pbInput: PBYTE,
+                                 cbInput: DWORD,
+                                 dwFlags: DWORD)
+                                 -> SECURITY_STATUS;
+        pub fn NCryptEnumAlgorithms(hProvider: NCRYPT_PROV_HANDLE,
+                                    dwAlgOperations: DWORD,
+                                    pdwAlgCount: *mut DWORD,
+                                    ppAlgList: *mut *mut NCryptAlgorithmName,
+                                    dwFlags: DWORD)
+                                    -> SECURITY_STATUS;
+        pub fn NCryptEnumKeys(hProvider: NCRYPT_PROV_HANDLE,
+                              pszScope: LPCWSTR,
+                              ppKeyName: *mut *mut NCryptKeyName,
+                              ppEnumState: *mut PVOID,
+                              dwFlags: DWORD)
+                              -> SECURITY_STATUS;
+        pub fn NCryptEnumStorageProviders(pdwProviderCount: *mut DWORD,
+                                          ppProviderList: *mut *mut NCryptProviderName,
+                                          dwFlags: DWORD)
+                                          -> SECURITY_STATUS;
+        pub fn NCryptFreeBuffer(pvInput: PVOID) -> SECURITY_STATUS;
+        pub fn NCryptGetProperty(hObject: NCRYPT_HANDLE,
+                                 pszProperty: LPCWSTR,
+                                 pbOutput: PBYTE,
+                                 cbOutput: DWORD,
+                                 pcbResult: *mut DWORD,
+                                 dwFlags: DWORD)
+                                 -> SECURITY_STATUS;
+        pub fn ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                StringSecurityDescriptor: LPCWSTR,
+                StringSDRevision: DWORD,
+                SecurityDescriptor: *mut PSECURITY_DESCRIPTOR,
+                SecurityDescriptorSize: *mut ULONG)
+                -> BOOL;
+    }
+
+    // FIXME https://github.com/retep998/winapi-rs/pull/318
+    #[repr(C)]
+    pub struct NCryptAlgorithmName {
+        pub pszName: LPWSTR,
+        pub dwClass: DWORD,
+        pub dwAlgOperations: DWORD,
+        pub dwFlags: DWORD,
+    }
+
+    #[repr(C)]
+    pub struct NCryptKeyName {
+        pub pszName: LPWSTR,
+        pub pszAlgid: LPWSTR,
+        pub dwLegacyKeySpec: DWORD,
+        pub dwFlags: DWORD,
+    }
+
+    #[repr(C)]
+    pub struct NCryptProviderName {
+        pub pszName: LPWSTR,
+        pub pszComment: LPWSTR,
+    }
+
+    #[repr(C)]
+    pub struct CERT_ECC_SIGNATURE {
+        pub r: CRYPT_UINT_BLOB,
+        pub s: CRYPT_UINT_BLOB,
+    }
+
+    // the union is represented by its largest member
+    #[repr(C)]
+    pub struct CERT_ALT_NAME_ENTRY {
+        pub dwAltNameChoice: DWORD,
+        pub Value: CRYPT_DATA_BLOB,
+    }
+
+    #[repr(C)]
+    pub struct CERT_ALT_NAME_INFO {
+        pub cAltEntry: DWORD,
+        pub rgAltEntry: *mut CERT_ALT_NAME_ENTRY,
+    }
+
+    pub const NCRYPT_SILENT_FLAG: DWORD = 0x40;
+    pub const CRYPT_ACQUIRE_COMPARE_KEY_FLAG: DWORD = 0x4;
+    pub const CRYPT_ACQUIRE_SILENT_FLAG: DWORD = 0x40;
+    pub const CRYPT_ACQUIRE_ALLOW_NCRYPT_KEY_FLAG: DWORD = 0x10000;
+    pub const CRYPT_STRING_BASE64HEADER: DWORD = 0x0;
+    pub const SDDL_REVISION_1: DWORD = 1;
+    pub const NCRYPT_OVERWRITE_KEY_FLAG: DWORD = 0x80;
+    pub const NCRYPT_ALLOW_EXPORT_FLAG: DWORD = 0x1;
+    pub const NCRYPT_ALLOW_PLAINTEXT_EXPORT_FLAG: DWORD = 0x2;
+    pub const NCRYPT_ALLOW_ARCHIVING_FLAG: DWORD = 0x4;
+    pub const NCRYPT_ALLOW_PLAINTEXT_ARCHIVING_FLAG: DWORD = 0x8;
+    pub const NCRYPT_ALLOW_DECRYPT_FLAG: DWORD = 0x1;
+    pub const NCRYPT_ALLOW_SIGNING_FLAG: DWORD = 0x2;
+    pub const NCRYPT_ALLOW_KEY_AGREEMENT_FLAG: DWORD = 0x4;
+    pub const NCRYPT_ALLOW_ALL_USAGES: DWORD = 0xffffff;
+    pub const NCRYPT_IMPL_HARDWARE_FLAG: DWORD = 0x1;
+    pub const NCRYPT_PERSIST_FLAG: DWORD = 0x80000000;
+    pub const NCRYPT_MACHINE_KEY_FLAG: DWORD = 0x20;
+    pub const NCRYPT_DO_NOT_FINALIZE_FLAG: DWORD = 0x400;
+    pub const NCRYPTBUFFER_PKCS_KEY_NAME: ULONG = 45;
+    pub const PKCS12_INCLUDE_EXTENDED_PROPERTIES: DWORD = 0x10;
+    pub const PKCS12_NO_PERSIST_KEY: DWORD = 0x8000;
+    pub const PKCS12_ALWAYS_CNG_KSP: DWORD = 0x200;
+    pub const EXPORT_PRIVATE_KEYS: DWORD = 0x4;
+    pub const REPORT_NO_PRIVATE_KEY: DWORD = 0x1;
+    pub const REPORT_NOT_ABLE_TO_EXPORT_PRIVATE_KEY: DWORD = 0x2;
+    pub const CRYPT_USER_KEYSET: DWORD = 0x1000;
+    pub const CERT_CLOSE_STORE_CHECK_FLAG: DWORD = 0x2;
+    pub const CRYPT_HASH_ALG_OID_GROUP_ID: DWORD = 1;
+    pub const CRYPT_OID_INFO_CNG_ALGID_KEY: DWORD = 5;
+    pub const CMSG_SIGNER_COUNT_PARAM: DWORD = 5;
+    pub const CTL_CERT_SUBJECT_TYPE: DWORD = 2;
+    pub const CERT_VERIFY_NO_TIME_CHECK_FLAG: DWORD = 0x4;
+    pub const HCCE_CURRENT_USER: HCERTCHAINENGINE = 0 as HCERTCHAINENGINE;
+    pub const CERT_TRUST_IS_EXPLICIT_DISTRUST: DWORD = 0x04000000;
+    pub const CERT_FIND_SHA256_HASH: DWORD = 0x200000;
+    pub const CALG_ECDH_EPHEM: ALG_ID = 0xae06;
+    pub const SECURITY_FLAG_IGNORE_CERT_CN_INVALID: DWORD = 0x00001000;
+    pub const CERT_OCSP_RESPONSE_PROP_ID: DWORD = 70;
+    pub const CERT_CREATE_SELFSIGN_NO_KEY_INFO: DWORD = 2;
+    pub const CERT_FIND_EXT_ONLY_ENHKEY_USAGE_FLAG: DWORD = 0x2;
+    pub const CERT_FIND_PROP_ONLY_ENHKEY_USAGE_FLAG: DWORD = 0x4;
+
+    // fields which the two bindings name or wrap differently
+    pub unsafe fn oid_info_oid(info: *const CRYPT_OID_INFO) -> LPCSTR {
+        (*info).oszOID
+    }
+
+    pub fn signer_crypt_prov(info: &mut CMSG_SIGNER_ENCODE_INFO) -> &mut HCRYPTPROV {
+        &mut info.hCryptProv
+    }
+
+    pub unsafe fn proc_address(module: HMODULE, name: LPCSTR) -> *const c_void {
+        GetProcAddress(module, name)
+    }
+
+    pub unsafe fn signer_ncrypt_key(info: &mut CMSG_SIGNER_ENCODE_INFO)
+                                    -> &mut NCRYPT_KEY_HANDLE {
+        info.hNCryptKey_mut()
+    }
+
+    pub fn alpn_negotiation_ext() -> u32 {
+        SecApplicationProtocolNegotiationExt_ALPN.0
+    }
+
+    pub unsafe fn alt_name_string(entry: &CERT_ALT_NAME_ENTRY) -> LPWSTR {
+        *(&entry.Value as *const CRYPT_DATA_BLOB as *const LPWSTR)
+    }
+
+    pub fn alt_name_blob(entry: &CERT_ALT_NAME_ENTRY) -> &CRYPT_DATA_BLOB {
+        &entry.Value
+    }
+
+    pub unsafe fn alt_name_from_string(choice: DWORD, s: LPWSTR) -> CERT_ALT_NAME_ENTRY {
+        let mut entry: CERT_ALT_NAME_ENTRY = ::std::mem::zeroed();
+        entry.dwAltNameChoice = choice;
+        *(&mut entry.Value as *mut CRYPT_DATA_BLOB as *mut LPWSTR) = s;
+        entry
+    }
+
+    pub fn alt_name_from_blob(choice: DWORD, blob: CRYPT_DATA_BLOB) -> CERT_ALT_NAME_ENTRY {
+        CERT_ALT_NAME_ENTRY {
+            dwAltNameChoice: choice,
+            Value: blob,
+        }
+    }
+}
+
+#[cfg(feature = "windows-sys")]
+mod backend {
+    pub use std::os::raw::{c_ulong, c_void};
+    pub use windows_sys::core::*;
+    pub use windows_sys::Win32::Foundation::*;
+    pub use windows_sys::Win32::Security::*;
+    pub use windows_sys::Win32::Security::Authentication::Identity::*;
+    pub use windows_sys::Win32::Security::Authorization::*;
+    pub use windows_sys::Win32::Security::Credentials::*;
+    pub use windows_sys::Win32::Security::Cryptography::*;
+    pub use windows_sys::Win32::System::LibraryLoader::GetModuleHandleW;
+    pub use windows_sys::Win32::System::LibraryLoader::GetProcAddress;
+    pub use windows_sys::Win32::System::SystemInformation::*;
+    pub use windows_sys::Win32::System::Time::*;
+
+    // `windows-sys` names these by their underlying types
+    pub type BYTE = u8;
+    pub type DWORD = u32;
+    pub type ULONG = u32;
+    pub type WCHAR = u16;
+    pub type ULONG_PTR = usize;
+    pub type PVOID = *mut c_void;
+    pub type PBYTE = *mut u8;
+    pub type PUCHAR = *mut u8;
+    pub type LPCSTR = PCSTR;
+    pub type LPSTR = PSTR;
+    pub type LPCWSTR = PCWSTR;
+    pub type LPWSTR = PWSTR;
+    pub type SECURITY_STATUS = HRESULT;
+
+    pub type HCRYPTPROV = usize;
+    pub type HCRYPTKEY = usize;
+    pub type HCRYPTHASH = usize;
+    pub type CredHandle = SecHandle;
+    pub type CtxtHandle = SecHandle;
+
+    pub type CERT_BLOB = CRYPT_INTEGER_BLOB;
+    pub type CERT_NAME_BLOB = CRYPT_INTEGER_BLOB;
+    pub type CRYPT_ATTR_BLOB = CRYPT_INTEGER_BLOB;
+    pub type CRYPT_DATA_BLOB = CRYPT_INTEGER_BLOB;
+    pub type CRYPT_DER_BLOB = CRYPT_INTEGER_BLOB;
+    pub type CRYPT_HASH_BLOB = CRYPT_INTEGER_BLOB;
+    pub type CRYPT_OBJID_BLOB = CRYPT_INTEGER_BLOB;
+    pub type CRYPT_UINT_BLOB = CRYPT_INTEGER_BLOB;
+
+    pub type PCCERT_CONTEXT = *const CERT_CONTEXT;
+    pub type PCCTL_CONTEXT = *const CTL_CONTEXT;
+    pub type PCERT_CHAIN_CONTEXT = *mut CERT_CHAIN_CONTEXT;
+    pub type PCERT_PUBLIC_KEY_INFO = *mut CERT_PUBLIC_KEY_INFO;
+    pub type PCERT_SIMPLE_CHAIN = *mut CERT_SIMPLE_CHAIN;
+    pub type PCTL_ENTRY = *mut CTL_ENTRY;
+
+    // `winapi` exposes these as string slices rather than C strings
+    pub const UNISP_NAME: &'static str = "Microsoft Unified Security Protocol Provider";
+    pub const szOID_COMMON_NAME: &'static str = "2.5.4.3";
+    pub const szOID_KEY_USAGE: &'static str = "2.5.29.15";
+    pub const szOID_OIWSEC_sha1: &'static str = "1.3.14.3.2.26";
+    pub const szOID_PKIX_KP_SERVER_AUTH: &'static str = "1.3.6.1.5.5.7.3.1";
+    pub const szOID_RSA_MD5: &'static str = "1.2.840.113549.2.5";
+    pub const szOID_RSA_SHA256RSA: &'static str = "1.2.840.113549.1.1.11";
+    pub const szOID_SERVER_GATED_CRYPTO: &'static str = "1.3.6.1.4.1.311.10.3.3";
+    pub const szOID_SGC_NETSCAPE: &'static str = "2.16.840.1.113730.4.1";
+    pub const szOID_SUBJECT_ALT_NAME2: &'static str = "2.5.29.17";
+
+    pub const UNLEN: DWORD = 256;
+    // only bound by `windows-sys` for WinHTTP
+    pub const SECURITY_FLAG_IGNORE_CERT_CN_INVALID: DWORD = 0x00001000;
+    // `windows-sys` types these for the certificate enrollment APIs
+    pub const CERT_ALT_NAME_RFC822_NAME: DWORD = 2;
+    pub const CERT_ALT_NAME_DNS_NAME: DWORD = 3;
+    pub const CERT_ALT_NAME_URL: DWORD = 7;
+    pub const CERT_ALT_NAME_IP_ADDRESS: DWORD = 8;
+    pub const HCCE_CURRENT_USER: HCERTCHAINENGINE = 0;
+    pub const CERT_FIND_SHA256_HASH: CERT_FIND_FLAGS = 0x200000;
+
+    // `windows-sys` omits the fields behind CERT_CHAIN_PARA_HAS_EXTRA_FIELDS
+    #[repr(C)]
+    pub struct CERT_CHAIN_PARA {
+        pub cbSize: DWORD,
+        pub RequestedUsage: CERT_USAGE_MATCH,
+        pub RequestedIssuancePolicy: CERT_USAGE_MATCH,
+        pub dwUrlRetrievalTimeout: DWORD,
+        pub fCheckRevocationFreshnessTime: BOOL,
+        pub dwRevocationFreshnessTime: DWORD,
+        pub pftCacheResync: *mut FILETIME,
+        pub pStrongSignPara: *const CERT_STRONG_SIGN_PARA,
+        pub dwStrongSignFlags: DWORD,
+    }
+
+    // `windows-sys` wraps `cbSize` in a union with `cbStruct`
+    #[repr(C)]
+    pub struct SSL_EXTRA_CERT_CHAIN_POLICY_PARA {
+        pub cbSize: DWORD,
+        pub dwAuthType: DWORD,
+        pub fdwChecks: DWORD,
+        pub pwszServerName: *mut WCHAR,
+    }
+
+    // fields which the two bindings name or wrap differently
+    pub unsafe fn oid_info_oid(info: *const CRYPT_OID_INFO) -> LPCSTR {
+        (*info).pszOID
+    }
+
+    pub fn signer_crypt_prov(info: &mut CMSG_SIGNER_ENCODE_INFO) -> &mut HCRYPTPROV {
+        unsafe { &mut info.Anonymous.hCryptProv }
+    }
+
+    pub unsafe fn proc_address(module: HMODULE, name: LPCSTR) -> *const c_void {
+        GetProcAddress(module, name).map_or(::std::ptr::null(), |f| f as *const c_void)
+    }
+
+    pub unsafe fn signer_ncrypt_key(info: &mut CMSG_SIGNER_ENCODE_INFO)
+                                    -> &mut NCRYPT_KEY_HANDLE {
+        &mut info.Anonymous.hNCryptKey
+    }
+
+    pub fn alpn_negotiation_ext() -> u32 {
+        SecApplicationProtocolNegotiationExt_ALPN as u32
+    }
+
+    pub unsafe fn alt_name_string(entry: &CERT_ALT_NAME_ENTRY) -> LPWSTR {
+        entry.Anonymous.pwszDNSName
+    }
+
+    pub fn alt_name_blob(entry: &CERT_ALT_NAME_ENTRY) -> &CRYPT_DATA_BLOB {
+        unsafe { &entry.Anonymous.IPAddress }
+    }
+
+    pub unsafe fn alt_name_from_string(choice: DWORD, s: LPWSTR) -> CERT_ALT_NAME_ENTRY {
+        CERT_ALT_NAME_ENTRY {
+            dwAltNameChoice: choice,
+            Anonymous: CERT_ALT_NAME_ENTRY_0 { pwszDNSName: s },
+        }
+    }
+
+    pub fn alt_name_from_blob(choice: DWORD, blob: CRYPT_DATA_BLOB) -> CERT_ALT_NAME_ENTRY {
+        CERT_ALT_NAME_ENTRY {
+            dwAltNameChoice: choice,
+            Anonymous: CERT_ALT_NAME_ENTRY_0 { IPAddress: blob },
+        }
+    }
+}
+
+// Both backends must hand Windows identically laid out structures. The
+// transmutes below only compile if the sizes match.
+#[cfg(target_pointer_width = "64")]
+const X64: usize = 1;
+#[cfg(target_pointer_width = "32")]
+const X64: usize = 0;
+
+macro_rules! assert_size {
+    ($t:ident, $x64:expr, $x86:expr) => {
+        mem::transmute::<$t, [u8; $x64 * X64 + $x86 * (1 - X64)]>(mem::zeroed());
+    }
+}
+
+#[allow(dead_code)]
+unsafe fn assert_sizes() {
+    assert_size!(BCRYPT_OAEP_PADDING_INFO, 24, 12);
+    assert_size!(BCRYPT_PKCS1_PADDING_INFO, 8, 4);
+    assert_size!(BCRYPT_PSS_PADDING_INFO, 16, 8);
+    assert_size!(BCRYPT_RSAKEY_BLOB, 24, 24);
+    assert_size!(BCryptBuffer, 16, 12);
+    assert_size!(BCryptBufferDesc, 16, 12);
+    assert_size!(CERT_ALT_NAME_ENTRY, 24, 12);
+    assert_size!(CERT_ALT_NAME_INFO, 16, 8);
+    assert_size!(CERT_CHAIN_PARA, 96, 52);
+    assert_size!(CERT_CHAIN_POLICY_PARA, 16, 12);
+    assert_size!(CERT_CHAIN_POLICY_STATUS, 24, 20);
+    assert_size!(CERT_CONTEXT, 40, 20);
+    assert_size!(CERT_ECC_SIGNATURE, 32, 16);
+    assert_size!(CERT_PUBLIC_KEY_INFO, 48, 24);
+    assert_size!(CMSG_SIGNED_ENCODE_INFO, 48, 28);
+    assert_size!(CMSG_SIGNER_ENCODE_INFO, 96, 48);
+    assert_size!(CredHandle, 16, 8);
+    assert_size!(CRYPT_ALGORITHM_IDENTIFIER, 24, 12);
+    assert_size!(CRYPT_ATTRIBUTE, 24, 12);
+    assert_size!(CRYPT_INTEGER_BLOB, 16, 8);
+    assert_size!(CRYPT_KEY_PROV_INFO, 48, 28);
+    assert_size!(CRYPT_PRIVATE_KEY_INFO, 56, 28);
+    assert_size!(CTL_CONTEXT, 64, 32);
+    assert_size!(CTL_ENTRY, 32, 16);
+    assert_size!(CTL_INFO, 128, 72);
+    assert_size!(CTL_USAGE, 16, 8);
+    assert_size!(CTL_VERIFY_USAGE_PARA, 56, 28);
+    assert_size!(CTL_VERIFY_USAGE_STATUS, 48, 28);
+    assert_size!(CtxtHandle, 16, 8);
+    assert_size!(FILETIME, 8, 8);
+    assert_size!(NCryptAlgorithmName, 24, 16);
+    assert_size!(NCryptKeyName, 24, 16);
+    assert_size!(NCryptProviderName, 16, 8);
+    assert_size!(SCHANNEL_CRED, 80, 56);
+    assert_size!(SEC_CHANNEL_BINDINGS, 32, 32);
+    assert_size!(SecBuffer, 16, 12);
+    assert_size!(SecBufferDesc, 16, 12);
+    assert_size!(SecPkgContext_ApplicationProtocol, 264, 264);
+    assert_size!(SecPkgContext_Bindings, 16, 8);
+    assert_size!(SecPkgContext_CipherInfo, 680, 680);
+    assert_size!(SecPkgContext_ConnectionInfo, 28, 28);
+    assert_size!(SecPkgContext_SessionInfo, 40, 40);
+    assert_size!(SecPkgContext_StreamSizes, 20, 20);
+    assert_size!(SSL_EXTRA_CERT_CHAIN_POLICY_PARA, 24, 16);
+    assert_size!(SYSTEMTIME, 16, 16);
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+
+    use super::*;
+
+    macro_rules! assert_offset {
+        ($t:ident, $field:ident, $x64:expr, $x86:expr) => {{
+            let value: $t = mem::zeroed();
+            let offset = &value.$field as *const _ as usize - &value as *const _ as usize;
+            let expected = if cfg!(target_pointer_width = "64") { $x64 } else { $x86 };
+            assert_eq!(offset, expected, "{}::{}", stringify!($t), stringify!($field));
+        }}
+    }
+
+    #[test]
+    fn offsets() {
+        unsafe {
+            assert_offset!(CERT_CONTEXT, pCertInfo, 24, 12);
+            assert_offset!(CERT_CONTEXT, hCertStore, 32, 16);
+            assert_offset!(CRYPT_KEY_PROV_INFO, dwProvType, 16, 8);
+            assert_offset!(CRYPT_KEY_PROV_INFO, dwFlags, 20, 12);
+            assert_offset!(CRYPT_KEY_PROV_INFO, dwKeySpec, 40, 24);
+            assert_offset!(NCryptKeyName, dwLegacyKeySpec, 16, 8);
+            assert_offset!(SCHANNEL_CRED, paCred, 8, 8);
+            assert_offset!(SCHANNEL_CRED, palgSupportedAlgs, 48, 28);
+            assert_offset!(SCHANNEL_CRED, grbitEnabledProtocols, 56, 32);
+            assert_offset!(SCHANNEL_CRED, dwFlags, 72, 48);
+            assert_offset!(SEC_CHANNEL_BINDINGS, cbApplicationDataLength, 24, 24);
+            assert_offset!(SEC_CHANNEL_BINDINGS, dwApplicationDataOffset, 28, 28);
+            assert_offset!(SecBuffer, BufferType, 4, 4);
+            assert_offset!(SecBuffer, pvBuffer, 8, 8);
+            assert_offset!(SecPkgContext_ConnectionInfo, aiExch, 20, 20);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! CNG hashing.
+use std::io;
+use std::ptr;
+use ffi;
+
+use cert_context::HashAlgorithm;
+
+struct Algorithm(ffi::BCRYPT_ALG_HANDLE);
+
+impl Drop for Algorithm {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::BCryptCloseAlgorithmProvider(self.0, 0);
+        }
+    }
+}
+
+/// An incremental hash computation.
+pub struct Hasher {
+    hash: ffi::BCRYPT_HASH_HANDLE,
+    len: usize,
+    // the algorithm provider must outlive the hash object
+    _alg: Algorithm,
+}
+
+impl Drop for Hasher {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::BCryptDestroyHash(self.hash);
+        }
+    }
+}
+
+impl Hasher {
+    /// Creates a new hasher for the specified algorithm.
+    pub fn new(alg: HashAlgorithm) -> io::Result<Hasher> {
+        unsafe {
+            let len = alg.digest_len();
+            let name = alg.cng_name().encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+            let mut handle = ptr::null_mut();
+            try!(check(ffi::BCryptOpenAlgorithmProvider(&mut handle, name.as_ptr(), ptr::null(), 0)));
+            let alg_handle = Algorithm(handle);
+
+            let mut hash = ptr::null_mut();
+            try!(check(ffi::BCryptCreateHash(alg_handle.0,
+                                             &mut hash,
+                                             ptr::null_mut(),
+                                             0,
+                                             ptr::null_mut(),
+                                             0,
+                                             0)));
+            Ok(Hasher {
+                hash: hash,
+                len: len,
+                _alg: alg_handle,
+            })
+        }
+    }
+
+    /// Feeds more data into the hash.
+    pub fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(ffi::ULONG::max_value() as usize) {
+            unsafe {
+                try!(check(ffi::BCryptHashData(self.hash,
+                                               chunk.as_ptr() as *mut _,
+                                               try!(::dword_len(chunk.len())),
+                                               0)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Completes the hash, returning the digest.
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        unsafe {
+            let mut buf = vec![0; self.len];
+            try!(check(ffi::BCryptFinishHash(self.hash,
+                                             buf.as_mut_ptr(),
+                                             try!(::dword_len(buf.len())),
+                                             0)));
+            Ok(buf)
+        }
+    }
+}
+
+/// Hashes `data` in one shot.
+pub fn hash(alg: HashAlgorithm, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut hasher = try!(Hasher::new(alg));
+    try!(hasher.update(data));
+    hasher.finish()
+}
+
+fn check(status: ffi::NTSTATUS) -> io::Result<()> {
+    if status == ffi::STATUS_SUCCESS {
+        Ok(())
+    } else {
+        Err(::error::from_code(status))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cert_context::HashAlgorithm;
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn known_answers() {
+        let cases = [(HashAlgorithm::sha256(),
+                      "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+                     (HashAlgorithm::sha384(),
+                      "cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed\
+                       8086072ba1e7cc2358baeca134c825a7"),
+                     (HashAlgorithm::sha512(),
+                      "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                       2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f")];
+        for &(ref alg, expected) in &cases {
+            let mut hasher = Hasher::new(alg.clone()).unwrap();
+            hasher.update(b"a").unwrap();
+            hasher.update(b"bc").unwrap();
+            let digest = hasher.finish().unwrap();
+            assert_eq!(digest.len(), alg.digest_len());
+            assert_eq!(hex(&digest), expected);
+            assert_eq!(hash(alg.clone(), b"abc").unwrap(), digest);
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let digest = Hasher::new(HashAlgorithm::sha256()).unwrap().finish().unwrap();
+        assert_eq!(hex(&digest),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+}
@@ -0,0 +1,186 @@
+//! Verification of the names a server certificate was issued for.
+use ffi;
+#[allow(unused_imports)]
+use std::ascii::AsciiExt;
+use std::io;
+use std::net::IpAddr;
+use std::ptr;
+
+use Inner;
+use cert_context::{AltName, CertContext};
+
+lazy_static! {
+    static ref szOID_COMMON_NAME: Vec<u8> =
+        ffi::szOID_COMMON_NAME.bytes().chain(Some(0)).collect();
+}
+
+/// The name a server is expected to present a certificate for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reference {
+    /// A DNS name, lowercased and without a trailing dot.
+    Dns(String),
+    /// An IP address literal.
+    Ip(IpAddr),
+}
+
+impl Reference {
+    /// Parses the domain a client connects to.
+    ///
+    /// IPv6 literals may be enclosed in brackets as they are in URLs.
+    pub fn parse(name: &str) -> Reference {
+        let bare = if name.starts_with('[') && name.ends_with(']') {
+            &name[1..name.len() - 1]
+        } else {
+            name
+        };
+        if let Ok(ip) = bare.parse() {
+            return Reference::Ip(ip);
+        }
+        Reference::Dns(trim_dot(name).to_ascii_lowercase())
+    }
+}
+
+/// Determines if `name` is an IP address rather than a DNS name.
+pub fn is_ip_address(name: &str) -> bool {
+    match Reference::parse(name) {
+        Reference::Ip(_) => true,
+        Reference::Dns(_) => false,
+    }
+}
+
+/// Determines if `cert` was issued for `reference`.
+///
+/// DNS names are matched against the certificate's DNS subject alternative
+/// names, or its common name if it has none, and IP addresses only against
+/// its IP address subject alternative names.
+pub fn verify(cert: &CertContext, reference: &Reference) -> io::Result<bool> {
+    let alt_names = try!(cert.subject_alt_names());
+    let dns_names = alt_names.iter()
+        .filter_map(|n| match *n {
+            AltName::Dns(ref name) => Some(name),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    match *reference {
+        Reference::Ip(ref ip) => Ok(alt_names.iter().any(|n| *n == AltName::Ip(*ip))),
+        Reference::Dns(ref host) => {
+            if !dns_names.is_empty() {
+                return Ok(dns_names.iter().any(|n| dns_name_matches(n, host)));
+            }
+            match try!(common_name(cert)) {
+                Some(ref cn) => Ok(dns_name_matches(cn, host)),
+                None => Ok(false),
+            }
+        }
+    }
+}
+
+/// Determines if a DNS name from a certificate matches `host`, which must
+/// already be lowercased and without a trailing dot.
+///
+/// Following RFC 6125, a wildcard is only recognized as the whole left-most
+/// label of `pattern`, followed by at least two more labels, and it stands
+/// for exactly one non-empty label of `host`.
+pub fn dns_name_matches(pattern: &str, host: &str) -> bool {
+    let pattern = trim_dot(pattern).to_ascii_lowercase();
+    if pattern.is_empty() || host.is_empty() {
+        return false;
+    }
+
+    if !pattern.starts_with("*.") {
+        return !pattern.contains('*') && pattern == host;
+    }
+
+    let suffix = &pattern[2..];
+    if suffix.contains('*') || suffix.split('.').count() < 2 ||
+       suffix.split('.').any(|label| label.is_empty()) {
+        return false;
+    }
+    match host.find('.') {
+        Some(i) => i > 0 && &host[i + 1..] == suffix,
+        None => false,
+    }
+}
+
+fn trim_dot(name: &str) -> &str {
+    if name.ends_with('.') {
+        &name[..name.len() - 1]
+    } else {
+        name
+    }
+}
+
+/// Returns the common name from the subject of `cert`, if it has one.
+fn common_name(cert: &CertContext) -> io::Result<Option<String>> {
+    unsafe {
+        let oid = szOID_COMMON_NAME.as_ptr() as *mut ffi::c_void;
+        let len = ffi::CertGetNameStringW(cert.as_inner(),
+                                          ffi::CERT_NAME_ATTR_TYPE,
+                                          0,
+                                          oid,
+                                          ptr::null_mut(),
+                                          0);
+        // the length includes the trailing nul, so 1 means there is no name
+        if len <= 1 {
+            return Ok(None);
+        }
+
+        let mut buf = vec![0u16; len as usize];
+        let len = ffi::CertGetNameStringW(cert.as_inner(),
+                                          ffi::CERT_NAME_ATTR_TYPE,
+                                          0,
+                                          oid,
+                                          buf.as_mut_ptr(),
+                                          len);
+        buf.truncate(len as usize - 1);
+        match String::from_utf16(&buf) {
+            Ok(s) => Ok(Some(s)),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                         "common name is not valid UTF-16")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(dns_name_matches("*.example.com", "a.example.com"));
+        assert!(dns_name_matches("*.Example.COM.", "a.example.com"));
+        assert!(!dns_name_matches("*.example.com", "a.b.example.com"));
+        assert!(!dns_name_matches("*.example.com", "example.com"));
+        assert!(!dns_name_matches("*.example.com", ".example.com"));
+        assert!(!dns_name_matches("*.com", "example.com"));
+        assert!(!dns_name_matches("a*.example.com", "ab.example.com"));
+        assert!(!dns_name_matches("*a.example.com", "ba.example.com"));
+        assert!(!dns_name_matches("a.*.example.com", "a.b.example.com"));
+        assert!(!dns_name_matches("*.*.example.com", "a.b.example.com"));
+        assert!(!dns_name_matches("*", "localhost"));
+    }
+
+    #[test]
+    fn exact_names() {
+        assert!(dns_name_matches("example.com", "example.com"));
+        assert!(dns_name_matches("EXAMPLE.com", "example.com"));
+        assert!(dns_name_matches("example.com.", "example.com"));
+        assert!(!dns_name_matches("example.com", "www.example.com"));
+        assert!(!dns_name_matches("", "example.com"));
+    }
+
+    #[test]
+    fn parse_references() {
+        assert_eq!(Reference::parse("Example.COM."),
+                   Reference::Dns("example.com".to_owned()));
+        assert_eq!(Reference::parse("127.0.0.1"),
+                   Reference::Ip("127.0.0.1".parse::<IpAddr>().unwrap()));
+        assert_eq!(Reference::parse("[::1]"),
+                   Reference::Ip("::1".parse::<IpAddr>().unwrap()));
+        assert!(is_ip_address("::1"));
+        assert!(!is_ip_address("localhost"));
+        assert!(!is_ip_address("1.2.3.4.example.com"));
+    }
+}
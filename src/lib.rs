@@ -3,16 +3,29 @@
 #![warn(missing_docs)]
 #![allow(non_upper_case_globals)]
 
+#[cfg(not(feature = "windows-sys"))]
 extern crate advapi32;
+#[cfg(not(feature = "windows-sys"))]
 extern crate crypt32;
+#[cfg(not(feature = "windows-sys"))]
 extern crate kernel32;
+#[cfg(not(feature = "windows-sys"))]
 extern crate secur32;
+#[cfg(not(feature = "windows-sys"))]
 extern crate winapi;
+#[cfg(feature = "windows-sys")]
+extern crate windows_sys;
 
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "log")]
+extern crate log;
 
+use std::cmp;
+use std::io;
 use std::ptr;
+use std::slice;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 macro_rules! inner {
     ($t:path, $raw:ty) => {
@@ -32,33 +45,73 @@ macro_rules! inner {
     }
 }
 
+// Diagnostics are only emitted with the `log` feature. Without it the
+// arguments are still type checked, but never evaluated.
+#[cfg(feature = "log")]
+macro_rules! debug {
+    ($($arg:tt)*) => (::log::debug!($($arg)*))
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! debug {
+    ($($arg:tt)*) => ({ if false { let _ = format_args!($($arg)*); } })
+}
+
+#[cfg(feature = "log")]
+macro_rules! trace {
+    ($($arg:tt)*) => (::log::trace!($($arg)*))
+}
+
+#[cfg(not(feature = "log"))]
+#[allow(unused_macros)]
+macro_rules! trace {
+    ($($arg:tt)*) => ({ if false { let _ = format_args!($($arg)*); } })
+}
+
 pub mod cert_chain;
 pub mod cert_context;
 pub mod cert_store;
+pub mod crypt_hash;
 pub mod crypt_key;
 pub mod crypt_prov;
-/* pub */ mod ctl_context;
+pub mod ctl_context;
+pub mod hash;
 pub mod key_handle;
 pub mod ncrypt_key;
+pub mod provision;
 pub mod schannel_cred;
 pub mod tls_stream;
 
 mod context_buffer;
+mod error;
+mod ffi;
+mod hostname;
+mod os_support;
 mod security_context;
 
+pub use error::{Error, ErrorKind, InvalidString, UnsupportedFeature};
+pub use os_support::{os_capabilities, Feature, OsCapabilities};
+
 #[cfg(test)]
 mod test;
 
-const ACCEPT_REQUESTS: winapi::c_ulong =
-    winapi::ASC_REQ_ALLOCATE_MEMORY | winapi::ASC_REQ_CONFIDENTIALITY |
-    winapi::ASC_REQ_SEQUENCE_DETECT | winapi::ASC_REQ_STREAM |
-    winapi::ASC_REQ_REPLAY_DETECT;
+const ACCEPT_REQUESTS: ffi::c_ulong =
+    ffi::ASC_REQ_ALLOCATE_MEMORY | ffi::ASC_REQ_CONFIDENTIALITY |
+    ffi::ASC_REQ_SEQUENCE_DETECT | ffi::ASC_REQ_STREAM |
+    ffi::ASC_REQ_REPLAY_DETECT;
+
+const INIT_REQUESTS: ffi::c_ulong =
+    ffi::ISC_REQ_CONFIDENTIALITY | ffi::ISC_REQ_INTEGRITY | ffi::ISC_REQ_REPLAY_DETECT |
+    ffi::ISC_REQ_SEQUENCE_DETECT | ffi::ISC_REQ_MANUAL_CRED_VALIDATION |
+    ffi::ISC_REQ_ALLOCATE_MEMORY | ffi::ISC_REQ_STREAM | ffi::ISC_REQ_USE_SUPPLIED_CREDS;
 
-const INIT_REQUESTS: winapi::c_ulong =
-    winapi::ISC_REQ_CONFIDENTIALITY | winapi::ISC_REQ_INTEGRITY | winapi::ISC_REQ_REPLAY_DETECT |
-    winapi::ISC_REQ_SEQUENCE_DETECT | winapi::ISC_REQ_MANUAL_CRED_VALIDATION |
-    winapi::ISC_REQ_ALLOCATE_MEMORY | winapi::ISC_REQ_STREAM | winapi::ISC_REQ_USE_SUPPLIED_CREDS;
+/// The number of seconds between 1601-01-01 and the Unix epoch.
+const UNIX_EPOCH_OFFSET_SECS: u64 = 11_644_473_600;
 
+/// Crate-internal access to wrapped handles.
+///
+/// This is not exported; the public equivalents are the `as_raw`, `into_raw`
+/// and `from_raw` methods of each handle type.
 trait Inner<T> {
     unsafe fn from_inner(t: T) -> Self;
 
@@ -67,23 +120,340 @@ trait Inner<T> {
     fn get_mut(&mut self) -> &mut T;
 }
 
-unsafe fn secbuf(buftype: winapi::c_ulong,
-                 bytes: Option<&mut [u8]>) -> winapi::SecBuffer {
+trait FromCertKey<T> {
+    /// Wraps a key handle which is owned by `cert` rather than the caller,
+    /// keeping `cert` alive instead of freeing the handle.
+    unsafe fn from_cert_key(t: T, cert: cert_context::CertContext) -> Self;
+}
+
+trait FromCertProv<T> {
+    /// Wraps a provider handle acquired for the private key of a certificate
+    /// bound to the key pair `spec` of its container.
+    unsafe fn from_cert_prov(t: T, spec: cert_context::KeySpec) -> Self;
+}
+
+trait FromProvKey<T> {
+    /// Wraps a handle to one of the key pairs in `prov`'s container, keeping
+    /// `prov` alive so that the key can sign.
+    unsafe fn from_prov_key(t: T, prov: crypt_prov::CryptProv, spec: cert_context::KeySpec)
+                            -> Self;
+}
+
+trait CredSettings {
+    /// Returns the builder and direction a credential was acquired with, or
+    /// `None` if it was created from a raw handle.
+    fn settings(&self) -> Option<&(schannel_cred::Builder, schannel_cred::Direction)>;
+}
+
+/// Converts a `FILETIME`, counted in 100ns intervals since 1601, to a
+/// `SystemTime`.
+fn filetime_to_system_time(time: &ffi::FILETIME) -> SystemTime {
+    let intervals = (time.dwHighDateTime as u64) << 32 | time.dwLowDateTime as u64;
+    // `SystemTime` can't represent values with the sign bit set
+    let intervals = cmp::min(intervals, i64::max_value() as u64);
+    let epoch = UNIX_EPOCH - Duration::from_secs(UNIX_EPOCH_OFFSET_SECS);
+    epoch + Duration::new(intervals / 10_000_000, (intervals % 10_000_000) as u32 * 100)
+}
+
+/// Converts a `SystemTime` to a `FILETIME`.
+fn system_time_to_filetime(time: SystemTime) -> ffi::FILETIME {
+    let epoch = UNIX_EPOCH - Duration::from_secs(UNIX_EPOCH_OFFSET_SECS);
+    let since = time.duration_since(epoch).unwrap_or(Duration::from_secs(0));
+    let intervals = since.as_secs() * 10_000_000 + since.subsec_nanos() as u64 / 100;
+    ffi::FILETIME {
+        dwLowDateTime: intervals as ffi::DWORD,
+        dwHighDateTime: (intervals >> 32) as ffi::DWORD,
+    }
+}
+
+type GetProperty<T> = unsafe extern "system" fn(T,
+                                                ffi::DWORD,
+                                                *mut ffi::c_void,
+                                                *mut ffi::DWORD)
+                                                -> ffi::BOOL;
+type SetProperty<T> = unsafe extern "system" fn(T,
+                                                ffi::DWORD,
+                                                ffi::DWORD,
+                                                *const ffi::c_void)
+                                                -> ffi::BOOL;
+
+/// Reads a property of a certificate or CTL context with `get`, which is
+/// `CertGetCertificateContextProperty` or `CertGetCTLContextProperty`.
+unsafe fn get_property<T: Copy>(get: GetProperty<T>,
+                                context: T,
+                                prop: ffi::DWORD)
+                                -> io::Result<Vec<u8>> {
+    let mut len = 0;
+    if get(context, prop, ptr::null_mut(), &mut len) != ffi::TRUE {
+        return Err(error::last_error());
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    if get(context, prop, buf.as_mut_ptr() as *mut ffi::c_void, &mut len) != ffi::TRUE {
+        return Err(error::last_error());
+    }
+    buf.truncate(len as usize);
+    Ok(buf)
+}
+
+unsafe fn get_string_property<T: Copy>(get: GetProperty<T>,
+                                       context: T,
+                                       prop: ffi::DWORD)
+                                       -> io::Result<String> {
+    let buf = try!(get_property(get, context, prop));
+    wide_bytes_to_string(buf)
+}
+
+/// Decodes a nul-terminated UTF-16LE property value.
+///
+/// Values which are not valid UTF-16, including ones of odd length, are
+/// rejected with an `InvalidData` error wrapping an `InvalidString` which
+/// holds the raw bytes.
+fn wide_bytes_to_string(buf: Vec<u8>) -> io::Result<String> {
+    if buf.len() % 2 != 0 {
+        return Err(error::invalid_string(buf));
+    }
+    let wide = buf.chunks(2)
+        .map(|c| c[0] as u16 | (c[1] as u16) << 8)
+        .collect::<Vec<_>>();
+    // Chop off the trailing nul
+    let len = wide.iter().position(|&c| c == 0).unwrap_or(wide.len());
+    match String::from_utf16(&wide[..len]) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(error::invalid_string(buf)),
+    }
+}
+
+/// Decodes a nul-terminated UTF-16 string owned by Windows.
+unsafe fn from_wide_ptr(s: *const u16) -> String {
+    let len = (0..).take_while(|&i| *s.offset(i) != 0).count();
+    String::from_utf16_lossy(slice::from_raw_parts(s, len))
+}
+
+/// Converts a buffer length to a `DWORD`, failing rather than truncating if
+/// it does not fit.
+fn dword_len(len: usize) -> io::Result<ffi::DWORD> {
+    if len > ffi::DWORD::max_value() as usize {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is too large"))
+    } else {
+        Ok(len as ffi::DWORD)
+    }
+}
+
+/// Sets a property of a certificate or CTL context to a `CRYPT_DATA_BLOB`
+/// holding `data` with `set`, which is `CertSetCertificateContextProperty` or
+/// `CertSetCTLContextProperty`.
+unsafe fn set_property<T>(set: SetProperty<T>,
+                          context: T,
+                          prop: ffi::DWORD,
+                          data: &[u8])
+                          -> io::Result<()> {
+    let data = ffi::CRYPT_DATA_BLOB {
+        cbData: try!(dword_len(data.len())),
+        pbData: data.as_ptr() as *mut _,
+    };
+    if set(context, prop, 0, &data as *const _ as *const _) == ffi::TRUE {
+        Ok(())
+    } else {
+        Err(error::last_error())
+    }
+}
+
+unsafe fn set_string_property<T>(set: SetProperty<T>,
+                                 context: T,
+                                 prop: ffi::DWORD,
+                                 s: &str)
+                                 -> io::Result<()> {
+    let data = s.encode_utf16()
+        .chain(Some(0))
+        .flat_map(|c| vec![c as u8, (c >> 8) as u8])
+        .collect::<Vec<_>>();
+    set_property(set, context, prop, &data)
+}
+
+unsafe fn secbuf(buftype: ffi::c_ulong,
+                 bytes: Option<&mut [u8]>) -> io::Result<ffi::SecBuffer> {
     let (ptr, len) = match bytes {
-        Some(bytes) => (bytes.as_mut_ptr(), bytes.len() as winapi::c_ulong),
+        Some(bytes) => (bytes.as_mut_ptr(), try!(dword_len(bytes.len()))),
         None => (ptr::null_mut(), 0),
     };
-    winapi::SecBuffer {
+    Ok(ffi::SecBuffer {
         BufferType: buftype,
         cbBuffer: len,
-        pvBuffer: ptr as *mut winapi::c_void,
-    }
+        pvBuffer: ptr as *mut ffi::c_void,
+    })
 }
 
-unsafe fn secbuf_desc(bufs: &mut [winapi::SecBuffer]) -> winapi::SecBufferDesc {
-    winapi::SecBufferDesc {
-        ulVersion: winapi::SECBUFFER_VERSION,
-        cBuffers: bufs.len() as winapi::c_ulong,
+unsafe fn secbuf_desc(bufs: &mut [ffi::SecBuffer]) -> ffi::SecBufferDesc {
+    ffi::SecBufferDesc {
+        ulVersion: ffi::SECBUFFER_VERSION,
+        cBuffers: bufs.len() as ffi::c_ulong,
         pBuffers: bufs.as_mut_ptr(),
     }
 }
+
+unsafe fn export_public_key_info(handle: ffi::HCRYPTPROV_OR_NCRYPT_KEY_HANDLE,
+                                 key_spec: ffi::DWORD)
+                                 -> io::Result<Vec<u8>> {
+    let mut len = 0;
+    let res = ffi::CryptExportPublicKeyInfo(handle,
+                                            key_spec,
+                                            ffi::X509_ASN_ENCODING,
+                                            ptr::null_mut(),
+                                            &mut len);
+    if res != ffi::TRUE {
+        return Err(error::last_error());
+    }
+
+    // The structure is followed by the data its pointers refer to, so the
+    // buffer needs to be suitably aligned for the structure itself.
+    let mut buf = vec![0u64; (len as usize + 7) / 8];
+    let info = buf.as_mut_ptr() as ffi::PCERT_PUBLIC_KEY_INFO;
+    let res = ffi::CryptExportPublicKeyInfo(handle,
+                                            key_spec,
+                                            ffi::X509_ASN_ENCODING,
+                                            info,
+                                            &mut len);
+    if res != ffi::TRUE {
+        return Err(error::last_error());
+    }
+
+    encode_public_key_info(&*info)
+}
+
+unsafe fn encode_public_key_info(info: &ffi::CERT_PUBLIC_KEY_INFO) -> io::Result<Vec<u8>> {
+    encode_object(ffi::X509_PUBLIC_KEY_INFO, info as *const _ as *const _)
+}
+
+unsafe fn encode_object(struct_type: ffi::LPCSTR,
+                        info: *const ffi::c_void)
+                        -> io::Result<Vec<u8>> {
+    let mut len = 0;
+    let res = ffi::CryptEncodeObjectEx(ffi::X509_ASN_ENCODING,
+                                       struct_type,
+                                       info,
+                                       0,
+                                       ptr::null_mut(),
+                                       ptr::null_mut(),
+                                       &mut len);
+    if res != ffi::TRUE {
+        return Err(error::last_error());
+    }
+
+    let mut buf = vec![0; len as usize];
+    let res = ffi::CryptEncodeObjectEx(ffi::X509_ASN_ENCODING,
+                                       struct_type,
+                                       info,
+                                       0,
+                                       ptr::null_mut(),
+                                       buf.as_mut_ptr() as *mut _,
+                                       &mut len);
+    if res != ffi::TRUE {
+        return Err(error::last_error());
+    }
+    buf.truncate(len as usize);
+    Ok(buf)
+}
+
+/// Signs a digest computed with `alg` using one of a CryptoAPI container's
+/// key pairs, returning the signature in big-endian order.
+unsafe fn capi_sign_digest(prov: ffi::HCRYPTPROV,
+                           key_spec: ffi::DWORD,
+                           digest: &[u8],
+                           alg: ffi::ALG_ID)
+                           -> io::Result<Vec<u8>> {
+    let mut handle = 0;
+    let res = ffi::CryptCreateHash(prov, alg, 0, 0, &mut handle);
+    if res != ffi::TRUE {
+        return Err(error::last_error());
+    }
+
+    let mut len = 0;
+    let mut res = ffi::CryptSetHashParam(handle, ffi::HP_HASHVAL, digest.as_ptr(), 0);
+    if res == ffi::TRUE {
+        res = ffi::CryptSignHashW(handle,
+                                  key_spec,
+                                  ptr::null(),
+                                  0,
+                                  ptr::null_mut(),
+                                  &mut len);
+    }
+    let mut buf = vec![0; len as usize];
+    if res == ffi::TRUE {
+        res = ffi::CryptSignHashW(handle,
+                                  key_spec,
+                                  ptr::null(),
+                                  0,
+                                  buf.as_mut_ptr(),
+                                  &mut len);
+    }
+    let err = error::last_error();
+    ffi::CryptDestroyHash(handle);
+
+    if res != ffi::TRUE {
+        return Err(err);
+    }
+    // CryptoAPI produces little-endian signatures
+    buf.truncate(len as usize);
+    buf.reverse();
+    Ok(buf)
+}
+
+/// Converts a CNG `r || s` ECDSA signature into its DER encoding.
+unsafe fn ecdsa_raw_to_der(raw: &[u8]) -> io::Result<Vec<u8>> {
+    // CRYPT_UINT_BLOBs are little-endian
+    let half = raw.len() / 2;
+    let mut r = raw[..half].iter().rev().cloned().collect::<Vec<_>>();
+    let mut s = raw[half..].iter().rev().cloned().collect::<Vec<_>>();
+    let sig = ffi::CERT_ECC_SIGNATURE {
+        r: ffi::CRYPT_UINT_BLOB {
+            cbData: try!(dword_len(r.len())),
+            pbData: r.as_mut_ptr(),
+        },
+        s: ffi::CRYPT_UINT_BLOB {
+            cbData: try!(dword_len(s.len())),
+            pbData: s.as_mut_ptr(),
+        },
+    };
+    encode_object(ffi::X509_ECC_SIGNATURE, &sig as *const _ as *const _)
+}
+
+/// Converts a DER-encoded ECDSA signature into the CNG `r || s` form, where
+/// each integer is `width` bytes long.
+unsafe fn ecdsa_der_to_raw(der: &[u8], width: usize) -> io::Result<Vec<u8>> {
+    let mut sig: *mut ffi::CERT_ECC_SIGNATURE = ptr::null_mut();
+    let mut len = 0;
+    let res = ffi::CryptDecodeObjectEx(ffi::X509_ASN_ENCODING,
+                                       ffi::X509_ECC_SIGNATURE,
+                                       der.as_ptr(),
+                                       try!(dword_len(der.len())),
+                                       ffi::CRYPT_DECODE_ALLOC_FLAG,
+                                       ptr::null_mut(),
+                                       &mut sig as *mut _ as *mut ffi::c_void,
+                                       &mut len);
+    if res != ffi::TRUE {
+        return Err(error::last_error());
+    }
+
+    let mut raw = vec![0; 2 * width];
+    let mut ok = true;
+    for (i, blob) in [&(*sig).r, &(*sig).s].iter().enumerate() {
+        let le = slice::from_raw_parts(blob.pbData, blob.cbData as usize);
+        let significant = le.iter().rposition(|&b| b != 0).map(|p| p + 1).unwrap_or(0);
+        if significant > width {
+            ok = false;
+            break;
+        }
+        let out = &mut raw[i * width..(i + 1) * width];
+        for (j, b) in le[..significant].iter().enumerate() {
+            out[width - 1 - j] = *b;
+        }
+    }
+    ffi::LocalFree(sig as *mut _);
+
+    if ok {
+        Ok(raw)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "ECDSA signature is too long for the key"))
+    }
+}
@@ -1,20 +1,1576 @@
 //! CNG private keys.
-use winapi;
+use ffi;
+use std::io;
+use std::mem;
+use std::ops::BitOr;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
 
-// FIXME https://github.com/retep998/winapi-rs/pull/319
-extern "system" {
-    fn NCryptFreeObject(handle: winapi::NCRYPT_HANDLE) -> winapi::SECURITY_STATUS;
-}
+use {FromCertKey, Inner};
+use cert_context::{CertContext, HashAlgorithm, KeySpec, Padding, PrivateKey};
+
+const MS_KEY_STORAGE_PROVIDER: &'static str = "Microsoft Software Key Storage Provider";
+const MS_PLATFORM_CRYPTO_PROVIDER: &'static str = "Microsoft Platform Crypto Provider";
+const MS_SMART_CARD_KEY_STORAGE_PROVIDER: &'static str = "Microsoft Smart Card Key Storage \
+                                                          Provider";
+const NCRYPT_PKCS8_PRIVATE_KEY_BLOB: &'static str = "PKCS8_PRIVATEKEY";
+const NCRYPT_ALGORITHM_GROUP_PROPERTY: &'static str = "Algorithm Group";
+const NCRYPT_LENGTH_PROPERTY: &'static str = "Length";
+const NCRYPT_EXPORT_POLICY_PROPERTY: &'static str = "Export Policy";
+const NCRYPT_KEY_USAGE_PROPERTY: &'static str = "Key Usage";
+const NCRYPT_PROVIDER_HANDLE_PROPERTY: &'static str = "Provider Handle";
+const NCRYPT_NAME_PROPERTY: &'static str = "Name";
+const NCRYPT_IMPL_TYPE_PROPERTY: &'static str = "Impl Type";
+const NCRYPT_SECURITY_DESCR_PROPERTY: &'static str = "Security Descr";
+const NCRYPT_WINDOW_HANDLE_PROPERTY: &'static str = "HWND Handle";
+const NCRYPT_USE_CONTEXT_PROPERTY: &'static str = "Use Context";
+const NCRYPT_READER_PROPERTY: &'static str = "SmartCardReader";
+const NCRYPT_SMARTCARD_GUID_PROPERTY: &'static str = "SmartCardGuid";
 
 /// A CNG handle to a key.
-pub struct NcryptKey(winapi::NCRYPT_KEY_HANDLE);
+///
+/// NCrypt handles cannot be duplicated, so clones of a key share its handle,
+/// which is freed once the last of them is dropped. Properties set through
+/// one clone, such as a parent window set by `set_ui_policy`, are visible
+/// through all of them; only the `silent` setting is tracked per clone.
+#[derive(Clone)]
+pub struct NcryptKey(ffi::NCRYPT_KEY_HANDLE, bool, Arc<KeyOwner>);
+
+// Key storage providers allow a key handle to be used from several threads
+// at once; the handle is only freed by the last clone.
+unsafe impl Send for NcryptKey {}
+unsafe impl Sync for NcryptKey {}
 
-impl Drop for NcryptKey {
+struct KeyOwner(ffi::NCRYPT_KEY_HANDLE, Option<CertContext>);
+
+impl Drop for KeyOwner {
     fn drop(&mut self) {
+        // handles owned by a certificate are freed along with it
+        if self.1.is_none() {
+            unsafe {
+                ffi::NCryptFreeObject(self.0);
+            }
+        }
+    }
+}
+
+impl Inner<ffi::NCRYPT_KEY_HANDLE> for NcryptKey {
+    unsafe fn from_inner(t: ffi::NCRYPT_KEY_HANDLE) -> NcryptKey {
+        NcryptKey(t, false, Arc::new(KeyOwner(t, None)))
+    }
+
+    fn as_inner(&self) -> ffi::NCRYPT_KEY_HANDLE {
+        self.0
+    }
+
+    fn get_mut(&mut self) -> &mut ffi::NCRYPT_KEY_HANDLE {
+        &mut self.0
+    }
+}
+
+impl FromCertKey<ffi::NCRYPT_KEY_HANDLE> for NcryptKey {
+    unsafe fn from_cert_key(t: ffi::NCRYPT_KEY_HANDLE, cert: CertContext) -> NcryptKey {
+        NcryptKey(t, false, Arc::new(KeyOwner(t, Some(cert))))
+    }
+}
+
+impl NcryptKey {
+    /// Returns the raw `NCRYPT_KEY_HANDLE`.
+    ///
+    /// The handle remains owned by this key and its clones, and is only valid
+    /// for as long as one of them is alive. It is not reference counted, so it
+    /// must not be passed to `NCryptFreeObject`.
+    pub fn as_raw(&self) -> ffi::NCRYPT_KEY_HANDLE {
+        self.0
+    }
+
+    /// Consumes this key, returning the raw `NCRYPT_KEY_HANDLE`.
+    ///
+    /// The caller becomes responsible for releasing it with
+    /// `NCryptFreeObject`, or for passing it back to `from_raw`. Keys owned by
+    /// a certificate, and keys which have live clones, cannot give up their
+    /// handle and are returned as the error.
+    pub fn into_raw(self) -> Result<ffi::NCRYPT_KEY_HANDLE, NcryptKey> {
+        if self.2 .1.is_some() {
+            return Err(self);
+        }
+        let NcryptKey(handle, silent, owner) = self;
+        match Arc::try_unwrap(owner) {
+            Ok(owner) => {
+                mem::forget(owner);
+                Ok(handle)
+            }
+            Err(owner) => Err(NcryptKey(handle, silent, owner)),
+        }
+    }
+
+    /// Wraps a raw `NCRYPT_KEY_HANDLE`, taking ownership of it.
+    ///
+    /// It is released with `NCryptFreeObject` once the returned key and all of
+    /// its clones have been dropped, so the caller must not release it as
+    /// well.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must be a valid key handle which is not owned by anything else.
+    pub unsafe fn from_raw(raw: ffi::NCRYPT_KEY_HANDLE) -> NcryptKey {
+        NcryptKey::from_inner(raw)
+    }
+
+    /// Generates a new key in `provider`.
+    ///
+    /// `length` is the length of the key in bits, for algorithms which have
+    /// a variable one. The key is persisted under `key_name` if one is given,
+    /// and is ephemeral otherwise. `CreateOptions` offers further control
+    /// over the new key.
+    pub fn generate(provider: &NcryptProvider,
+                    algorithm: KeyAlgorithm,
+                    length: Option<u32>,
+                    key_name: Option<&str>)
+                    -> io::Result<NcryptKey> {
+        let mut options = CreateOptions::new();
+        options.provider(provider);
+        if let Some(length) = length {
+            options.length(length);
+        }
+        if let Some(key_name) = key_name {
+            options.name(key_name);
+        }
+        options.create(algorithm)
+    }
+
+    /// Deletes this key from its key storage provider.
+    ///
+    /// Certificates whose key provider information still refers to this key
+    /// are not updated by this call. Their `CERT_KEY_PROV_INFO_PROP_ID`
+    /// property will name a key which no longer exists, and acquiring their
+    /// private key will fail afterwards.
+    ///
+    /// Keys owned by a certificate, and keys which have live clones, cannot
+    /// be deleted.
+    pub fn delete(self) -> io::Result<()> {
+        if self.2 .1.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "key is owned by a certificate"));
+        }
+        let owner = match Arc::try_unwrap(self.2) {
+            Ok(owner) => owner,
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "key is shared with other clones"))
+            }
+        };
+        unsafe {
+            // on success the handle is freed by `NCryptDeleteKey` itself
+            try!(check(ffi::NCryptDeleteKey(owner.0, 0)));
+            mem::forget(owner);
+            Ok(())
+        }
+    }
+
+    /// Returns the DER-encoded `SubjectPublicKeyInfo` of this key.
+    ///
+    /// This is byte-for-byte identical to the public key information of a
+    /// certificate created over this key.
+    pub fn export_public_spki(&self) -> io::Result<Vec<u8>> {
+        unsafe { ::export_public_key_info(self.0, ffi::CERT_NCRYPT_KEY_SPEC) }
+    }
+
+    /// Exports this key as an unencrypted DER-encoded PKCS #8
+    /// `PrivateKeyInfo`.
+    ///
+    /// The result contains the private key in plaintext, and should be
+    /// handled accordingly. The export policy of the key must include
+    /// `ExportPolicy::plaintext_export()`; the provider's error is returned
+    /// otherwise.
+    pub fn export_pkcs8(&self) -> io::Result<Vec<u8>> {
         unsafe {
-            NCryptFreeObject(self.0);
+            let blob_type = wide(NCRYPT_PKCS8_PRIVATE_KEY_BLOB);
+            let mut len = 0;
+            try!(check(ffi::NCryptExportKey(self.0,
+                                            0,
+                                            blob_type.as_ptr(),
+                                            ptr::null_mut(),
+                                            ptr::null_mut(),
+                                            0,
+                                            &mut len,
+                                            0)));
+
+            let mut buf = vec![0; len as usize];
+            try!(check(ffi::NCryptExportKey(self.0,
+                                            0,
+                                            blob_type.as_ptr(),
+                                            ptr::null_mut(),
+                                            buf.as_mut_ptr(),
+                                            len,
+                                            &mut len,
+                                            0)));
+            buf.truncate(len as usize);
+            Ok(buf)
         }
     }
+
+    /// Signs `data` with this key.
+    ///
+    /// The data is hashed with `hash` before being signed. This is a
+    /// shorthand for `PrivateKey::sign`.
+    pub fn sign(&self, data: &[u8], hash: HashAlgorithm, padding: Padding) -> io::Result<Vec<u8>> {
+        PrivateKey::NcryptKey(self.clone()).sign(data, hash, padding)
+    }
+
+    /// Signs a digest which has already been computed with `hash`.
+    ///
+    /// Hash algorithms without a constructor on `HashAlgorithm` can be named
+    /// with `HashAlgorithm::from_cng_name`. Unless this key is silent, the
+    /// provider may prompt for a PIN. This is a shorthand for
+    /// `PrivateKey::sign_digest`.
+    pub fn sign_digest(&self,
+                       digest: &[u8],
+                       hash: HashAlgorithm,
+                       padding: Padding)
+                       -> io::Result<Vec<u8>> {
+        PrivateKey::NcryptKey(self.clone()).sign_digest(digest, hash, padding)
+    }
+
+    /// Returns the name of this key in its key storage provider.
+    ///
+    /// Ephemeral keys do not have a name.
+    pub fn key_name(&self) -> io::Result<Option<String>> {
+        get_string(self.0, NCRYPT_NAME_PROPERTY)
+    }
+
+    /// Returns the name of the algorithm group of this key, such as `RSA` or
+    /// `ECDSA`.
+    pub fn algorithm_group(&self) -> io::Result<Option<String>> {
+        get_string(self.0, NCRYPT_ALGORITHM_GROUP_PROPERTY)
+    }
+
+    /// Returns the length of this key in bits.
+    pub fn length(&self) -> io::Result<Option<u32>> {
+        get_dword(self.0, NCRYPT_LENGTH_PROPERTY)
+    }
+
+    /// Returns the export policy of this key.
+    pub fn export_policy(&self) -> io::Result<Option<ExportPolicy>> {
+        Ok(try!(get_dword(self.0, NCRYPT_EXPORT_POLICY_PROPERTY)).map(ExportPolicy))
+    }
+
+    /// Returns the operations this key may be used for.
+    pub fn key_usage(&self) -> io::Result<Option<NcryptKeyUsage>> {
+        Ok(try!(get_dword(self.0, NCRYPT_KEY_USAGE_PROPERTY)).map(NcryptKeyUsage))
+    }
+
+    /// Returns the name of the key storage provider holding this key.
+    pub fn provider_name(&self) -> io::Result<Option<String>> {
+        match try!(self.provider()) {
+            Some(prov) => get_string(prov.0, NCRYPT_NAME_PROPERTY),
+            None => Ok(None),
+        }
+    }
+
+    /// Determines if this key is held by a hardware device such as a smart
+    /// card or TPM, based on the implementation type of its provider.
+    pub fn is_hardware_backed(&self) -> io::Result<Option<bool>> {
+        let prov = match try!(self.provider()) {
+            Some(prov) => prov,
+            None => return Ok(None),
+        };
+        let type_ = try!(get_dword(prov.0, NCRYPT_IMPL_TYPE_PROPERTY));
+        Ok(type_.map(|t| t & ffi::NCRYPT_IMPL_HARDWARE_FLAG != 0))
+    }
+
+    /// Returns the self-relative security descriptor of this key, including
+    /// its owner, group and DACL.
+    ///
+    /// Ephemeral keys do not have a security descriptor.
+    pub fn security_descriptor(&self) -> io::Result<Option<Vec<u8>>> {
+        let flags = ffi::OWNER_SECURITY_INFORMATION | ffi::GROUP_SECURITY_INFORMATION |
+                    ffi::DACL_SECURITY_INFORMATION;
+        get_property(self.0, NCRYPT_SECURITY_DESCR_PROPERTY, flags)
+    }
+
+    /// Configures the user interface displayed when this key requires a PIN
+    /// or password.
+    ///
+    /// `window` is used as the parent of any dialog, and `use_context`
+    /// describes to the user what the key is being used for. If `silent` is
+    /// set, no dialog is displayed at all; signing and decryption with this
+    /// handle then fail instead, typically with
+    /// `SCARD_W_CARD_NOT_AUTHENTICATED` for smart cards.
+    pub fn set_ui_policy(&mut self,
+                         window: Option<ffi::HWND>,
+                         use_context: Option<&str>,
+                         silent: bool)
+                         -> io::Result<()> {
+        if let Some(window) = window {
+            let window = window as usize;
+            let bytes = unsafe {
+                slice::from_raw_parts(&window as *const _ as *const u8, mem::size_of_val(&window))
+            };
+            try!(set_property(self.0, NCRYPT_WINDOW_HANDLE_PROPERTY, bytes, 0));
+        }
+        if let Some(use_context) = use_context {
+            let use_context = wide(use_context);
+            let bytes = unsafe {
+                slice::from_raw_parts(use_context.as_ptr() as *const u8, use_context.len() * 2)
+            };
+            try!(set_property(self.0, NCRYPT_USE_CONTEXT_PROPERTY, bytes, 0));
+        }
+        self.1 = silent;
+        Ok(())
+    }
+
+    /// Determines if operations with this handle will fail rather than
+    /// display a dialog.
+    ///
+    /// This is set for keys opened with `OpenOptions::silent` and by
+    /// `set_ui_policy`.
+    pub fn is_silent(&self) -> bool {
+        self.1
+    }
+
+    /// Returns a Platform Crypto Provider property of this key, such as its
+    /// attestation blob.
+    ///
+    /// Returns `None` if the key is not held by the Platform Crypto Provider.
+    pub fn pcp_property(&self, property: PcpProperty) -> io::Result<Option<Vec<u8>>> {
+        get_property(self.0, property.0, 0)
+    }
+
+    /// Returns the raw value of the property of this key with the specified
+    /// name, such as a vendor-specific property of a hardware key.
+    ///
+    /// Returns `None` if the key's provider does not support the property or
+    /// it is not set.
+    pub fn get_raw_property(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        get_property(self.0, name, 0)
+    }
+
+    /// Sets the raw value of the property of this key with the specified
+    /// name.
+    ///
+    /// `flags` is passed through to `NCryptSetProperty`, and may include
+    /// `NCRYPT_PERSIST_FLAG` to persist the property with the key.
+    pub fn set_raw_property(&self,
+                            name: &str,
+                            value: &[u8],
+                            flags: ffi::DWORD)
+                            -> io::Result<()> {
+        set_property(self.0, name, value, flags)
+    }
+
+    fn provider(&self) -> io::Result<Option<NcryptProvider>> {
+        let prop = try!(get_property(self.0, NCRYPT_PROVIDER_HANDLE_PROPERTY, 0));
+        Ok(prop.and_then(|buf| {
+            if buf.len() == mem::size_of::<ffi::NCRYPT_PROV_HANDLE>() {
+                let mut handle: ffi::NCRYPT_PROV_HANDLE = 0;
+                unsafe {
+                    ptr::copy_nonoverlapping(buf.as_ptr(),
+                                             &mut handle as *mut _ as *mut u8,
+                                             buf.len());
+                }
+                Some(NcryptProvider(handle))
+            } else {
+                None
+            }
+        }))
+    }
+
 }
 
-inner!(NcryptKey, winapi::NCRYPT_KEY_HANDLE);
+/// A property specific to the Platform Crypto Provider, exposed as raw bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PcpProperty(&'static str);
+
+impl PcpProperty {
+    /// The TPM 1.2 attestation blob of a key.
+    pub fn key_attestation() -> PcpProperty {
+        PcpProperty("PCP_TPM12_KEYATTESTATION")
+    }
+
+    /// The public portion of the TPM's endorsement key.
+    pub fn ek_public() -> PcpProperty {
+        PcpProperty("PCP_EKPUB")
+    }
+
+    /// The certificates of the TPM's endorsement key.
+    pub fn ek_certificate() -> PcpProperty {
+        PcpProperty("PCP_EKCERT")
+    }
+
+    /// The public portion of the TPM's storage root key.
+    pub fn srk_public() -> PcpProperty {
+        PcpProperty("PCP_SRKPUB")
+    }
+
+    /// The platform type string of the TPM.
+    pub fn platform_type() -> PcpProperty {
+        PcpProperty("PCP_PLATFORM_TYPE")
+    }
+
+    /// The version of the TPM.
+    pub fn tpm_version() -> PcpProperty {
+        PcpProperty("PCP_TPM_VERSION")
+    }
+
+    /// Returns the name of the property.
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+/// An identifier of the algorithm of a CNG key.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeyAlgorithm(&'static str);
+
+#[allow(missing_docs)]
+impl KeyAlgorithm {
+    pub fn rsa() -> KeyAlgorithm {
+        KeyAlgorithm("RSA")
+    }
+
+    pub fn ecdsa_p256() -> KeyAlgorithm {
+        KeyAlgorithm("ECDSA_P256")
+    }
+
+    pub fn ecdsa_p384() -> KeyAlgorithm {
+        KeyAlgorithm("ECDSA_P384")
+    }
+
+    pub fn ecdsa_p521() -> KeyAlgorithm {
+        KeyAlgorithm("ECDSA_P521")
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+/// The export policy of a CNG key.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ExportPolicy(ffi::DWORD);
+
+impl ExportPolicy {
+    /// The key may not be exported.
+    pub fn none() -> ExportPolicy {
+        ExportPolicy(0)
+    }
+
+    /// The key may be exported encrypted.
+    pub fn export() -> ExportPolicy {
+        ExportPolicy(ffi::NCRYPT_ALLOW_EXPORT_FLAG)
+    }
+
+    /// The key may be exported in plaintext.
+    pub fn plaintext_export() -> ExportPolicy {
+        ExportPolicy(ffi::NCRYPT_ALLOW_PLAINTEXT_EXPORT_FLAG)
+    }
+
+    /// The key may be exported encrypted once, for archival.
+    pub fn archiving() -> ExportPolicy {
+        ExportPolicy(ffi::NCRYPT_ALLOW_ARCHIVING_FLAG)
+    }
+
+    /// The key may be exported in plaintext once, for archival.
+    pub fn plaintext_archiving() -> ExportPolicy {
+        ExportPolicy(ffi::NCRYPT_ALLOW_PLAINTEXT_ARCHIVING_FLAG)
+    }
+
+    /// Determines if every export allowed by `other` is allowed by this
+    /// policy.
+    pub fn contains(&self, other: ExportPolicy) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the raw `NCRYPT_ALLOW_*` flags of this policy.
+    pub fn as_raw(&self) -> ffi::DWORD {
+        self.0
+    }
+}
+
+impl BitOr for ExportPolicy {
+    type Output = ExportPolicy;
+
+    fn bitor(self, other: ExportPolicy) -> ExportPolicy {
+        ExportPolicy(self.0 | other.0)
+    }
+}
+
+/// The operations a CNG key may be used for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NcryptKeyUsage(ffi::DWORD);
+
+impl NcryptKeyUsage {
+    /// The key may be used for decryption.
+    pub fn decrypt() -> NcryptKeyUsage {
+        NcryptKeyUsage(ffi::NCRYPT_ALLOW_DECRYPT_FLAG)
+    }
+
+    /// The key may be used for signing.
+    pub fn signing() -> NcryptKeyUsage {
+        NcryptKeyUsage(ffi::NCRYPT_ALLOW_SIGNING_FLAG)
+    }
+
+    /// The key may be used for secret agreement.
+    pub fn key_agreement() -> NcryptKeyUsage {
+        NcryptKeyUsage(ffi::NCRYPT_ALLOW_KEY_AGREEMENT_FLAG)
+    }
+
+    /// The key may be used for any operation.
+    pub fn all() -> NcryptKeyUsage {
+        NcryptKeyUsage(ffi::NCRYPT_ALLOW_ALL_USAGES)
+    }
+
+    /// Determines if every operation allowed by `other` is allowed by this
+    /// usage.
+    pub fn contains(&self, other: NcryptKeyUsage) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the raw `NCRYPT_ALLOW_*` flags of this usage.
+    pub fn as_raw(&self) -> ffi::DWORD {
+        self.0
+    }
+}
+
+impl BitOr for NcryptKeyUsage {
+    type Output = NcryptKeyUsage;
+
+    fn bitor(self, other: NcryptKeyUsage) -> NcryptKeyUsage {
+        NcryptKeyUsage(self.0 | other.0)
+    }
+}
+
+/// A builder used to open existing CNG keys.
+#[derive(Default)]
+pub struct OpenOptions<'a> {
+    provider: Option<&'a NcryptProvider>,
+    flags: ffi::DWORD,
+}
+
+impl<'a> OpenOptions<'a> {
+    /// Returns a new `OpenOptions` with default settings.
+    pub fn new() -> OpenOptions<'a> {
+        OpenOptions::default()
+    }
+
+    /// Sets the key storage provider.
+    ///
+    /// Defaults to the Microsoft Software Key Storage Provider.
+    pub fn provider(&mut self, provider: &'a NcryptProvider) -> &mut OpenOptions<'a> {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// If set, an error will be returned if user intervention is required
+    /// rather than displaying a dialog.
+    pub fn silent(&mut self, silent: bool) -> &mut OpenOptions<'a> {
+        if silent {
+            self.flags |= ffi::NCRYPT_SILENT_FLAG;
+        } else {
+            self.flags &= !ffi::NCRYPT_SILENT_FLAG;
+        }
+        self
+    }
+
+    /// If set, the key will be opened from the machine key set rather than
+    /// the current user's.
+    pub fn machine_key(&mut self, machine_key: bool) -> &mut OpenOptions<'a> {
+        if machine_key {
+            self.flags |= ffi::NCRYPT_MACHINE_KEY_FLAG;
+        } else {
+            self.flags &= !ffi::NCRYPT_MACHINE_KEY_FLAG;
+        }
+        self
+    }
+
+    /// Opens the key with the specified name.
+    pub fn open(&self, name: &str) -> io::Result<NcryptKey> {
+        with_provider(self.provider, |prov| unsafe {
+            let name = wide(name);
+            let mut key = 0;
+            try!(check(ffi::NCryptOpenKey(prov.0, &mut key, name.as_ptr(), 0, self.flags)));
+            let mut key = NcryptKey::from_inner(key);
+            key.1 = self.flags & ffi::NCRYPT_SILENT_FLAG != 0;
+            Ok(key)
+        })
+    }
+}
+
+/// A builder used to create new CNG keys.
+#[derive(Default)]
+pub struct CreateOptions<'a> {
+    provider: Option<&'a NcryptProvider>,
+    name: Option<Vec<u16>>,
+    length: Option<u32>,
+    export_policy: Option<ExportPolicy>,
+    key_usage: Option<NcryptKeyUsage>,
+    security_descriptor: Option<Vec<u8>>,
+    flags: ffi::DWORD,
+}
+
+impl<'a> CreateOptions<'a> {
+    /// Returns a new `CreateOptions` with default settings.
+    pub fn new() -> CreateOptions<'a> {
+        CreateOptions::default()
+    }
+
+    /// Sets the key storage provider.
+    ///
+    /// Defaults to the Microsoft Software Key Storage Provider.
+    pub fn provider(&mut self, provider: &'a NcryptProvider) -> &mut CreateOptions<'a> {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// Sets the name of the key.
+    ///
+    /// If not set, the key is ephemeral and will not be persisted.
+    pub fn name(&mut self, name: &str) -> &mut CreateOptions<'a> {
+        self.name = Some(wide(name));
+        self
+    }
+
+    /// Sets the length of the key in bits.
+    ///
+    /// This is only meaningful for algorithms with a variable key size, such
+    /// as RSA.
+    pub fn length(&mut self, length: u32) -> &mut CreateOptions<'a> {
+        self.length = Some(length);
+        self
+    }
+
+    /// Sets the export policy of the key.
+    ///
+    /// The policy cannot be changed once the key has been created. Defaults
+    /// to the policy of the key storage provider, which for the Microsoft
+    /// Software Key Storage Provider is `ExportPolicy::none()`.
+    pub fn export_policy(&mut self, export_policy: ExportPolicy) -> &mut CreateOptions<'a> {
+        self.export_policy = Some(export_policy);
+        self
+    }
+
+    /// Sets the operations the key may be used for.
+    ///
+    /// The usage cannot be changed once the key has been created. Defaults to
+    /// `NcryptKeyUsage::all()`.
+    pub fn key_usage(&mut self, key_usage: NcryptKeyUsage) -> &mut CreateOptions<'a> {
+        self.key_usage = Some(key_usage);
+        self
+    }
+
+    /// Sets the discretionary access control list of the key from a
+    /// self-relative security descriptor.
+    ///
+    /// `grant_read_descriptor` can be used to build a descriptor allowing
+    /// another account, such as a service, to use the key.
+    pub fn security_descriptor(&mut self, security_descriptor: &[u8]) -> &mut CreateOptions<'a> {
+        self.security_descriptor = Some(security_descriptor.to_owned());
+        self
+    }
+
+    /// If set, an existing key with the same name will be replaced rather than
+    /// causing an error.
+    pub fn overwrite(&mut self, overwrite: bool) -> &mut CreateOptions<'a> {
+        self.flag(ffi::NCRYPT_OVERWRITE_KEY_FLAG, overwrite)
+    }
+
+    /// If set, the key will be created in the machine key set rather than the
+    /// current user's.
+    ///
+    /// Creating machine keys requires administrator rights; an error of kind
+    /// `PermissionDenied` is returned otherwise.
+    pub fn machine_key(&mut self, machine_key: bool) -> &mut CreateOptions<'a> {
+        self.flag(ffi::NCRYPT_MACHINE_KEY_FLAG, machine_key)
+    }
+
+    fn flag(&mut self, flag: ffi::DWORD, on: bool) -> &mut CreateOptions<'a> {
+        if on {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+
+    /// Generates a new key.
+    pub fn create(&self, algorithm: KeyAlgorithm) -> io::Result<NcryptKey> {
+        let res = with_provider(self.provider, |prov| unsafe {
+            let algorithm = wide(algorithm.as_str());
+            let name = self.name.as_ref().map(|s| s.as_ptr()).unwrap_or(ptr::null());
+
+            let mut key = 0;
+            try!(check(ffi::NCryptCreatePersistedKey(prov.0,
+                                                     &mut key,
+                                                     algorithm.as_ptr(),
+                                                     name,
+                                                     0,
+                                                     self.flags)));
+            let key = NcryptKey::from_inner(key);
+
+            if let Some(length) = self.length {
+                try!(set_dword(key.0, NCRYPT_LENGTH_PROPERTY, length, 0));
+            }
+            self.finalize(key)
+        });
+        self.map_err(res)
+    }
+
+    /// Imports an unencrypted DER-encoded PKCS #8 `PrivateKeyInfo`.
+    ///
+    /// The length set by `length` is ignored.
+    pub fn import_pkcs8(&self, der: &[u8]) -> io::Result<NcryptKey> {
+        let res = with_provider(self.provider, |prov| unsafe {
+            let blob_type = wide(NCRYPT_PKCS8_PRIVATE_KEY_BLOB);
+            let mut name = self.name.clone().unwrap_or_else(Vec::new);
+            let mut buffer = ffi::BCryptBuffer {
+                cbBuffer: try!(::dword_len(name.len() * 2)),
+                BufferType: ffi::NCRYPTBUFFER_PKCS_KEY_NAME,
+                pvBuffer: name.as_mut_ptr() as ffi::PVOID,
+            };
+            let mut params = ffi::BCryptBufferDesc {
+                ulVersion: ffi::BCRYPTBUFFER_VERSION,
+                cBuffers: 1,
+                pBuffers: &mut buffer,
+            };
+            let params = if name.is_empty() {
+                ptr::null_mut()
+            } else {
+                &mut params as *mut _
+            };
+
+            let mut key = 0;
+            try!(check(ffi::NCryptImportKey(prov.0,
+                                            0,
+                                            blob_type.as_ptr(),
+                                            params,
+                                            &mut key,
+                                            der.as_ptr() as *mut _,
+                                            try!(::dword_len(der.len())),
+                                            self.flags | ffi::NCRYPT_DO_NOT_FINALIZE_FLAG)));
+            self.finalize(NcryptKey::from_inner(key))
+        });
+        self.map_err(res)
+    }
+
+    /// Sets the properties which are fixed once the key is finalized, and then
+    /// finalizes it.
+    unsafe fn finalize(&self, key: NcryptKey) -> io::Result<NcryptKey> {
+        if let Some(export_policy) = self.export_policy {
+            let res = set_dword(key.0,
+                                NCRYPT_EXPORT_POLICY_PROPERTY,
+                                export_policy.0,
+                                ffi::NCRYPT_PERSIST_FLAG);
+            match res {
+                // the Platform Crypto Provider doesn't support the property,
+                // but its keys can never be exported anyway
+                Err(ref e) if ::error::code(e) == Some(ffi::NTE_NOT_SUPPORTED) &&
+                              export_policy == ExportPolicy::none() => {}
+                res => try!(res),
+            }
+        }
+        if let Some(key_usage) = self.key_usage {
+            try!(set_dword(key.0, NCRYPT_KEY_USAGE_PROPERTY, key_usage.0, ffi::NCRYPT_PERSIST_FLAG));
+        }
+        if let Some(ref security_descriptor) = self.security_descriptor {
+            try!(set_property(key.0,
+                              NCRYPT_SECURITY_DESCR_PROPERTY,
+                              security_descriptor,
+                              ffi::DACL_SECURITY_INFORMATION));
+        }
+
+        try!(check(ffi::NCryptFinalizeKey(key.0, 0)));
+        Ok(key)
+    }
+
+    fn map_err(&self, res: io::Result<NcryptKey>) -> io::Result<NcryptKey> {
+        res.map_err(|e| {
+            match ::error::code(&e) {
+                Some(ffi::E_ACCESSDENIED) |
+                Some(ffi::NTE_PERM) if self.flags & ffi::NCRYPT_MACHINE_KEY_FLAG != 0 => {
+                    io::Error::new(io::ErrorKind::PermissionDenied, e)
+                }
+                _ => e,
+            }
+        })
+    }
+}
+
+/// Returns the key storage providers installed on this machine.
+pub fn providers() -> io::Result<Vec<ProviderName>> {
+    unsafe {
+        let mut count = 0;
+        let mut list = ptr::null_mut();
+        try!(check(ffi::NCryptEnumStorageProviders(&mut count, &mut list, 0)));
+
+        let providers = slice::from_raw_parts(list, count as usize)
+            .iter()
+            .map(|prov| {
+                ProviderName {
+                    name: ::from_wide_ptr(prov.pszName),
+                    comment: if prov.pszComment.is_null() {
+                        None
+                    } else {
+                        Some(::from_wide_ptr(prov.pszComment))
+                    },
+                }
+            })
+            .collect();
+        ffi::NCryptFreeBuffer(list as ffi::PVOID);
+        Ok(providers)
+    }
+}
+
+/// The name of an installed key storage provider.
+pub struct ProviderName {
+    name: String,
+    comment: Option<String>,
+}
+
+impl ProviderName {
+    /// Returns the name of the provider, which can be passed to
+    /// `NcryptProvider::open`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the description of the provider, if it has one.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_ref().map(|s| &s[..])
+    }
+}
+
+/// Builds a self-relative security descriptor granting full control of a key
+/// to the local system, administrators and the key's owner, and read access
+/// to the account identified by the string SID `sid`, such as `S-1-5-20`.
+pub fn grant_read_descriptor(sid: &str) -> io::Result<Vec<u8>> {
+    let sddl = format!("D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;GA;;;OW)(A;;GR;;;{})", sid);
+    unsafe {
+        let sddl = wide(&sddl);
+        let mut sd = ptr::null_mut();
+        let mut len = 0;
+        let res = ffi::ConvertStringSecurityDescriptorToSecurityDescriptorW(sddl.as_ptr(),
+                                                                            ffi::SDDL_REVISION_1,
+                                                                            &mut sd,
+                                                                            &mut len);
+        if res != ffi::TRUE {
+            return Err(::error::last_error());
+        }
+        let buf = slice::from_raw_parts(sd as *const u8, len as usize).to_owned();
+        ffi::LocalFree(sd);
+        Ok(buf)
+    }
+}
+
+/// A CNG handle to a key storage provider.
+pub struct NcryptProvider(ffi::NCRYPT_PROV_HANDLE);
+
+unsafe impl Send for NcryptProvider {}
+
+impl Drop for NcryptProvider {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::NCryptFreeObject(self.0);
+        }
+    }
+}
+
+inner!(NcryptProvider, ffi::NCRYPT_PROV_HANDLE);
+
+impl NcryptProvider {
+    /// Opens the key storage provider with the specified name.
+    pub fn open(name: &str) -> io::Result<NcryptProvider> {
+        unsafe {
+            let name = wide(name);
+            let mut prov = 0;
+            try!(check(ffi::NCryptOpenStorageProvider(&mut prov, name.as_ptr(), 0)));
+            Ok(NcryptProvider(prov))
+        }
+    }
+
+    /// Opens the Microsoft Software Key Storage Provider.
+    pub fn software() -> io::Result<NcryptProvider> {
+        NcryptProvider::open(MS_KEY_STORAGE_PROVIDER)
+    }
+
+    /// Opens the Microsoft Platform Crypto Provider, which stores keys in the
+    /// TPM.
+    ///
+    /// Keys held by this provider can never be exported, and operations on
+    /// them are considerably slower than on software keys. Only the key sizes
+    /// supported by the TPM can be created, which is typically 2048 bit RSA
+    /// and ECDSA P-256 for TPM 2.0, and 2048 bit RSA for TPM 1.2.
+    pub fn platform_crypto() -> io::Result<NcryptProvider> {
+        NcryptProvider::open(MS_PLATFORM_CRYPTO_PROVIDER)
+    }
+
+    /// Determines if the Platform Crypto Provider can be used on this
+    /// machine, which requires a TPM.
+    pub fn platform_crypto_available() -> bool {
+        NcryptProvider::platform_crypto().is_ok()
+    }
+
+    /// Opens the Microsoft Smart Card Key Storage Provider.
+    pub fn smart_card() -> io::Result<NcryptProvider> {
+        NcryptProvider::open(MS_SMART_CARD_KEY_STORAGE_PROVIDER)
+    }
+
+    /// Returns the name of this provider.
+    pub fn name(&self) -> io::Result<String> {
+        try!(get_string(self.0, NCRYPT_NAME_PROPERTY))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "provider has no name"))
+    }
+
+    /// Returns a Platform Crypto Provider property of this provider, such as
+    /// the public portion of the TPM's endorsement key.
+    ///
+    /// Returns `None` if this is not the Platform Crypto Provider.
+    pub fn pcp_property(&self, property: PcpProperty) -> io::Result<Option<Vec<u8>>> {
+        get_property(self.0, property.0, 0)
+    }
+
+    /// Returns the names of the key algorithms supported by this provider,
+    /// such as `RSA` or `ECDSA_P256`.
+    pub fn algorithms(&self) -> io::Result<Vec<String>> {
+        unsafe {
+            let mut count = 0;
+            let mut list = ptr::null_mut();
+            try!(check(ffi::NCryptEnumAlgorithms(self.0, 0, &mut count, &mut list, 0)));
+
+            let names = slice::from_raw_parts(list, count as usize)
+                .iter()
+                .map(|alg| ::from_wide_ptr(alg.pszName))
+                .collect();
+            ffi::NCryptFreeBuffer(list as ffi::PVOID);
+            Ok(names)
+        }
+    }
+
+    /// Returns the names of the keys held by this provider for the current
+    /// user, or for the machine if `machine` is set.
+    pub fn keys(&self, machine: bool) -> io::Result<Vec<KeyName>> {
+        unsafe {
+            let flags = if machine { ffi::NCRYPT_MACHINE_KEY_FLAG } else { 0 } | ffi::NCRYPT_SILENT_FLAG;
+            let mut state = ptr::null_mut();
+            let mut keys = vec![];
+            let mut res = Ok(());
+            loop {
+                let mut name = ptr::null_mut();
+                match ffi::NCryptEnumKeys(self.0, ptr::null(), &mut name, &mut state, flags) {
+                    ffi::NTE_NO_MORE_ITEMS => break,
+                    status if status != ffi::ERROR_SUCCESS as ffi::SECURITY_STATUS => {
+                        res = Err(::error::from_code(status));
+                        break;
+                    }
+                    _ => {}
+                }
+                keys.push(KeyName {
+                    name: ::from_wide_ptr((*name).pszName),
+                    algorithm: ::from_wide_ptr((*name).pszAlgid),
+                    key_spec: match (*name).dwLegacyKeySpec {
+                        ffi::AT_KEYEXCHANGE => Some(KeySpec::key_exchange()),
+                        ffi::AT_SIGNATURE => Some(KeySpec::signature()),
+                        _ => None,
+                    },
+                });
+                ffi::NCryptFreeBuffer(name as ffi::PVOID);
+            }
+            if !state.is_null() {
+                ffi::NCryptFreeBuffer(state);
+            }
+            res.map(|()| keys)
+        }
+    }
+
+    /// Sets the name of the smart card reader subsequent operations with this
+    /// provider will use.
+    pub fn set_reader(&self, reader: &str) -> io::Result<()> {
+        let reader = wide(reader);
+        let bytes = unsafe {
+            slice::from_raw_parts(reader.as_ptr() as *const u8, reader.len() * 2)
+        };
+        set_property(self.0, NCRYPT_READER_PROPERTY, bytes, 0)
+    }
+
+    /// Sets the GUID of the smart card subsequent operations with this
+    /// provider will use.
+    pub fn set_smart_card_guid(&self, guid: &[u8; 16]) -> io::Result<()> {
+        set_property(self.0, NCRYPT_SMARTCARD_GUID_PROPERTY, guid, 0)
+    }
+}
+
+/// The name of a key held by a key storage provider.
+pub struct KeyName {
+    name: String,
+    algorithm: String,
+    key_spec: Option<KeySpec>,
+}
+
+impl KeyName {
+    /// Returns the name of the key, which can be passed to
+    /// `OpenOptions::open`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the name of the algorithm of the key, such as `RSA`.
+    pub fn algorithm(&self) -> &str {
+        &self.algorithm
+    }
+
+    /// Returns the CryptoAPI key specification of the key, if it has one.
+    pub fn key_spec(&self) -> Option<KeySpec> {
+        self.key_spec
+    }
+}
+
+/// Calls `f` with `provider`, or with the Microsoft Software Key Storage
+/// Provider if none was specified.
+fn with_provider<T, F>(provider: Option<&NcryptProvider>, f: F) -> io::Result<T>
+    where F: FnOnce(&NcryptProvider) -> io::Result<T>
+{
+    match provider {
+        Some(prov) => f(prov),
+        None => f(&try!(NcryptProvider::software())),
+    }
+}
+
+/// Reads a property of a CNG object, returning `None` if the object does not
+/// support it.
+fn get_property(handle: ffi::NCRYPT_HANDLE,
+                name: &str,
+                flags: ffi::DWORD)
+                -> io::Result<Option<Vec<u8>>> {
+    unsafe {
+        let name = wide(name);
+        let mut len = 0;
+        let status = ffi::NCryptGetProperty(handle, name.as_ptr(), ptr::null_mut(), 0, &mut len, flags);
+        match status {
+            ffi::NTE_NOT_SUPPORTED | ffi::NTE_NOT_FOUND => return Ok(None),
+            status => try!(check(status)),
+        }
+
+        let mut buf = vec![0; len as usize];
+        try!(check(ffi::NCryptGetProperty(handle,
+                                          name.as_ptr(),
+                                          buf.as_mut_ptr(),
+                                          len,
+                                          &mut len,
+                                          flags)));
+        buf.truncate(len as usize);
+        Ok(Some(buf))
+    }
+}
+
+fn set_property(handle: ffi::NCRYPT_HANDLE,
+                name: &str,
+                value: &[u8],
+                flags: ffi::DWORD)
+                -> io::Result<()> {
+    unsafe {
+        let name = wide(name);
+        check(ffi::NCryptSetProperty(handle,
+                                     name.as_ptr(),
+                                     value.as_ptr() as *mut _,
+                                     try!(::dword_len(value.len())),
+                                     flags))
+    }
+}
+
+fn set_dword(handle: ffi::NCRYPT_HANDLE,
+             name: &str,
+             value: ffi::DWORD,
+             flags: ffi::DWORD)
+             -> io::Result<()> {
+    let bytes = [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+    set_property(handle, name, &bytes, flags)
+}
+
+fn get_dword(handle: ffi::NCRYPT_HANDLE, name: &str) -> io::Result<Option<ffi::DWORD>> {
+    match try!(get_property(handle, name, 0)) {
+        Some(ref buf) if buf.len() == 4 => {
+            Ok(Some(buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 |
+                    (buf[3] as u32) << 24))
+        }
+        Some(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "property is not a DWORD")),
+        None => Ok(None),
+    }
+}
+
+fn get_string(handle: ffi::NCRYPT_HANDLE, name: &str) -> io::Result<Option<String>> {
+    let buf = match try!(get_property(handle, name, 0)) {
+        Some(buf) => buf,
+        None => return Ok(None),
+    };
+    ::wide_bytes_to_string(buf).map(Some)
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+fn check(status: ffi::SECURITY_STATUS) -> io::Result<()> {
+    if status == ffi::ERROR_SUCCESS as ffi::SECURITY_STATUS {
+        Ok(())
+    } else {
+        Err(::error::from_code(status))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ffi;
+    use std::ptr;
+
+    use Inner;
+    use cert_context::{CertContext, EcdsaFormat, RsaPadding};
+    use super::*;
+
+    const SHA256_RSA: &'static [u8] = b"1.2.840.113549.1.1.11\0";
+    const SHA256_ECDSA: &'static [u8] = b"1.2.840.10045.4.3.2\0";
+
+    fn self_signed(key: &NcryptKey, signature_algorithm: &[u8]) -> CertContext {
+        unsafe {
+            let name = wide("CN=schannel-rs test");
+            let mut len = 0;
+            let res = ffi::CertStrToNameW(ffi::X509_ASN_ENCODING,
+                                          name.as_ptr(),
+                                          ffi::CERT_X500_NAME_STR,
+                                          ptr::null_mut(),
+                                          ptr::null_mut(),
+                                          &mut len,
+                                          ptr::null_mut());
+            assert_eq!(res, ffi::TRUE);
+            let mut encoded = vec![0u8; len as usize];
+            let res = ffi::CertStrToNameW(ffi::X509_ASN_ENCODING,
+                                          name.as_ptr(),
+                                          ffi::CERT_X500_NAME_STR,
+                                          ptr::null_mut(),
+                                          encoded.as_mut_ptr(),
+                                          &mut len,
+                                          ptr::null_mut());
+            assert_eq!(res, ffi::TRUE);
+
+            let mut subject = ffi::CERT_NAME_BLOB {
+                cbData: len,
+                pbData: encoded.as_mut_ptr(),
+            };
+            let mut algorithm: ffi::CRYPT_ALGORITHM_IDENTIFIER = mem::zeroed();
+            algorithm.pszObjId = signature_algorithm.as_ptr() as *mut _;
+
+            let cert = ffi::CertCreateSelfSignCertificate(key.0,
+                                                          &mut subject,
+                                                          0,
+                                                          ptr::null_mut(),
+                                                          &mut algorithm,
+                                                          ptr::null_mut(),
+                                                          ptr::null_mut(),
+                                                          ptr::null_mut());
+            assert!(!cert.is_null());
+            CertContext::from_inner(cert)
+        }
+    }
+
+    #[test]
+    fn raw() {
+        let key = CreateOptions::new().create(KeyAlgorithm::ecdsa_p256()).unwrap();
+        let spki = key.export_public_spki().unwrap();
+
+        let clone = key.clone();
+        let key = key.into_raw().unwrap_err();
+        drop(clone);
+        let raw = match key.into_raw() {
+            Ok(raw) => raw,
+            Err(_) => panic!("expected the key to no longer be shared"),
+        };
+        let key = unsafe { NcryptKey::from_raw(raw) };
+        assert_eq!(key.as_raw(), raw);
+        assert_eq!(key.export_public_spki().unwrap(), spki);
+
+        let pfx = include_bytes!("../test/identity.p12");
+        let (_, key) = ::cert_store::pfx_to_cert_and_key(pfx, "mypass").unwrap();
+        assert!(key.into_raw().is_err());
+    }
+
+    #[test]
+    fn export_public_spki_rsa() {
+        let key = CreateOptions::new().length(2048).create(KeyAlgorithm::rsa()).unwrap();
+        let cert = self_signed(&key, SHA256_RSA);
+        assert_eq!(key.export_public_spki().unwrap(), cert.public_key().unwrap());
+    }
+
+    #[test]
+    fn export_public_spki_ecdsa() {
+        let key = CreateOptions::new().create(KeyAlgorithm::ecdsa_p256()).unwrap();
+        let cert = self_signed(&key, SHA256_ECDSA);
+        assert_eq!(key.export_public_spki().unwrap(), cert.public_key().unwrap());
+    }
+
+    #[test]
+    fn delete() {
+        let name = "schannel-rs-test-ncrypt-delete";
+        let key = CreateOptions::new()
+            .name(name)
+            .overwrite(true)
+            .create(KeyAlgorithm::rsa())
+            .unwrap();
+        key.delete().unwrap();
+
+        let err = OpenOptions::new().silent(true).open(name).err().unwrap();
+        assert_eq!(::error::code(&err), Some(ffi::NTE_BAD_KEYSET));
+    }
+
+    #[test]
+    fn sign_rsa() {
+        let key = CreateOptions::new().length(2048).create(KeyAlgorithm::rsa()).unwrap();
+        let cert = self_signed(&key, SHA256_RSA);
+        let key = PrivateKey::NcryptKey(key);
+
+        for &padding in &[Padding::Pkcs1, Padding::Pss(32)] {
+            let sig = key.sign(b"hello", HashAlgorithm::sha256(), padding).unwrap();
+            assert_eq!(sig.len(), 256);
+            assert!(cert.verify_signature(b"hello", HashAlgorithm::sha256(), padding, &sig)
+                .unwrap());
+            assert!(!cert.verify_signature(b"world", HashAlgorithm::sha256(), padding, &sig)
+                .unwrap());
+        }
+    }
+
+    #[test]
+    fn sign_with_key() {
+        let name = "schannel-rs-test-ncrypt-sign";
+        let key = CreateOptions::new()
+            .name(name)
+            .overwrite(true)
+            .length(2048)
+            .create(KeyAlgorithm::rsa())
+            .unwrap();
+        let cert = self_signed(&key, SHA256_RSA);
+        assert_eq!(key.key_name().unwrap(), Some(name.to_owned()));
+        assert_eq!(key.algorithm_group().unwrap(), Some("RSA".to_owned()));
+        assert_eq!(key.length().unwrap(), Some(2048));
+
+        let key = OpenOptions::new().silent(true).open(name).unwrap();
+        let digest = ::hash::hash(HashAlgorithm::sha256(), b"hello").unwrap();
+        for &padding in &[Padding::Pkcs1, Padding::Pss(32)] {
+            let sig = key.sign_digest(&digest, HashAlgorithm::sha256(), padding).unwrap();
+            assert!(cert.verify_signature(b"hello", HashAlgorithm::sha256(), padding, &sig)
+                .unwrap());
+            let sig = key.sign(b"world", HashAlgorithm::sha256(), padding).unwrap();
+            assert!(cert.verify_signature(b"world", HashAlgorithm::sha256(), padding, &sig)
+                .unwrap());
+        }
+        key.delete().unwrap();
+
+        let key = CreateOptions::new().create(KeyAlgorithm::ecdsa_p256()).unwrap();
+        assert_eq!(key.key_name().unwrap(), None);
+    }
+
+    #[test]
+    fn clone() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<NcryptKey>();
+
+        let key = CreateOptions::new().length(2048).create(KeyAlgorithm::rsa()).unwrap();
+        let cert = self_signed(&key, SHA256_RSA);
+        let clone = key.clone();
+        assert!(key.delete().is_err());
+
+        let key = PrivateKey::NcryptKey(clone);
+        let sig = key.sign(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1).unwrap();
+        assert!(cert.verify_signature(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1, &sig)
+            .unwrap());
+    }
+
+    #[test]
+    fn sign_ecdsa() {
+        let key = CreateOptions::new().create(KeyAlgorithm::ecdsa_p256()).unwrap();
+        let cert = self_signed(&key, SHA256_ECDSA);
+        let key = PrivateKey::NcryptKey(key);
+
+        let raw = Padding::Ecdsa(EcdsaFormat::Raw);
+        let sig = key.sign(b"hello", HashAlgorithm::sha256(), raw).unwrap();
+        assert_eq!(sig.len(), 64);
+        assert!(cert.verify_signature(b"hello", HashAlgorithm::sha256(), raw, &sig).unwrap());
+
+        let der = Padding::Ecdsa(EcdsaFormat::Der);
+        let sig = key.sign(b"hello", HashAlgorithm::sha256(), der).unwrap();
+        assert_eq!(sig[0], 0x30);
+        assert!(cert.verify_signature(b"hello", HashAlgorithm::sha256(), der, &sig).unwrap());
+        assert!(!cert.verify_signature(b"world", HashAlgorithm::sha256(), der, &sig).unwrap());
+    }
+
+    #[test]
+    fn decrypt() {
+        let key = CreateOptions::new().length(2048).create(KeyAlgorithm::rsa()).unwrap();
+        let cert = self_signed(&key, SHA256_RSA);
+        let key = PrivateKey::NcryptKey(key);
+
+        let paddings = [RsaPadding::Pkcs1,
+                        RsaPadding::Oaep(HashAlgorithm::sha1()),
+                        RsaPadding::Oaep(HashAlgorithm::sha256())];
+        for padding in &paddings {
+            let ciphertext = cert.encrypt(b"hello", padding.clone()).unwrap();
+            assert_eq!(ciphertext.len(), 256);
+            assert_eq!(key.decrypt(&ciphertext, padding.clone()).unwrap(), b"hello");
+        }
+
+        let ciphertext = cert.encrypt(b"hello", paddings[2].clone()).unwrap();
+        assert!(key.decrypt(&ciphertext, paddings[0].clone()).is_err());
+        assert!(key.decrypt(&ciphertext, paddings[1].clone()).is_err());
+    }
+
+    #[test]
+    fn properties() {
+        let name = "schannel-rs-test-ncrypt-properties";
+        let key = CreateOptions::new()
+            .name(name)
+            .length(2048)
+            .overwrite(true)
+            .create(KeyAlgorithm::rsa())
+            .unwrap();
+
+        assert_eq!(key.algorithm_group().unwrap().unwrap(), "RSA");
+        assert_eq!(key.length().unwrap(), Some(2048));
+        let policy = key.export_policy().unwrap().unwrap();
+        assert!(!policy.contains(ExportPolicy::plaintext_export()));
+        let usage = key.key_usage().unwrap().unwrap();
+        assert!(usage.contains(NcryptKeyUsage::signing() | NcryptKeyUsage::decrypt()));
+        assert_eq!(key.provider_name().unwrap().unwrap(), MS_KEY_STORAGE_PROVIDER);
+        assert_eq!(key.is_hardware_backed().unwrap(), Some(false));
+        assert!(!key.security_descriptor().unwrap().unwrap().is_empty());
+        assert_eq!(get_property(key.0, "schannel-rs unknown", 0).unwrap(), None);
+
+        key.delete().unwrap();
+    }
+
+    #[test]
+    fn raw_property() {
+        let key = CreateOptions::new().length(2048).create(KeyAlgorithm::rsa()).unwrap();
+
+        let length = key.get_raw_property(NCRYPT_LENGTH_PROPERTY).unwrap().unwrap();
+        assert_eq!(length, [0, 8, 0, 0]);
+        assert_eq!(key.length().unwrap(), Some(2048));
+        assert_eq!(key.get_raw_property("schannel-rs unknown").unwrap(), None);
+
+        let context = "schannel-rs".encode_utf16()
+            .chain(Some(0))
+            .flat_map(|c| vec![c as u8, (c >> 8) as u8])
+            .collect::<Vec<_>>();
+        key.set_raw_property(NCRYPT_USE_CONTEXT_PROPERTY, &context, 0).unwrap();
+        assert_eq!(key.get_raw_property(NCRYPT_USE_CONTEXT_PROPERTY).unwrap(),
+                   Some(context));
+    }
+
+    #[test]
+    fn export_policy() {
+        let key = CreateOptions::new()
+            .export_policy(ExportPolicy::none())
+            .create(KeyAlgorithm::rsa())
+            .unwrap();
+        assert_eq!(key.export_policy().unwrap(), Some(ExportPolicy::none()));
+        assert!(key.export_pkcs8().is_err());
+
+        let policy = ExportPolicy::export() | ExportPolicy::plaintext_export();
+        let key = CreateOptions::new()
+            .export_policy(policy)
+            .create(KeyAlgorithm::rsa())
+            .unwrap();
+        assert_eq!(key.export_policy().unwrap(), Some(policy));
+        key.export_pkcs8().unwrap();
+    }
+
+    #[test]
+    fn key_usage() {
+        let key = CreateOptions::new()
+            .length(2048)
+            .key_usage(NcryptKeyUsage::signing())
+            .create(KeyAlgorithm::rsa())
+            .unwrap();
+        assert_eq!(key.key_usage().unwrap(), Some(NcryptKeyUsage::signing()));
+        let cert = self_signed(&key, SHA256_RSA);
+        let key = PrivateKey::NcryptKey(key);
+
+        key.sign(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1).unwrap();
+        let ciphertext = cert.encrypt(b"hello", RsaPadding::Pkcs1).unwrap();
+        assert!(key.decrypt(&ciphertext, RsaPadding::Pkcs1).is_err());
+    }
+
+    #[test]
+    fn security_descriptor() {
+        // NETWORK SERVICE
+        let sd = grant_read_descriptor("S-1-5-20").unwrap();
+        let name = "schannel-rs-test-ncrypt-security-descriptor";
+        let key = CreateOptions::new()
+            .name(name)
+            .overwrite(true)
+            .security_descriptor(&sd)
+            .create(KeyAlgorithm::rsa())
+            .unwrap();
+        assert!(!key.security_descriptor().unwrap().unwrap().is_empty());
+        drop(key);
+
+        let key = OpenOptions::new().silent(true).open(name).unwrap();
+        key.delete().unwrap();
+
+        assert!(grant_read_descriptor("not a sid").is_err());
+    }
+
+    #[test]
+    #[ignore] // requires administrator rights
+    fn machine_key() {
+        let name = "schannel-rs-test-ncrypt-machine-key";
+        let key = CreateOptions::new()
+            .name(name)
+            .overwrite(true)
+            .machine_key(true)
+            .create(KeyAlgorithm::rsa())
+            .unwrap();
+        drop(key);
+
+        assert!(OpenOptions::new().silent(true).open(name).is_err());
+        let key = OpenOptions::new().silent(true).machine_key(true).open(name).unwrap();
+        key.delete().unwrap();
+    }
+
+    #[test]
+    fn provider() {
+        let prov = NcryptProvider::software().unwrap();
+        assert_eq!(prov.name().unwrap(), MS_KEY_STORAGE_PROVIDER);
+        let algorithms = prov.algorithms().unwrap();
+        assert!(algorithms.iter().any(|a| a == "RSA"));
+        assert!(algorithms.iter().any(|a| a == "ECDSA_P256"));
+
+        let key = CreateOptions::new().provider(&prov).create(KeyAlgorithm::ecdsa_p256()).unwrap();
+        assert_eq!(key.provider_name().unwrap().unwrap(), MS_KEY_STORAGE_PROVIDER);
+    }
+
+    #[test]
+    #[ignore] // requires a smart card reader
+    fn smart_card_provider() {
+        let prov = NcryptProvider::smart_card().unwrap();
+        assert_eq!(prov.name().unwrap(), MS_SMART_CARD_KEY_STORAGE_PROVIDER);
+        assert!(!prov.algorithms().unwrap().is_empty());
+    }
+
+    #[test]
+    fn platform_crypto() {
+        if !NcryptProvider::platform_crypto_available() {
+            return;
+        }
+        let prov = NcryptProvider::platform_crypto().unwrap();
+        assert!(prov.pcp_property(PcpProperty::platform_type()).unwrap().is_some());
+
+        let key = CreateOptions::new()
+            .provider(&prov)
+            .length(2048)
+            .export_policy(ExportPolicy::none())
+            .create(KeyAlgorithm::rsa())
+            .unwrap();
+        assert_eq!(key.is_hardware_backed().unwrap(), Some(true));
+        assert!(key.export_pkcs8().is_err());
+
+        let cert = self_signed(&key, SHA256_RSA);
+        let key = PrivateKey::NcryptKey(key);
+        let sig = key.sign(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1).unwrap();
+        assert!(cert.verify_signature(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1, &sig)
+            .unwrap());
+    }
+
+    #[test]
+    fn pcp_property_software() {
+        let prov = NcryptProvider::software().unwrap();
+        assert_eq!(prov.pcp_property(PcpProperty::platform_type()).unwrap(), None);
+    }
+
+    #[test]
+    fn keys() {
+        let names = ["schannel-rs-test-ncrypt-keys-1", "schannel-rs-test-ncrypt-keys-2"];
+        let created = names.iter()
+            .map(|name| {
+                CreateOptions::new()
+                    .name(name)
+                    .overwrite(true)
+                    .create(KeyAlgorithm::ecdsa_p256())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let keys = NcryptProvider::software().unwrap().keys(false).unwrap();
+        for name in &names {
+            let key = keys.iter().find(|k| k.name() == *name).unwrap();
+            assert_eq!(key.algorithm(), "ECDSA_P256");
+            assert!(key.key_spec().is_none());
+        }
+        assert!(keys.iter().filter(|k| k.algorithm() == "ECDSA_P256").count() >= 2);
+
+        for key in created {
+            key.delete().unwrap();
+        }
+    }
+
+    #[test]
+    fn enumerate_providers() {
+        let providers = providers().unwrap();
+        assert!(providers.iter().any(|p| p.name() == MS_KEY_STORAGE_PROVIDER));
+    }
+
+    #[test]
+    fn ui_policy() {
+        let mut key = CreateOptions::new().create(KeyAlgorithm::ecdsa_p256()).unwrap();
+        assert!(!key.is_silent());
+
+        key.set_ui_policy(None, Some("schannel-rs test"), true).unwrap();
+        assert!(key.is_silent());
+        assert_eq!(get_string(key.0, NCRYPT_USE_CONTEXT_PROPERTY).unwrap().unwrap(),
+                   "schannel-rs test");
+
+        let key = PrivateKey::NcryptKey(key);
+        let raw = Padding::Ecdsa(EcdsaFormat::Raw);
+        key.sign(b"hello", HashAlgorithm::sha256(), raw).unwrap();
+    }
+
+    #[test]
+    #[ignore] // requires a PIN protected smart card
+    fn ui_policy_smart_card() {
+        let prov = NcryptProvider::smart_card().unwrap();
+        let name = &prov.keys(false).unwrap()[0];
+        let mut key = OpenOptions::new().provider(&prov).open(name.name()).unwrap();
+        key.set_ui_policy(None, None, true).unwrap();
+
+        let key = PrivateKey::NcryptKey(key);
+        let err = key.sign(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1).err().unwrap();
+        assert_eq!(::error::code(&err), Some(ffi::SCARD_W_CARD_NOT_AUTHENTICATED));
+    }
+
+    #[test]
+    fn export_import_pkcs8() {
+        let key = CreateOptions::new()
+            .length(2048)
+            .export_policy(ExportPolicy::plaintext_export())
+            .create(KeyAlgorithm::rsa())
+            .unwrap();
+        let pkcs8 = key.export_pkcs8().unwrap();
+        assert_eq!(pkcs8[0], 0x30);
+
+        let name = "schannel-rs-test-ncrypt-import-pkcs8";
+        let imported = CreateOptions::new()
+            .name(name)
+            .overwrite(true)
+            .import_pkcs8(&pkcs8)
+            .unwrap();
+        assert_eq!(imported.export_public_spki().unwrap(), key.export_public_spki().unwrap());
+
+        let cert = self_signed(&key, SHA256_RSA);
+        let key = PrivateKey::NcryptKey(key);
+        let imported = PrivateKey::NcryptKey(imported);
+        for key in &[key, imported] {
+            let sig = key.sign(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1).unwrap();
+            assert!(cert.verify_signature(b"hello", HashAlgorithm::sha256(), Padding::Pkcs1, &sig)
+                .unwrap());
+        }
+
+        OpenOptions::new().open(name).unwrap().delete().unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+//! Detection of features which depend on the version of Windows.
+use ffi;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ptr;
+#[cfg(test)]
+use std::cell::Cell;
+
+/// A feature which is only available on some versions of Windows.
+///
+/// More variants may be added in the future, so matches should include a
+/// wildcard arm.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// TLS 1.1 and 1.2.
+    Tls12,
+    /// PKCS #12 imports which keep private keys in memory only.
+    Pkcs12NoPersistKey,
+    /// The cached SHA-256 thumbprint of a certificate, which certificates can
+    /// be looked up by.
+    Sha256Find,
+    /// Application-Layer Protocol Negotiation.
+    Alpn,
+    /// The `SECPKG_ATTR_CIPHER_INFO` attribute, which names the cipher suite
+    /// of a connection.
+    CipherInfo,
+    /// Export of keying material from a connection.
+    KeyingMaterial,
+    /// Credentials described by `SCH_CREDENTIALS` rather than
+    /// `SCHANNEL_CRED`.
+    SchCredentials,
+    /// Certificate chain engines which only trust the roots of a given store.
+    ExclusiveRoot,
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+const FEATURES: [Feature; 8] = [Feature::Tls12,
+                                Feature::Pkcs12NoPersistKey,
+                                Feature::Sha256Find,
+                                Feature::Alpn,
+                                Feature::CipherInfo,
+                                Feature::KeyingMaterial,
+                                Feature::SchCredentials,
+                                Feature::ExclusiveRoot];
+
+impl Feature {
+    /// Returns the name of the earliest version of Windows supporting this
+    /// feature.
+    pub fn minimum_version(&self) -> &'static str {
+        match *self {
+            Feature::CipherInfo => "Windows Vista",
+            Feature::Tls12 | Feature::ExclusiveRoot => "Windows 7",
+            Feature::Pkcs12NoPersistKey | Feature::Sha256Find => "Windows 8",
+            Feature::Alpn => "Windows 8.1",
+            Feature::KeyingMaterial => "Windows 10",
+            Feature::SchCredentials => "Windows 10 version 1809",
+            Feature::__Nonexhaustive => "an unknown version of Windows",
+        }
+    }
+
+    fn minimum(&self) -> (u32, u32, u32) {
+        match *self {
+            Feature::CipherInfo => (6, 0, 0),
+            Feature::Tls12 | Feature::ExclusiveRoot => (6, 1, 0),
+            Feature::Pkcs12NoPersistKey | Feature::Sha256Find => (6, 2, 0),
+            Feature::Alpn => (6, 3, 0),
+            Feature::KeyingMaterial => (10, 0, 0),
+            Feature::SchCredentials => (10, 0, 17763),
+            Feature::__Nonexhaustive => (!0, !0, !0),
+        }
+    }
+
+    fn bit(&self) -> u32 {
+        FEATURES.iter().position(|f| f == self).map_or(0, |i| 1 << i)
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match *self {
+            Feature::Tls12 => "TLS 1.1 and 1.2",
+            Feature::Pkcs12NoPersistKey => "importing PKCS #12 keys without persisting them",
+            Feature::Sha256Find => "SHA-256 certificate thumbprints",
+            Feature::Alpn => "ALPN",
+            Feature::CipherInfo => "cipher suite information",
+            Feature::KeyingMaterial => "keying material export",
+            Feature::SchCredentials => "SCH_CREDENTIALS",
+            Feature::ExclusiveRoot => "exclusive trust stores",
+            Feature::__Nonexhaustive => "an unknown feature",
+        })
+    }
+}
+
+/// The features supported by the running version of Windows.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct OsCapabilities {
+    version: Option<(u32, u32, u32)>,
+    disabled: u32,
+}
+
+impl OsCapabilities {
+    /// Returns the major version, minor version and build number of Windows.
+    ///
+    /// This is `(0, 0, 0)` if the version could not be detected, in which
+    /// case every feature is assumed to be available and left to the OS to
+    /// reject.
+    pub fn version(&self) -> (u32, u32, u32) {
+        self.version.unwrap_or((0, 0, 0))
+    }
+
+    /// Determines if `feature` is available.
+    pub fn supports(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::__Nonexhaustive => false,
+            _ => {
+                self.disabled & feature.bit() == 0 &&
+                self.version.map_or(true, |v| v >= feature.minimum())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for OsCapabilities {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = fmt.debug_struct("OsCapabilities");
+        s.field("version", &self.version());
+        for feature in &FEATURES {
+            s.field(&format!("{:?}", feature), &self.supports(*feature));
+        }
+        s.finish()
+    }
+}
+
+lazy_static! {
+    static ref VERSION: Option<(u32, u32, u32)> = unsafe { detect_version() };
+}
+
+// `GetVersionEx` reports the version an application was manifested for, so
+// the real one is read with `RtlGetVersion`, which isn't in any import library.
+unsafe fn detect_version() -> Option<(u32, u32, u32)> {
+    type RtlGetVersion = unsafe extern "system" fn(*mut ffi::OSVERSIONINFOW) -> ffi::NTSTATUS;
+
+    let ntdll = "ntdll.dll".encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+    let module = ffi::GetModuleHandleW(ntdll.as_ptr());
+    let f = if module.is_null() {
+        ptr::null()
+    } else {
+        ffi::proc_address(module, b"RtlGetVersion\0".as_ptr() as ffi::LPCSTR)
+    };
+    if f.is_null() {
+        debug!("RtlGetVersion is unavailable; assuming all features are available");
+        return None;
+    }
+
+    let f: RtlGetVersion = mem::transmute(f);
+    let mut info: ffi::OSVERSIONINFOW = mem::zeroed();
+    info.dwOSVersionInfoSize = mem::size_of::<ffi::OSVERSIONINFOW>() as ffi::DWORD;
+    if f(&mut info) != 0 {
+        debug!("RtlGetVersion failed; assuming all features are available");
+        return None;
+    }
+    let version = (info.dwMajorVersion, info.dwMinorVersion, info.dwBuildNumber);
+    debug!("detected Windows {}.{}.{}", version.0, version.1, version.2);
+    Some(version)
+}
+
+#[cfg(test)]
+thread_local!(static DISABLED: Cell<u32> = Cell::new(0));
+
+/// Returns the features supported by the running version of Windows.
+///
+/// The version is detected once per process. Functions relying on a feature
+/// which is missing either fall back to an older mechanism, or fail with an
+/// error wrapping an `UnsupportedFeature`.
+pub fn os_capabilities() -> OsCapabilities {
+    #[cfg(not(test))]
+    let disabled = 0;
+    #[cfg(test)]
+    let disabled = DISABLED.with(|d| d.get());
+
+    OsCapabilities {
+        version: *VERSION,
+        disabled: disabled,
+    }
+}
+
+/// Fails with an `UnsupportedFeature` error unless `feature` is available.
+pub fn require(feature: Feature) -> io::Result<()> {
+    if os_capabilities().supports(feature) {
+        Ok(())
+    } else {
+        debug!("{} requires {} or later", feature, feature.minimum_version());
+        Err(::error::unsupported(feature))
+    }
+}
+
+/// Makes `feature` appear unsupported on the current thread until the
+/// returned guard is dropped.
+#[cfg(test)]
+pub fn disable(feature: Feature) -> Disabled {
+    let old = DISABLED.with(|d| {
+        let old = d.get();
+        d.set(old | feature.bit());
+        old
+    });
+    Disabled(old)
+}
+
+#[cfg(test)]
+pub struct Disabled(u32);
+
+#[cfg(test)]
+impl Drop for Disabled {
+    fn drop(&mut self) {
+        DISABLED.with(|d| d.set(self.0));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::{Error, ErrorKind, UnsupportedFeature};
+    use super::*;
+
+    #[test]
+    fn detected() {
+        let caps = os_capabilities();
+        assert!(caps.version() >= (6, 1, 0));
+        assert!(caps.supports(Feature::Tls12));
+        assert!(caps.supports(Feature::CipherInfo));
+        assert!(!caps.supports(Feature::__Nonexhaustive));
+        assert!(require(Feature::__Nonexhaustive).is_err());
+        assert!(format!("{:?}", caps).contains("Tls12: true"));
+    }
+
+    #[test]
+    fn undetected() {
+        let caps = OsCapabilities { version: None, disabled: Feature::Alpn.bit() };
+        assert_eq!(caps.version(), (0, 0, 0));
+        assert!(caps.supports(Feature::Tls12));
+        assert!(caps.supports(Feature::SchCredentials));
+        assert!(!caps.supports(Feature::Alpn));
+        assert!(!caps.supports(Feature::__Nonexhaustive));
+    }
+
+    #[test]
+    fn disabled() {
+        {
+            let _alpn = disable(Feature::Alpn);
+            let _tls12 = disable(Feature::Tls12);
+            assert!(!os_capabilities().supports(Feature::Alpn));
+            assert!(!os_capabilities().supports(Feature::Tls12));
+            assert!(os_capabilities().supports(Feature::CipherInfo));
+
+            let err = require(Feature::Tls12).unwrap_err();
+            assert_eq!(err.to_string(), "TLS 1.1 and 1.2 requires Windows 7 or later");
+            assert_eq!(err.get_ref()
+                           .and_then(|e| e.downcast_ref::<UnsupportedFeature>())
+                           .map(|e| e.feature()),
+                       Some(Feature::Tls12));
+            assert_eq!(Error::from(err).kind(), ErrorKind::Unsupported);
+        }
+        assert!(os_capabilities().supports(Feature::Tls12));
+        require(Feature::Tls12).unwrap();
+    }
+}
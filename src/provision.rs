@@ -0,0 +1,379 @@
+//! Installation of certificates with private keys into system stores.
+//!
+//! `install_pfx` imports the certificate with a private key from a PKCS #12
+//! archive, persisting its key and adding the certificate to a system store.
+//! `remove` undoes this, deleting both the certificate and its key.
+use std::io;
+
+use cert_context::{CertContext, HashAlgorithm, PrivateKey};
+use cert_store::{CertAdd, PfxImportOptions, SystemStoreOptions};
+pub use cert_store::StoreLocation;
+use crypt_prov::AcquireOptions;
+use schannel_cred;
+use {Error, ErrorKind};
+
+/// A builder type for the options of `install_pfx`.
+#[derive(Default, Clone, Debug)]
+pub struct InstallOptions {
+    exportable: bool,
+    friendly_name: Option<String>,
+}
+
+impl InstallOptions {
+    /// Returns a new `InstallOptions` with default settings.
+    pub fn new() -> InstallOptions {
+        InstallOptions::default()
+    }
+
+    /// If set, the installed private key can be exported again.
+    ///
+    /// Defaults to `false`.
+    pub fn exportable(&mut self, exportable: bool) -> &mut InstallOptions {
+        self.exportable = exportable;
+        self
+    }
+
+    /// Sets the friendly name of the installed certificate.
+    pub fn friendly_name(&mut self, friendly_name: &str) -> &mut InstallOptions {
+        self.friendly_name = Some(friendly_name.to_owned());
+        self
+    }
+}
+
+/// A certificate installed by `install_pfx`.
+pub struct InstalledCert {
+    cert: CertContext,
+    thumbprint: Vec<u8>,
+    location: StoreLocation,
+    store_name: String,
+}
+
+impl InstalledCert {
+    /// Returns the installed certificate, as found in its store.
+    pub fn cert(&self) -> &CertContext {
+        &self.cert
+    }
+
+    /// Returns the SHA-1 thumbprint of the certificate, which identifies it to
+    /// `remove`.
+    pub fn thumbprint(&self) -> &[u8] {
+        &self.thumbprint
+    }
+
+    /// Returns the location of the store the certificate was installed to.
+    pub fn location(&self) -> StoreLocation {
+        self.location
+    }
+
+    /// Returns the name of the store the certificate was installed to.
+    pub fn store_name(&self) -> &str {
+        &self.store_name
+    }
+
+    /// Returns a credential builder which presents the certificate.
+    pub fn cred_builder(&self) -> schannel_cred::Builder {
+        let mut builder = schannel_cred::Builder::new();
+        builder.cert(self.cert.clone());
+        builder
+    }
+
+    /// Removes the certificate and its private key.
+    ///
+    /// This is a shorthand for `remove`.
+    pub fn remove(self) -> io::Result<()> {
+        remove(self.location, &self.store_name, &self.thumbprint)
+    }
+}
+
+/// Installs the certificate with a private key from a PKCS #12 archive into
+/// the system store `store_name`.
+///
+/// The key is persisted in the Microsoft Software Key Storage Provider as a
+/// user or machine key, according to `location`, and the certificate is then
+/// added to the store and checked to be bound to the key.
+///
+/// Installation fails if the archive does not contain exactly one
+/// certificate with a private key, or if the store already contains the
+/// certificate. Nothing is left behind on failure: keys persisted from the
+/// archive are deleted again, as is the certificate if it was added.
+pub fn install_pfx(location: StoreLocation,
+                   store_name: &str,
+                   pfx: &[u8],
+                   password: &str,
+                   options: &InstallOptions)
+                   -> io::Result<InstalledCert> {
+    let machine = location == StoreLocation::LocalMachine;
+    let imported = try!(PfxImportOptions::new()
+        .password(password)
+        .always_cng(true)
+        .exportable(options.exportable)
+        .machine_keyset(machine)
+        .user_keyset(!machine)
+        .import(pfx));
+
+    // every key in the archive is persisted by the import, so all of them
+    // have to be deleted again unless the installation succeeds
+    let mut keys = vec![];
+    let mut certs = vec![];
+    for cert in imported.certs() {
+        if let Ok(key) = cert.private_key().silent(true).acquire() {
+            keys.push(key);
+            certs.push(cert);
+        }
+    }
+
+    let res = match certs.len() {
+        0 => {
+            Err(io::Error::new(io::ErrorKind::NotFound,
+                               "archive contains no certificate with a private key"))
+        }
+        1 => install(&certs[0], location, store_name, options),
+        _ => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               "archive contains multiple certificates with private keys"))
+        }
+    };
+    if res.is_err() {
+        for key in keys {
+            if let Err(err) = delete_key(key, machine) {
+                debug!("failed to delete a key imported from the archive: {}", err);
+            }
+        }
+    }
+    res
+}
+
+fn install(cert: &CertContext,
+           location: StoreLocation,
+           store_name: &str,
+           options: &InstallOptions)
+           -> io::Result<InstalledCert> {
+    let thumbprint = try!(cert.fingerprint(HashAlgorithm::sha1()));
+    let mut store = try!(SystemStoreOptions::new().location(location).open(store_name));
+    let installed = try!(store.add_cert(cert, CertAdd::New));
+
+    let res = (|| {
+        if let Some(ref friendly_name) = options.friendly_name {
+            try!(installed.set_friendly_name(friendly_name));
+        }
+        installed.private_key().compare_key(true).silent(true).acquire().map(|_| ())
+    })();
+    if let Err(err) = res {
+        if let Err(err) = installed.delete() {
+            debug!("failed to delete a partially installed certificate: {}", err);
+        }
+        return Err(err);
+    }
+
+    debug!("installed certificate into store {:?}", store_name);
+    Ok(InstalledCert {
+        cert: installed,
+        thumbprint: thumbprint,
+        location: location,
+        store_name: store_name.to_owned(),
+    })
+}
+
+/// Removes the certificate with the SHA-1 `thumbprint` from the system store
+/// `store_name`, deleting its private key as well.
+///
+/// The key is deleted first, so a failure leaves the certificate in place to
+/// retry with. Certificates whose key is already gone are removed all the
+/// same.
+pub fn remove(location: StoreLocation, store_name: &str, thumbprint: &[u8]) -> io::Result<()> {
+    let store = try!(SystemStoreOptions::new()
+        .location(location)
+        .open_existing(true)
+        .open(store_name));
+    let cert = store.certs().find(|c| {
+        c.fingerprint(HashAlgorithm::sha1()).ok().map_or(false, |t| t == thumbprint)
+    });
+    let cert = try!(cert.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "store contains no matching certificate")
+    }));
+
+    if try!(cert.get_raw_property(::ffi::CERT_KEY_PROV_INFO_PROP_ID)).is_some() {
+        match cert.private_key().silent(true).acquire() {
+            Ok(key) => try!(delete_key(key, location == StoreLocation::LocalMachine)),
+            Err(err) => {
+                let err = Error::from(err);
+                if err.kind() != ErrorKind::NotFound {
+                    return Err(err.into_inner());
+                }
+            }
+        }
+    }
+    debug!("removing certificate from store {:?}", store_name);
+    cert.delete()
+}
+
+fn delete_key(key: PrivateKey, machine: bool) -> io::Result<()> {
+    match key {
+        PrivateKey::NcryptKey(key) => key.delete(),
+        PrivateKey::CryptProv(prov) => {
+            let container = try!(prov.container_name());
+            let provider = try!(prov.provider_name());
+            let type_ = try!(prov.provider_type());
+            drop(prov);
+            AcquireOptions::new()
+                .container(&container)
+                .provider(&provider)
+                .machine_keyset(machine)
+                .delete(type_)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cert_store::CertStore;
+    use ffi;
+    use ncrypt_key::{NcryptProvider, OpenOptions};
+    use schannel_cred::Direction;
+    use super::*;
+
+    /// A registry-backed store of the current user, which is unregistered
+    /// again when dropped.
+    struct TempStore(&'static str);
+
+    impl TempStore {
+        fn new(name: &'static str) -> TempStore {
+            let store = TempStore(name);
+            store.unregister();
+            store
+        }
+
+        fn unregister(&self) {
+            let name = self.0.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+            unsafe {
+                ffi::CertUnregisterSystemStore(name.as_ptr() as *const _,
+                                               ffi::CERT_SYSTEM_STORE_CURRENT_USER |
+                                               ffi::CERT_STORE_DELETE_FLAG);
+            }
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            self.unregister();
+        }
+    }
+
+    fn identity() -> CertContext {
+        CertContext::new(include_bytes!("../test/cert.der")).unwrap()
+    }
+
+    /// Returns the names of user keys holding the identity's private key.
+    fn identity_keys() -> Vec<String> {
+        let provider = NcryptProvider::software().unwrap();
+        let public_key = identity().public_key().unwrap();
+        provider.keys(false).unwrap().iter().filter(|name| {
+            OpenOptions::new()
+                .provider(&provider)
+                .silent(true)
+                .open(name.name())
+                .and_then(|k| k.export_public_spki())
+                .map(|k| k == public_key)
+                .unwrap_or(false)
+        }).map(|name| name.name().to_owned()).collect()
+    }
+
+    #[test]
+    fn install_and_remove() {
+        let store = TempStore::new("schannel-rs-provision-install");
+        let pfx = include_bytes!("../test/identity.p12");
+        let installed = install_pfx(StoreLocation::CurrentUser,
+                                    store.0,
+                                    pfx,
+                                    "mypass",
+                                    InstallOptions::new().friendly_name("schannel-rs"))
+            .unwrap();
+        assert_eq!(installed.thumbprint(),
+                   &identity().fingerprint(HashAlgorithm::sha1()).unwrap()[..]);
+        assert_eq!(installed.store_name(), store.0);
+
+        let opened = CertStore::open_current_user(store.0).unwrap();
+        let cert = opened.certs().next().unwrap();
+        assert_eq!(cert, identity());
+        assert_eq!(cert.friendly_name().unwrap(), "schannel-rs");
+        match cert.private_key().compare_key(true).silent(true).acquire().unwrap() {
+            PrivateKey::NcryptKey(key) => {
+                let policy = key.export_policy().unwrap().unwrap();
+                assert!(!policy.contains(::ncrypt_key::ExportPolicy::export()));
+            }
+            PrivateKey::CryptProv(_) => panic!("key was not imported into CNG"),
+        }
+        installed.cred_builder().acquire(Direction::Inbound).unwrap();
+
+        installed.remove().unwrap();
+        assert!(opened.certs().next().is_none());
+        let err = cert.private_key().silent(true).acquire().err().unwrap();
+        assert_eq!(Error::from(err).kind(), ErrorKind::NotFound);
+
+        let thumbprint = identity().fingerprint(HashAlgorithm::sha1()).unwrap();
+        let err = remove(StoreLocation::CurrentUser, store.0, &thumbprint);
+        assert_eq!(err.err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn exportable() {
+        let store = TempStore::new("schannel-rs-provision-exportable");
+        let pfx = include_bytes!("../test/identity.p12");
+        let installed = install_pfx(StoreLocation::CurrentUser,
+                                    store.0,
+                                    pfx,
+                                    "mypass",
+                                    InstallOptions::new().exportable(true))
+            .unwrap();
+        match installed.cert().private_key().silent(true).acquire().unwrap() {
+            PrivateKey::NcryptKey(key) => {
+                let policy = key.export_policy().unwrap().unwrap();
+                assert!(policy.contains(::ncrypt_key::ExportPolicy::export()));
+            }
+            PrivateKey::CryptProv(_) => panic!("key was not imported into CNG"),
+        }
+        installed.remove().unwrap();
+    }
+
+    #[test]
+    fn rollback() {
+        let store = TempStore::new("schannel-rs-provision-rollback");
+        let pfx = include_bytes!("../test/identity.p12");
+        let installed = install_pfx(StoreLocation::CurrentUser,
+                                    store.0,
+                                    pfx,
+                                    "mypass",
+                                    &InstallOptions::new())
+            .unwrap();
+        let keys = identity_keys();
+
+        // the certificate is already installed, so the key persisted by the
+        // second import has to be deleted again
+        let err = install_pfx(StoreLocation::CurrentUser,
+                              store.0,
+                              pfx,
+                              "mypass",
+                              &InstallOptions::new())
+            .err()
+            .unwrap();
+        assert_eq!(Error::from(err).kind(), ErrorKind::Exists);
+        assert_eq!(identity_keys(), keys);
+
+        installed.remove().unwrap();
+        assert!(identity_keys().iter().all(|k| !keys.contains(k)));
+    }
+
+    #[test]
+    fn wrong_password() {
+        let store = TempStore::new("schannel-rs-provision-password");
+        let pfx = include_bytes!("../test/identity.p12");
+        assert!(install_pfx(StoreLocation::CurrentUser,
+                            store.0,
+                            pfx,
+                            "wrong",
+                            &InstallOptions::new())
+            .is_err());
+        let opened = CertStore::open_current_user(store.0).unwrap();
+        assert!(opened.certs().next().is_none());
+    }
+}
@@ -1,15 +1,17 @@
 //! Schannel credentials.
-use winapi;
-use secur32;
+use ffi;
 use std::io;
 use std::mem;
 use std::ptr;
+use std::sync::Arc;
 
-use Inner;
+use {CredSettings, Inner};
 use cert_context::CertContext;
+use cert_store::{CertAdd, Memory};
+use os_support::{self, Feature};
 
 lazy_static! {
-    static ref UNISP_NAME: Vec<u8> = winapi::UNISP_NAME.bytes().chain(Some(0)).collect();
+    static ref UNISP_NAME: Vec<u8> = ffi::UNISP_NAME.bytes().chain(Some(0)).collect();
 }
 
 /// The communication direction that an `SchannelCred` will support.
@@ -23,83 +25,85 @@ pub enum Direction {
 
 /// Algorithms supported by Schannel.
 // https://msdn.microsoft.com/en-us/library/windows/desktop/aa375549(v=vs.85).aspx
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u32)]
 pub enum Algorithm {
     /// Advanced Encryption Standard (AES).
-    Aes = winapi::CALG_AES,
+    Aes = ffi::CALG_AES,
     /// 128 bit AES.
-    Aes128 = winapi::CALG_AES_128,
+    Aes128 = ffi::CALG_AES_128,
     /// 192 bit AES.
-    Aes192 = winapi::CALG_AES_192,
+    Aes192 = ffi::CALG_AES_192,
     /// 256 bit AES.
-    Aes256 = winapi::CALG_AES_256,
+    Aes256 = ffi::CALG_AES_256,
     /// Temporary algorithm identifier for handles of Diffie-Hellman–agreed keys.
-    AgreedkeyAny = winapi::CALG_AGREEDKEY_ANY,
+    AgreedkeyAny = ffi::CALG_AGREEDKEY_ANY,
     /// An algorithm to create a 40-bit DES key that has parity bits and zeroed key bits to make
     /// its key length 64 bits.
-    CylinkMek = winapi::CALG_CYLINK_MEK,
+    CylinkMek = ffi::CALG_CYLINK_MEK,
     /// DES encryption algorithm.
-    Des = winapi::CALG_DES,
+    Des = ffi::CALG_DES,
     /// DESX encryption algorithm.
-    Desx = winapi::CALG_DESX,
+    Desx = ffi::CALG_DESX,
     /// Diffie-Hellman ephemeral key exchange algorithm.
-    DhEphem = winapi::CALG_DH_EPHEM,
+    DhEphem = ffi::CALG_DH_EPHEM,
     /// Diffie-Hellman store and forward key exchange algorithm.
-    DhSf = winapi::CALG_DH_SF,
+    DhSf = ffi::CALG_DH_SF,
     /// DSA public key signature algorithm.
-    DssSign = winapi::CALG_DSS_SIGN,
+    DssSign = ffi::CALG_DSS_SIGN,
     /// Elliptic curve Diffie-Hellman key exchange algorithm.
-    Ecdh = winapi::CALG_ECDH,
+    Ecdh = ffi::CALG_ECDH,
     // https://github.com/retep998/winapi-rs/issues/287
     // /// Ephemeral elliptic curve Diffie-Hellman key exchange algorithm.
-    // EcdhEphem = winapi::CALG_ECDH_EPHEM,
+    // EcdhEphem = ffi::CALG_ECDH_EPHEM,
     /// Elliptic curve digital signature algorithm.
-    Ecdsa = winapi::CALG_ECDSA,
+    Ecdsa = ffi::CALG_ECDSA,
     /// One way function hashing algorithm.
-    HashReplaceOwf = winapi::CALG_HASH_REPLACE_OWF,
+    HashReplaceOwf = ffi::CALG_HASH_REPLACE_OWF,
     /// Hughes MD5 hashing algorithm.
-    HughesMd5 = winapi::CALG_HUGHES_MD5,
+    HughesMd5 = ffi::CALG_HUGHES_MD5,
     /// HMAC keyed hash algorithm.
-    Hmac = winapi::CALG_HMAC,
+    Hmac = ffi::CALG_HMAC,
     /// MAC keyed hash algorithm.
-    Mac = winapi::CALG_MAC,
+    Mac = ffi::CALG_MAC,
     /// MD2 hashing algorithm.
-    Md2 = winapi::CALG_MD2,
+    Md2 = ffi::CALG_MD2,
     /// MD4 hashing algorithm.
-    Md4 = winapi::CALG_MD4,
+    Md4 = ffi::CALG_MD4,
     /// MD5 hashing algorithm.
-    Md5 = winapi::CALG_MD5,
+    Md5 = ffi::CALG_MD5,
     /// No signature algorithm..
-    NoSign = winapi::CALG_NO_SIGN,
+    NoSign = ffi::CALG_NO_SIGN,
     /// RC2 block encryption algorithm.
-    Rc2 = winapi::CALG_RC2,
+    Rc2 = ffi::CALG_RC2,
     /// RC4 stream encryption algorithm.
-    Rc4 = winapi::CALG_RC4,
+    Rc4 = ffi::CALG_RC4,
     /// RC5 block encryption algorithm.
-    Rc5 = winapi::CALG_RC5,
+    Rc5 = ffi::CALG_RC5,
     /// RSA public key exchange algorithm.
-    RsaKeyx = winapi::CALG_RSA_KEYX,
+    RsaKeyx = ffi::CALG_RSA_KEYX,
     /// RSA public key signature algorithm.
-    RsaSign = winapi::CALG_RSA_SIGN,
+    RsaSign = ffi::CALG_RSA_SIGN,
     /// SHA hashing algorithm.
-    Sha1 = winapi::CALG_SHA1,
+    Sha1 = ffi::CALG_SHA1,
     /// 256 bit SHA hashing algorithm.
-    Sha256 = winapi::CALG_SHA_256,
+    Sha256 = ffi::CALG_SHA_256,
     /// 384 bit SHA hashing algorithm.
-    Sha384 = winapi::CALG_SHA_384,
+    Sha384 = ffi::CALG_SHA_384,
     /// 512 bit SHA hashing algorithm.
-    Sha512 = winapi::CALG_SHA_512,
+    Sha512 = ffi::CALG_SHA_512,
     /// Triple DES encryption algorithm.
-    TripleDes = winapi::CALG_3DES,
+    TripleDes = ffi::CALG_3DES,
     /// Two-key triple DES encryption with effective key length equal to 112 bits.
-    TripleDes112 = winapi::CALG_3DES_112,
+    TripleDes112 = ffi::CALG_3DES_112,
     #[doc(hidden)]
     __ForExtensibility,
 }
 
 /// Protocols supported by Schannel.
-#[derive(Debug, Copy, Clone)]
+///
+/// Protocols are ordered by version, from oldest to newest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Protocol {
     /// Secure Sockets Layer 3.0
     Ssl3,
@@ -114,27 +118,32 @@ pub enum Protocol {
 }
 
 impl Protocol {
-    fn dword(self, direction: Direction) -> winapi::DWORD {
+    fn dword(self, direction: Direction) -> ffi::DWORD {
         match (self, direction) {
-            (Protocol::Ssl3, Direction::Inbound) => winapi::SP_PROT_SSL3_SERVER,
-            (Protocol::Tls10, Direction::Inbound) => winapi::SP_PROT_TLS1_0_SERVER,
-            (Protocol::Tls11, Direction::Inbound) => winapi::SP_PROT_TLS1_1_SERVER,
-            (Protocol::Tls12, Direction::Inbound) => winapi::SP_PROT_TLS1_2_SERVER,
-            (Protocol::Ssl3, Direction::Outbound) => winapi::SP_PROT_SSL3_CLIENT,
-            (Protocol::Tls10, Direction::Outbound) => winapi::SP_PROT_TLS1_0_CLIENT,
-            (Protocol::Tls11, Direction::Outbound) => winapi::SP_PROT_TLS1_1_CLIENT,
-            (Protocol::Tls12, Direction::Outbound) => winapi::SP_PROT_TLS1_2_CLIENT,
+            (Protocol::Ssl3, Direction::Inbound) => ffi::SP_PROT_SSL3_SERVER,
+            (Protocol::Tls10, Direction::Inbound) => ffi::SP_PROT_TLS1_0_SERVER,
+            (Protocol::Tls11, Direction::Inbound) => ffi::SP_PROT_TLS1_1_SERVER,
+            (Protocol::Tls12, Direction::Inbound) => ffi::SP_PROT_TLS1_2_SERVER,
+            (Protocol::Ssl3, Direction::Outbound) => ffi::SP_PROT_SSL3_CLIENT,
+            (Protocol::Tls10, Direction::Outbound) => ffi::SP_PROT_TLS1_0_CLIENT,
+            (Protocol::Tls11, Direction::Outbound) => ffi::SP_PROT_TLS1_1_CLIENT,
+            (Protocol::Tls12, Direction::Outbound) => ffi::SP_PROT_TLS1_2_CLIENT,
             (Protocol::__ForExtensibility, _) => unreachable!(),
         }
     }
 }
 
 /// A builder type for `SchannelCred`s.
-#[derive(Default, Debug)]
+///
+/// A builder may be cloned and reused to acquire any number of credentials.
+#[derive(Default, Debug, Clone)]
 pub struct Builder {
     supported_algorithms: Option<Vec<Algorithm>>,
     enabled_protocols: Option<Vec<Protocol>>,
     certs: Vec<CertContext>,
+    disable_reconnects: bool,
+    request_ocsp: bool,
+    ocsp_response: Option<Vec<u8>>,
 }
 
 impl Builder {
@@ -187,15 +196,118 @@ impl Builder {
         self
     }
 
+    /// Determines if sessions of credentials created from this builder may be
+    /// resumed.
+    ///
+    /// Schannel caches sessions per credential and by default resumes them
+    /// when it reconnects to a peer, skipping the full handshake. Disabling
+    /// resumption sets `SCH_CRED_DISABLE_RECONNECTS`, so that every handshake
+    /// is a full one, as may be wanted after a certificate has been replaced.
+    ///
+    /// Defaults to `true`.
+    pub fn session_resumption(&mut self, enabled: bool) -> &mut Builder {
+        self.disable_reconnects = !enabled;
+        self
+    }
+
+    /// Determines if OCSP stapling is enabled for credentials created from
+    /// this builder.
+    ///
+    /// This sets `SCH_CRED_SNI_ENABLE_OCSP`, so that clients ask servers to
+    /// staple an OCSP response for their certificate through the
+    /// `status_request` extension, and servers staple one for clients which
+    /// ask. A client can read the response from
+    /// `TlsStream::ocsp_response` once the handshake has completed.
+    ///
+    /// Defaults to `false`.
+    pub fn request_ocsp(&mut self, request: bool) -> &mut Builder {
+        self.request_ocsp = request;
+        self
+    }
+
+    /// Sets a pre-fetched DER-encoded OCSP response which servers staple for
+    /// clients asking for one.
+    ///
+    /// The response is attached to copies of every certificate added with
+    /// `cert` when the credentials are acquired, as with
+    /// `CertContext::set_ocsp_response`, so it should be the response for
+    /// the only certificate of the credentials. The certificates themselves
+    /// are left unchanged.
+    pub fn ocsp_response(&mut self, response: &[u8]) -> &mut Builder {
+        self.ocsp_response = Some(response.to_owned());
+        self
+    }
+
+    /// Returns the algorithms set by `supported_algorithms`, if any.
+    pub fn get_supported_algorithms(&self) -> Option<&[Algorithm]> {
+        self.supported_algorithms.as_ref().map(|a| &a[..])
+    }
+
+    /// Returns the protocols set by `enabled_protocols`, if any.
+    pub fn get_enabled_protocols(&self) -> Option<&[Protocol]> {
+        self.enabled_protocols.as_ref().map(|p| &p[..])
+    }
+
+    /// Returns the certificates added with `cert`.
+    pub fn get_certs(&self) -> &[CertContext] {
+        &self.certs
+    }
+
+    /// Returns whether session resumption is enabled, as set by
+    /// `session_resumption`.
+    pub fn get_session_resumption(&self) -> bool {
+        !self.disable_reconnects
+    }
+
+    /// Returns whether OCSP stapling is enabled, as set by `request_ocsp`.
+    pub fn get_request_ocsp(&self) -> bool {
+        self.request_ocsp
+    }
+
+    /// Returns the OCSP response set by `ocsp_response`, if any.
+    pub fn get_ocsp_response(&self) -> Option<&[u8]> {
+        self.ocsp_response.as_ref().map(|r| &r[..])
+    }
+
     /// Creates a new `SchannelCred`.
+    ///
+    /// Enabling TLS 1.1 or 1.2 on a version of Windows without them fails
+    /// with an `UnsupportedFeature` error.
     pub fn acquire(&self, direction: Direction) -> io::Result<SchannelCred> {
+        if let Some(ref enabled_protocols) = self.enabled_protocols {
+            let tls12 = enabled_protocols.iter().any(|p| match *p {
+                Protocol::Tls11 | Protocol::Tls12 => true,
+                _ => false,
+            });
+            if tls12 {
+                try!(os_support::require(Feature::Tls12));
+            }
+        }
+
+        // the response is attached to copies of the certificates, as setting
+        // it on the caller's ones could also persist it to their stores
+        let mut certs = self.certs.clone();
+        if let Some(ref response) = self.ocsp_response {
+            let mut store = try!(Memory::new()).into_store();
+            for cert in &mut certs {
+                *cert = try!(store.add_cert(cert, CertAdd::Always));
+                try!(cert.set_ocsp_response(response));
+            }
+        }
+
         unsafe {
             let mut handle = mem::zeroed();
-            let mut cred_data: winapi::SCHANNEL_CRED = mem::zeroed();
-            cred_data.dwVersion = winapi::SCHANNEL_CRED_VERSION;
-            cred_data.dwFlags = winapi::SCH_USE_STRONG_CRYPTO | winapi::SCH_CRED_NO_DEFAULT_CREDS;
+            let mut cred_data: ffi::SCHANNEL_CRED = mem::zeroed();
+            cred_data.dwVersion = ffi::SCHANNEL_CRED_VERSION;
+            cred_data.dwFlags = ffi::SCH_USE_STRONG_CRYPTO | ffi::SCH_CRED_NO_DEFAULT_CREDS;
+            if self.disable_reconnects {
+                cred_data.dwFlags |= ffi::SCH_CRED_DISABLE_RECONNECTS;
+            }
+            if self.request_ocsp {
+                cred_data.dwFlags |= ffi::SCH_CRED_SNI_ENABLE_OCSP;
+            }
             if let Some(ref supported_algorithms) = self.supported_algorithms {
-                cred_data.cSupportedAlgs = supported_algorithms.len() as winapi::DWORD;
+                cred_data.cSupportedAlgs = try!(::dword_len(supported_algorithms.len()));
                 cred_data.palgSupportedAlgs = supported_algorithms.as_ptr() as *mut _;
             }
             if let Some(ref enabled_protocols) = self.enabled_protocols {
@@ -203,57 +315,120 @@ impl Builder {
                     .map(|p| p.dword(direction))
                     .fold(0, |acc, p| acc | p);
             }
-            let mut certs = self.certs.iter().map(|c| c.as_inner()).collect::<Vec<_>>();
-            cred_data.cCreds = certs.len() as winapi::DWORD;
-            cred_data.paCred = certs.as_mut_ptr();
+            let mut handles = certs.iter().map(|c| c.as_inner()).collect::<Vec<_>>();
+            cred_data.cCreds = try!(::dword_len(handles.len()));
+            cred_data.paCred = handles.as_mut_ptr() as *mut _;
 
-            let direction = match direction {
-                Direction::Inbound => winapi::SECPKG_CRED_INBOUND,
-                Direction::Outbound => winapi::SECPKG_CRED_OUTBOUND,
+            let cred_use = match direction {
+                Direction::Inbound => ffi::SECPKG_CRED_INBOUND,
+                Direction::Outbound => ffi::SECPKG_CRED_OUTBOUND,
             };
 
-            match secur32::AcquireCredentialsHandleA(ptr::null_mut(),
-                                                     UNISP_NAME.as_ptr() as *const _ as *mut _,
-                                                     direction,
-                                                     ptr::null_mut(),
-                                                     &mut cred_data as *mut _ as *mut _,
-                                                     None,
-                                                     ptr::null_mut(),
-                                                     &mut handle,
-                                                     ptr::null_mut()) {
-                winapi::SEC_E_OK => Ok(SchannelCred(handle)),
-                err => Err(io::Error::from_raw_os_error(err as i32)),
+            match ffi::AcquireCredentialsHandleA(ptr::null_mut(),
+                                                 UNISP_NAME.as_ptr() as *const _ as *mut _,
+                                                 cred_use,
+                                                 ptr::null_mut(),
+                                                 &mut cred_data as *mut _ as *mut _,
+                                                 None,
+                                                 ptr::null_mut(),
+                                                 &mut handle,
+                                                 ptr::null_mut()) {
+                ffi::SEC_E_OK => {
+                    let mut cred = SchannelCred::from_inner(handle);
+                    cred.2 = Some(Arc::new((self.clone(), direction)));
+                    Ok(cred)
+                }
+                err => Err(::error::from_code(err as i32)),
             }
         }
     }
 }
 
 /// An SChannel credential.
-pub struct SchannelCred(winapi::CredHandle);
+///
+/// Credential handles cannot be duplicated, so clones of a credential share
+/// its handle, which is freed once the last of them is dropped. Any number of
+/// streams may be set up from clones of one credential, which also lets them
+/// share Schannel's session cache.
+#[derive(Clone)]
+pub struct SchannelCred(ffi::CredHandle, Arc<CredOwner>, Option<Arc<(Builder, Direction)>>);
+
+// Schannel serializes access to a credential internally, and it is designed
+// to be used by many concurrent handshakes, so sharing the handle is safe.
+unsafe impl Send for SchannelCred {}
+unsafe impl Sync for SchannelCred {}
 
-impl Drop for SchannelCred {
+struct CredOwner(ffi::CredHandle);
+
+impl Drop for CredOwner {
     fn drop(&mut self) {
         unsafe {
-            secur32::FreeCredentialsHandle(&mut self.0);
+            ffi::FreeCredentialsHandle(&mut self.0);
         }
     }
 }
 
-impl Inner<winapi::CredHandle> for SchannelCred {
-    unsafe fn from_inner(inner: winapi::CredHandle) -> SchannelCred {
-        SchannelCred(inner)
+impl Inner<ffi::CredHandle> for SchannelCred {
+    unsafe fn from_inner(inner: ffi::CredHandle) -> SchannelCred {
+        SchannelCred(inner, Arc::new(CredOwner(inner)), None)
     }
 
-    fn as_inner(&self) -> winapi::CredHandle {
+    fn as_inner(&self) -> ffi::CredHandle {
         self.0
     }
 
-    fn get_mut(&mut self) -> &mut winapi::CredHandle {
+    fn get_mut(&mut self) -> &mut ffi::CredHandle {
         &mut self.0
     }
 }
 
+impl CredSettings for SchannelCred {
+    fn settings(&self) -> Option<&(Builder, Direction)> {
+        self.2.as_ref().map(|s| &**s)
+    }
+}
+
 impl SchannelCred {
+    /// Returns the raw `CredHandle`.
+    ///
+    /// The credential handle remains owned by this value and its clones, and is
+    /// only valid for as long as one of them is alive. It is not reference
+    /// counted, so it must not be passed to `FreeCredentialsHandle`.
+    pub fn as_raw(&self) -> ffi::CredHandle {
+        self.0
+    }
+
+    /// Consumes this value, returning the raw `CredHandle`.
+    ///
+    /// The caller becomes responsible for releasing it with
+    /// `FreeCredentialsHandle`, or for passing it back to `from_raw`.
+    /// Credentials which have live clones cannot give up their handle and are
+    /// returned as the error.
+    pub fn into_raw(self) -> Result<ffi::CredHandle, SchannelCred> {
+        let SchannelCred(handle, owner, settings) = self;
+        match Arc::try_unwrap(owner) {
+            Ok(owner) => {
+                mem::forget(owner);
+                Ok(handle)
+            }
+            Err(owner) => Err(SchannelCred(handle, owner, settings)),
+        }
+    }
+
+    /// Wraps a raw `CredHandle`, taking ownership of it.
+    ///
+    /// It is released with `FreeCredentialsHandle` once the returned value and
+    /// all of its clones have been dropped, so the caller must not release it
+    /// as well.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must be a valid credential handle which is not owned by anything
+    /// else.
+    pub unsafe fn from_raw(raw: ffi::CredHandle) -> SchannelCred {
+        SchannelCred::from_inner(raw)
+    }
+
     /// Returns a builder.
     pub fn builder() -> Builder {
         Builder::new()
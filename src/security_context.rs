@@ -1,107 +1,528 @@
-use winapi;
-use secur32;
+use ffi;
 use std::mem;
 use std::ptr;
 use std::io;
+use std::slice;
 
 use {INIT_REQUESTS, Inner, secbuf, secbuf_desc};
 use cert_context::CertContext;
 use context_buffer::ContextBuffer;
+use os_support::{self, Feature};
+use schannel_cred::{Algorithm, Protocol};
 
 use schannel_cred::SchannelCred;
 
-pub struct SecurityContext(winapi::CtxtHandle);
+/// An SSPI security context of a TLS session, wrapping a `CtxtHandle`.
+pub struct SecurityContext(ffi::CtxtHandle);
 
 impl Drop for SecurityContext {
     fn drop(&mut self) {
         unsafe {
-            secur32::DeleteSecurityContext(&mut self.0);
+            ffi::DeleteSecurityContext(&mut self.0);
         }
     }
 }
 
-impl Inner<winapi::CtxtHandle> for SecurityContext {
-    unsafe fn from_inner(inner: winapi::CtxtHandle) -> SecurityContext {
+impl Inner<ffi::CtxtHandle> for SecurityContext {
+    unsafe fn from_inner(inner: ffi::CtxtHandle) -> SecurityContext {
         SecurityContext(inner)
     }
 
-    fn as_inner(&self) -> winapi::CtxtHandle {
+    fn as_inner(&self) -> ffi::CtxtHandle {
         self.0
     }
 
-    fn get_mut(&mut self) -> &mut winapi::CtxtHandle {
+    fn get_mut(&mut self) -> &mut ffi::CtxtHandle {
         &mut self.0
     }
 }
 
+pub fn initialize(cred: &mut SchannelCred,
+                  accept: bool,
+                  target_name: Option<&[u16]>,
+                  application_protocols: Option<&mut [u8]>)
+                  -> io::Result<(SecurityContext, Option<ContextBuffer>)> {
+    unsafe {
+        let mut ctxt = mem::zeroed();
+
+        if accept {
+            // If we're performing an accept then we need to wait to call
+            // `AcceptSecurityContext` until we've actually read some data.
+            return Ok((SecurityContext(ctxt), None))
+        }
+
+        let target_name = target_name.map(|b| b.as_ptr() as *mut u16)
+            .unwrap_or(ptr::null_mut());
+
+        let mut inbuf = match application_protocols {
+            Some(b) => Some([try!(secbuf(ffi::SECBUFFER_APPLICATION_PROTOCOLS, Some(b)))]),
+            None => None,
+        };
+        let mut inbuf_desc = inbuf.as_mut().map(|b| secbuf_desc(b));
+        let inbuf_desc = inbuf_desc.as_mut()
+            .map(|d| d as *mut _)
+            .unwrap_or(ptr::null_mut());
+
+        let mut outbuf = [try!(secbuf(ffi::SECBUFFER_EMPTY, None))];
+        let mut outbuf_desc = secbuf_desc(&mut outbuf);
+
+        let mut attributes = 0;
+
+        match ffi::InitializeSecurityContextW(cred.get_mut(),
+                                              ptr::null_mut(),
+                                              target_name,
+                                              INIT_REQUESTS,
+                                              0,
+                                              0,
+                                              inbuf_desc,
+                                              0,
+                                              &mut ctxt,
+                                              &mut outbuf_desc,
+                                              &mut attributes,
+                                              ptr::null_mut()) {
+            ffi::SEC_I_CONTINUE_NEEDED => {
+                Ok((SecurityContext(ctxt), Some(ContextBuffer(outbuf[0]))))
+            }
+            err => {
+                Err(::error::from_code(err as i32))
+            }
+        }
+    }
+}
+
 impl SecurityContext {
-    pub fn initialize(cred: &mut SchannelCred,
-                      accept: bool,
-                      domain: Option<&[u16]>)
-                      -> io::Result<(SecurityContext, Option<ContextBuffer>)> {
+    /// Returns the raw `CtxtHandle`.
+    ///
+    /// The security context remains owned by this value, and is only valid for
+    /// as long as it is alive. It is not reference counted, so it must not be
+    /// passed to `DeleteSecurityContext`.
+    pub fn as_raw(&self) -> ffi::CtxtHandle {
+        self.0
+    }
+
+    /// Consumes this value, returning the raw `CtxtHandle`.
+    ///
+    /// The caller becomes responsible for releasing it with
+    /// `DeleteSecurityContext`, or for passing it back to `from_raw`.
+    pub fn into_raw(self) -> ffi::CtxtHandle {
+        let raw = self.0;
+        mem::forget(self);
+        raw
+    }
+
+    /// Wraps a raw `CtxtHandle`, taking ownership of it.
+    ///
+    /// It is released with `DeleteSecurityContext` when the returned value is
+    /// dropped, so the caller must not release it as well.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must be a valid security context which is not owned by anything
+    /// else.
+    pub unsafe fn from_raw(raw: ffi::CtxtHandle) -> SecurityContext {
+        SecurityContext(raw)
+    }
+
+    /// Returns the sizes of the headers, trailers and messages of the
+    /// session.
+    pub fn stream_sizes(&mut self) -> io::Result<ffi::SecPkgContext_StreamSizes> {
+        unsafe {
+            let mut stream_sizes = mem::zeroed();
+            let status = ffi::QueryContextAttributesW(&mut self.0,
+                                                      ffi::SECPKG_ATTR_STREAM_SIZES,
+                                                      &mut stream_sizes as *mut _ as *mut _);
+            if status == ffi::SEC_E_OK {
+                Ok(stream_sizes)
+            } else {
+                Err(::error::from_code(status as i32))
+            }
+        }
+    }
+
+    /// Returns the protocol negotiated through ALPN, if any.
+    ///
+    /// On versions of Windows without ALPN support this is always `None`.
+    pub fn application_protocol(&self) -> io::Result<Option<Vec<u8>>> {
+        if !os_support::os_capabilities().supports(Feature::Alpn) {
+            return Ok(None);
+        }
+
         unsafe {
-            let mut ctxt = mem::zeroed();
+            let mut handle = self.0;
+            let mut protocol: ffi::SecPkgContext_ApplicationProtocol = mem::zeroed();
+            let status = ffi::QueryContextAttributesW(&mut handle,
+                                                      ffi::SECPKG_ATTR_APPLICATION_PROTOCOL,
+                                                      &mut protocol as *mut _ as *mut _);
+            if status != ffi::SEC_E_OK {
+                return Err(::error::from_code(status as i32));
+            }
+            if protocol.ProtoNegoStatus != ffi::SecApplicationProtocolNegotiationStatus_Success {
+                return Ok(None);
+            }
+            let len = protocol.ProtocolIdSize as usize;
+            Ok(Some(protocol.ProtocolId[..len].to_vec()))
+        }
+    }
 
-            if accept {
-                // If we're performing an accept then we need to wait to call
-                // `AcceptSecurityContext` until we've actually read some data.
-                return Ok((SecurityContext(ctxt), None))
+    /// Returns the protocol and algorithms negotiated for the session.
+    pub fn connection_info(&self) -> io::Result<ConnectionInfo> {
+        unsafe {
+            let mut handle = self.0;
+            let mut info: ffi::SecPkgContext_ConnectionInfo = mem::zeroed();
+            let status = ffi::QueryContextAttributesW(&mut handle,
+                                                      ffi::SECPKG_ATTR_CONNECTION_INFO,
+                                                      &mut info as *mut _ as *mut _);
+            if status != ffi::SEC_E_OK {
+                return Err(::error::from_code(status as i32));
             }
 
-            let domain = domain.map(|b| b.as_ptr() as *mut u16).unwrap_or(ptr::null_mut());
-
-            let mut outbuf = [secbuf(winapi::SECBUFFER_EMPTY, None)];
-            let mut outbuf_desc = secbuf_desc(&mut outbuf);
-
-            let mut attributes = 0;
-
-            match secur32::InitializeSecurityContextW(cred.get_mut(),
-                                                      ptr::null_mut(),
-                                                      domain,
-                                                      INIT_REQUESTS,
-                                                      0,
-                                                      0,
-                                                      ptr::null_mut(),
-                                                      0,
-                                                      &mut ctxt,
-                                                      &mut outbuf_desc,
-                                                      &mut attributes,
-                                                      ptr::null_mut()) {
-                winapi::SEC_I_CONTINUE_NEEDED => {
-                    Ok((SecurityContext(ctxt), Some(ContextBuffer(outbuf[0]))))
-                }
-                err => {
-                    Err(io::Error::from_raw_os_error(err as i32))
+            // the cipher suite name is only available from Windows Vista on,
+            // and is left out rather than failing the whole query
+            let cipher_suite = if os_support::os_capabilities().supports(Feature::CipherInfo) {
+                let mut cipher_info: ffi::SecPkgContext_CipherInfo = mem::zeroed();
+                cipher_info.dwVersion = ffi::SECPKGCONTEXT_CIPHERINFO_V1;
+                let status = ffi::QueryContextAttributesW(&mut handle,
+                                                          ffi::SECPKG_ATTR_CIPHER_INFO,
+                                                          &mut cipher_info as *mut _ as *mut _);
+                if status == ffi::SEC_E_OK {
+                    let name = &cipher_info.szCipherSuite;
+                    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                    Some(String::from_utf16_lossy(&name[..len]))
+                } else {
+                    debug!("failed to query the cipher suite: {}",
+                           ::error::from_code(status as i32));
+                    None
                 }
+            } else {
+                None
+            };
+
+            Ok(ConnectionInfo {
+                protocol: info.dwProtocol,
+                cipher: info.aiCipher,
+                cipher_strength: info.dwCipherStrength,
+                hash: info.aiHash,
+                hash_strength: info.dwHashStrength,
+                key_exchange: info.aiExch,
+                key_exchange_strength: info.dwExchStrength,
+                cipher_suite: cipher_suite,
+            })
+        }
+    }
+
+    /// Returns the server name which the client requested through Server
+    /// Name Indication, if any.
+    ///
+    /// This is only available to servers.
+    pub fn client_specified_target(&self) -> io::Result<Option<String>> {
+        unsafe {
+            let mut handle = self.0;
+            let mut target: ffi::SecPkgContext_ClientSpecifiedTarget = mem::zeroed();
+            let status = ffi::QueryContextAttributesW(&mut handle,
+                                                      ffi::SECPKG_ATTR_CLIENT_SPECIFIED_TARGET,
+                                                      &mut target as *mut _ as *mut _);
+            match status {
+                ffi::SEC_E_OK => {}
+                // the client did not send a name
+                ffi::SEC_E_TARGET_UNKNOWN => return Ok(None),
+                _ => return Err(::error::from_code(status as i32)),
             }
+            if target.sTargetName.is_null() {
+                return Ok(None);
+            }
+            let name = ::from_wide_ptr(target.sTargetName);
+            ffi::FreeContextBuffer(target.sTargetName as *mut _);
+            Ok(Some(name))
         }
     }
 
-    pub fn stream_sizes(&mut self) -> io::Result<winapi::SecPkgContext_StreamSizes> {
+    /// Determines if the session was resumed from Schannel's session cache
+    /// rather than set up by a full handshake.
+    pub fn session_resumed(&self) -> io::Result<bool> {
         unsafe {
-            let mut stream_sizes = mem::zeroed();
-            let status = secur32::QueryContextAttributesW(&mut self.0,
-                                                          winapi::SECPKG_ATTR_STREAM_SIZES,
-                                                          &mut stream_sizes as *mut _ as *mut _);
-            if status == winapi::SEC_E_OK {
-                Ok(stream_sizes)
+            let mut handle = self.0;
+            let mut info: ffi::SecPkgContext_SessionInfo = mem::zeroed();
+            let status = ffi::QueryContextAttributesW(&mut handle,
+                                                      ffi::SECPKG_ATTR_SESSION_INFO,
+                                                      &mut info as *mut _ as *mut _);
+            if status == ffi::SEC_E_OK {
+                Ok(info.dwFlags & ffi::SSL_SESSION_RECONNECT != 0)
             } else {
-                Err(io::Error::from_raw_os_error(status as i32))
+                Err(::error::from_code(status as i32))
+            }
+        }
+    }
+
+    /// Returns the channel bindings of the session of the given kind.
+    ///
+    /// This is `None` if Schannel does not support them.
+    pub fn channel_bindings(&self, kind: ChannelBinding) -> io::Result<Option<ChannelBindings>> {
+        let attribute = match kind {
+            ChannelBinding::TlsUnique => ffi::SECPKG_ATTR_UNIQUE_BINDINGS,
+            ChannelBinding::TlsServerEndPoint => ffi::SECPKG_ATTR_ENDPOINT_BINDINGS,
+        };
+        unsafe {
+            let mut handle = self.0;
+            let mut bindings: ffi::SecPkgContext_Bindings = mem::zeroed();
+            let status = ffi::QueryContextAttributesW(&mut handle,
+                                                      attribute,
+                                                      &mut bindings as *mut _ as *mut _);
+            match status {
+                ffi::SEC_E_OK => {}
+                ffi::SEC_E_UNSUPPORTED_FUNCTION => return Ok(None),
+                _ => return Err(::error::from_code(status as i32)),
+            }
+            if bindings.Bindings.is_null() {
+                return Ok(None);
             }
+            let raw = slice::from_raw_parts(bindings.Bindings as *const u8,
+                                            bindings.BindingsLength as usize)
+                .to_vec();
+            ffi::FreeContextBuffer(bindings.Bindings as *mut _);
+            ChannelBindings::from_raw(raw).map(Some)
         }
     }
 
-    pub fn remote_cert(&mut self) -> io::Result<CertContext> {
+    /// Returns the certificate presented by the peer.
+    ///
+    /// This fails with `SEC_E_NO_CREDENTIALS` if the peer did not present
+    /// one.
+    pub fn remote_cert(&self) -> io::Result<CertContext> {
         unsafe {
+            let mut handle = self.0;
             let mut cert_context = mem::zeroed();
-            let status = secur32::QueryContextAttributesW(&mut self.0,
-                                                          winapi::SECPKG_ATTR_REMOTE_CERT_CONTEXT,
-                                                          &mut cert_context as *mut _ as *mut _);
-            if status == winapi::SEC_E_OK {
+            let status = ffi::QueryContextAttributesW(&mut handle,
+                                                      ffi::SECPKG_ATTR_REMOTE_CERT_CONTEXT,
+                                                      &mut cert_context as *mut _ as *mut _);
+            if status == ffi::SEC_E_OK {
                 Ok(CertContext::from_inner(cert_context))
             } else {
-                Err(io::Error::from_raw_os_error(status as i32))
+                Err(::error::from_code(status as i32))
             }
         }
     }
 }
+
+/// A kind of TLS channel binding, as defined by RFC 5929.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChannelBinding {
+    /// `tls-unique`, which is taken from the first Finished message of the
+    /// most recent handshake.
+    TlsUnique,
+    /// `tls-server-end-point`, which is a hash of the server's certificate.
+    TlsServerEndPoint,
+}
+
+/// TLS channel bindings, as used by Extended Protection for
+/// Authentication.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelBindings {
+    raw: Vec<u8>,
+    data_start: usize,
+    data_end: usize,
+}
+
+impl ChannelBindings {
+    fn from_raw(raw: Vec<u8>) -> io::Result<ChannelBindings> {
+        if raw.len() < mem::size_of::<ffi::SEC_CHANNEL_BINDINGS>() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated channel bindings"));
+        }
+        // the buffer isn't necessarily aligned for the structure, so copy
+        // it out of the bytes a field at a time
+        let header = ffi::SEC_CHANNEL_BINDINGS {
+            dwInitiatorAddrType: dword_at(&raw, 0),
+            cbInitiatorLength: dword_at(&raw, 4),
+            dwInitiatorOffset: dword_at(&raw, 8),
+            dwAcceptorAddrType: dword_at(&raw, 12),
+            cbAcceptorLength: dword_at(&raw, 16),
+            dwAcceptorOffset: dword_at(&raw, 20),
+            cbApplicationDataLength: dword_at(&raw, 24),
+            dwApplicationDataOffset: dword_at(&raw, 28),
+        };
+        let start = header.dwApplicationDataOffset as usize;
+        let end = match start.checked_add(header.cbApplicationDataLength as usize) {
+            Some(end) if end <= raw.len() => end,
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "truncated channel bindings"))
+            }
+        };
+        Ok(ChannelBindings {
+            raw: raw,
+            data_start: start,
+            data_end: end,
+        })
+    }
+
+    /// Returns the `SEC_CHANNEL_BINDINGS` structure followed by the data it
+    /// refers to.
+    ///
+    /// This is the form SSPI authentication packages such as Negotiate
+    /// accept in a `SECBUFFER_CHANNEL_BINDINGS` buffer.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Returns the application data of the bindings, which is the binding
+    /// type's prefix, such as `tls-unique:`, followed by its value.
+    pub fn application_data(&self) -> &[u8] {
+        &self.raw[self.data_start..self.data_end]
+    }
+
+    /// Returns the value of the bindings, without the binding type's prefix.
+    pub fn value(&self) -> &[u8] {
+        let data = self.application_data();
+        match data.iter().position(|&b| b == b':') {
+            Some(i) => &data[i + 1..],
+            None => data,
+        }
+    }
+}
+
+/// Reads the little-endian `DWORD` at `offset` in `buf`.
+fn dword_at(buf: &[u8], offset: usize) -> ffi::DWORD {
+    buf[offset..offset + 4].iter().rev().fold(0, |acc, &b| acc << 8 | b as ffi::DWORD)
+}
+
+/// The protocol and algorithms negotiated for a TLS session.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    protocol: ffi::DWORD,
+    cipher: ffi::ALG_ID,
+    cipher_strength: ffi::DWORD,
+    hash: ffi::ALG_ID,
+    hash_strength: ffi::DWORD,
+    key_exchange: ffi::ALG_ID,
+    key_exchange_strength: ffi::DWORD,
+    cipher_suite: Option<String>,
+}
+
+impl ConnectionInfo {
+    /// Returns the protocol of the session.
+    ///
+    /// This is `None` for protocols which `Protocol` does not name.
+    pub fn protocol(&self) -> Option<Protocol> {
+        match self.protocol {
+            ffi::SP_PROT_SSL3_SERVER | ffi::SP_PROT_SSL3_CLIENT => Some(Protocol::Ssl3),
+            ffi::SP_PROT_TLS1_0_SERVER | ffi::SP_PROT_TLS1_0_CLIENT => Some(Protocol::Tls10),
+            ffi::SP_PROT_TLS1_1_SERVER | ffi::SP_PROT_TLS1_1_CLIENT => Some(Protocol::Tls11),
+            ffi::SP_PROT_TLS1_2_SERVER | ffi::SP_PROT_TLS1_2_CLIENT => Some(Protocol::Tls12),
+            _ => None,
+        }
+    }
+
+    /// Returns the raw `SP_PROT_*` value of the protocol of the session.
+    pub fn raw_protocol(&self) -> u32 {
+        self.protocol
+    }
+
+    /// Returns the bulk encryption algorithm of the session.
+    ///
+    /// This is `None` for algorithms which `Algorithm` does not name.
+    pub fn cipher(&self) -> Option<Algorithm> {
+        algorithm(self.cipher)
+    }
+
+    /// Returns the raw `ALG_ID` of the bulk encryption algorithm.
+    pub fn raw_cipher(&self) -> u32 {
+        self.cipher
+    }
+
+    /// Returns the strength of the bulk encryption algorithm, in bits.
+    pub fn cipher_strength(&self) -> u32 {
+        self.cipher_strength
+    }
+
+    /// Returns the hash algorithm used to authenticate messages.
+    ///
+    /// This is `None` for algorithms which `Algorithm` does not name.
+    pub fn hash(&self) -> Option<Algorithm> {
+        algorithm(self.hash)
+    }
+
+    /// Returns the raw `ALG_ID` of the hash algorithm.
+    pub fn raw_hash(&self) -> u32 {
+        self.hash
+    }
+
+    /// Returns the strength of the hash algorithm, in bits.
+    pub fn hash_strength(&self) -> u32 {
+        self.hash_strength
+    }
+
+    /// Returns the key exchange algorithm of the session.
+    ///
+    /// This is `None` for algorithms which `Algorithm` does not name, such as
+    /// ephemeral elliptic curve Diffie-Hellman.
+    pub fn key_exchange(&self) -> Option<Algorithm> {
+        algorithm(self.key_exchange)
+    }
+
+    /// Returns the raw `ALG_ID` of the key exchange algorithm.
+    pub fn raw_key_exchange(&self) -> u32 {
+        self.key_exchange
+    }
+
+    /// Returns the strength of the key exchange algorithm, in bits.
+    pub fn key_exchange_strength(&self) -> u32 {
+        self.key_exchange_strength
+    }
+
+    /// Returns the name of the cipher suite, such as
+    /// `TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384`.
+    ///
+    /// This requires Windows Vista or later, and is `None` on older versions
+    /// or if Schannel could not report it.
+    pub fn cipher_suite(&self) -> Option<&str> {
+        self.cipher_suite.as_ref().map(|s| &s[..])
+    }
+}
+
+fn algorithm(alg_id: ffi::ALG_ID) -> Option<Algorithm> {
+    const ALGORITHMS: &'static [Algorithm] = &[
+        Algorithm::Aes, Algorithm::Aes128, Algorithm::Aes192, Algorithm::Aes256,
+        Algorithm::AgreedkeyAny, Algorithm::CylinkMek, Algorithm::Des, Algorithm::Desx,
+        Algorithm::DhEphem, Algorithm::DhSf, Algorithm::DssSign, Algorithm::Ecdh,
+        Algorithm::Ecdsa, Algorithm::HashReplaceOwf, Algorithm::HughesMd5, Algorithm::Hmac,
+        Algorithm::Mac, Algorithm::Md2, Algorithm::Md4, Algorithm::Md5, Algorithm::NoSign,
+        Algorithm::Rc2, Algorithm::Rc4, Algorithm::Rc5, Algorithm::RsaKeyx, Algorithm::RsaSign,
+        Algorithm::Sha1, Algorithm::Sha256, Algorithm::Sha384, Algorithm::Sha512,
+        Algorithm::TripleDes, Algorithm::TripleDes112,
+    ];
+
+    ALGORITHMS.iter().cloned().find(|&a| a as ffi::ALG_ID == alg_id)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::*;
+
+    fn raw_bindings(data_len: u32, data_offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0; 24];
+        for &dword in &[data_len, data_offset] {
+            raw.extend((0..4).map(|i| (dword >> (i * 8)) as u8));
+        }
+        raw.extend_from_slice(data);
+        raw
+    }
+
+    #[test]
+    fn channel_bindings() {
+        let bindings = ChannelBindings::from_raw(raw_bindings(7, 32, b"tls:abc")).unwrap();
+        assert_eq!(bindings.application_data(), b"tls:abc");
+        assert_eq!(bindings.value(), b"abc");
+
+        // the buffer may start at an odd address
+        let mut raw = raw_bindings(7, 32, b"tls:abc");
+        raw.insert(0, 0);
+        let bindings = ChannelBindings::from_raw(raw[1..].to_owned()).unwrap();
+        assert_eq!(bindings.value(), b"abc");
+
+        for raw in vec![vec![0; 31], raw_bindings(8, 32, b"tls:abc"), raw_bindings(!0, !0, b"")] {
+            let err = ChannelBindings::from_raw(raw).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -1,7 +1,5 @@
-extern crate advapi32;
-
-use crypt32;
-use kernel32;
+use ffi;
+use std::cmp;
 use std::env;
 use std::io::{self, Read, Write, Error};
 use std::mem;
@@ -9,13 +7,14 @@ use std::net::{TcpStream, TcpListener};
 use std::ptr;
 use std::sync::{Once, ONCE_INIT};
 use std::thread;
-use winapi;
 
 use Inner;
 use crypt_prov::{AcquireOptions, ProviderType};
-use cert_context::{CertContext, KeySpec, HashAlgorithm};
-use cert_store::{CertStore, Memory, CertAdd};
+use ncrypt_key::{KeyAlgorithm, NcryptKey, NcryptProvider};
+use cert_context::{CertContext, KeySpec, KeyUsage, HashAlgorithm, PrivateKey};
+use cert_store::{self, CertStore, Memory, CertAdd};
 use schannel_cred::{Direction, Protocol, Algorithm, SchannelCred};
+use security_context;
 use tls_stream::{self, HandshakeError};
 
 #[test]
@@ -38,8 +37,8 @@ fn invalid_algorithms() {
     let creds = SchannelCred::builder()
         .supported_algorithms(&[Algorithm::Rc2, Algorithm::Ecdsa])
         .acquire(Direction::Outbound);
-    assert_eq!(creds.err().unwrap().raw_os_error().unwrap(),
-               winapi::SEC_E_ALGORITHM_MISMATCH as i32);
+    assert_eq!(::error::code(&creds.err().unwrap()).unwrap(),
+               ffi::SEC_E_ALGORITHM_MISMATCH as i32);
 }
 
 #[test]
@@ -81,8 +80,24 @@ fn invalid_protocol() {
         .err()
         .unwrap();
     let err = unwrap_handshake(err);
-    assert_eq!(err.raw_os_error().unwrap(),
-               winapi::SEC_E_UNSUPPORTED_FUNCTION as i32);
+    assert_eq!(::error::code(&err).unwrap(),
+               ffi::SEC_E_UNSUPPORTED_FUNCTION as i32);
+}
+
+#[test]
+fn protocol_unsupported_by_os() {
+    let _disabled = ::os_support::disable(::Feature::Tls12);
+    let creds = SchannelCred::builder()
+        .enabled_protocols(&[Protocol::Tls12])
+        .acquire(Direction::Outbound);
+    let err = creds.err().unwrap();
+    assert_eq!(err.to_string(), "TLS 1.1 and 1.2 requires Windows 7 or later");
+    assert_eq!(::Error::from(err).kind(), ::ErrorKind::Unsupported);
+
+    SchannelCred::builder()
+        .enabled_protocols(&[Protocol::Tls10])
+        .acquire(Direction::Outbound)
+        .unwrap();
 }
 
 #[test]
@@ -115,7 +130,7 @@ fn expired_cert() {
         .err()
         .unwrap();
     let err = unwrap_handshake(err);
-    assert_eq!(err.raw_os_error().unwrap(), winapi::CERT_E_EXPIRED as i32);
+    assert_eq!(::error::code(&err).unwrap(), ffi::CERT_E_EXPIRED as i32);
 }
 
 #[test]
@@ -130,8 +145,8 @@ fn self_signed_cert() {
         .err()
         .unwrap();
     let err = unwrap_handshake(err);
-    assert_eq!(err.raw_os_error().unwrap(),
-               winapi::CERT_E_UNTRUSTEDROOT as i32);
+    assert_eq!(::error::code(&err).unwrap(),
+               ffi::CERT_E_UNTRUSTEDROOT as i32);
 }
 
 #[test]
@@ -163,8 +178,8 @@ fn wrong_host_cert() {
         .err()
         .unwrap();
     let err = unwrap_handshake(err);
-    assert_eq!(err.raw_os_error().unwrap(),
-               winapi::CERT_E_CN_NO_MATCH as i32);
+    assert_eq!(::error::code(&err).unwrap(),
+               ffi::CERT_E_CN_NO_MATCH as i32);
 }
 
 #[test]
@@ -194,8 +209,8 @@ fn validation_failure_is_permanent() {
     };
     stream.get_ref().set_nonblocking(false).unwrap();
     let err = unwrap_handshake(stream.handshake().err().unwrap());
-    assert_eq!(err.raw_os_error().unwrap(),
-               winapi::CERT_E_UNTRUSTEDROOT as i32);
+    assert_eq!(::error::code(&err).unwrap(),
+               ffi::CERT_E_UNTRUSTEDROOT as i32);
 }
 
 #[test]
@@ -229,14 +244,14 @@ fn verify_callback_error() {
         .domain("google.com")
         .verify_callback(|validation_result| {
             assert!(validation_result.result().is_ok());
-            Err(io::Error::from_raw_os_error(winapi::CERT_E_UNTRUSTEDROOT))
+            Err(io::Error::from_raw_os_error(ffi::CERT_E_UNTRUSTEDROOT))
         })
         .connect(creds, stream)
         .err()
         .unwrap();
     let err = unwrap_handshake(err);
-    assert_eq!(err.raw_os_error().unwrap(),
-               winapi::CERT_E_UNTRUSTEDROOT as i32);
+    assert_eq!(::error::code(&err).unwrap(),
+               ffi::CERT_E_UNTRUSTEDROOT as i32);
 }
 
 #[test]
@@ -250,21 +265,21 @@ fn verify_callback_gives_failed_cert() {
         .verify_callback(|validation_result| {
             let expected_finger = vec!(100, 20, 80, 217, 74, 101, 250, 235, 59, 99, 16, 40, 216, 232, 108, 149, 67, 29, 184, 17);
             assert_eq!(validation_result.failed_certificate().unwrap().fingerprint(HashAlgorithm::sha1()).unwrap(), expected_finger);
-            Err(io::Error::from_raw_os_error(winapi::CERT_E_UNTRUSTEDROOT))
+            Err(io::Error::from_raw_os_error(ffi::CERT_E_UNTRUSTEDROOT))
         })
         .connect(creds, stream)
         .err()
         .unwrap();
     let err = unwrap_handshake(err);
-    assert_eq!(err.raw_os_error().unwrap(),
-               winapi::CERT_E_UNTRUSTEDROOT as i32);
+    assert_eq!(::error::code(&err).unwrap(),
+               ffi::CERT_E_UNTRUSTEDROOT as i32);
 }
 
 const FRIENDLY_NAME: &'static str = "schannel-rs localhost testing cert";
 
 lazy_static! {
     static ref szOID_RSA_SHA256RSA: Vec<u8> =
-        winapi::szOID_RSA_SHA256RSA.bytes().chain(Some(0)).collect();
+        ffi::szOID_RSA_SHA256RSA.bytes().chain(Some(0)).collect();
 }
 
 fn install_certificate() -> io::Result<CertContext> {
@@ -275,29 +290,29 @@ fn install_certificate() -> io::Result<CertContext> {
         let mut buffer = "schannel-rs test suite".encode_utf16()
                                                  .chain(Some(0))
                                                  .collect::<Vec<_>>();
-        let res = advapi32::CryptAcquireContextW(&mut provider,
-                                                 buffer.as_ptr(),
-                                                 ptr::null_mut(),
-                                                 winapi::PROV_RSA_FULL,
-                                                 winapi::CRYPT_MACHINE_KEYSET);
-        if res != winapi::TRUE {
+        let res = ffi::CryptAcquireContextW(&mut provider,
+                                            buffer.as_ptr(),
+                                            ptr::null_mut(),
+                                            ffi::PROV_RSA_FULL,
+                                            ffi::CRYPT_MACHINE_KEYSET);
+        if res != ffi::TRUE {
             // create a new key container (since it does not exist)
-            let res = advapi32::CryptAcquireContextW(&mut provider,
-                                                     buffer.as_ptr(),
-                                                     ptr::null_mut(),
-                                                     winapi::PROV_RSA_FULL,
-                                                     winapi::CRYPT_NEWKEYSET | winapi::CRYPT_MACHINE_KEYSET);
-            if res != winapi::TRUE {
+            let res = ffi::CryptAcquireContextW(&mut provider,
+                                                buffer.as_ptr(),
+                                                ptr::null_mut(),
+                                                ffi::PROV_RSA_FULL,
+                                                ffi::CRYPT_NEWKEYSET | ffi::CRYPT_MACHINE_KEYSET);
+            if res != ffi::TRUE {
                 return Err(Error::last_os_error())
             }
         }
 
         // create a new keypair (RSA-2048)
-        let res = advapi32::CryptGenKey(provider,
-                                        winapi::AT_SIGNATURE,
-                                        0x0800<<16 | winapi::CRYPT_EXPORTABLE,
-                                        &mut hkey);
-        if res != winapi::TRUE {
+        let res = ffi::CryptGenKey(provider,
+                                   ffi::AT_SIGNATURE,
+                                   0x0800<<16 | ffi::CRYPT_EXPORTABLE,
+                                   &mut hkey);
+        if res != ffi::TRUE {
             return Err(Error::last_os_error());
         }
 
@@ -305,42 +320,42 @@ fn install_certificate() -> io::Result<CertContext> {
         let name = "CN=localhost,O=schannel-rs,OU=schannel-rs,G=schannel_rs".encode_utf16()
                                                .chain(Some(0))
                                                .collect::<Vec<_>>();
-        let mut cname_buffer: [winapi::WCHAR; winapi::UNLEN as usize + 1] = mem::zeroed();
-        let mut cname_len = cname_buffer.len() as winapi::DWORD;
-        let res = crypt32::CertStrToNameW(winapi::X509_ASN_ENCODING,
-                                          name.as_ptr(),
-                                          winapi::CERT_X500_NAME_STR,
-                                          ptr::null_mut(),
-                                          cname_buffer.as_mut_ptr() as *mut u8,
-                                          &mut cname_len,
-                                          ptr::null_mut());
-        if res != winapi::TRUE {
+        let mut cname_buffer: [ffi::WCHAR; ffi::UNLEN as usize + 1] = mem::zeroed();
+        let mut cname_len = cname_buffer.len() as ffi::DWORD;
+        let res = ffi::CertStrToNameW(ffi::X509_ASN_ENCODING,
+                                      name.as_ptr(),
+                                      ffi::CERT_X500_NAME_STR,
+                                      ptr::null_mut(),
+                                      cname_buffer.as_mut_ptr() as *mut u8,
+                                      &mut cname_len,
+                                      ptr::null_mut());
+        if res != ffi::TRUE {
             return Err(Error::last_os_error());
         }
 
-        let mut subject_issuer = winapi::CERT_NAME_BLOB {
+        let mut subject_issuer = ffi::CERT_NAME_BLOB {
             cbData: cname_len,
             pbData: cname_buffer.as_ptr() as *mut u8,
         };
-        let mut key_provider = winapi::CRYPT_KEY_PROV_INFO {
+        let mut key_provider = ffi::CRYPT_KEY_PROV_INFO {
             pwszContainerName: buffer.as_mut_ptr(),
             pwszProvName: ptr::null_mut(),
-            dwProvType: winapi::PROV_RSA_FULL,
-            dwFlags: winapi::CRYPT_MACHINE_KEYSET,
+            dwProvType: ffi::PROV_RSA_FULL,
+            dwFlags: ffi::CRYPT_MACHINE_KEYSET,
             cProvParam: 0,
             rgProvParam: ptr::null_mut(),
-            dwKeySpec: winapi::AT_SIGNATURE,
+            dwKeySpec: ffi::AT_SIGNATURE,
         };
-        let mut sig_algorithm = winapi::CRYPT_ALGORITHM_IDENTIFIER {
+        let mut sig_algorithm = ffi::CRYPT_ALGORITHM_IDENTIFIER {
             pszObjId: szOID_RSA_SHA256RSA.as_ptr() as *mut _,
             Parameters: mem::zeroed(),
         };
-        let mut expiration_date: winapi::SYSTEMTIME = mem::zeroed();
-        kernel32::GetSystemTime(&mut expiration_date);
-        let mut file_time: winapi::FILETIME = mem::zeroed();
-        let res = kernel32::SystemTimeToFileTime(&mut expiration_date,
-                                                 &mut file_time);
-        if res != winapi::TRUE {
+        let mut expiration_date: ffi::SYSTEMTIME = mem::zeroed();
+        ffi::GetSystemTime(&mut expiration_date);
+        let mut file_time: ffi::FILETIME = mem::zeroed();
+        let res = ffi::SystemTimeToFileTime(&mut expiration_date,
+                                            &mut file_time);
+        if res != ffi::TRUE {
             return Err(Error::last_os_error());
         }
         let mut timestamp: u64 = file_time.dwLowDateTime as u64 |
@@ -349,21 +364,21 @@ fn install_certificate() -> io::Result<CertContext> {
         timestamp += (1E9 as u64) / 100 * (60 * 60 * 24);
         file_time.dwLowDateTime = timestamp as u32;
         file_time.dwHighDateTime = (timestamp >> 32) as u32;
-        let res = kernel32::FileTimeToSystemTime(&file_time,
-                                                 &mut expiration_date);
-        if res != winapi::TRUE {
+        let res = ffi::FileTimeToSystemTime(&file_time,
+                                            &mut expiration_date);
+        if res != ffi::TRUE {
             return Err(Error::last_os_error());
         }
 
         // create a self signed certificate
-        let cert_context = crypt32::CertCreateSelfSignCertificate(0 as winapi::ULONG_PTR,
-                                                                  &mut subject_issuer,
-                                                                  0,
-                                                                  &mut key_provider,
-                                                                  &mut sig_algorithm,
-                                                                  ptr::null_mut(),
-                                                                  &mut expiration_date,
-                                                                  ptr::null_mut());
+        let cert_context = ffi::CertCreateSelfSignCertificate(0 as ffi::ULONG_PTR,
+                                                              &mut subject_issuer,
+                                                              0,
+                                                              &mut key_provider,
+                                                              &mut sig_algorithm,
+                                                              ptr::null_mut(),
+                                                              &mut expiration_date,
+                                                              ptr::null_mut());
         if cert_context.is_null() {
             return Err(Error::last_os_error());
         }
@@ -561,52 +576,154 @@ fn accept_one_byte_at_a_time() {
 }
 
 #[test]
-fn split_cert_key() {
+fn application_protocols() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
     let addr = listener.local_addr().unwrap();
     let t = thread::spawn(move || {
-        let cert = include_bytes!("../test/cert.der");
-        let mut store = Memory::new().unwrap();
-        store.add_encoded_certificate(cert).unwrap();
-        let store = store.into_store();
+        for &alpn in &[true, false] {
+            let stream = TcpStream::connect(&addr).unwrap();
+            let creds = SchannelCred::builder()
+                                     .acquire(Direction::Outbound).unwrap();
+            let mut builder = tls_stream::Builder::new();
+            builder.domain("localhost");
+            if alpn {
+                builder.request_application_protocols(&[b"h2", b"http/1.1"]);
+            }
+            let mut stream = builder.connect(creds, stream).unwrap();
+            let expected = if alpn { Some(b"h2".to_vec()) } else { None };
+            assert_eq!(stream.negotiated_application_protocol().unwrap(), expected);
+            stream.shutdown().unwrap();
+        }
+    });
+
+    for _ in 0..2 {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+                            .cert(cert.clone())
+                            .acquire(Direction::Inbound)
+                            .unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .request_application_protocols(&[b"h2", b"http/1.1"])
+            .accept(creds, stream)
+            .unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    t.join().unwrap();
+}
+
+#[test]
+fn application_protocols_unsupported_by_os() {
+    let _disabled = ::os_support::disable(::Feature::Alpn);
+    let creds = SchannelCred::builder().acquire(Direction::Outbound).unwrap();
+    let stream = io::Cursor::new(vec![]);
+    let err = tls_stream::Builder::new()
+        .domain("localhost")
+        .request_application_protocols(&[b"h2"])
+        .connect(creds, stream)
+        .err()
+        .unwrap();
+    let err = unwrap_handshake(err);
+    assert_eq!(err.to_string(), "ALPN requires Windows 8.1 or later");
+}
+
+#[test]
+fn connection_info() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    fn check(info: &tls_stream::ConnectionInfo) {
+        assert!(info.protocol().unwrap() >= Protocol::Tls12);
+        assert!(info.cipher_strength() >= 128);
+        if ::os_capabilities().supports(::Feature::CipherInfo) {
+            assert!(info.cipher_suite().unwrap().starts_with("TLS_"));
+        } else {
+            assert!(info.cipher_suite().is_none());
+        }
+    }
 
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
         let stream = TcpStream::connect(&addr).unwrap();
         let creds = SchannelCred::builder()
                                  .acquire(Direction::Outbound).unwrap();
         let mut stream = tls_stream::Builder::new()
-            .domain("foobar.com")
-            .cert_store(store)
+            .domain("localhost")
             .connect(creds, stream)
             .unwrap();
-        stream.write_all(&[1, 2, 3, 4]).unwrap();
-        stream.flush().unwrap();
-        assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+        let info = stream.connection_info().unwrap();
+        check(&info);
         stream.shutdown().unwrap();
+        info
     });
 
-    let cert = include_bytes!("../test/cert.der");
-    let cert = CertContext::new(cert).unwrap();
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .accept(creds, stream)
+        .unwrap();
+    let info = stream.connection_info().unwrap();
+    check(&info);
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
 
-    let mut options = AcquireOptions::new();
-    options.container("schannel-test");
-    let type_ = ProviderType::rsa_full();
+    let client = t.join().unwrap();
+    assert_eq!(client.protocol(), info.protocol());
+    assert_eq!(client.cipher_suite(), info.cipher_suite());
+}
 
-    let mut container = match options.acquire(type_) {
-        Ok(container) => container,
-        Err(_) => options.new_keyset(true).acquire(type_).unwrap(),
+#[test]
+fn channel_bindings() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
     };
-    let key = include_bytes!("../test/key.key");
-    container.import()
-        .import(key)
-        .unwrap();
 
-    cert.set_key_prov_info()
-        .container("schannel-test")
-        .type_(type_)
-        .keep_open(true)
-        .key_spec(KeySpec::key_exchange())
-        .set()
-        .unwrap();
+    fn bindings<S: Read + Write>(stream: &tls_stream::TlsStream<S>) -> Option<(Vec<u8>, Vec<u8>)> {
+        let unique = match stream.tls_unique().unwrap() {
+            Some(unique) => unique,
+            None => return None,
+        };
+        let end_point = stream.tls_server_end_point().unwrap().unwrap();
+        let raw = stream.channel_bindings(tls_stream::ChannelBinding::TlsUnique)
+            .unwrap()
+            .unwrap();
+        assert!(raw.application_data().starts_with(b"tls-unique:"));
+        assert!(raw.as_bytes().ends_with(raw.application_data()));
+        assert_eq!(raw.value(), &unique[..]);
+        assert!(!unique.is_empty());
+        // at least SHA-256 is used for the certificate hash
+        assert!(end_point.len() >= 32);
+        Some((unique, end_point))
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(&addr).unwrap();
+        let creds = SchannelCred::builder()
+                                 .enabled_protocols(&[Protocol::Tls12])
+                                 .acquire(Direction::Outbound).unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+        let bindings = bindings(&stream);
+        stream.shutdown().unwrap();
+        bindings
+    });
 
     let stream = listener.accept().unwrap().0;
     let creds = SchannelCred::builder()
@@ -616,11 +733,1272 @@ fn split_cert_key() {
     let mut stream = tls_stream::Builder::new()
         .accept(creds, stream)
         .unwrap();
-    assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
-    stream.write_all(&[1, 2, 3, 4]).unwrap();
-    stream.flush().unwrap();
+    let server = bindings(&stream);
     let mut buf = [0; 1];
     assert_eq!(stream.read(&mut buf).unwrap(), 0);
 
+    let client = t.join().unwrap();
+    assert_eq!(client, server);
+}
+
+#[test]
+fn connection_restrictions() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let client_creds = SchannelCred::builder()
+                                    .acquire(Direction::Outbound)
+                                    .unwrap();
+
+    let mut allowed = tls_stream::Builder::new();
+    allowed.domain("localhost")
+        .enabled_protocols(&[Protocol::Tls12])
+        .supported_algorithms(&[Algorithm::Aes, Algorithm::Sha256, Algorithm::Sha384,
+                                Algorithm::Ecdh, Algorithm::RsaKeyx, Algorithm::DhEphem]);
+    assert_eq!(allowed.get_enabled_protocols(), Some(&[Protocol::Tls12][..]));
+    assert_eq!(allowed.get_supported_algorithms().unwrap().len(), 6);
+
+    let mut old_protocol = tls_stream::Builder::new();
+    old_protocol.domain("localhost").enabled_protocols(&[Protocol::Tls10]);
+    let mut weak_cipher = tls_stream::Builder::new();
+    weak_cipher.domain("localhost").supported_algorithms(&[Algorithm::Rc4, Algorithm::TripleDes]);
+
+    for &(ref builder, ok) in &[(allowed, true), (old_protocol, false), (weak_cipher, false)] {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut builder = builder.clone();
+        let client_creds = client_creds.clone();
+        let t = thread::spawn(move || {
+            let stream = TcpStream::connect(&addr).unwrap();
+            match builder.connect(client_creds, stream) {
+                Ok(mut stream) => {
+                    assert!(ok);
+                    stream.write_all(&[1]).unwrap();
+                }
+                Err(e) => {
+                    assert!(!ok);
+                    unwrap_handshake(e);
+                }
+            }
+        });
+
+        // the server has nothing to check and may complete its side of the
+        // handshake either way
+        let stream = listener.accept().unwrap().0;
+        if let Ok(mut stream) = tls_stream::Builder::new().accept(creds.clone(), stream) {
+            let mut buf = [0; 1];
+            if ok {
+                stream.read_exact(&mut buf).unwrap();
+            }
+        }
+
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn connection_protocol_unsupported_by_os() {
+    let _disabled = ::os_support::disable(::Feature::Tls12);
+    let creds = SchannelCred::builder().acquire(Direction::Outbound).unwrap();
+    // the check fails before anything is written to the stream
+    let stream = io::Cursor::new(vec![]);
+    let err = tls_stream::Builder::new()
+        .domain("google.com")
+        .enabled_protocols(&[Protocol::Tls12])
+        .connect(creds, stream)
+        .err()
+        .unwrap();
+    let err = unwrap_handshake(err);
+    assert_eq!(err.to_string(), "TLS 1.1 and 1.2 requires Windows 7 or later");
+}
+
+#[test]
+fn trust_store() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let mut trusted = Memory::new().unwrap().into_store();
+    trusted.add_cert(&cert, CertAdd::Always).unwrap();
+
+    let mut untrusted = Memory::new().unwrap();
+    untrusted.add_encoded_certificate(include_bytes!("../test/self-signed.badssl.com.cer"))
+        .unwrap();
+    let untrusted = untrusted.into_store();
+
+    // the localhost certificate is in the system's root store, which is
+    // ignored along with the rest of the system's roots
+    let cases = [(trusted.clone(), "localhost", None),
+                 (untrusted, "localhost", Some(ffi::CERT_E_UNTRUSTEDROOT)),
+                 (trusted, "example.com", Some(ffi::CERT_E_CN_NO_MATCH))];
+    for &(ref store, domain, error) in &cases {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = store.clone();
+        let t = thread::spawn(move || {
+            let stream = TcpStream::connect(&addr).unwrap();
+            let creds = SchannelCred::builder()
+                                     .acquire(Direction::Outbound).unwrap();
+            let mut builder = tls_stream::Builder::new();
+            builder.domain(domain).trust_store(store);
+            assert!(builder.get_trust_store().is_some());
+            match builder.connect(creds, stream) {
+                Ok(mut stream) => {
+                    assert_eq!(error, None);
+                    stream.write_all(&[1]).unwrap();
+                }
+                Err(e) => {
+                    let err = unwrap_handshake(e);
+                    assert_eq!(::error::code(&err), error.map(|e| e as i32));
+                }
+            }
+        });
+
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+                            .cert(cert.clone())
+                            .acquire(Direction::Inbound)
+                            .unwrap();
+        if let Ok(mut stream) = tls_stream::Builder::new().accept(creds, stream) {
+            if error.is_none() {
+                let mut buf = [0; 1];
+                stream.read_exact(&mut buf).unwrap();
+            }
+        }
+
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn trust_store_unsupported_by_os() {
+    let _disabled = ::os_support::disable(::Feature::ExclusiveRoot);
+    let creds = SchannelCred::builder().acquire(Direction::Outbound).unwrap();
+    // the check fails before anything is written to the stream
+    let stream = io::Cursor::new(vec![]);
+    let err = tls_stream::Builder::new()
+        .domain("google.com")
+        .trust_store(Memory::new().unwrap().into_store())
+        .connect(creds, stream)
+        .err()
+        .unwrap();
+    let err = unwrap_handshake(err);
+    assert_eq!(err.to_string(), "exclusive trust stores requires Windows 7 or later");
+}
+
+#[test]
+fn hostname_verification() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    // the localhost certificate has no subject alternative names, so its
+    // common name is used, and it is never valid for an IP address
+    let cases = [("LOCALHOST.", false, None),
+                 ("example.com", false, Some(ffi::CERT_E_CN_NO_MATCH)),
+                 ("example.com", true, None),
+                 ("127.0.0.1", false, Some(ffi::CERT_E_CN_NO_MATCH)),
+                 ("127.0.0.1", true, None)];
+    for &(domain, accept_invalid, error) in &cases {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let t = thread::spawn(move || {
+            let stream = TcpStream::connect(&addr).unwrap();
+            let creds = SchannelCred::builder()
+                                     .acquire(Direction::Outbound).unwrap();
+            let mut builder = tls_stream::Builder::new();
+            builder.domain(domain).accept_invalid_hostnames(accept_invalid);
+            assert_eq!(builder.get_accept_invalid_hostnames(), accept_invalid);
+            match builder.connect(creds, stream) {
+                Ok(mut stream) => {
+                    assert_eq!(error, None);
+                    // IP addresses are not sent for SNI
+                    let sni = stream.requested_server_name().unwrap();
+                    assert_eq!(sni.is_none(), domain == "127.0.0.1");
+                    stream.write_all(&[1]).unwrap();
+                }
+                Err(e) => {
+                    let err = unwrap_handshake(e);
+                    assert_eq!(::error::code(&err), error.map(|e| e as i32));
+                }
+            }
+        });
+
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+                            .cert(cert.clone())
+                            .acquire(Direction::Inbound)
+                            .unwrap();
+        if let Ok(mut stream) = tls_stream::Builder::new().accept(creds, stream) {
+            if error.is_none() {
+                let mut buf = [0; 1];
+                stream.read_exact(&mut buf).unwrap();
+            }
+        }
+
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn large_writes() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let data = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let len = data.len();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(&addr).unwrap();
+        let creds = SchannelCred::builder()
+                                 .acquire(Direction::Outbound).unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+
+        let sizes = stream.stream_sizes().unwrap();
+        assert!(sizes.header() > 0);
+        assert!(sizes.trailer() > 0);
+        let max_message = sizes.max_message() as usize;
+        assert!(max_message > 0);
+
+        // a single write covers several records
+        let n = stream.write(&data).unwrap();
+        assert!(n > max_message);
+        stream.write_all(&data[n..]).unwrap();
+
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        assert!(buf == data);
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .accept(creds, stream)
+        .unwrap();
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).unwrap();
+    stream.write_all(&buf).unwrap();
+    stream.shutdown().unwrap();
+
     t.join().unwrap();
 }
+
+#[test]
+fn buffered_reads() {
+    use std::io::BufRead;
+
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let data = (0..1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let expected = data.clone();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(&addr).unwrap();
+        let creds = SchannelCred::builder()
+                                 .acquire(Direction::Outbound).unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+
+        // records of varying sizes, so that they straddle the reads from
+        // the wrapped stream in different places
+        for (i, chunk) in data.chunks(1000).enumerate() {
+            let len = cmp::max(1, chunk.len().saturating_sub((i % 7) * 100));
+            stream.write_all(&chunk[..len]).unwrap();
+            stream.write_all(&chunk[len..]).unwrap();
+        }
+        stream.shutdown().unwrap();
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .accept(creds, stream)
+        .unwrap();
+
+    // alternate between borrowing the decrypted data in place and copying it
+    let mut out = vec![];
+    let mut buf = [0; 777];
+    loop {
+        let len = {
+            let available = stream.fill_buf().unwrap();
+            let len = cmp::min(available.len(), cmp::max(1, available.len() / 2));
+            out.extend_from_slice(&available[..len]);
+            len
+        };
+        if len == 0 {
+            break;
+        }
+        stream.consume(len);
+
+        let len = stream.read(&mut buf).unwrap();
+        if len == 0 {
+            break;
+        }
+        out.extend_from_slice(&buf[..len]);
+    }
+    assert!(out == expected);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn session_resumption() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    for &resumption in &[true, false] {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let t = thread::spawn(move || {
+            let creds = SchannelCred::builder()
+                                     .session_resumption(resumption)
+                                     .acquire(Direction::Outbound).unwrap();
+            (0..2).map(|_| {
+                let stream = TcpStream::connect(&addr).unwrap();
+                let mut stream = tls_stream::Builder::new()
+                    .domain("localhost")
+                    .connect(creds.clone(), stream)
+                    .unwrap();
+                let resumed = stream.session_resumed().unwrap();
+                stream.shutdown().unwrap();
+                resumed
+            }).collect::<Vec<_>>()
+        });
+
+        let creds = SchannelCred::builder()
+                            .cert(cert.clone())
+                            .session_resumption(resumption)
+                            .acquire(Direction::Inbound)
+                            .unwrap();
+        let mut server = vec![];
+        for _ in 0..2 {
+            let stream = listener.accept().unwrap().0;
+            let mut stream = tls_stream::Builder::new()
+                .accept(creds.clone(), stream)
+                .unwrap();
+            server.push(stream.session_resumed().unwrap());
+            let mut buf = [0; 1];
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        }
+
+        let client = t.join().unwrap();
+        assert_eq!(client, [false, resumption]);
+        assert_eq!(server, [false, resumption]);
+    }
+}
+
+#[test]
+fn generated_self_signed_cert() {
+    if env::var("SCHANNEL_RS_SKIP_SERVER_TESTS").is_ok() {
+        return
+    }
+
+    let name = format!("schannel-rs-self-signed-{}", ::std::process::id());
+    let provider = NcryptProvider::software().unwrap();
+    let key = NcryptKey::generate(&provider, KeyAlgorithm::rsa(), Some(2048), Some(&name))
+        .unwrap();
+    let key = PrivateKey::NcryptKey(key);
+    let cert = CertContext::self_signed("CN=localhost", &key)
+        .key_usage(KeyUsage::digital_signature() | KeyUsage::key_encipherment())
+        .dns_name("localhost")
+        .create()
+        .unwrap();
+    let fingerprint = cert.fingerprint(HashAlgorithm::sha256()).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(&addr).unwrap();
+        let creds = SchannelCred::builder()
+                                 .acquire(Direction::Outbound).unwrap();
+        // the certificate isn't trusted, so validation is left to the
+        // callback, which accepts anything
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .verify_callback(|_| Ok(()))
+            .connect(creds, stream)
+            .unwrap();
+        let peer = stream.peer_certificate().unwrap().unwrap();
+        stream.write_all(&[1]).unwrap();
+        peer.fingerprint(HashAlgorithm::sha256()).unwrap()
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+    let mut buf = [0; 1];
+    stream.read_exact(&mut buf).unwrap();
+
+    assert_eq!(t.join().unwrap(), fingerprint);
+    drop(stream);
+    match key {
+        PrivateKey::NcryptKey(key) => key.delete().unwrap(),
+        PrivateKey::CryptProv(_) => unreachable!(),
+    }
+}
+
+#[test]
+fn ocsp_stapled() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+    let response = [0x30, 0x03, 0x0a, 0x01, 0x00];
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(&addr).unwrap();
+        let creds = SchannelCred::builder()
+                            .request_ocsp(true)
+                            .acquire(Direction::Outbound)
+                            .unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+        // Schannel only passes stapled responses on where it supports them
+        if let Some(stapled) = stream.ocsp_response().unwrap() {
+            assert_eq!(stapled, response);
+        }
+        stream.write_all(&[1]).unwrap();
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert.clone())
+                        .ocsp_response(&response)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    assert_eq!(cert.ocsp_response().unwrap(), None);
+    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+    let mut buf = [0; 1];
+    stream.read_exact(&mut buf).unwrap();
+
+    t.join().unwrap();
+}
+
+#[test]
+fn ocsp_not_stapled() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    // the localhost certificate names no OCSP responder, so there is nothing
+    // for the server to staple
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(&addr).unwrap();
+        let mut builder = SchannelCred::builder();
+        builder.request_ocsp(true);
+        assert!(builder.get_request_ocsp());
+        let creds = builder.acquire(Direction::Outbound).unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+        assert_eq!(stream.ocsp_response().unwrap(), None);
+        stream.write_all(&[1]).unwrap();
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .request_ocsp(true)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+    assert_eq!(stream.ocsp_response().unwrap(), None);
+    let mut buf = [0; 1];
+    stream.read_exact(&mut buf).unwrap();
+
+    t.join().unwrap();
+}
+
+#[test]
+fn server_name_indication() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    // the certificate is always validated against `domain`, whichever name
+    // is sent on the wire
+    let cases = [(None, true, Some("localhost")),
+                 (Some("example.com"), true, Some("example.com")),
+                 (Some("example.com"), false, None)];
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        for &(sni_hostname, use_sni, expected) in &cases {
+            let stream = TcpStream::connect(&addr).unwrap();
+            let creds = SchannelCred::builder()
+                                     .acquire(Direction::Outbound).unwrap();
+            let mut builder = tls_stream::Builder::new();
+            builder.domain("localhost").use_sni(use_sni);
+            if let Some(sni_hostname) = sni_hostname {
+                builder.sni_hostname(sni_hostname);
+            }
+            let mut stream = builder.connect(creds, stream).unwrap();
+            assert_eq!(stream.requested_server_name().unwrap(),
+                       expected.map(|s| s.to_owned()));
+            stream.shutdown().unwrap();
+        }
+    });
+
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    for &(_, _, expected) in &cases {
+        let stream = listener.accept().unwrap().0;
+        let mut stream = tls_stream::Builder::new()
+            .accept(creds.clone(), stream)
+            .unwrap();
+        assert_eq!(stream.requested_server_name().unwrap(),
+                   expected.map(|s| s.to_owned()));
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    t.join().unwrap();
+}
+
+#[test]
+fn client_certificate() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    // (client sends a certificate, server requires one)
+    let cases = [(true, false), (false, false), (true, true), (false, true)];
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client_cert = cert.clone();
+    let t = thread::spawn(move || {
+        for &(send, require) in &cases {
+            let stream = TcpStream::connect(&addr).unwrap();
+            let mut creds = SchannelCred::builder();
+            if send {
+                creds.cert(client_cert.clone());
+            }
+            let creds = creds.acquire(Direction::Outbound).unwrap();
+            let stream = tls_stream::Builder::new()
+                .domain("localhost")
+                .connect(creds, stream);
+            if send || !require {
+                let mut stream = stream.unwrap();
+                assert!(stream.peer_certificate().unwrap().is_some());
+                stream.shutdown().unwrap();
+            }
+        }
+    });
+
+    let creds = SchannelCred::builder()
+                        .cert(cert.clone())
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    for &(send, require) in &cases {
+        let stream = listener.accept().unwrap().0;
+        let stream = tls_stream::Builder::new()
+            .request_client_certificate(true)
+            .require_client_certificate(require)
+            .accept(creds.clone(), stream);
+        if !send && require {
+            let err = unwrap_handshake(stream.err().unwrap());
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            continue;
+        }
+
+        let mut stream = stream.unwrap();
+        match stream.peer_certificate().unwrap() {
+            Some(peer) => {
+                assert!(send);
+                assert_eq!(peer.fingerprint(HashAlgorithm::sha1()).unwrap(),
+                           cert.fingerprint(HashAlgorithm::sha1()).unwrap());
+            }
+            None => assert!(!send),
+        }
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    t.join().unwrap();
+}
+
+#[test]
+fn shutdown_close_notify() {
+    use std::io::BufRead;
+    use std::sync::mpsc;
+
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done_tx, done_rx) = mpsc::channel();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(&addr).unwrap();
+        let creds = SchannelCred::builder()
+                                 .acquire(Direction::Outbound).unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+        while stream.get_buf().len() < 4 {
+            stream.fill_buf().unwrap();
+        }
+
+        // buffered data is still readable after shutting down
+        stream.shutdown().unwrap();
+        stream.shutdown().unwrap();
+        let mut buf = [0; 1024];
+        assert_eq!(stream.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &[1, 2, 3, 4]);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(stream.write_all(&[1]).is_err());
+
+        // keep the connection open until the server has shut down too
+        done_rx.recv().unwrap();
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .accept(creds, stream)
+        .unwrap();
+    stream.write_all(&[1, 2, 3, 4]).unwrap();
+    stream.flush().unwrap();
+
+    // the client's close_notify is a clean end of file
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    stream.shutdown().unwrap();
+    stream.shutdown().unwrap();
+    assert!(stream.write_all(&[1]).is_err());
+    done_tx.send(()).unwrap();
+
+    t.join().unwrap();
+}
+
+#[test]
+fn would_block() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    // Fails every other call with `WouldBlock`, and otherwise transfers at
+    // most a few bytes.
+    #[derive(Debug)]
+    struct Interrupting<S> {
+        inner: S,
+        block_read: bool,
+        block_write: bool,
+    }
+
+    impl<S: Read> Read for Interrupting<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.block_read = !self.block_read;
+            if self.block_read {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "blocked"));
+            }
+            let len = cmp::min(buf.len(), 7);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    impl<S: Write> Write for Interrupting<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.block_write = !self.block_write;
+            if self.block_write {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "blocked"));
+            }
+            let len = cmp::min(buf.len(), 7);
+            self.inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    fn interrupting<S>(inner: S) -> Interrupting<S> {
+        Interrupting { inner: inner, block_read: false, block_write: false }
+    }
+
+    fn handshake<S: Read + Write>(mut res: Result<tls_stream::TlsStream<S>, HandshakeError<S>>)
+                                  -> tls_stream::TlsStream<S> {
+        let mut interruptions = 0;
+        loop {
+            match res {
+                Ok(stream) => {
+                    assert!(interruptions > 0);
+                    return stream;
+                }
+                Err(HandshakeError::Interrupted(s)) => {
+                    interruptions += 1;
+                    res = s.handshake();
+                }
+                Err(HandshakeError::Failure(e)) => panic!("{}", e),
+            }
+        }
+    }
+
+    fn retry<T, F: FnMut() -> io::Result<T>>(mut f: F) -> T {
+        loop {
+            match f() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res.unwrap(),
+            }
+        }
+    }
+
+    fn echo<S: Read + Write>(stream: &mut tls_stream::TlsStream<S>, data: &[u8]) {
+        let mut n = 0;
+        while n < data.len() {
+            n += retry(|| stream.write(&data[n..]));
+        }
+        retry(|| stream.flush());
+        let mut buf = vec![];
+        while buf.len() < data.len() {
+            let mut chunk = [0; 1024];
+            let len = retry(|| stream.read(&mut chunk));
+            assert!(len > 0);
+            buf.extend_from_slice(&chunk[..len]);
+        }
+        assert_eq!(buf, data);
+    }
+
+    let data = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+    let client_data = data.clone();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(&addr).unwrap();
+        let creds = SchannelCred::builder()
+                                 .acquire(Direction::Outbound).unwrap();
+        let mut stream = handshake(tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, interrupting(stream)));
+        echo(&mut stream, &client_data);
+        retry(|| stream.shutdown());
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let mut stream = handshake(tls_stream::Builder::new()
+        .accept(creds, interrupting(stream)));
+    echo(&mut stream, &data);
+    let mut buf = [0; 1];
+    assert_eq!(retry(|| stream.read(&mut buf)), 0);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn split_cert_key() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let cert = include_bytes!("../test/cert.der");
+        let mut store = Memory::new().unwrap();
+        store.add_encoded_certificate(cert).unwrap();
+        let store = store.into_store();
+
+        let stream = TcpStream::connect(&addr).unwrap();
+        let creds = SchannelCred::builder()
+                                 .acquire(Direction::Outbound).unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("foobar.com")
+            .cert_store(store)
+            .connect(creds, stream)
+            .unwrap();
+        stream.write_all(&[1, 2, 3, 4]).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+        stream.shutdown().unwrap();
+    });
+
+    let cert = include_bytes!("../test/cert.der");
+    let cert = CertContext::new(cert).unwrap();
+
+    let mut options = AcquireOptions::new();
+    options.container("schannel-test");
+    let type_ = ProviderType::rsa_full();
+
+    let mut container = match options.acquire(type_) {
+        Ok(container) => container,
+        Err(_) => options.new_keyset(true).acquire(type_).unwrap(),
+    };
+    let key = include_bytes!("../test/key.key");
+    container.import()
+        .import(key)
+        .unwrap();
+
+    cert.set_key_prov_info()
+        .container("schannel-test")
+        .type_(type_)
+        .keep_open(true)
+        .key_spec(KeySpec::key_exchange())
+        .set()
+        .unwrap();
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .accept(creds, stream)
+        .unwrap();
+    assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+    stream.write_all(&[1, 2, 3, 4]).unwrap();
+    stream.flush().unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn pfx_cert_and_key() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let cert = include_bytes!("../test/cert.der");
+        let mut store = Memory::new().unwrap();
+        store.add_encoded_certificate(cert).unwrap();
+        let store = store.into_store();
+
+        let stream = TcpStream::connect(&addr).unwrap();
+        let creds = SchannelCred::builder()
+                                 .acquire(Direction::Outbound).unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("foobar.com")
+            .cert_store(store)
+            .connect(creds, stream)
+            .unwrap();
+        stream.write_all(&[1, 2, 3, 4]).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+        stream.shutdown().unwrap();
+    });
+
+    let pfx = include_bytes!("../test/identity.p12");
+    let (cert, key) = cert_store::pfx_to_cert_and_key(pfx, "mypass").unwrap();
+    assert_eq!(cert, CertContext::new(include_bytes!("../test/cert.der")).unwrap());
+    assert_eq!(key.export_public_spki().unwrap(), cert.public_key().unwrap());
+
+    // the key lives only in memory, so there's no container to refer to
+    unsafe {
+        let mut len = 0;
+        let res = ffi::CertGetCertificateContextProperty(cert.as_inner(),
+                                                         ffi::CERT_KEY_PROV_INFO_PROP_ID,
+                                                         ptr::null_mut(),
+                                                         &mut len);
+        assert_eq!(res, ffi::FALSE);
+    }
+
+    let thumbprint = cert.fingerprint(HashAlgorithm::sha1()).unwrap();
+    cert_store::pfx_to_cert_and_key_by_thumbprint(pfx, "mypass", &thumbprint).unwrap();
+    assert!(cert_store::pfx_to_cert_and_key_by_thumbprint(pfx, "mypass", &[0; 20]).is_err());
+    drop(key);
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .accept(creds, stream)
+        .unwrap();
+    assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+    stream.write_all(&[1, 2, 3, 4]).unwrap();
+    stream.flush().unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn raw_handles() {
+    // a context which has only produced its ClientHello is still owned by us
+    let mut creds = SchannelCred::builder().acquire(Direction::Outbound).unwrap();
+    let (context, _) = security_context::initialize(&mut creds, false, None, None).unwrap();
+    let raw = context.into_raw();
+    let context = unsafe { tls_stream::SecurityContext::from_raw(raw) };
+    assert_eq!(context.as_raw().dwLower, raw.dwLower);
+    assert_eq!(context.as_raw().dwUpper, raw.dwUpper);
+    drop(context);
+    drop(creds);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let cert = include_bytes!("../test/cert.der");
+        let mut store = Memory::new().unwrap();
+        store.add_encoded_certificate(cert).unwrap();
+        let store = store.into_store();
+
+        let creds = SchannelCred::builder().acquire(Direction::Outbound).unwrap();
+        // a shared credential keeps its handle
+        let clone = creds.clone();
+        let creds = match creds.into_raw() {
+            Ok(_) => panic!("shared credential gave up its handle"),
+            Err(creds) => creds,
+        };
+        drop(clone);
+        let creds = match creds.into_raw() {
+            Ok(raw) => unsafe { SchannelCred::from_raw(raw) },
+            Err(_) => panic!("credential is still shared"),
+        };
+        let stream = TcpStream::connect(&addr).unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("foobar.com")
+            .cert_store(store)
+            .connect(creds, stream)
+            .unwrap();
+
+        // the stream still owns its context, but it can be queried directly
+        unsafe {
+            let mut handle = stream.security_context().as_raw();
+            let mut sizes: ffi::SecPkgContext_StreamSizes = mem::zeroed();
+            let status = ffi::QueryContextAttributesW(&mut handle,
+                                                      ffi::SECPKG_ATTR_STREAM_SIZES,
+                                                      &mut sizes as *mut _ as *mut _);
+            assert_eq!(status, ffi::SEC_E_OK);
+            assert!(sizes.cbMaximumMessage > 0);
+        }
+
+        stream.write_all(&[1, 2, 3, 4]).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+        stream.shutdown().unwrap();
+    });
+
+    let pfx = include_bytes!("../test/identity.p12");
+    let (cert, _) = cert_store::pfx_to_cert_and_key(pfx, "mypass").unwrap();
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+                        .cert(cert)
+                        .acquire(Direction::Inbound)
+                        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .accept(creds, stream)
+        .unwrap();
+    assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+    stream.write_all(&[1, 2, 3, 4]).unwrap();
+    stream.flush().unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn builder_reuse() {
+    let pfx = include_bytes!("../test/identity.p12");
+    let (cert, _) = cert_store::pfx_to_cert_and_key(pfx, "mypass").unwrap();
+    let mut server = SchannelCred::builder();
+    server.enabled_protocols(&[Protocol::Tls12]).cert(cert.clone());
+
+    let mut store = Memory::new().unwrap();
+    store.add_encoded_certificate(include_bytes!("../test/cert.der")).unwrap();
+    let mut client = tls_stream::Builder::new();
+    client.domain("foobar.com")
+          .cert_store(store.into_store())
+          .verify_callback(|res| res.result());
+
+    let mut server_clone = server.clone();
+    server_clone.enabled_protocols(&[Protocol::Tls11])
+                .supported_algorithms(&[Algorithm::Aes128, Algorithm::Ecdsa])
+                .cert(cert.clone());
+    let mut client_clone = client.clone();
+    client_clone.domain("example.com");
+
+    assert_eq!(server.get_enabled_protocols(), Some(&[Protocol::Tls12][..]));
+    assert_eq!(server.get_supported_algorithms(), None);
+    assert_eq!(server.get_certs(), &[cert.clone()][..]);
+    assert_eq!(server_clone.get_enabled_protocols(), Some(&[Protocol::Tls11][..]));
+    assert_eq!(server_clone.get_supported_algorithms(),
+               Some(&[Algorithm::Aes128, Algorithm::Ecdsa][..]));
+    assert_eq!(server_clone.get_certs().len(), 2);
+    assert!(server.get_session_resumption());
+    assert!(!server.clone().session_resumption(false).get_session_resumption());
+    assert_eq!(client.get_domain(), Some("foobar.com".to_owned()));
+    assert!(client.has_verify_callback());
+    assert!(client.get_cert_store().is_some());
+    assert_eq!(client_clone.get_domain(), Some("example.com".to_owned()));
+    assert!(client.get_sni_hostname().is_none());
+    assert!(client.get_use_sni());
+    assert!(!client.get_request_client_certificate());
+    assert!(client.get_renegotiation());
+    assert!(client.clone().require_client_certificate(true).get_request_client_certificate());
+    assert!(format!("{:?}", client).contains("foobar.com"));
+    assert!(tls_stream::Builder::new().get_domain().is_none());
+
+    // both handshakes are set up from the original builders
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let clients = (0..2).map(|_| {
+        let client = client.clone();
+        thread::spawn(move || {
+            let creds = SchannelCred::builder().acquire(Direction::Outbound).unwrap();
+            let stream = TcpStream::connect(&addr).unwrap();
+            let mut stream = client.clone().connect(creds, stream).unwrap();
+            stream.write_all(&[1]).unwrap();
+            stream.shutdown().unwrap();
+        })
+    }).collect::<Vec<_>>();
+
+    let servers = (0..2).map(|_| {
+        let creds = server.acquire(Direction::Inbound).unwrap();
+        let stream = listener.accept().unwrap().0;
+        thread::spawn(move || {
+            let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+            let mut buf = vec![];
+            stream.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, [1]);
+        })
+    }).collect::<Vec<_>>();
+
+    for t in clients.into_iter().chain(servers) {
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn filetime_limits() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let epoch = ffi::FILETIME { dwLowDateTime: 0, dwHighDateTime: 0 };
+    let epoch = ::filetime_to_system_time(&epoch);
+    assert_eq!(UNIX_EPOCH.duration_since(epoch).unwrap(),
+               Duration::from_secs(::UNIX_EPOCH_OFFSET_SECS));
+    let max = ffi::FILETIME { dwLowDateTime: !0, dwHighDateTime: !0 };
+    assert!(::filetime_to_system_time(&max) > UNIX_EPOCH);
+
+    let before = ::system_time_to_filetime(epoch - Duration::from_secs(1));
+    assert_eq!((before.dwLowDateTime, before.dwHighDateTime), (0, 0));
+}
+
+#[test]
+fn send_sync() {
+    fn send_sync<T: Send + Sync>() {}
+    send_sync::<CertContext>();
+    send_sync::<CertStore>();
+    send_sync::<::cert_chain::CertChainContext>();
+    send_sync::<::ctl_context::CtlContext>();
+    send_sync::<::crypt_key::CryptKey>();
+    send_sync::<::crypt_prov::CryptProv>();
+    send_sync::<::ncrypt_key::NcryptKey>();
+    send_sync::<SchannelCred>();
+    send_sync::<tls_stream::TlsStream<TcpStream>>();
+}
+
+#[test]
+fn shared_credentials() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let pfx = include_bytes!("../test/identity.p12");
+    let (cert, _) = cert_store::pfx_to_cert_and_key(pfx, "mypass").unwrap();
+    let server_creds = SchannelCred::builder()
+                               .cert(cert)
+                               .acquire(Direction::Inbound)
+                               .unwrap();
+    let client_creds = SchannelCred::builder().acquire(Direction::Outbound).unwrap();
+
+    let clients = (0..8).map(|_| {
+        let creds = client_creds.clone();
+        thread::spawn(move || {
+            let cert = include_bytes!("../test/cert.der");
+            let mut store = Memory::new().unwrap();
+            store.add_encoded_certificate(cert).unwrap();
+
+            let stream = TcpStream::connect(&addr).unwrap();
+            let mut stream = tls_stream::Builder::new()
+                .domain("foobar.com")
+                .cert_store(store.into_store())
+                .connect(creds, stream)
+                .unwrap();
+            stream.write_all(&[1, 2, 3, 4]).unwrap();
+            stream.flush().unwrap();
+            assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+            stream.shutdown().unwrap();
+        })
+    }).collect::<Vec<_>>();
+
+    let servers = (0..8).map(|_| {
+        let creds = server_creds.clone();
+        let stream = listener.accept().unwrap().0;
+        thread::spawn(move || {
+            let mut stream = tls_stream::Builder::new()
+                .accept(creds, stream)
+                .unwrap();
+            assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+            stream.write_all(&[1, 2, 3, 4]).unwrap();
+            stream.flush().unwrap();
+            let mut buf = [0; 1];
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        })
+    }).collect::<Vec<_>>();
+
+    for t in clients.into_iter().chain(servers) {
+        t.join().unwrap();
+    }
+}
+
+#[cfg(feature = "log")]
+mod logging {
+    use log::{self, LevelFilter, Log, Metadata, Record};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Mutex, Once, ONCE_INIT};
+    use std::thread;
+
+    use cert_store::{self, Memory};
+    use schannel_cred::{Direction, SchannelCred};
+    use tls_stream;
+
+    lazy_static! {
+        static ref EVENTS: Mutex<Vec<String>> = Mutex::new(vec![]);
+    }
+
+    struct Capture;
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            if record.target().starts_with("schannel") {
+                EVENTS.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture;
+
+    // Tests run concurrently and share the logger, so they only check that
+    // their own events are among those captured.
+    fn captured(event: &str) -> bool {
+        EVENTS.lock().unwrap().iter().any(|e| e.starts_with(event))
+    }
+
+    fn install() {
+        static INIT: Once = ONCE_INIT;
+        INIT.call_once(|| {
+            log::set_logger(&CAPTURE).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+    }
+
+    #[test]
+    fn handshake_events() {
+        install();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let t = thread::spawn(move || {
+            let cert = include_bytes!("../test/cert.der");
+            let mut store = Memory::new().unwrap();
+            store.add_encoded_certificate(cert).unwrap();
+
+            let creds = SchannelCred::builder().acquire(Direction::Outbound).unwrap();
+            let stream = TcpStream::connect(&addr).unwrap();
+            let mut stream = tls_stream::Builder::new()
+                .domain("foobar.com")
+                .cert_store(store.into_store())
+                .connect(creds, stream)
+                .unwrap();
+            stream.write_all(&[1]).unwrap();
+            stream.shutdown().unwrap();
+        });
+
+        let pfx = include_bytes!("../test/identity.p12");
+        let (cert, _) = cert_store::pfx_to_cert_and_key(pfx, "mypass").unwrap();
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+                            .cert(cert)
+                            .acquire(Direction::Inbound)
+                            .unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .accept(creds, stream)
+            .unwrap();
+        stream.read_to_end(&mut vec![]).unwrap();
+        t.join().unwrap();
+
+        assert!(captured("imported PKCS #12 archive"));
+        assert!(captured("acquired CNG private key"));
+        assert!(captured("InitializeSecurityContextW returned 0x90312"));
+        assert!(captured("AcceptSecurityContext returned 0x0"));
+        assert!(captured("validating the server certificate"));
+        assert!(captured("chain contains a certificate from the configured store"));
+        assert!(captured("chain policy returned 0x0"));
+        assert!(captured("handshake finished"));
+        assert!(captured("shutting down"));
+    }
+
+    #[test]
+    fn store_events() {
+        install();
+
+        let count = super::local_root_store().certs().count();
+        assert!(captured("opened current user store \"Root\"") ||
+                captured("opened local machine store \"Root\""));
+        assert!(captured(&format!("enumerated {} certificates in store", count)));
+    }
+}
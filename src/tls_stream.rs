@@ -1,6 +1,5 @@
 //! Schannel TLS streams.
-use crypt32;
-use secur32;
+use ffi;
 use std::any::Any;
 use std::cmp;
 use std::error::Error;
@@ -10,31 +9,66 @@ use std::mem;
 use std::ptr;
 use std::slice;
 use std::sync::Arc;
-use winapi;
 
-use {INIT_REQUESTS, ACCEPT_REQUESTS, Inner, secbuf, secbuf_desc};
+use {INIT_REQUESTS, ACCEPT_REQUESTS, CredSettings, Inner, secbuf, secbuf_desc};
 use cert_chain::{CertChain, CertChainContext};
 use cert_store::CertStore;
 use cert_context::CertContext;
-use security_context::SecurityContext;
+use hostname::{self, Reference};
+use security_context;
 use context_buffer::ContextBuffer;
-use schannel_cred::SchannelCred;
+use os_support::{self, Feature};
+use schannel_cred::{Algorithm, Protocol, SchannelCred};
+
+pub use security_context::{ChannelBinding, ChannelBindings, ConnectionInfo, SecurityContext};
 
 lazy_static! {
     static ref szOID_PKIX_KP_SERVER_AUTH: Vec<u8> =
-        winapi::szOID_PKIX_KP_SERVER_AUTH.bytes().chain(Some(0)).collect();
+        ffi::szOID_PKIX_KP_SERVER_AUTH.bytes().chain(Some(0)).collect();
     static ref szOID_SERVER_GATED_CRYPTO: Vec<u8> =
-        winapi::szOID_SERVER_GATED_CRYPTO.bytes().chain(Some(0)).collect();
+        ffi::szOID_SERVER_GATED_CRYPTO.bytes().chain(Some(0)).collect();
     static ref szOID_SGC_NETSCAPE: Vec<u8> =
-        winapi::szOID_SGC_NETSCAPE.bytes().chain(Some(0)).collect();
+        ffi::szOID_SGC_NETSCAPE.bytes().chain(Some(0)).collect();
 }
 
 /// A builder type for `TlsStream`s.
-#[derive(Default)]
+///
+/// A builder may be cloned and reused to set up any number of streams.
+#[derive(Default, Clone)]
 pub struct Builder {
     domain: Option<Vec<u16>>,
     verify_callback: Option<Arc<Fn(CertValidationResult) -> io::Result<()> + Sync + Send>>,
     cert_store: Option<CertStore>,
+    trust_store: Option<CertStore>,
+    application_protocols: Option<Vec<Vec<u8>>>,
+    sni_hostname: Option<Vec<u16>>,
+    disable_sni: bool,
+    accept_invalid_hostnames: bool,
+    request_client_cert: bool,
+    require_client_cert: bool,
+    disable_renegotiation: bool,
+    enabled_protocols: Option<Vec<Protocol>>,
+    supported_algorithms: Option<Vec<Algorithm>>,
+}
+
+impl fmt::Debug for Builder {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Builder")
+            .field("domain", &self.get_domain())
+            .field("verify_callback", &self.has_verify_callback())
+            .field("cert_store", &self.cert_store)
+            .field("trust_store", &self.trust_store)
+            .field("application_protocols", &self.application_protocols)
+            .field("sni_hostname", &self.get_sni_hostname())
+            .field("use_sni", &self.get_use_sni())
+            .field("accept_invalid_hostnames", &self.accept_invalid_hostnames)
+            .field("request_client_certificate", &self.request_client_cert)
+            .field("require_client_certificate", &self.require_client_cert)
+            .field("renegotiation", &self.get_renegotiation())
+            .field("enabled_protocols", &self.enabled_protocols)
+            .field("supported_algorithms", &self.supported_algorithms)
+            .finish()
+    }
 }
 
 impl Builder {
@@ -46,12 +80,142 @@ impl Builder {
     /// Sets the domain associated with connections created with this `Builder`.
     ///
     /// The domain will be used for Server Name Indication as well as
-    /// certificate validation.
+    /// certificate validation, unless `sni_hostname` or `use_sni` override
+    /// the former.
+    ///
+    /// The domain may also be an IP address, which is matched against the IP
+    /// addresses the server's certificate was issued for and, as IP addresses
+    /// are not valid for SNI, is only sent to the server if `sni_hostname` is
+    /// set. DNS names are matched case-insensitively, and wildcards in
+    /// certificates follow RFC 6125: `*.example.com` matches `a.example.com`,
+    /// but neither `a.b.example.com` nor `example.com`.
     pub fn domain(&mut self, domain: &str) -> &mut Builder {
         self.domain = Some(domain.encode_utf16().chain(Some(0)).collect());
         self
     }
 
+    /// Sets the hostname sent for Server Name Indication, overriding the one
+    /// set by `domain`.
+    ///
+    /// The server's certificate is still validated against `domain`, so this
+    /// can be used to reach a server through a name other than the one its
+    /// certificate was issued for.
+    pub fn sni_hostname(&mut self, hostname: &str) -> &mut Builder {
+        self.sni_hostname = Some(hostname.encode_utf16().chain(Some(0)).collect());
+        self
+    }
+
+    /// Determines if a hostname is sent for Server Name Indication.
+    ///
+    /// Disabling SNI is appropriate for connections to IP addresses, for which
+    /// certificates can still be validated through `domain`.
+    ///
+    /// Defaults to `true`.
+    pub fn use_sni(&mut self, use_sni: bool) -> &mut Builder {
+        self.disable_sni = !use_sni;
+        self
+    }
+
+    /// Determines if the server's certificate is accepted even if it was not
+    /// issued for `domain`.
+    ///
+    /// The rest of the certificate is still validated, so unlike a
+    /// `verify_callback` ignoring errors, this only disables the check of
+    /// the hostname.
+    ///
+    /// Defaults to `false`.
+    pub fn accept_invalid_hostnames(&mut self, accept: bool) -> &mut Builder {
+        self.accept_invalid_hostnames = accept;
+        self
+    }
+
+    /// Determines if servers accepting connections with this `Builder` ask
+    /// clients for a certificate.
+    ///
+    /// Clients may still decline to send one, which can be checked with
+    /// `TlsStream::peer_certificate` once the handshake has completed.
+    ///
+    /// Defaults to `false`.
+    pub fn request_client_certificate(&mut self, request: bool) -> &mut Builder {
+        self.request_client_cert = request;
+        self
+    }
+
+    /// Determines if servers accepting connections with this `Builder`
+    /// require clients to present a certificate.
+    ///
+    /// This implies `request_client_certificate`, and the handshake fails
+    /// with a `PermissionDenied` error if the client does not send one.
+    ///
+    /// Defaults to `false`.
+    pub fn require_client_certificate(&mut self, require: bool) -> &mut Builder {
+        self.require_client_cert = require;
+        self
+    }
+
+    /// Determines if the peer may renegotiate the session.
+    ///
+    /// Servers may ask for renegotiation in the middle of a session, for
+    /// example to request a client certificate once they have seen a request.
+    /// The new handshake is performed transparently by reads, including
+    /// validation of the server's certificate. If renegotiation is disabled
+    /// such a request instead makes the read fail.
+    ///
+    /// Defaults to `true`.
+    pub fn renegotiation(&mut self, allow: bool) -> &mut Builder {
+        self.disable_renegotiation = !allow;
+        self
+    }
+
+    /// Restricts the protocols of connections created with this `Builder`
+    /// beyond those enabled for their credential.
+    ///
+    /// Schannel only applies such restrictions to whole credentials, so each
+    /// connection acquires a credential of its own, with the settings of the
+    /// one it is given but only the protocols enabled for both. Such
+    /// connections don't share the session cache of the original credential.
+    /// Credentials created with `SchannelCred::from_raw` can't be reacquired,
+    /// so for those, and as a safety net, the handshake also fails if the
+    /// negotiated protocol is not one of `enabled_protocols`. Enabling TLS 1.1
+    /// or 1.2 on a version of Windows without them fails with an
+    /// `UnsupportedFeature` error.
+    pub fn enabled_protocols(&mut self, enabled_protocols: &[Protocol]) -> &mut Builder {
+        assert!(enabled_protocols.iter()
+            .all(|a| {
+                match *a {
+                    Protocol::__ForExtensibility => false,
+                    _ => true,
+                }
+            }));
+        self.enabled_protocols = Some(enabled_protocols.to_owned());
+        self
+    }
+
+    /// Restricts the algorithms of connections created with this `Builder`
+    /// beyond those supported by their credential.
+    ///
+    /// Like `enabled_protocols`, these are applied to a credential acquired
+    /// for each connection, and are also checked once the handshake has
+    /// completed, which fails if the negotiated cipher, hash or key exchange
+    /// algorithm is not one of `supported_algorithms`. `Algorithm::Aes`
+    /// stands for AES with any key size, and `Algorithm::Ecdh` covers
+    /// ephemeral elliptic curve Diffie-Hellman as well. Algorithms which
+    /// Schannel does not report, such as the hash of AEAD cipher suites, are
+    /// not checked.
+    pub fn supported_algorithms(&mut self,
+                                supported_algorithms: &[Algorithm])
+                                -> &mut Builder {
+        assert!(supported_algorithms.iter()
+            .all(|a| {
+                match *a {
+                    Algorithm::__ForExtensibility => false,
+                    _ => true,
+                }
+            }));
+        self.supported_algorithms = Some(supported_algorithms.to_owned());
+        self
+    }
+
     /// Set a verification callback to be used for connections created with this `Builder`.
     ///
     /// The callback is provided with an io::Result indicating if the (pre)validation was
@@ -79,6 +243,106 @@ impl Builder {
         self
     }
 
+    /// Specifies the only certificate store whose roots are trusted when
+    /// validating a server's certificate.
+    ///
+    /// Unlike with `cert_store`, the system's root store is not trusted at
+    /// all: the handshake fails unless the server's certificate chains up to
+    /// one of the certificates in `trust_store`. The server's certificate is
+    /// still checked against `domain`.
+    ///
+    /// This option is only used for client connections, and requires Windows
+    /// 7 or later. On older versions the handshake fails with an
+    /// `UnsupportedFeature` error.
+    pub fn trust_store(&mut self, trust_store: CertStore) -> &mut Builder {
+        self.trust_store = Some(trust_store);
+        self
+    }
+
+    /// Sets the protocols offered through Application-Layer Protocol
+    /// Negotiation (ALPN), in order of preference.
+    ///
+    /// Clients offer these protocols to the server, while servers select the
+    /// first of them which the client also offers. The result is available
+    /// from `TlsStream::negotiated_application_protocol` once the handshake
+    /// has completed.
+    ///
+    /// ALPN requires Windows 8.1 or later, and on older versions the
+    /// handshake fails with an `UnsupportedFeature` error. Each protocol must
+    /// be between 1 and 255 bytes long.
+    pub fn request_application_protocols(&mut self, protos: &[&[u8]]) -> &mut Builder {
+        self.application_protocols = Some(protos.iter().map(|p| p.to_vec()).collect());
+        self
+    }
+
+    /// Returns the domain set by `domain`, if any.
+    pub fn get_domain(&self) -> Option<String> {
+        self.domain.as_ref().map(|d| String::from_utf16_lossy(&d[..d.len() - 1]))
+    }
+
+    /// Returns the hostname set by `sni_hostname`, if any.
+    pub fn get_sni_hostname(&self) -> Option<String> {
+        self.sni_hostname.as_ref().map(|d| String::from_utf16_lossy(&d[..d.len() - 1]))
+    }
+
+    /// Returns whether Server Name Indication is used, as set by `use_sni`.
+    pub fn get_use_sni(&self) -> bool {
+        !self.disable_sni
+    }
+
+    /// Returns whether certificates are accepted for any hostname, as set by
+    /// `accept_invalid_hostnames`.
+    pub fn get_accept_invalid_hostnames(&self) -> bool {
+        self.accept_invalid_hostnames
+    }
+
+    /// Returns whether client certificates are requested, as set by
+    /// `request_client_certificate` or `require_client_certificate`.
+    pub fn get_request_client_certificate(&self) -> bool {
+        self.request_client_cert || self.require_client_cert
+    }
+
+    /// Returns whether client certificates are required, as set by
+    /// `require_client_certificate`.
+    pub fn get_require_client_certificate(&self) -> bool {
+        self.require_client_cert
+    }
+
+    /// Returns whether renegotiation is allowed, as set by `renegotiation`.
+    pub fn get_renegotiation(&self) -> bool {
+        !self.disable_renegotiation
+    }
+
+    /// Returns the protocols set by `enabled_protocols`, if any.
+    pub fn get_enabled_protocols(&self) -> Option<&[Protocol]> {
+        self.enabled_protocols.as_ref().map(|p| &p[..])
+    }
+
+    /// Returns the algorithms set by `supported_algorithms`, if any.
+    pub fn get_supported_algorithms(&self) -> Option<&[Algorithm]> {
+        self.supported_algorithms.as_ref().map(|a| &a[..])
+    }
+
+    /// Determines if a verification callback has been set by `verify_callback`.
+    pub fn has_verify_callback(&self) -> bool {
+        self.verify_callback.is_some()
+    }
+
+    /// Returns the certificate store set by `cert_store`, if any.
+    pub fn get_cert_store(&self) -> Option<&CertStore> {
+        self.cert_store.as_ref()
+    }
+
+    /// Returns the certificate store set by `trust_store`, if any.
+    pub fn get_trust_store(&self) -> Option<&CertStore> {
+        self.trust_store.as_ref()
+    }
+
+    /// Returns the protocols set by `request_application_protocols`, if any.
+    pub fn get_application_protocols(&self) -> Option<&[Vec<u8>]> {
+        self.application_protocols.as_ref().map(|p| &p[..])
+    }
+
     /// Initialize a new TLS session where the stream provided will be
     /// connecting to a remote TLS server.
     ///
@@ -123,10 +387,61 @@ impl Builder {
                          -> Result<TlsStream<S>, HandshakeError<S>>
         where S: Read + Write
     {
-        let domain = self.domain.as_ref().map(|s| &s[..]);
-        let (ctxt, buf) = match SecurityContext::initialize(&mut cred,
-                                                            accept,
-                                                            domain) {
+        if let Some(ref enabled_protocols) = self.enabled_protocols {
+            let tls12 = enabled_protocols.iter().any(|p| match *p {
+                Protocol::Tls11 | Protocol::Tls12 => true,
+                _ => false,
+            });
+            if tls12 {
+                if let Err(e) = os_support::require(Feature::Tls12) {
+                    return Err(HandshakeError::Failure(e));
+                }
+            }
+        }
+
+        if self.enabled_protocols.is_some() || self.supported_algorithms.is_some() {
+            match restrict_cred(&cred,
+                                self.get_enabled_protocols(),
+                                self.get_supported_algorithms()) {
+                Ok(Some(restricted)) => cred = restricted,
+                Ok(None) => {}
+                Err(e) => return Err(HandshakeError::Failure(e)),
+            }
+        }
+
+        if self.trust_store.is_some() && !accept {
+            if let Err(e) = os_support::require(Feature::ExclusiveRoot) {
+                return Err(HandshakeError::Failure(e));
+            }
+        }
+
+        let mut application_protocols = match self.application_protocols {
+            Some(ref protos) => match application_protocols_buffer(protos) {
+                Ok(buf) => Some(buf),
+                Err(e) => return Err(HandshakeError::Failure(e)),
+            },
+            None => None,
+        };
+
+        // the name sent for SNI, while `domain` is used for validation, and
+        // IP addresses are never sent
+        let target_name = if self.disable_sni {
+            None
+        } else if self.sni_hostname.is_some() {
+            self.sni_hostname.clone()
+        } else {
+            match self.get_domain() {
+                Some(ref domain) if hostname::is_ip_address(domain) => None,
+                _ => self.domain.clone(),
+            }
+        };
+
+        let alpn = application_protocols.as_mut().map(|b| &mut b[..]);
+        let (ctxt, buf) = match security_context::initialize(&mut cred,
+                                                             accept,
+                                                             target_name.as_ref()
+                                                                 .map(|s| &s[..]),
+                                                             alpn) {
             Ok(pair) => pair,
             Err(e) => return Err(HandshakeError::Failure(e)),
         };
@@ -135,8 +450,17 @@ impl Builder {
             cred: cred,
             context: ctxt,
             cert_store: self.cert_store.clone(),
+            trust_store: self.trust_store.clone(),
             domain: self.domain.clone(),
+            target_name: target_name,
             verify_callback: self.verify_callback.clone(),
+            accept_invalid_hostnames: self.accept_invalid_hostnames,
+            application_protocols: application_protocols,
+            request_client_cert: self.get_request_client_certificate(),
+            require_client_cert: self.require_client_cert,
+            allow_renegotiation: !self.disable_renegotiation,
+            enabled_protocols: self.enabled_protocols.clone(),
+            supported_algorithms: self.supported_algorithms.clone(),
             stream: stream,
             accept: accept,
             accept_first: true,
@@ -147,8 +471,10 @@ impl Builder {
                 validated: false,
             },
             needs_read: 1,
-            dec_in: Cursor::new(Vec::new()),
             enc_in: Cursor::new(Vec::new()),
+            enc_start: 0,
+            dec_start: 0,
+            dec_end: 0,
             out_buf: Cursor::new(buf.map(|b| b.to_owned()).unwrap_or(Vec::new())),
             last_write_len: 0,
         };
@@ -159,6 +485,36 @@ impl Builder {
     }
 }
 
+/// Encodes `protos` as a `SEC_APPLICATION_PROTOCOLS` structure offering them
+/// through ALPN.
+fn application_protocols_buffer(protos: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+    try!(os_support::require(Feature::Alpn));
+
+    let mut list = vec![];
+    for proto in protos {
+        if proto.is_empty() || proto.len() > 255 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "ALPN protocols must be between 1 and 255 bytes long"));
+        }
+        list.push(proto.len() as u8);
+        list.extend_from_slice(proto);
+    }
+    if list.len() > 0xffff {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many ALPN protocols"));
+    }
+
+    // ProtocolListsSize, followed by a single SEC_APPLICATION_PROTOCOL_LIST
+    let ext = ffi::alpn_negotiation_ext();
+    let lists_size = (4 + 2 + list.len()) as u32;
+    let list_size = list.len() as u16;
+    let mut buf = vec![lists_size as u8, (lists_size >> 8) as u8,
+                       (lists_size >> 16) as u8, (lists_size >> 24) as u8,
+                       ext as u8, (ext >> 8) as u8, (ext >> 16) as u8, (ext >> 24) as u8,
+                       list_size as u8, (list_size >> 8) as u8];
+    buf.extend_from_slice(&list);
+    Ok(buf)
+}
+
 enum State {
     Initializing {
         needs_flush: bool,
@@ -166,33 +522,95 @@ enum State {
         shutting_down: bool,
         validated: bool,
     },
-    Streaming { sizes: winapi::SecPkgContext_StreamSizes, },
+    Streaming { sizes: ffi::SecPkgContext_StreamSizes, },
     Shutdown,
 }
 
+/// The sizes of the records exchanged over a TLS session.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamSizes {
+    header: u32,
+    trailer: u32,
+    max_message: u32,
+}
+
+impl StreamSizes {
+    fn from_raw(sizes: &ffi::SecPkgContext_StreamSizes) -> StreamSizes {
+        StreamSizes {
+            header: sizes.cbHeader,
+            trailer: sizes.cbTrailer,
+            max_message: sizes.cbMaximumMessage,
+        }
+    }
+
+    /// Returns the size of the header preceding each record, in bytes.
+    pub fn header(&self) -> u32 {
+        self.header
+    }
+
+    /// Returns the maximum size of the trailer following each record, in
+    /// bytes.
+    pub fn trailer(&self) -> u32 {
+        self.trailer
+    }
+
+    /// Returns the maximum amount of plaintext carried by a single record, in
+    /// bytes.
+    ///
+    /// Writes of larger buffers are split into several records.
+    pub fn max_message(&self) -> u32 {
+        self.max_message
+    }
+}
+
+// the most records encrypted by a single call to `write`, which bounds the
+// size of `out_buf`
+const MAX_WRITE_RECORDS: usize = 16;
+
 /// An Schannel TLS stream.
+///
+/// The wrapped stream may be nonblocking, in which case reads, writes and
+/// `shutdown` fail with `WouldBlock` errors whenever it would block, including
+/// in the middle of a renegotiation. They pick up where they left off when
+/// called again, and writes must then be retried with the same data.
 pub struct TlsStream<S> {
     cred: SchannelCred,
     context: SecurityContext,
     cert_store: Option<CertStore>,
+    trust_store: Option<CertStore>,
     domain: Option<Vec<u16>>,
+    target_name: Option<Vec<u16>>,
     verify_callback: Option<Arc<Fn(CertValidationResult) -> io::Result<()> + Sync + Send>>,
+    accept_invalid_hostnames: bool,
+    // the encoded SEC_APPLICATION_PROTOCOLS passed to every handshake call
+    application_protocols: Option<Vec<u8>>,
+    request_client_cert: bool,
+    require_client_cert: bool,
+    allow_renegotiation: bool,
+    enabled_protocols: Option<Vec<Protocol>>,
+    supported_algorithms: Option<Vec<Algorithm>>,
     stream: S,
     state: State,
     accept: bool,
     accept_first: bool,
     needs_read: usize,
-    // valid from position() to len()
-    dec_in: Cursor<Vec<u8>>,
-    // valid from 0 to position()
+    // input which has not been consumed yet is valid from enc_start to
+    // position(), and records before enc_start are decrypted in place
     enc_in: Cursor<Vec<u8>>,
-    // valid from position() to len()
+    enc_start: usize,
+    // decrypted data which has not been read yet, within enc_in
+    dec_start: usize,
+    dec_end: usize,
+    // valid from position() to len(), and reused across writes
     out_buf: Cursor<Vec<u8>>,
     /// the (unencrypted) length of the last write call used to track writes
     last_write_len: usize,
 }
 
 /// ensures that a TlsStream is always Sync/Send
+///
+/// All methods taking `&self` only read state owned by the stream or query
+/// the security context, which Schannel permits concurrently.
 fn _is_sync() {
     fn sync<T: Sync + Send>() {}
     sync::<TlsStream<()>>();
@@ -233,8 +651,8 @@ impl CertValidationResult {
 
     /// Returns the result of the built-in certificate verification process.
     pub fn result(&self) -> io::Result<()> {
-        if self.res as u32 != winapi::ERROR_SUCCESS {
-                Err(io::Error::from_raw_os_error(self.res))
+        if self.res as u32 != ffi::ERROR_SUCCESS {
+                Err(::error::from_code(self.res))
         } else {
                 Ok(())
         }
@@ -296,35 +714,172 @@ impl<S> TlsStream<S>
         &mut self.stream
     }
 
+    /// Returns the security context of the session.
+    ///
+    /// Its raw handle can be passed to other SSPI functions with
+    /// `SecurityContext::as_raw`, but remains owned by this stream.
+    pub fn security_context(&self) -> &SecurityContext {
+        &self.context
+    }
+
+    /// Returns the sizes of the records of the session.
+    ///
+    /// This is `None` while a renegotiation is in progress and after the
+    /// stream has been shut down.
+    pub fn stream_sizes(&self) -> Option<StreamSizes> {
+        match self.state {
+            State::Streaming { ref sizes } => Some(StreamSizes::from_raw(sizes)),
+            _ => None,
+        }
+    }
+
+    /// Returns the protocol and algorithms negotiated for the session.
+    pub fn connection_info(&self) -> io::Result<ConnectionInfo> {
+        self.context.connection_info()
+    }
+
+    /// Returns the certificate presented by the peer, if any.
+    ///
+    /// Servers always present a certificate, but clients only do so when
+    /// asked through `Builder::request_client_certificate`, and may decline
+    /// even then.
+    pub fn peer_certificate(&self) -> io::Result<Option<CertContext>> {
+        match self.context.remote_cert() {
+            Ok(cert) => Ok(Some(cert)),
+            Err(ref e) if ::error::code(e) == Some(ffi::SEC_E_NO_CREDENTIALS as i32) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the DER-encoded OCSP response stapled by the server, if any.
+    ///
+    /// Servers are only asked for a response if the client's credentials
+    /// were acquired with `schannel_cred::Builder::request_ocsp`, and may not
+    /// staple one even then, in which case this returns `None`. It is always
+    /// `None` on server-side streams.
+    pub fn ocsp_response(&self) -> io::Result<Option<Vec<u8>>> {
+        if self.accept {
+            return Ok(None);
+        }
+        match try!(self.peer_certificate()) {
+            Some(cert) => cert.ocsp_response(),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the `tls-unique` channel binding of the session, without its
+    /// `tls-unique:` prefix.
+    ///
+    /// See `channel_bindings` for when this is `None`.
+    pub fn tls_unique(&self) -> io::Result<Option<Vec<u8>>> {
+        let bindings = try!(self.channel_bindings(ChannelBinding::TlsUnique));
+        Ok(bindings.map(|b| b.value().to_vec()))
+    }
+
+    /// Returns the `tls-server-end-point` channel binding of the session,
+    /// without its `tls-server-end-point:` prefix.
+    ///
+    /// See `channel_bindings` for when this is `None`.
+    pub fn tls_server_end_point(&self) -> io::Result<Option<Vec<u8>>> {
+        let bindings = try!(self.channel_bindings(ChannelBinding::TlsServerEndPoint));
+        Ok(bindings.map(|b| b.value().to_vec()))
+    }
+
+    /// Returns the channel bindings of the session of the given kind, which
+    /// can be passed on to SSPI authentication such as Negotiate for
+    /// Extended Protection.
+    ///
+    /// This is `None` while a handshake is in progress and on versions of
+    /// Windows which do not support channel bindings.
+    pub fn channel_bindings(&self, kind: ChannelBinding) -> io::Result<Option<ChannelBindings>> {
+        if let State::Initializing { .. } = self.state {
+            return Ok(None);
+        }
+        self.context.channel_bindings(kind)
+    }
+
+    /// Returns the hostname requested through Server Name Indication, if
+    /// any.
+    ///
+    /// On server-side streams this is the name sent by the client, which can
+    /// be used for virtual hosting. On client-side streams it is the name
+    /// sent to the server, as set by `Builder::domain` or
+    /// `Builder::sni_hostname`, and is `None` if the domain is an IP address.
+    pub fn requested_server_name(&self) -> io::Result<Option<String>> {
+        if self.accept {
+            self.context.client_specified_target()
+        } else {
+            Ok(self.target_name
+                .as_ref()
+                .map(|d| String::from_utf16_lossy(&d[..d.len() - 1])))
+        }
+    }
+
+    /// Determines if the session was resumed rather than set up by a full
+    /// handshake.
+    ///
+    /// Sessions are cached per `SchannelCred`, so only streams set up with
+    /// clones of one credential can resume each other's sessions.
+    pub fn session_resumed(&self) -> io::Result<bool> {
+        self.context.session_resumed()
+    }
+
+    /// Returns the protocol negotiated through ALPN, if any.
+    ///
+    /// This is `None` if no protocols were requested with
+    /// `Builder::request_application_protocols`, or if the peer did not
+    /// agree on one.
+    pub fn negotiated_application_protocol(&self) -> io::Result<Option<Vec<u8>>> {
+        if self.application_protocols.is_none() {
+            return Ok(None);
+        }
+        self.context.application_protocol()
+    }
+
     /// Returns a reference to the buffer of pending data.
     ///
     /// Like `BufRead::fill_buf` except that it will return an empty slice
     /// rather than reading from the wrapped stream if there is no buffered
     /// data.
     pub fn get_buf(&self) -> &[u8] {
-        &self.dec_in.get_ref()[self.dec_in.position() as usize..]
+        &self.enc_in.get_ref()[self.dec_start..self.dec_end]
     }
 
     /// Shuts the TLS session down.
+    ///
+    /// This sends a `close_notify` alert to the peer and flushes the wrapped
+    /// stream, which is left open. Dropping a stream without calling this
+    /// method leaves the peer unable to tell a closed session from a
+    /// truncated one.
+    ///
+    /// Once the session is shut down writes fail, while reads return any
+    /// data which was already decrypted and then end of file. Calling this
+    /// method again has no effect. If the wrapped stream is nonblocking this
+    /// may fail with `WouldBlock`, in which case it should be called again.
+    ///
+    /// A `close_notify` alert received from the peer is reported by reads as
+    /// end of file.
     pub fn shutdown(&mut self) -> io::Result<()> {
         match self.state {
             State::Shutdown => return Ok(()),
             State::Initializing { shutting_down: true, .. } => {}
             _ => {
                 unsafe {
-                    let mut token = winapi::SCHANNEL_SHUTDOWN;
+                    let mut token = ffi::SCHANNEL_SHUTDOWN;
                     let ptr = &mut token as *mut _ as *mut u8;
                     let size = mem::size_of_val(&token);
                     let token = slice::from_raw_parts_mut(ptr, size);
-                    let mut buf = [secbuf(winapi::SECBUFFER_TOKEN, Some(token))];
+                    let mut buf = [try!(secbuf(ffi::SECBUFFER_TOKEN, Some(token)))];
                     let mut desc = secbuf_desc(&mut buf);
 
-                    match secur32::ApplyControlToken(self.context.get_mut(), &mut desc) {
-                        winapi::SEC_E_OK => {}
-                        err => return Err(io::Error::from_raw_os_error(err as i32)),
+                    match ffi::ApplyControlToken(self.context.get_mut(), &mut desc) {
+                        ffi::SEC_E_OK => {}
+                        err => return Err(::error::from_code(err as i32)),
                     }
                 }
 
+                debug!("shutting down");
+
                 self.state = State::Initializing {
                     needs_flush: false,
                     more_calls: true,
@@ -332,6 +887,11 @@ impl<S> TlsStream<S>
                     validated: false,
                 };
                 self.needs_read = 0;
+
+                // Records we have not decrypted, such as the peer's own
+                // close_notify, must not be mistaken for a handshake token.
+                let len = self.enc_in_len();
+                self.consume_enc_in(len);
             }
         }
 
@@ -340,62 +900,77 @@ impl<S> TlsStream<S>
 
     fn step_initialize(&mut self) -> io::Result<()> {
         unsafe {
+            let start = self.enc_start;
             let pos = self.enc_in.position() as usize;
-            let mut inbufs = [secbuf(winapi::SECBUFFER_TOKEN,
-                                     Some(&mut self.enc_in.get_mut()[..pos])),
-                              secbuf(winapi::SECBUFFER_EMPTY, None)];
-            let mut inbuf_desc = secbuf_desc(&mut inbufs);
-
-            let mut outbufs = [secbuf(winapi::SECBUFFER_TOKEN, None),
-                               secbuf(winapi::SECBUFFER_ALERT, None),
-                               secbuf(winapi::SECBUFFER_EMPTY, None)];
+            let alpn = self.application_protocols.as_mut().map(|b| &mut b[..]);
+            let ninbufs = if alpn.is_some() { 3 } else { 2 };
+            let mut inbufs = [try!(secbuf(ffi::SECBUFFER_TOKEN,
+                                          Some(&mut self.enc_in.get_mut()[start..pos]))),
+                              try!(secbuf(ffi::SECBUFFER_EMPTY, None)),
+                              try!(secbuf(ffi::SECBUFFER_APPLICATION_PROTOCOLS, alpn))];
+            let mut inbuf_desc = secbuf_desc(&mut inbufs[..ninbufs]);
+
+            let mut outbufs = [try!(secbuf(ffi::SECBUFFER_TOKEN, None)),
+                               try!(secbuf(ffi::SECBUFFER_ALERT, None)),
+                               try!(secbuf(ffi::SECBUFFER_EMPTY, None))];
             let mut outbuf_desc = secbuf_desc(&mut outbufs);
 
             let mut attributes = 0;
 
+            #[cfg(feature = "log-tokens")]
+            trace!("handshake token in: {:?}", &self.enc_in.get_ref()[start..pos]);
+
             let status = if self.accept {
                 let ptr = if self.accept_first {
                     ptr::null_mut()
                 } else {
                     self.context.get_mut()
                 };
-                secur32::AcceptSecurityContext(self.cred.get_mut(),
-                                               ptr,
-                                               &mut inbuf_desc,
-                                               ACCEPT_REQUESTS,
-                                               0,
-                                               self.context.get_mut(),
-                                               &mut outbuf_desc,
-                                               &mut attributes,
-                                               ptr::null_mut())
+                let mut requests = ACCEPT_REQUESTS;
+                if self.request_client_cert {
+                    requests |= ffi::ASC_REQ_MUTUAL_AUTH;
+                }
+                ffi::AcceptSecurityContext(self.cred.get_mut(),
+                                           ptr,
+                                           &mut inbuf_desc,
+                                           requests,
+                                           0,
+                                           self.context.get_mut(),
+                                           &mut outbuf_desc,
+                                           &mut attributes,
+                                           ptr::null_mut())
             } else {
-                let domain = self.domain
+                let target_name = self.target_name
                     .as_ref()
                     .map(|b| b.as_ptr() as *mut u16)
                     .unwrap_or(ptr::null_mut());
 
-                secur32::InitializeSecurityContextW(self.cred.get_mut(),
-                                                    self.context.get_mut(),
-                                                    domain,
-                                                    INIT_REQUESTS,
-                                                    0,
-                                                    0,
-                                                    &mut inbuf_desc,
-                                                    0,
-                                                    ptr::null_mut(),
-                                                    &mut outbuf_desc,
-                                                    &mut attributes,
-                                                    ptr::null_mut())
+                ffi::InitializeSecurityContextW(self.cred.get_mut(),
+                                                self.context.get_mut(),
+                                                target_name,
+                                                INIT_REQUESTS,
+                                                0,
+                                                0,
+                                                &mut inbuf_desc,
+                                                0,
+                                                ptr::null_mut(),
+                                                &mut outbuf_desc,
+                                                &mut attributes,
+                                                ptr::null_mut())
             };
 
             for buf in &outbufs[1..] {
                 if !buf.pvBuffer.is_null() {
-                    secur32::FreeContextBuffer(buf.pvBuffer);
+                    ffi::FreeContextBuffer(buf.pvBuffer);
                 }
             }
 
+            debug!("{} returned {:#x}",
+                   if self.accept { "AcceptSecurityContext" } else { "InitializeSecurityContextW" },
+                   status);
+
             match status {
-                winapi::SEC_I_CONTINUE_NEEDED => {
+                ffi::SEC_I_CONTINUE_NEEDED => {
                     // Windows apparently doesn't like AcceptSecurityContext
                     // being called as if it were the second time unless the
                     // first call to AcceptSecurityContext succeeded with
@@ -410,30 +985,24 @@ impl<S> TlsStream<S>
                     // once we've actually successfully received the full
                     // "token" from the client.
                     self.accept_first = false;
-                    let nread = if inbufs[1].BufferType == winapi::SECBUFFER_EXTRA {
-                        self.enc_in.position() as usize - inbufs[1].cbBuffer as usize
-                    } else {
-                        self.enc_in.position() as usize
-                    };
+                    let nread = consumed(self.enc_in_len(), &inbufs[1]);
                     let to_write = ContextBuffer(outbufs[0]);
+                    #[cfg(feature = "log-tokens")]
+                    trace!("handshake token out: {:?}", &to_write[..]);
 
                     self.consume_enc_in(nread);
-                    self.needs_read = (self.enc_in.position() == 0) as usize;
+                    self.needs_read = (self.enc_in_len() == 0) as usize;
                     self.out_buf.get_mut().extend_from_slice(&to_write);
                 }
-                winapi::SEC_E_INCOMPLETE_MESSAGE => {
-                    self.needs_read = if inbufs[1].BufferType == winapi::SECBUFFER_MISSING {
+                ffi::SEC_E_INCOMPLETE_MESSAGE => {
+                    self.needs_read = if inbufs[1].BufferType == ffi::SECBUFFER_MISSING {
                         inbufs[1].cbBuffer as usize
                     } else {
                         1
                     };
                 }
-                winapi::SEC_E_OK => {
-                    let nread = if inbufs[1].BufferType == winapi::SECBUFFER_EXTRA {
-                        self.enc_in.position() as usize - inbufs[1].cbBuffer as usize
-                    } else {
-                        self.enc_in.position() as usize
-                    };
+                ffi::SEC_E_OK => {
+                    let nread = consumed(self.enc_in_len(), &inbufs[1]);
                     let to_write = if outbufs[0].pvBuffer.is_null() {
                         None
                     } else {
@@ -441,11 +1010,13 @@ impl<S> TlsStream<S>
                     };
 
                     self.consume_enc_in(nread);
-                    self.needs_read = (self.enc_in.position() == 0) as usize;
+                    self.needs_read = (self.enc_in_len() == 0) as usize;
                     if let Some(to_write) = to_write {
+                        #[cfg(feature = "log-tokens")]
+                        trace!("handshake token out: {:?}", &to_write[..]);
                         self.out_buf.get_mut().extend_from_slice(&to_write);
                     }
-                    if self.enc_in.position() != 0 {
+                    if self.enc_in_len() != 0 {
                         try!(self.decrypt());
                     }
                     if let State::Initializing { ref mut more_calls, .. } = self.state {
@@ -453,14 +1024,14 @@ impl<S> TlsStream<S>
                     }
                 }
                 _ => {
-                    return Err(io::Error::from_raw_os_error(status as i32))
+                    return Err(::error::from_code(status as i32))
                 }
             }
             Ok(())
         }
     }
 
-    fn initialize(&mut self) -> io::Result<Option<winapi::SecPkgContext_StreamSizes>> {
+    fn initialize(&mut self) -> io::Result<Option<ffi::SecPkgContext_StreamSizes>> {
         loop {
             match self.state {
                 State::Initializing { mut needs_flush, more_calls, shutting_down, validated } => {
@@ -488,6 +1059,11 @@ impl<S> TlsStream<S>
                     }
 
                     if !more_calls {
+                        debug!("{} finished",
+                               if shutting_down { "shutdown" } else { "handshake" });
+                        if !shutting_down {
+                            try!(self.check_connection());
+                        }
                         self.state = if shutting_down {
                             State::Shutdown
                         } else {
@@ -511,20 +1087,73 @@ impl<S> TlsStream<S>
         }
     }
 
+    /// Checks the negotiated protocol and algorithms against the restrictions
+    /// of the builder.
+    fn check_connection(&self) -> io::Result<()> {
+        if self.enabled_protocols.is_none() && self.supported_algorithms.is_none() {
+            return Ok(());
+        }
+        let info = try!(self.connection_info());
+
+        if let Some(ref enabled_protocols) = self.enabled_protocols {
+            let enabled = match info.protocol() {
+                Some(protocol) => enabled_protocols.contains(&protocol),
+                None => false,
+            };
+            if !enabled {
+                let msg = match info.protocol() {
+                    Some(protocol) => format!("the negotiated protocol {:?} is not enabled", protocol),
+                    None => format!("the negotiated protocol {:#x} is not enabled",
+                                    info.raw_protocol()),
+                };
+                debug!("{}", msg);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        }
+
+        if let Some(ref supported_algorithms) = self.supported_algorithms {
+            let negotiated = [("cipher", info.raw_cipher()),
+                              ("hash", info.raw_hash()),
+                              ("key exchange", info.raw_key_exchange())];
+            for &(kind, alg_id) in &negotiated {
+                if !algorithm_supported(supported_algorithms, alg_id) {
+                    let msg = format!("the negotiated {} algorithm {:#x} is not supported",
+                                      kind, alg_id);
+                    debug!("{}", msg);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Returns true when the certificate was succesfully verified
     /// Returns false, when a verification isn't necessary (yet)
     /// Returns an error when the verification failed
     fn validate(&mut self, require_cert: bool) -> io::Result<bool> {
         // If we're accepting connections then we don't perform any validation
-        // for the remote certificate, that's what they're doing!
+        // for the remote certificate, that's what they're doing! We only check
+        // that one was presented at all if the builder asked for that.
         if self.accept {
-            return Ok(false);
+            if !require_cert || !self.require_client_cert {
+                return Ok(false);
+            }
+            return match try!(self.peer_certificate()) {
+                Some(_) => Ok(true),
+                None => {
+                    debug!("the client did not present a certificate");
+                    Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                       "the client did not present a certificate"))
+                }
+            };
         }
 
         let cert_context = match self.context.remote_cert() {
             Err(_) if !require_cert => return Ok(false),
             ret => try!(ret)
         };
+        debug!("validating the server certificate");
 
         let cert_chain = unsafe {
             let cert_store = self.cert_store
@@ -532,76 +1161,99 @@ impl<S> TlsStream<S>
                 .map(|s| s.as_inner())
                 .unwrap_or(ptr::null_mut());
 
-            let flags = winapi::CERT_CHAIN_CACHE_END_CERT |
-                        winapi::CERT_CHAIN_REVOCATION_CHECK_CACHE_ONLY |
-                        winapi::CERT_CHAIN_REVOCATION_CHECK_CHAIN_EXCLUDE_ROOT;
+            let flags = ffi::CERT_CHAIN_CACHE_END_CERT |
+                        ffi::CERT_CHAIN_REVOCATION_CHECK_CACHE_ONLY |
+                        ffi::CERT_CHAIN_REVOCATION_CHECK_CHAIN_EXCLUDE_ROOT;
 
-            let mut para: winapi::CERT_CHAIN_PARA = mem::zeroed();
-            para.cbSize = mem::size_of_val(&para) as winapi::DWORD;
-            para.RequestedUsage.dwType = winapi::USAGE_MATCH_TYPE_OR;
+            let mut para: ffi::CERT_CHAIN_PARA = mem::zeroed();
+            para.cbSize = mem::size_of_val(&para) as ffi::DWORD;
+            para.RequestedUsage.dwType = ffi::USAGE_MATCH_TYPE_OR;
 
-            let mut identifiers = [szOID_PKIX_KP_SERVER_AUTH.as_ptr() as winapi::LPSTR,
-                                   szOID_SERVER_GATED_CRYPTO.as_ptr() as winapi::LPSTR,
-                                   szOID_SGC_NETSCAPE.as_ptr() as winapi::LPSTR];
-            para.RequestedUsage.Usage.cUsageIdentifier = identifiers.len() as winapi::DWORD;
+            let mut identifiers = [szOID_PKIX_KP_SERVER_AUTH.as_ptr() as ffi::LPSTR,
+                                   szOID_SERVER_GATED_CRYPTO.as_ptr() as ffi::LPSTR,
+                                   szOID_SGC_NETSCAPE.as_ptr() as ffi::LPSTR];
+            para.RequestedUsage.Usage.cUsageIdentifier = try!(::dword_len(identifiers.len()));
             para.RequestedUsage.Usage.rgpszUsageIdentifier = identifiers.as_mut_ptr();
 
+            // only the roots of the trust store are trusted if there is one
+            let engine = match self.trust_store {
+                Some(ref trust_store) => Some(try!(ChainEngine::exclusive(trust_store))),
+                None => None,
+            };
+            let engine_handle = engine.as_ref().map_or(ffi::HCCE_CURRENT_USER, |e| e.0);
+
             let mut cert_chain = mem::zeroed();
 
-            let res = crypt32::CertGetCertificateChain(ptr::null_mut(),
-                                                       cert_context.as_inner(),
-                                                       ptr::null_mut(),
-                                                       cert_store,
-                                                       &mut para,
-                                                       flags,
-                                                       ptr::null_mut(),
-                                                       &mut cert_chain);
+            let res = ffi::CertGetCertificateChain(engine_handle,
+                                                   cert_context.as_inner(),
+                                                   ptr::null_mut(),
+                                                   cert_store,
+                                                   &mut para as *mut _ as *mut _,
+                                                   flags,
+                                                   ptr::null_mut(),
+                                                   &mut cert_chain);
 
-            if res == winapi::TRUE {
+            if res == ffi::TRUE {
                 CertChainContext(cert_chain as *mut _)
             } else {
-                return Err(io::Error::last_os_error())
+                return Err(::error::last_error())
             }
         };
 
         unsafe {
             // check if we trust the root-CA explicitly
-            let mut para_flags = winapi::CERT_CHAIN_POLICY_IGNORE_ALL_REV_UNKNOWN_FLAGS;
+            let mut para_flags = ffi::CERT_CHAIN_POLICY_IGNORE_ALL_REV_UNKNOWN_FLAGS;
             if let Some(ref mut store) = self.cert_store {
                 if let Some(chain) = cert_chain.final_chain() {
                     // check if any cert of the chain is in the passed store (and therefore trusted)
                     if chain.certificates().any(|cert| store.certs().any(|root_cert| root_cert == cert)) {
-                        para_flags |= winapi::CERT_CHAIN_POLICY_ALLOW_UNKNOWN_CA_FLAG;
+                        debug!("chain contains a certificate from the configured store");
+                        para_flags |= ffi::CERT_CHAIN_POLICY_ALLOW_UNKNOWN_CA_FLAG;
                     }
                 }
             }
 
-            let mut extra_para: winapi::SSL_EXTRA_CERT_CHAIN_POLICY_PARA = mem::zeroed();
-            extra_para.cbSize = mem::size_of_val(&extra_para) as winapi::DWORD;
-            extra_para.dwAuthType = winapi::AUTHTYPE_SERVER;
-            if let Some(ref mut name) = self.domain {
-                extra_para.pwszServerName = name.as_mut_ptr();
-            }
+            // the hostname is checked below instead, as Windows neither
+            // follows RFC 6125 for wildcards nor handles IP addresses
+            let mut extra_para: ffi::SSL_EXTRA_CERT_CHAIN_POLICY_PARA = mem::zeroed();
+            extra_para.cbSize = mem::size_of_val(&extra_para) as ffi::DWORD;
+            extra_para.dwAuthType = ffi::AUTHTYPE_SERVER;
+            extra_para.fdwChecks = ffi::SECURITY_FLAG_IGNORE_CERT_CN_INVALID;
 
-            let mut para: winapi::CERT_CHAIN_POLICY_PARA = mem::zeroed();
-            para.cbSize = mem::size_of_val(&para) as winapi::DWORD;
+            let mut para: ffi::CERT_CHAIN_POLICY_PARA = mem::zeroed();
+            para.cbSize = mem::size_of_val(&para) as ffi::DWORD;
             para.dwFlags = para_flags;
             para.pvExtraPolicyPara = &mut extra_para as *mut _ as *mut _;
 
-            let mut status: winapi::CERT_CHAIN_POLICY_STATUS = mem::zeroed();
-            status.cbSize = mem::size_of_val(&status) as winapi::DWORD;
+            let mut status: ffi::CERT_CHAIN_POLICY_STATUS = mem::zeroed();
+            status.cbSize = mem::size_of_val(&status) as ffi::DWORD;
 
-            let verify_chain_policy_structure = winapi::CERT_CHAIN_POLICY_SSL as winapi::LPCSTR;
-            let res = crypt32::CertVerifyCertificateChainPolicy(verify_chain_policy_structure,
-                                                                cert_chain.0,
-                                                                &mut para,
-                                                                &mut status);
-            if res == winapi::FALSE {
-                return Err(io::Error::last_os_error())
+            let verify_chain_policy_structure = ffi::CERT_CHAIN_POLICY_SSL as ffi::LPCSTR;
+            let res = ffi::CertVerifyCertificateChainPolicy(verify_chain_policy_structure,
+                                                            cert_chain.0,
+                                                            &mut para,
+                                                            &mut status);
+            if res == ffi::FALSE {
+                return Err(::error::last_error())
             }
 
-            let mut verify_result = if status.dwError != winapi::ERROR_SUCCESS {
-                Err(io::Error::from_raw_os_error(status.dwError as i32))
+            debug!("chain policy returned {:#x} (chain {}, element {})",
+                   status.dwError,
+                   status.lChainIndex,
+                   status.lElementIndex);
+            if status.dwError == ffi::ERROR_SUCCESS && !self.accept_invalid_hostnames {
+                if let Some(ref domain) = self.domain {
+                    let domain = String::from_utf16_lossy(&domain[..domain.len() - 1]);
+                    if !try!(hostname::verify(&cert_context, &Reference::parse(&domain))) {
+                        debug!("the certificate was not issued for {}", domain);
+                        status.dwError = ffi::CERT_E_CN_NO_MATCH as ffi::DWORD;
+                        status.lChainIndex = 0;
+                        status.lElementIndex = 0;
+                    }
+                }
+            }
+            let mut verify_result = if status.dwError != ffi::ERROR_SUCCESS {
+                Err(::error::from_code(status.dwError as i32))
             } else {
                 Ok(())
             };
@@ -613,6 +1265,8 @@ impl<S> TlsStream<S>
                     res: status.dwError as i32,
                     chain_index: status.lChainIndex,
                     element_index: status.lElementIndex});
+                debug!("verify callback {} the certificate",
+                       if verify_result.is_ok() { "accepted" } else { "rejected" });
             }
             try!(verify_result);
         }
@@ -624,6 +1278,10 @@ impl<S> TlsStream<S>
         while self.out_buf.position() as usize != self.out_buf.get_ref().len() {
             let position = self.out_buf.position() as usize;
             let nwritten = try!(self.stream.write(&self.out_buf.get_ref()[position..]));
+            if nwritten == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                          "failed to write buffered TLS data"));
+            }
             out += nwritten;
             self.out_buf.set_position((position + nwritten) as u64);
         }
@@ -632,6 +1290,18 @@ impl<S> TlsStream<S>
     }
 
     fn read_in(&mut self) -> io::Result<usize> {
+        // once everything decrypted in place has been read, the space it
+        // takes up can be reused
+        if self.dec_start == self.dec_end && self.enc_start > 0 {
+            let start = self.enc_start;
+            self.enc_in.get_mut().drain(..start);
+            let len = self.enc_in.position() as usize - start;
+            self.enc_in.set_position(len as u64);
+            self.enc_start = 0;
+            self.dec_start = 0;
+            self.dec_end = 0;
+        }
+
         let mut sum_nread = 0;
 
         while self.needs_read > 0 {
@@ -655,60 +1325,58 @@ impl<S> TlsStream<S>
         Ok(sum_nread)
     }
 
-    fn consume_enc_in(&mut self, nread: usize) {
-        let size = self.enc_in.position() as usize;
-        assert!(size >= nread);
-        let count = size - nread;
-
-        if count > 0 {
-            self.enc_in.get_mut().drain(..nread);
-        }
+    // the length of the input which has not been consumed yet
+    fn enc_in_len(&self) -> usize {
+        self.enc_in.position() as usize - self.enc_start
+    }
 
-        self.enc_in.set_position(count as u64);
+    fn consume_enc_in(&mut self, nread: usize) {
+        assert!(self.enc_in_len() >= nread);
+        self.enc_start += nread;
     }
 
     fn decrypt(&mut self) -> io::Result<bool> {
         unsafe {
+            let start = self.enc_start;
             let position = self.enc_in.position() as usize;
-            let mut bufs = [secbuf(winapi::SECBUFFER_DATA,
-                                   Some(&mut self.enc_in.get_mut()[..position])),
-                            secbuf(winapi::SECBUFFER_EMPTY, None),
-                            secbuf(winapi::SECBUFFER_EMPTY, None),
-                            secbuf(winapi::SECBUFFER_EMPTY, None)];
+            let mut bufs = [try!(secbuf(ffi::SECBUFFER_DATA,
+                                        Some(&mut self.enc_in.get_mut()[start..position]))),
+                            try!(secbuf(ffi::SECBUFFER_EMPTY, None)),
+                            try!(secbuf(ffi::SECBUFFER_EMPTY, None)),
+                            try!(secbuf(ffi::SECBUFFER_EMPTY, None))];
             let mut bufdesc = secbuf_desc(&mut bufs);
 
-            match secur32::DecryptMessage(self.context.get_mut(),
-                                          &mut bufdesc,
-                                          0,
-                                          ptr::null_mut()) {
-                winapi::SEC_E_OK => {
+            let status = ffi::DecryptMessage(self.context.get_mut(),
+                                             &mut bufdesc,
+                                             0,
+                                             ptr::null_mut());
+            match try!(decrypted(status, self.allow_renegotiation)) {
+                Decrypted::Data => {
+                    // the record is decrypted in place, so it is read
+                    // straight out of enc_in
                     let start = bufs[1].pvBuffer as usize - self.enc_in.get_ref().as_ptr() as usize;
-                    let end = start + bufs[1].cbBuffer as usize;
-                    self.dec_in.get_mut().clear();
-                    self.dec_in
-                        .get_mut()
-                        .extend_from_slice(&self.enc_in.get_ref()[start..end]);
-                    self.dec_in.set_position(0);
-
-                    let nread = if bufs[3].BufferType == winapi::SECBUFFER_EXTRA {
-                        self.enc_in.position() as usize - bufs[3].cbBuffer as usize
-                    } else {
-                        self.enc_in.position() as usize
-                    };
+                    self.dec_start = start;
+                    self.dec_end = start + bufs[1].cbBuffer as usize;
+
+                    let nread = consumed(self.enc_in_len(), &bufs[3]);
                     self.consume_enc_in(nread);
-                    self.needs_read = (self.enc_in.position() == 0) as usize;
+                    self.needs_read = (self.enc_in_len() == 0) as usize;
                     Ok(false)
                 }
-                winapi::SEC_E_INCOMPLETE_MESSAGE => {
-                    self.needs_read = if bufs[1].BufferType == winapi::SECBUFFER_MISSING {
+                Decrypted::Incomplete => {
+                    self.needs_read = if bufs[1].BufferType == ffi::SECBUFFER_MISSING {
                         bufs[1].cbBuffer as usize
                     } else {
                         1
                     };
                     Ok(false)
                 }
-                winapi::SEC_I_CONTEXT_EXPIRED => Ok(true),
-                winapi::SEC_I_RENEGOTIATE => {
+                Decrypted::Closed => {
+                    debug!("peer closed the session");
+                    Ok(true)
+                }
+                Decrypted::Renegotiate => {
+                    debug!("peer requested renegotiation");
                     self.state = State::Initializing {
                         needs_flush: false,
                         more_calls: true,
@@ -716,63 +1384,220 @@ impl<S> TlsStream<S>
                         validated: false,
                     };
 
-                    let nread = if bufs[3].BufferType == winapi::SECBUFFER_EXTRA {
-                        self.enc_in.position() as usize - bufs[3].cbBuffer as usize
-                    } else {
-                        self.enc_in.position() as usize
-                    };
+                    let nread = consumed(self.enc_in_len(), &bufs[3]);
                     self.consume_enc_in(nread);
-                    self.needs_read = (self.enc_in.position() == 0) as usize;
+                    self.needs_read = (self.enc_in_len() == 0) as usize;
                     Ok(false)
                 }
-                e => Err(io::Error::from_raw_os_error(e as i32)),
             }
         }
     }
 
-    fn encrypt(&mut self, buf: &[u8], sizes: &winapi::SecPkgContext_StreamSizes) -> io::Result<()> {
-        assert!(buf.len() <= sizes.cbMaximumMessage as usize);
+    /// Encrypts as many records of `buf` as fit in one write into `out_buf`,
+    /// returning the number of bytes of `buf` encrypted.
+    fn encrypt(&mut self, buf: &[u8], sizes: &ffi::SecPkgContext_StreamSizes) -> io::Result<usize> {
+        let header = sizes.cbHeader as usize;
+        let trailer = sizes.cbTrailer as usize;
+        let max_message = sizes.cbMaximumMessage as usize;
+
+        self.out_buf.get_mut().truncate(0);
+        self.out_buf.set_position(0);
+
+        let mut nencrypted = 0;
+        for chunk in buf.chunks(max_message).take(MAX_WRITE_RECORDS) {
+            match self.encrypt_record(chunk, header, trailer) {
+                Ok(()) => nencrypted += chunk.len(),
+                // the records encrypted so far must still be sent, and the
+                // error will come up again on the next write
+                Err(_) if nencrypted > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(nencrypted)
+    }
 
+    fn encrypt_record(&mut self, buf: &[u8], header: usize, trailer: usize) -> io::Result<()> {
         unsafe {
-            let len = sizes.cbHeader as usize + buf.len() + sizes.cbTrailer as usize;
-
-            if self.out_buf.get_ref().len() < len {
-                self.out_buf.get_mut().resize(len, 0);
-            }
+            let start = self.out_buf.get_ref().len();
+            // does not reallocate once out_buf has grown to its largest size
+            self.out_buf.get_mut().resize(start + header + buf.len() + trailer, 0);
 
-            let message_start = sizes.cbHeader as usize;
+            let message_start = start + header;
             self.out_buf
                 .get_mut()[message_start..message_start + buf.len()]
                 .clone_from_slice(buf);
 
             let mut bufs = {
-                let out_buf = self.out_buf.get_mut();
-                let size = sizes.cbHeader as usize;
-
-                let header = secbuf(winapi::SECBUFFER_STREAM_HEADER,
-                                    Some(&mut out_buf[..size]));
-                let data = secbuf(winapi::SECBUFFER_DATA,
-                                  Some(&mut out_buf[size..size + buf.len()]));
-                let trailer = secbuf(winapi::SECBUFFER_STREAM_TRAILER,
-                                     Some(&mut out_buf[size + buf.len()..]));
-                let empty = secbuf(winapi::SECBUFFER_EMPTY, None);
-                [header, data, trailer, empty]
+                let out_buf = &mut self.out_buf.get_mut()[start..];
+
+                let header_buf = try!(secbuf(ffi::SECBUFFER_STREAM_HEADER,
+                                             Some(&mut out_buf[..header])));
+                let data = try!(secbuf(ffi::SECBUFFER_DATA,
+                                       Some(&mut out_buf[header..header + buf.len()])));
+                let trailer_buf = try!(secbuf(ffi::SECBUFFER_STREAM_TRAILER,
+                                              Some(&mut out_buf[header + buf.len()..])));
+                let empty = try!(secbuf(ffi::SECBUFFER_EMPTY, None));
+                [header_buf, data, trailer_buf, empty]
             };
             let mut bufdesc = secbuf_desc(&mut bufs);
 
-            match secur32::EncryptMessage(self.context.get_mut(), 0, &mut bufdesc, 0) {
-                winapi::SEC_E_OK => {
+            match ffi::EncryptMessage(self.context.get_mut(), 0, &mut bufdesc, 0) {
+                ffi::SEC_E_OK => {
+                    // the trailer may be shorter than its maximum size
                     let len = bufs[0].cbBuffer + bufs[1].cbBuffer + bufs[2].cbBuffer;
-                    self.out_buf.get_mut().truncate(len as usize);
-                    self.out_buf.set_position(0);
+                    self.out_buf.get_mut().truncate(start + len as usize);
                     Ok(())
                 }
-                err => Err(io::Error::from_raw_os_error(err as i32)),
+                err => {
+                    self.out_buf.get_mut().truncate(start);
+                    Err(::error::from_code(err as i32))
+                }
             }
         }
     }
 }
 
+/// A certificate chain engine which only trusts the roots of a store.
+struct ChainEngine(ffi::HCERTCHAINENGINE);
+
+impl ChainEngine {
+    fn exclusive(roots: &CertStore) -> io::Result<ChainEngine> {
+        unsafe {
+            let mut config: ffi::CERT_CHAIN_ENGINE_CONFIG = mem::zeroed();
+            config.cbSize = mem::size_of_val(&config) as ffi::DWORD;
+            config.hExclusiveRoot = roots.as_inner();
+
+            let mut engine = mem::zeroed();
+            if ffi::CertCreateCertificateChainEngine(&mut config, &mut engine) == ffi::TRUE {
+                Ok(ChainEngine(engine))
+            } else {
+                Err(::error::last_error())
+            }
+        }
+    }
+}
+
+impl Drop for ChainEngine {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::CertFreeCertificateChainEngine(self.0);
+        }
+    }
+}
+
+/// Acquires a credential with the settings of `cred`, restricted to the
+/// protocols and algorithms it has in common with `enabled_protocols` and
+/// `supported_algorithms`.
+///
+/// Returns `None` if the settings of `cred` are unknown.
+fn restrict_cred(cred: &SchannelCred,
+                 enabled_protocols: Option<&[Protocol]>,
+                 supported_algorithms: Option<&[Algorithm]>)
+                 -> io::Result<Option<SchannelCred>> {
+    let &(ref builder, direction) = match cred.settings() {
+        Some(settings) => settings,
+        None => return Ok(None),
+    };
+    let mut builder = builder.clone();
+
+    if let Some(enabled_protocols) = enabled_protocols {
+        let protocols = match builder.get_enabled_protocols() {
+            Some(protocols) => {
+                enabled_protocols.iter().filter(|p| protocols.contains(p)).cloned().collect()
+            }
+            None => enabled_protocols.to_owned(),
+        };
+        // an empty set would enable the system defaults instead
+        if protocols.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "none of the protocols are enabled for the credential"));
+        }
+        builder.enabled_protocols(&protocols);
+    }
+
+    if let Some(supported_algorithms) = supported_algorithms {
+        let algorithms = match builder.get_supported_algorithms() {
+            Some(algorithms) => {
+                // either list may name AES without a key size
+                let mut common = algorithms.iter()
+                    .cloned()
+                    .filter(|&a| algorithm_supported(supported_algorithms, a as ffi::ALG_ID))
+                    .collect::<Vec<_>>();
+                let narrower = supported_algorithms.iter()
+                    .cloned()
+                    .filter(|&a| algorithm_supported(algorithms, a as ffi::ALG_ID))
+                    .filter(|a| !common.contains(a))
+                    .collect::<Vec<_>>();
+                common.extend(narrower);
+                common
+            }
+            None => supported_algorithms.to_owned(),
+        };
+        if algorithms.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "none of the algorithms are supported by the credential"));
+        }
+        builder.supported_algorithms(&algorithms);
+    }
+
+    builder.acquire(direction).map(Some)
+}
+
+/// Determines if a negotiated algorithm is among `supported_algorithms`.
+fn algorithm_supported(supported_algorithms: &[Algorithm], alg_id: ffi::ALG_ID) -> bool {
+    // nothing to check when Schannel does not report the algorithm
+    if alg_id == 0 {
+        return true;
+    }
+    supported_algorithms.iter().any(|&a| {
+        let a = a as ffi::ALG_ID;
+        a == alg_id ||
+        (a == ffi::CALG_AES && (alg_id == ffi::CALG_AES_128 ||
+                                alg_id == ffi::CALG_AES_192 ||
+                                alg_id == ffi::CALG_AES_256)) ||
+        (a == ffi::CALG_ECDH && alg_id == ffi::CALG_ECDH_EPHEM)
+    })
+}
+
+/// How a stream proceeds after a call to `DecryptMessage`.
+#[derive(Debug, PartialEq)]
+enum Decrypted {
+    /// A record was decrypted.
+    Data,
+    /// More input is needed to decrypt a record.
+    Incomplete,
+    /// The peer sent a close_notify alert.
+    Closed,
+    /// The peer asked for a new handshake, which starts with the unconsumed
+    /// input.
+    Renegotiate,
+}
+
+fn decrypted(status: ffi::SECURITY_STATUS, allow_renegotiation: bool) -> io::Result<Decrypted> {
+    match status {
+        ffi::SEC_E_OK => Ok(Decrypted::Data),
+        ffi::SEC_E_INCOMPLETE_MESSAGE => Ok(Decrypted::Incomplete),
+        ffi::SEC_I_CONTEXT_EXPIRED => Ok(Decrypted::Closed),
+        ffi::SEC_I_RENEGOTIATE if allow_renegotiation => Ok(Decrypted::Renegotiate),
+        ffi::SEC_I_RENEGOTIATE => {
+            debug!("refusing renegotiation");
+            Err(io::Error::new(io::ErrorKind::Other,
+                               "the peer requested renegotiation, which is disabled"))
+        }
+        e => Err(::error::from_code(e as i32)),
+    }
+}
+
+/// Returns how much of the `len` bytes of input passed to an SSPI call was
+/// consumed, given the buffer in which it may have reported leftover input.
+fn consumed(len: usize, extra: &ffi::SecBuffer) -> usize {
+    if extra.BufferType == ffi::SECBUFFER_EXTRA {
+        len - extra.cbBuffer as usize
+    } else {
+        len
+    }
+}
+
 impl<S> MidHandshakeTlsStream<S>
     where S: Read + Write,
 {
@@ -787,6 +1612,12 @@ impl<S> MidHandshakeTlsStream<S>
     }
 
     /// Restarts the handshake process.
+    ///
+    /// This should be called once the wrapped stream is ready for more I/O,
+    /// which can be waited for through `get_ref` or `get_mut`. Handshake
+    /// data which was not yet written or fully read when the stream was
+    /// interrupted is kept, and `HandshakeError::Interrupted` is returned
+    /// again if the wrapped stream would still block.
     pub fn handshake(mut self) -> Result<TlsStream<S>, HandshakeError<S>> {
         match self.inner.initialize() {
             Ok(_) => Ok(self.inner),
@@ -807,15 +1638,13 @@ impl<S> Write for TlsStream<S>
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let sizes = match try!(self.initialize()) {
             Some(sizes) => sizes,
-            None => return Err(io::Error::from_raw_os_error(winapi::SEC_E_CONTEXT_EXPIRED as i32)),
+            None => return Err(::error::from_code(ffi::SEC_E_CONTEXT_EXPIRED as i32)),
         };
 
         // if we have pending output data, it must have been because a previous
         // attempt to send this part of the data ran into an error.
         if self.out_buf.position() == self.out_buf.get_ref().len() as u64 {
-            let len = cmp::min(buf.len(), sizes.cbMaximumMessage as usize);
-            try!(self.encrypt(&buf[..len], &sizes));
-            self.last_write_len = len;
+            self.last_write_len = try!(self.encrypt(buf, &sizes));
         }
         try!(self.write_out());
 
@@ -870,8 +1699,89 @@ impl<S> BufRead for TlsStream<S>
     }
 
     fn consume(&mut self, amt: usize) {
-        let pos = self.dec_in.position() + amt as u64;
-        assert!(pos <= self.dec_in.get_ref().len() as u64);
-        self.dec_in.set_position(pos);
+        let pos = self.dec_start + amt;
+        assert!(pos <= self.dec_end);
+        self.dec_start = pos;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use ffi;
+    use schannel_cred::Direction;
+    use secbuf;
+    use super::*;
+
+    #[test]
+    fn decrypt_status() {
+        assert_eq!(decrypted(ffi::SEC_E_OK, false).unwrap(), Decrypted::Data);
+        assert_eq!(decrypted(ffi::SEC_E_INCOMPLETE_MESSAGE, false).unwrap(),
+                   Decrypted::Incomplete);
+        assert_eq!(decrypted(ffi::SEC_I_CONTEXT_EXPIRED, false).unwrap(), Decrypted::Closed);
+        assert_eq!(decrypted(ffi::SEC_I_RENEGOTIATE, true).unwrap(), Decrypted::Renegotiate);
+
+        let err = decrypted(ffi::SEC_I_RENEGOTIATE, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(::error::code(&err).is_none());
+
+        let err = decrypted(ffi::SEC_E_DECRYPT_FAILURE, true).unwrap_err();
+        assert_eq!(::error::code(&err), Some(ffi::SEC_E_DECRYPT_FAILURE as i32));
+    }
+
+    #[test]
+    fn extra_input() {
+        unsafe {
+            let mut extra = [0; 5];
+            let extra_buf = secbuf(ffi::SECBUFFER_EXTRA, Some(&mut extra)).unwrap();
+            assert_eq!(consumed(20, &extra_buf), 15);
+            assert_eq!(consumed(20, &secbuf(ffi::SECBUFFER_EMPTY, None).unwrap()), 20);
+            let missing = secbuf(ffi::SECBUFFER_MISSING, Some(&mut extra)).unwrap();
+            assert_eq!(consumed(20, &missing), 20);
+        }
+    }
+
+    #[test]
+    fn supported_algorithms() {
+        let algorithms = [Algorithm::Aes, Algorithm::Sha256, Algorithm::Ecdh];
+        assert!(algorithm_supported(&algorithms, ffi::CALG_AES_128));
+        assert!(algorithm_supported(&algorithms, ffi::CALG_AES_256));
+        assert!(algorithm_supported(&algorithms, ffi::CALG_SHA_256));
+        assert!(algorithm_supported(&algorithms, ffi::CALG_ECDH_EPHEM));
+        assert!(algorithm_supported(&algorithms, 0));
+        assert!(!algorithm_supported(&algorithms, ffi::CALG_RC4));
+        assert!(!algorithm_supported(&algorithms, ffi::CALG_3DES));
+        assert!(!algorithm_supported(&algorithms, ffi::CALG_SHA1));
+
+        assert!(!algorithm_supported(&[Algorithm::Aes128], ffi::CALG_AES_256));
+    }
+
+    #[test]
+    fn restricted_cred() {
+        let cred = SchannelCred::builder()
+            .enabled_protocols(&[Protocol::Tls11, Protocol::Tls12])
+            .supported_algorithms(&[Algorithm::Aes, Algorithm::Sha256, Algorithm::RsaKeyx])
+            .acquire(Direction::Outbound)
+            .unwrap();
+
+        let restricted = restrict_cred(&cred,
+                                       Some(&[Protocol::Tls10, Protocol::Tls12][..]),
+                                       Some(&[Algorithm::Aes128, Algorithm::Sha256,
+                                              Algorithm::Sha1][..]))
+            .unwrap()
+            .unwrap();
+        let &(ref builder, direction) = restricted.settings().unwrap();
+        assert_eq!(direction, Direction::Outbound);
+        assert_eq!(builder.get_enabled_protocols(), Some(&[Protocol::Tls12][..]));
+        assert_eq!(builder.get_supported_algorithms(),
+                   Some(&[Algorithm::Sha256, Algorithm::Aes128][..]));
+
+        let err = restrict_cred(&cred, Some(&[Protocol::Tls10][..]), None).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let raw = SchannelCred::builder().acquire(Direction::Outbound).unwrap();
+        let raw = unsafe { SchannelCred::from_raw(raw.into_raw().ok().unwrap()) };
+        assert!(restrict_cred(&raw, Some(&[Protocol::Tls12][..]), None).unwrap().is_none());
     }
 }